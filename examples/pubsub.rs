@@ -0,0 +1,69 @@
+//! Measures how long it takes to deliver large messages to many subscribers
+//! of the same channel. Every subscriber gets the same published buffer, so
+//! the store doesn't make a copy of the message for each of them. Run it
+//! with optimizations:
+//!
+//! ```text
+//! cargo run --release --example pubsub
+//! ```
+
+use std::time::{Duration, Instant};
+
+use redis_starter_rust::{server::Server, testclient::TestClient, types::RedisType};
+
+const SUBSCRIBERS: usize = 1000;
+const MESSAGE_SIZE: usize = 64 * 1024;
+const MESSAGES: usize = 20;
+
+/// Time from the first PUBLISH until every subscriber got every message
+async fn measure() -> Duration {
+    let server = Server::builder().bind("127.0.0.1:0").run().await.unwrap();
+    let address = server.local_addr();
+
+    let mut subscribers = vec![];
+    for _ in 0..SUBSCRIBERS {
+        let mut connection = TestClient::connect(address).await.unwrap();
+        connection.raw(&["SUBSCRIBE", "news"]).await.unwrap();
+        subscribers.push(connection);
+    }
+
+    let mut tasks = vec![];
+    for mut connection in subscribers {
+        tasks.push(tokio::spawn(async move {
+            for _ in 0..MESSAGES {
+                match connection.read().await.unwrap() {
+                    RedisType::Array(parts) if parts.len() == 3 => {}
+                    other => panic!("unexpected message: {other:?}"),
+                }
+            }
+            Instant::now()
+        }));
+    }
+
+    let message = "x".repeat(MESSAGE_SIZE);
+    let mut publisher = TestClient::connect(address).await.unwrap();
+    let start = Instant::now();
+    for _ in 0..MESSAGES {
+        publisher.raw(&["PUBLISH", "news", &message]).await.unwrap();
+    }
+
+    let mut finish = start;
+    for task in tasks {
+        finish = finish.max(task.await.unwrap());
+    }
+    server.shutdown().await.unwrap();
+
+    finish - start
+}
+
+#[tokio::main]
+async fn main() {
+    let elapsed = measure().await;
+    let delivered = (SUBSCRIBERS * MESSAGES * MESSAGE_SIZE) as f64 / (1024.0 * 1024.0);
+    println!(
+        "{MESSAGES} messages of {}KB to {SUBSCRIBERS} subscribers: {:.1}ms per message, {:.0}MB/s delivered",
+        MESSAGE_SIZE / 1024,
+        elapsed.as_secs_f64() * 1e3 / MESSAGES as f64,
+        delivered / elapsed.as_secs_f64(),
+    );
+}
@@ -0,0 +1,58 @@
+//! Compares the latency of a store served by a single task with that of a
+//! sharded one, under many clients at once. The shards can only run in
+//! parallel with several cores to spare: with a single one, all there is to
+//! see is the cost of sharding. Run it with optimizations:
+//!
+//! ```text
+//! cargo run --release --example shards
+//! ```
+
+use std::{
+    thread::available_parallelism,
+    time::{Duration, Instant},
+};
+
+use redis_starter_rust::{config::Configuration, server::Server, testclient::TestClient};
+
+const CLIENTS: usize = 64;
+const REQUESTS: usize = 2000;
+
+/// Average time each command took, from the point of view of the clients
+async fn measure(shards: usize) -> Duration {
+    let mut config = Configuration::default();
+    config.update("store-shards".into(), shards.to_string()).unwrap();
+    let server = Server::builder().config(config).bind("127.0.0.1:0").run().await.unwrap();
+    let address = server.local_addr();
+
+    let mut tasks = vec![];
+    for client in 0..CLIENTS {
+        tasks.push(tokio::spawn(async move {
+            let mut connection = TestClient::connect(address).await.unwrap();
+            let start = Instant::now();
+            for request in 0..REQUESTS {
+                let key = format!("key:{client}:{}", request % 100);
+                connection.set(&key, "value").await.unwrap();
+                connection.get(&key).await.unwrap();
+            }
+            start.elapsed()
+        }));
+    }
+
+    let mut total = Duration::ZERO;
+    for task in tasks {
+        total += task.await.unwrap();
+    }
+    server.shutdown().await.unwrap();
+
+    total / (CLIENTS * REQUESTS * 2) as u32
+}
+
+#[tokio::main]
+async fn main() {
+    let cores = available_parallelism().map_or(1, |cores| cores.get());
+    println!("{cores} core(s) available");
+    for shards in [1, 2, 4, 8] {
+        let latency = measure(shards).await;
+        println!("{shards} shard(s): {:.1}µs per command, {CLIENTS} clients", latency.as_secs_f64() * 1e6);
+    }
+}
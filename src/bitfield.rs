@@ -0,0 +1,325 @@
+use anyhow::{bail, Error, Result};
+
+use crate::common_cli_rep::parse_integer;
+
+// Strings can't be longer than 512MB, so bit offsets stay within that many bits
+const MAX_BIT_OFFSET: u64 = 512 * 1024 * 1024 * 8;
+
+/// Type of a field: signed or unsigned, and its width in bits. Signed fields
+/// go up to 64 bits, unsigned ones up to 63 (like in Redis)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FieldType {
+    signed: bool,
+    bits: u32,
+}
+
+impl FieldType {
+    fn parse(arg: &str) -> Result<Self> {
+        let invalid = || Error::msg("Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is.");
+        let signed = match arg.chars().next() {
+            Some('i' | 'I') => true,
+            Some('u' | 'U') => false,
+            _ => return Err(invalid()),
+        };
+        let bits = arg[1..].parse::<u32>().map_err(|_| invalid())?;
+        let max_bits = if signed { 64 } else { 63 };
+        if bits == 0 || bits > max_bits {
+            return Err(invalid())
+        }
+
+        Ok(FieldType { signed, bits })
+    }
+
+    fn min(&self) -> i128 {
+        if self.signed { -(1 << (self.bits - 1)) } else { 0 }
+    }
+
+    fn max(&self) -> i128 {
+        if self.signed { (1 << (self.bits - 1)) - 1 } else { (1 << self.bits) - 1 }
+    }
+
+    /// Turn the raw bits into a number, extending the sign if needed
+    fn decode(&self, raw: u64) -> i64 {
+        if self.signed && self.bits < 64 && raw & (1 << (self.bits - 1)) != 0 {
+            (raw | (u64::MAX << self.bits)) as i64
+        } else {
+            raw as i64
+        }
+    }
+
+    /// Apply the overflow policy to a value that may not fit in the field.
+    /// Returns `None` if the operation must fail
+    fn fit(&self, value: i128, overflow: Overflow) -> Option<i64> {
+        if (self.min()..=self.max()).contains(&value) {
+            return Some(value as i64)
+        }
+
+        match overflow {
+            Overflow::Wrap => {
+                let raw = value.rem_euclid(1 << self.bits) as u64;
+                Some(self.decode(raw))
+            }
+            Overflow::Sat => Some(value.clamp(self.min(), self.max()) as i64),
+            Overflow::Fail => None,
+        }
+    }
+}
+
+impl std::fmt::Display for FieldType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", if self.signed { 'i' } else { 'u' }, self.bits)
+    }
+}
+
+/// What to do when SET or INCRBY don't fit in the field
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Overflow {
+    #[default]
+    Wrap,
+    Sat,
+    Fail,
+}
+
+impl Overflow {
+    fn name(&self) -> &'static str {
+        match self {
+            Overflow::Wrap => "WRAP",
+            Overflow::Sat => "SAT",
+            Overflow::Fail => "FAIL",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BitfieldOp {
+    Get { field: FieldType, offset: u64 },
+    Set { field: FieldType, offset: u64, value: i64, overflow: Overflow },
+    IncrBy { field: FieldType, offset: u64, increment: i64, overflow: Overflow },
+}
+
+impl BitfieldOp {
+    pub fn is_write(&self) -> bool {
+        !matches!(self, BitfieldOp::Get { .. })
+    }
+
+    /// Last bit touched by the operation, plus one
+    fn end(&self) -> u64 {
+        match self {
+            BitfieldOp::Get { field, offset } |
+            BitfieldOp::Set { field, offset, .. } |
+            BitfieldOp::IncrBy { field, offset, .. } => offset + field.bits as u64,
+        }
+    }
+
+    /// Arguments to run the same operation again, eg. on a replica
+    pub fn to_args(&self) -> Vec<String> {
+        match self {
+            BitfieldOp::Get { field, offset } => {
+                vec!["GET".into(), field.to_string(), offset.to_string()]
+            }
+            BitfieldOp::Set { field, offset, value, overflow } => vec![
+                "OVERFLOW".into(), overflow.name().into(),
+                "SET".into(), field.to_string(), offset.to_string(), value.to_string(),
+            ],
+            BitfieldOp::IncrBy { field, offset, increment, overflow } => vec![
+                "OVERFLOW".into(), overflow.name().into(),
+                "INCRBY".into(), field.to_string(), offset.to_string(), increment.to_string(),
+            ],
+        }
+    }
+}
+
+/// Parse an offset. "#N" means the N-th field of the given type
+fn parse_offset(arg: &str, field: FieldType) -> Result<u64> {
+    let invalid = || Error::msg("bit offset is not an integer or out of range");
+    let (multiplier, number) = match arg.strip_prefix('#') {
+        Some(number) => (field.bits as u64, number),
+        None => (1, arg),
+    };
+
+    number.parse::<u64>().ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .filter(|&offset| offset <= MAX_BIT_OFFSET - field.bits as u64)
+        .ok_or_else(invalid)
+}
+
+/// Parse the arguments of BITFIELD, after the key
+pub fn parse_bitfield(args: &[&str]) -> Result<Vec<BitfieldOp>> {
+    let mut ops = vec![];
+    let mut overflow = Overflow::default();
+    let mut args = args.iter();
+
+    while let Some(subcommand) = args.next() {
+        let subcommand = subcommand.to_ascii_lowercase();
+        if subcommand == "overflow" {
+            let Some(mode) = args.next() else { bail!("syntax error") };
+            overflow = match mode.to_ascii_lowercase().as_str() {
+                "wrap" => Overflow::Wrap,
+                "sat" => Overflow::Sat,
+                "fail" => Overflow::Fail,
+                _ => bail!("Invalid OVERFLOW type specified"),
+            };
+            continue
+        }
+
+        let arg_count = match subcommand.as_str() {
+            "get" => 2,
+            "set" | "incrby" => 3,
+            _ => bail!("syntax error"),
+        };
+        let op_args = args.by_ref().take(arg_count).collect::<Vec<_>>();
+        if op_args.len() != arg_count {
+            bail!("syntax error")
+        }
+        let field = FieldType::parse(op_args[0])?;
+        let offset = parse_offset(op_args[1], field)?;
+
+        ops.push(match subcommand.as_str() {
+            "get" => BitfieldOp::Get { field, offset },
+            "set" => BitfieldOp::Set { field, offset, value: parse_integer(op_args[2])?, overflow },
+            _ => BitfieldOp::IncrBy { field, offset, increment: parse_integer(op_args[2])?, overflow },
+        });
+    }
+
+    Ok(ops)
+}
+
+/// Read `bits` bits starting at `offset`. Bit 0 is the most significant bit of
+/// the first byte. Bits past the end of the string read as 0
+fn get_bits(bytes: &[u8], offset: u64, bits: u32) -> u64 {
+    (offset..offset + bits as u64).fold(0, |raw, position| {
+        let byte = bytes.get((position / 8) as usize).copied().unwrap_or(0);
+        let bit = (byte >> (7 - position % 8)) & 1;
+        (raw << 1) | bit as u64
+    })
+}
+
+/// Write the lowest `bits` bits of `raw` starting at `offset`. The string must
+/// be long enough
+fn set_bits(bytes: &mut [u8], offset: u64, bits: u32, raw: u64) {
+    for index in 0..bits {
+        let position = offset + index as u64;
+        let bit = (raw >> (bits - 1 - index)) & 1;
+        let mask = 1 << (7 - position % 8);
+        let byte = &mut bytes[(position / 8) as usize];
+        if bit == 1 {
+            *byte |= mask;
+        } else {
+            *byte &= !mask;
+        }
+    }
+}
+
+/// Run the operations on a string, growing it if a write needs more room.
+/// Returns one result per operation: `None` when an overflow made it fail
+pub fn apply(bytes: &mut Vec<u8>, ops: &[BitfieldOp]) -> Vec<Option<i64>> {
+    let needed = ops.iter()
+        .filter(|op| op.is_write())
+        .map(|op| op.end().div_ceil(8) as usize)
+        .max()
+        .unwrap_or(0);
+    if bytes.len() < needed {
+        bytes.resize(needed, 0);
+    }
+
+    ops.iter().map(|op| match *op {
+        BitfieldOp::Get { field, offset } => {
+            Some(field.decode(get_bits(bytes, offset, field.bits)))
+        }
+        BitfieldOp::Set { field, offset, value, overflow } => {
+            let previous = field.decode(get_bits(bytes, offset, field.bits));
+            let value = field.fit(value as i128, overflow)?;
+            set_bits(bytes, offset, field.bits, value as u64);
+            Some(previous)
+        }
+        BitfieldOp::IncrBy { field, offset, increment, overflow } => {
+            let current = field.decode(get_bits(bytes, offset, field.bits));
+            let value = field.fit(current as i128 + increment as i128, overflow)?;
+            set_bits(bytes, offset, field.bits, value as u64);
+            Some(value)
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitfield::{apply, parse_bitfield};
+
+    fn run(bytes: &mut Vec<u8>, args: &[&str]) -> Vec<Option<i64>> {
+        apply(bytes, &parse_bitfield(args).unwrap())
+    }
+
+    #[test]
+    fn test_get_and_set() {
+        let mut bytes = vec![];
+        assert_eq!(run(&mut bytes, &["SET", "u8", "0", "255", "GET", "u4", "4"]), vec![Some(0), Some(15)]);
+        assert_eq!(bytes, vec![0xff]);
+
+        // Fields don't need to be aligned, and "#N" counts fields instead of bits
+        assert_eq!(run(&mut bytes, &["SET", "u8", "#1", "66", "GET", "u8", "8", "GET", "u12", "4"]), vec![Some(0), Some(66), Some(0xf42)]);
+        assert_eq!(bytes, vec![0xff, 0x42]);
+
+        // Reading past the end doesn't grow the string
+        assert_eq!(run(&mut bytes, &["GET", "u16", "100"]), vec![Some(0)]);
+        assert_eq!(bytes.len(), 2);
+    }
+
+    #[test]
+    fn test_sign_extension() {
+        let mut bytes = vec![0xff, 0x80];
+        assert_eq!(run(&mut bytes, &["GET", "i8", "0", "GET", "u8", "0", "GET", "i4", "8", "GET", "i1", "8"]),
+                   vec![Some(-1), Some(255), Some(-8), Some(-1)]);
+        assert_eq!(run(&mut bytes, &["SET", "i16", "0", "-2", "GET", "u16", "0"]), vec![Some(-128), Some(0xfffe)]);
+
+        let mut bytes = vec![];
+        assert_eq!(run(&mut bytes, &["SET", "i64", "0", "-1", "GET", "i64", "0"]), vec![Some(0), Some(-1)]);
+    }
+
+    #[test]
+    fn test_overflow_wrap() {
+        let mut bytes = vec![];
+        assert_eq!(run(&mut bytes, &["INCRBY", "u8", "0", "300"]), vec![Some(44)]);
+        assert_eq!(run(&mut bytes, &["INCRBY", "u8", "0", "-45"]), vec![Some(255)]);
+        assert_eq!(run(&mut bytes, &["INCRBY", "i8", "8", "127", "INCRBY", "i8", "8", "1"]), vec![Some(127), Some(-128)]);
+        assert_eq!(run(&mut bytes, &["SET", "u4", "0", "17", "GET", "u4", "0"]), vec![Some(15), Some(1)]);
+        assert_eq!(run(&mut bytes, &["SET", "i64", "0", "9223372036854775807", "INCRBY", "i64", "0", "1"]),
+                   vec![Some(0x1f80_0000_0000_0000), Some(i64::MIN)]);
+    }
+
+    #[test]
+    fn test_overflow_sat() {
+        let mut bytes = vec![];
+        assert_eq!(run(&mut bytes, &["OVERFLOW", "SAT", "INCRBY", "u8", "0", "300"]), vec![Some(255)]);
+        assert_eq!(run(&mut bytes, &["OVERFLOW", "SAT", "INCRBY", "u8", "0", "-1000"]), vec![Some(0)]);
+        assert_eq!(run(&mut bytes, &["OVERFLOW", "SAT", "SET", "i8", "0", "-200", "GET", "i8", "0"]), vec![Some(0), Some(-128)]);
+        assert_eq!(run(&mut bytes, &["OVERFLOW", "SAT", "INCRBY", "i64", "8", "9223372036854775807", "INCRBY", "i64", "8", "10"]),
+                   vec![Some(i64::MAX), Some(i64::MAX)]);
+    }
+
+    #[test]
+    fn test_overflow_fail() {
+        let mut bytes = vec![];
+        assert_eq!(run(&mut bytes, &["OVERFLOW", "FAIL", "INCRBY", "u2", "0", "3", "INCRBY", "u2", "0", "1"]), vec![Some(3), None]);
+        assert_eq!(run(&mut bytes, &["GET", "u2", "0"]), vec![Some(3)]);
+        // The mode only applies to the operations after it
+        assert_eq!(run(&mut bytes, &["INCRBY", "u2", "0", "1", "OVERFLOW", "FAIL", "SET", "i4", "4", "8"]), vec![Some(0), None]);
+        assert_eq!(bytes, vec![0]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for wrong in [
+            &["GET", "u64", "0"][..],
+            &["GET", "i65", "0"],
+            &["GET", "x8", "0"],
+            &["GET", "u8", "-1"],
+            &["GET", "u8", "4294967296"],
+            &["GET", "u8"],
+            &["SET", "u8", "0", "ten"],
+            &["OVERFLOW", "SOMETIMES"],
+            &["FOO"],
+        ] {
+            assert!(parse_bitfield(wrong).is_err(), "{wrong:?} should be rejected");
+        }
+    }
+}
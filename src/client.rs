@@ -1,125 +1,1489 @@
-use std::sync::OnceLock;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Error, Result};
+use bytes::Bytes;
 use itertools::Itertools;
+use sha1::{Digest, Sha1};
 
 use tokio::{
-    sync::mpsc::{Receiver, Sender, self},
-    sync::oneshot,
-    io::{AsyncWriteExt, BufReader}, net::TcpStream,
+    sync::mpsc::{Receiver, Sender, WeakSender, self},
+    sync::{oneshot, watch},
+    fs::File,
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter}, net::TcpStream,
 };
 
 use crate::{
+    bitfield::parse_bitfield,
+    zset::{parse_score, parse_score_bound, parse_zadd, parse_zrange, parse_zrangebyscore, ZAddOptions},
+    info,
     io::*,
-    store::{CommandResponse, StoreCommand},
-    common_cli_rep::handle_set,
-    config::ConfigCommand,
-    types::RedisType,
+    notify::NotifyFlags,
+    replica::ConnectedReplica,
+    rdb::{write_rdb, RedisFileEntry},
+    shard::StoreHandle,
+    store::{CommandResponse, SetOperation, StoreCommand, TtlChange},
+    common_cli_rep::{parse_expire_condition, parse_expiry, parse_integer, parse_lpos_options, parse_set_options},
+    config::{parse_memory, ConfigCommand},
+    server::ShutdownTrigger,
+    types::{Protocol, RedisType},
 };
 
 const CLIENT_BUFFER: usize = 32;
-static HELLO_INFO: OnceLock<RedisType> = OnceLock::new();
+const MESSAGE_BUFFER: usize = 1024;
+const REPLICA_BUFFER: usize = 1024;
+const RDB_CHUNK_SIZE: usize = 64 * 1024;
 
-const HELP_LINES: [&str; 5] = [
+const HELP_LINES: [&str; 7] = [
     "CONFIG <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
     "GET <pattern>",
     "    Return parameters matching the glob-like <pattern> and their values.",
+    "SET <directive> <value>",
+    "    Set the configuration <directive> to <value>.",
     "HELP",
     "    Prints this help."
 ];
 
-pub fn init_static_data() {
-    HELLO_INFO.set(RedisType::Array(vec![
-        RedisType::String("server".into()),
-        RedisType::String("codecrafters-redis".into()),
-        RedisType::String("version".into()),
-        RedisType::String("0.2".into()),
-        RedisType::String("proto".into()),
-        RedisType::Int(2),
-        RedisType::String("mode".into()),
-        RedisType::String("standalone".into()),
-        RedisType::String("role".into()),
-        RedisType::String("master".into()),
-        RedisType::String("modules".into()),
-        RedisType::Array(vec![]),
-    ])).unwrap();
+/// Reply to HELLO, describing the server and the connection
+fn hello_info(proto: Protocol, id: usize) -> RedisType {
+    let version = match proto {
+        Protocol::Resp2 => 2,
+        Protocol::Resp3 => 3,
+    };
+    RedisType::Map(vec![
+        (RedisType::from("server"), RedisType::from("codecrafters-redis")),
+        (RedisType::from("version"), RedisType::from("0.2")),
+        (RedisType::from("proto"), RedisType::Int(version)),
+        (RedisType::from("id"), RedisType::Int(id as i64)),
+        (RedisType::from("mode"), RedisType::from("standalone")),
+        (RedisType::from("role"), RedisType::from("master")),
+        (RedisType::from("modules"), RedisType::Array(vec![])),
+    ])
+}
+
+/// Client names can't have spaces, newlines or other special characters
+fn check_client_name(name: &str) -> Result<()> {
+    if !name.bytes().all(|c| (b'!'..=b'~').contains(&c)) {
+        bail!("Client names cannot contain spaces, newlines or special characters.")
+    }
+    Ok(())
+}
+
+/// Resolve the inclusive `start`..`end` range used by GETRANGE into valid
+/// indices for a string of `length` bytes. Negative indices count from the end
+/// and out of range indices are clamped, following Redis' rules. Returns `None`
+/// when the range is empty.
+fn string_range(start: i64, end: i64, length: usize) -> Option<(usize, usize)> {
+    let length = length as i64;
+    if length == 0 || (start < 0 && end < 0 && start > end) {
+        return None
+    }
+
+    let start = if start < 0 { length + start } else { start }.max(0);
+    let end = if end < 0 { length + end } else { end }.max(0).min(length - 1);
+
+    if start > end {
+        None
+    } else {
+        Some((start as usize, end as usize))
+    }
+}
+
+/// Write the entries to an RDB file. The data goes to a temporary file first,
+/// which then replaces the one at `path`, so a failed save doesn't leave a
+/// truncated database behind
+async fn save_rdb(path: &Path, entries: &[RedisFileEntry]) -> Result<()> {
+    let temporary = path.with_extension(format!("tmp-{}", std::process::id()));
+
+    let file = File::create(&temporary).await?;
+    let file = write_rdb(BufWriter::new(file), &[], entries).await?.into_inner();
+    file.sync_all().await?;
+    tokio::fs::rename(&temporary, path).await?;
+
+    Ok(())
+}
+
+/// 40 characters delimiter for diskless RDB transfers
+fn eof_mark(replica_id: &str) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let digest = Sha1::digest(format!("{replica_id}:{}", now.as_nanos()));
+
+    format!("{digest:x}")
+}
+
+pub(crate) struct Client {
+    id: usize,
+    rx: Receiver<CommandResponse>,
+    store_tx: StoreHandle,
+    config_tx: Sender<ConfigCommand>,
+    max_args: usize,
+    // Longest argument accepted, and longest string that can be built
+    max_bulk_len: usize,
+    proto: Protocol,
+    // Set with CLIENT SETNAME, or HELLO
+    name: Option<String>,
+    // Set by CLIENT REPLY OFF
+    replies_off: bool,
+    // Number of upcoming commands whose replies are dropped (CLIENT REPLY SKIP)
+    skip_replies: usize,
+    // Only set for connections from regular clients
+    shutdown: Option<ShutdownTrigger>,
+    // Replies are buffered here until the command has been fully processed
+    out: Vec<u8>,
+    // Port announced by a replica with REPLCONF listening-port
+    replica_port: Option<String>,
+    // Number of channels the client is subscribed to. Published messages
+    // arrive through `messages`. The store keeps the only sender, so that the
+    // channel closes if it lets go of the client for falling behind
+    subscriptions: usize,
+    messages_tx: WeakSender<CommandResponse>,
+    messages: Receiver<CommandResponse>,
+    // Commands queued since MULTI, to be run on EXEC
+    transaction: Option<Vec<Vec<Vec<u8>>>>,
+    // Keys named in WATCH, with the version they had at the time
+    watched: Vec<(Vec<u8>, u64)>,
+}
+
+pub(crate) enum ClientStatus {
+    Normal,
+    Replica,
 }
 
+impl Client {
+    /// Register a new client with the store
+    pub(crate) async fn new(store_tx: StoreHandle, config_tx: Sender<ConfigCommand>) -> Result<Self> {
+        // Send an endpoint to the store so that we can receive responses
+        // to certain commands.
+        let (client_tx, mut client_rx) = mpsc::channel::<CommandResponse>(CLIENT_BUFFER);
+        store_tx.send(StoreCommand::InitClient(client_tx)).await?;
+
+        let id = match client_rx.recv().await {
+            Some(CommandResponse::ClientId(id)) => id,
+            _ => bail!("Client didn't receive an ID!"),
+        };
+
+        let (messages_tx, messages) = mpsc::channel::<CommandResponse>(MESSAGE_BUFFER);
+        let messages_tx = messages_tx.downgrade();
+        let mut client = Client {
+            id,
+            rx: client_rx,
+            store_tx,
+            config_tx,
+            max_args: 0,
+            max_bulk_len: 0,
+            proto: Protocol::default(),
+            name: None,
+            replies_off: false,
+            skip_replies: 0,
+            shutdown: None,
+            out: vec![],
+            replica_port: None,
+            subscriptions: 0,
+            messages_tx,
+            messages,
+            transaction: None,
+            watched: vec![],
+        };
+        client.max_args = client.get_config_value("max-command-args").await
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(usize::MAX);
+        client.max_bulk_len = client.get_config_value("proto-max-bulk-len").await
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(usize::MAX);
+
+        Ok(client)
+    }
+
+    /// Largest number of elements accepted in a single command
+    pub(crate) fn max_args(&self) -> usize {
+        self.max_args
+    }
+
+    /// Longest argument accepted in a command
+    pub(crate) fn max_bulk_len(&self) -> usize {
+        self.max_bulk_len
+    }
+
+    /// Send the pending replies
+    async fn flush(&mut self, stream: &mut TcpReader) -> Result<()> {
+        stream.write_all(&self.out).await?;
+        self.out.clear();
+        Ok(())
+    }
+
+    /// Queue a reply, serialized for the protocol used by this connection
+    async fn reply(&mut self, value: &RedisType) -> Result<()> {
+        value.write_as(&mut self.out, self.proto).await
+    }
+
+    /// Null reply where an array is expected. RESP3 has a single null
+    async fn reply_null_array(&mut self) -> Result<()> {
+        match self.proto {
+            Protocol::Resp2 => write_null_array(&mut self.out).await,
+            Protocol::Resp3 => self.reply(&RedisType::Null).await,
+        }
+    }
+
+    /// Throw away the pending replies
+    pub(crate) fn discard_replies(&mut self) {
+        self.out.clear();
+    }
+
+    /// Called after each command, with where its replies start. Drops them if
+    /// the client asked so, keeping those of earlier commands
+    fn apply_reply_mode(&mut self, start: usize) {
+        if self.replies_off || self.skip_replies > 0 {
+            self.out.truncate(start);
+            self.skip_replies = self.skip_replies.saturating_sub(1);
+        }
+    }
+
+    async fn send_error_message(&mut self, msg: &str) {
+        let msg = format!("-ERR {}\r\n", msg);
+        let _ = self.out.write_all(msg.as_bytes()).await;
+    }
+
+    /// Respond to a PING command
+    async fn handle_ping(&mut self, args: &[&str]) -> Result<()> {
+        // Subscribed RESP2 clients get a reply that can't be mistaken for a message
+        if self.subscriptions > 0 && self.proto == Protocol::Resp2 {
+            return match args {
+                [] => self.reply(&RedisType::from(vec!["pong", ""])).await,
+                [message] => self.reply(&RedisType::from(vec!["pong", message])).await,
+                _ => bail!("wrong number of arguments for 'ping' command"),
+            }
+        }
+        match args.len() {
+            0 => write_simple_string(&mut self.out, "PONG").await,
+            1 => write_string(&mut self.out, args[0]).await,
+            _ => bail!("wrong number of arguments for 'ping' command") }
+    }
+
+    /// Respond to an ECHO command
+    async fn handle_echo(&mut self, args: &[&[u8]]) -> Result<()> {
+        match args.len() {
+            1 => write_bulk_bytes(&mut self.out, args[0]).await,
+            _ => bail!("wrong number of arguments for 'echo' command")
+        }
+    }
+
+    /// HELLO [protover [AUTH username password] [SETNAME clientname]]
+    async fn handle_hello(&mut self, args: &[&str]) -> Result<()> {
+        let proto = match args.first() {
+            None => self.proto,
+            Some(version) => match version.parse::<i64>() {
+                Ok(2) => Protocol::Resp2,
+                Ok(3) => Protocol::Resp3,
+                Ok(_) => return write_simple_error(&mut self.out, "NOPROTO unsupported protocol version").await,
+                Err(_) => bail!("Protocol version is not an integer or out of range"),
+            },
+        };
+
+        // Nothing changes unless all the options are fine
+        let mut name = None;
+        let mut options = args.iter().skip(1);
+        while let Some(option) = options.next() {
+            match option.to_ascii_lowercase().as_str() {
+                "auth" => {
+                    let (Some(&username), Some(_)) = (options.next(), options.next()) else {
+                        bail!("Syntax error in HELLO option '{option}'")
+                    };
+                    // There are no passwords: the default user is the only one, and takes any
+                    if username != "default" {
+                        return write_simple_error(
+                            &mut self.out,
+                            "WRONGPASS invalid username-password pair or user is disabled.",
+                        ).await
+                    }
+                }
+                "setname" => {
+                    let Some(&client_name) = options.next() else {
+                        bail!("Syntax error in HELLO option '{option}'")
+                    };
+                    check_client_name(client_name)?;
+                    name = Some(client_name);
+                }
+                _ => bail!("Syntax error in HELLO option '{option}'"),
+            }
+        }
+
+        self.proto = proto;
+        if let Some(name) = name {
+            self.name = Some(String::from(name)).filter(|name| !name.is_empty());
+        }
+        self.reply(&hello_info(self.proto, self.id)).await
+    }
+
+    async fn handle_set(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() < 2 {
+            bail!("wrong number of arguments for 'set' command")
+        }
+        let options = parse_set_options(&args[2..])?;
+        let get = options.get;
+
+        let (key, value) = (raw[0].to_vec(), RedisType::from(raw[1].to_vec()));
+        self.store_tx.send(StoreCommand::SetWith { id: self.id, key, value, options }).await.unwrap();
+        if get {
+            return self.write_get_response().await
+        }
+        match self.rx.recv().await {
+            Some(CommandResponse::Integer(1)) => write_ok(&mut self.out).await,
+            Some(CommandResponse::Integer(_)) => self.reply(&RedisType::Null).await,
+            _ => bail!("internal error trying to set the value"),
+        }
+    }
+
+    /// Write the answer to a command that reads a string value
+    async fn write_get_response(&mut self) -> Result<()> {
+        match self.rx.recv().await {
+            Some(CommandResponse::Get(resp)) => match resp.as_ref().map(|value| value.string_bytes()) {
+                Some(Some(bytes)) => write_bulk_bytes(&mut self.out, &bytes).await,
+                Some(None) => write_wrongtype(&mut self.out).await,
+                None => self.reply(&RedisType::Null).await,
+            },
+            Some(CommandResponse::WrongType) => write_wrongtype(&mut self.out).await,
+            _ => bail!("internal error trying to get the value"),
+        }
+    }
+
+    async fn handle_get(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        match args.len() {
+            1 => {
+                let key = raw[0].to_vec();
+                self.store_tx.send(StoreCommand::Get { id: self.id, key }).await.unwrap();
+                self.write_get_response().await
+            },
+            _ => bail!("wrong number of arguments for 'get' command")
+        }
+    }
+
+    async fn handle_getset(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 2 {
+            bail!("wrong number of arguments for 'getset' command")
+        }
+
+        let (key, value) = (raw[0].to_vec(), RedisType::from(raw[1].to_vec()));
+        self.store_tx.send(StoreCommand::GetSet { id: self.id, key, value }).await.unwrap();
+        self.write_get_response().await
+    }
+
+    /// SETEX and PSETEX. `unit` is the expiry option they are equivalent to
+    async fn handle_setex(&mut self, args: &[&str], raw: &[&[u8]], name: &str, unit: &str) -> Result<()> {
+        if args.len() != 3 {
+            bail!("wrong number of arguments for '{name}' command")
+        }
+        let until = parse_expiry(unit, args[1], name)?;
+
+        let (key, value) = (raw[0].to_vec(), RedisType::from(raw[2].to_vec()));
+        self.store_tx.send(StoreCommand::SetEx { key, value, until }).await.unwrap();
+        write_ok(&mut self.out).await
+    }
+
+    async fn handle_setnx(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 2 {
+            bail!("wrong number of arguments for 'setnx' command")
+        }
+
+        let (key, value) = (raw[0].to_vec(), RedisType::from(raw[1].to_vec()));
+        self.store_tx.send(StoreCommand::SetNx { id: self.id, key, value }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    async fn handle_getex(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        let ttl = match args {
+            [] => bail!("wrong number of arguments for 'getex' command"),
+            [_] => None,
+            [_, option] if option.eq_ignore_ascii_case("persist") => Some(TtlChange::Persist),
+            [_, option, arg] => Some(TtlChange::ExpireAt(parse_expiry(option, arg, "getex")?)),
+            _ => bail!("syntax error"),
+        };
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::GetEx { id: self.id, key, ttl }).await.unwrap();
+        self.write_get_response().await
+    }
+
+    /// Write the answer to a command that returns a number
+    async fn write_integer_response(&mut self) -> Result<()> {
+        match self.rx.recv().await {
+            Some(CommandResponse::Integer(number)) => write_integer(&mut self.out, number).await,
+            Some(CommandResponse::WrongType) => write_wrongtype(&mut self.out).await,
+            Some(CommandResponse::Error(message)) => bail!(message),
+            _ => bail!("internal error waiting for the store"),
+        }
+    }
+
+    /// Write the total of the numbers answered by several shards
+    async fn write_integer_sum(&mut self, parts: usize) -> Result<()> {
+        let mut total = Some(0);
+        for _ in 0..parts {
+            total = match self.rx.recv().await {
+                Some(CommandResponse::Integer(number)) => total.map(|total| total + number),
+                _ => None,
+            };
+        }
+        match total {
+            Some(total) => write_integer(&mut self.out, total).await,
+            None => bail!("internal error waiting for the store"),
+        }
+    }
+
+    /// Write whatever value the store answered with
+    async fn write_value_response(&mut self) -> Result<()> {
+        match self.rx.recv().await {
+            Some(CommandResponse::Get(value)) => self.reply(&value.unwrap_or(RedisType::Null)).await,
+            Some(CommandResponse::WrongType) => write_wrongtype(&mut self.out).await,
+            Some(CommandResponse::Error(message)) => bail!(message),
+            _ => bail!("internal error waiting for the store"),
+        }
+    }
+
+    async fn incr_by(&mut self, key: &[u8], delta: i64) -> Result<()> {
+        let key = key.to_vec();
+        self.store_tx.send(StoreCommand::IncrBy { id: self.id, key, delta }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    async fn handle_incr(&mut self, args: &[&str], raw: &[&[u8]], name: &str, sign: i64) -> Result<()> {
+        if args.len() != 1 {
+            bail!("wrong number of arguments for '{name}' command")
+        }
+        self.incr_by(raw[0], sign).await
+    }
+
+    async fn handle_incrby(&mut self, args: &[&str], raw: &[&[u8]], name: &str, sign: i64) -> Result<()> {
+        if args.len() != 2 {
+            bail!("wrong number of arguments for '{name}' command")
+        }
+        let Some(delta) = parse_integer(args[1])?.checked_mul(sign) else {
+            bail!("decrement would overflow")
+        };
+        self.incr_by(raw[0], delta).await
+    }
+
+    /// EXPIRE and PEXPIRE. `unit` is the length in milliseconds of one unit
+    /// of the argument
+    async fn handle_expire(&mut self, args: &[&str], raw: &[&[u8]], name: &str, unit: u64) -> Result<()> {
+        if args.len() < 2 {
+            bail!("wrong number of arguments for '{name}' command")
+        }
+        let condition = parse_expire_condition(&args[2..])?;
+        let amount = parse_integer(args[1])?;
+        // Non-positive amounts expire the key right away
+        let until = if amount > 0 {
+            (amount as u64).checked_mul(unit)
+                .and_then(|millis| SystemTime::now().checked_add(Duration::from_millis(millis)))
+                .ok_or_else(|| Error::msg(format!("invalid expire time in '{name}' command")))?
+        } else {
+            UNIX_EPOCH
+        };
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::Expire { id: self.id, key, until, condition }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    /// EXPIREAT and PEXPIREAT. `unit` is the length in milliseconds of one
+    /// unit of the argument
+    async fn handle_expireat(&mut self, args: &[&str], raw: &[&[u8]], name: &str, unit: u64) -> Result<()> {
+        if args.len() < 2 {
+            bail!("wrong number of arguments for '{name}' command")
+        }
+        let condition = parse_expire_condition(&args[2..])?;
+        // Times in the past are fine: the key expires right away
+        let amount = parse_integer(args[1])?.max(0) as u64;
+        let until = amount.checked_mul(unit)
+            .and_then(|millis| UNIX_EPOCH.checked_add(Duration::from_millis(millis)))
+            .ok_or_else(|| Error::msg(format!("invalid expire time in '{name}' command")))?;
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::Expire { id: self.id, key, until, condition }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    /// EXPIRETIME and PEXPIRETIME, depending on `millis`
+    async fn handle_expiretime(&mut self, args: &[&str], raw: &[&[u8]], millis: bool) -> Result<()> {
+        if args.len() != 1 {
+            let name = if millis { "pexpiretime" } else { "expiretime" };
+            bail!("wrong number of arguments for '{name}' command")
+        }
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::ExpireTime { id: self.id, key }).await.unwrap();
+        match self.rx.recv().await {
+            Some(CommandResponse::Integer(time)) if time < 0 || millis => write_integer(&mut self.out, time).await,
+            Some(CommandResponse::Integer(time)) => write_integer(&mut self.out, time / 1000).await,
+            _ => bail!("internal error waiting for the store"),
+        }
+    }
+
+    async fn handle_persist(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 1 {
+            bail!("wrong number of arguments for 'persist' command")
+        }
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::Persist { id: self.id, key }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    async fn handle_mset(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.is_empty() || !args.len().is_multiple_of(2) {
+            bail!("wrong number of arguments for 'mset' command")
+        }
+
+        // Each shard sets its own keys
+        let keys = raw.iter().step_by(2).copied().collect::<Vec<_>>();
+        let parts = self.store_tx.by_shard(&keys).into_iter()
+            .map(|(shard, indexes)| {
+                let pairs = indexes.into_iter()
+                    .map(|index| (raw[index * 2].to_vec(), RedisType::from(raw[index * 2 + 1].to_vec())))
+                    .collect();
+                (shard, StoreCommand::MSet { pairs })
+            })
+            .collect();
+        self.store_tx.send_parts(parts).await.unwrap();
+        write_ok(&mut self.out).await
+    }
+
+    async fn handle_mget(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.is_empty() {
+            bail!("wrong number of arguments for 'mget' command")
+        }
+
+        // Ask every shard for its keys at once, then put the answers together
+        let mut parts = vec![];
+        let mut answers = vec![];
+        for (shard, indexes) in self.store_tx.by_shard(raw) {
+            let keys = indexes.iter().map(|&index| raw[index].to_vec()).collect();
+            let (tx, rx) = oneshot::channel();
+            parts.push((shard, StoreCommand::MGetPart { keys, tx }));
+            answers.push((indexes, rx));
+        }
+        self.store_tx.send_parts(parts).await.unwrap();
+
+        let mut values = vec![RedisType::Null; raw.len()];
+        for (indexes, rx) in answers {
+            let Ok(found) = rx.await else {
+                bail!("internal error trying to get the values")
+            };
+            for (index, value) in indexes.into_iter().zip(found) {
+                values[index] = value.unwrap_or(RedisType::Null);
+            }
+        }
+        self.reply(&RedisType::Array(values)).await
+    }
+
+    /// LPUSH and RPUSH, depending on `left`, or LPUSHX and RPUSHX if
+    /// `must_exist` is set
+    async fn handle_push(&mut self, args: &[&str], raw: &[&[u8]], left: bool, must_exist: bool) -> Result<()> {
+        if args.len() < 2 {
+            let name = if left { "lpush" } else { "rpush" };
+            let suffix = if must_exist { "x" } else { "" };
+            bail!("wrong number of arguments for '{name}{suffix}' command")
+        }
+
+        let key = raw[0].to_vec();
+        let values = raw[1..].iter().map(|value| value.to_vec()).collect();
+        self.store_tx.send(StoreCommand::Push { id: self.id, key, values, left, must_exist }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    /// LPOP and RPOP, depending on `left`
+    async fn handle_pop(&mut self, args: &[&str], raw: &[&[u8]], left: bool) -> Result<()> {
+        if args.is_empty() || args.len() > 2 {
+            let name = if left { "lpop" } else { "rpop" };
+            bail!("wrong number of arguments for '{name}' command")
+        }
+        let count = match args.get(1) {
+            Some(count) => Some(usize::try_from(parse_integer(count)?)
+                .map_err(|_| Error::msg("value is out of range, must be positive"))?),
+            None => None,
+        };
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::Pop { id: self.id, key, count, left }).await.unwrap();
+        if count.is_none() {
+            return self.write_value_response().await
+        }
+        // With a count, a missing key is a null array rather than a null string
+        match self.rx.recv().await {
+            Some(CommandResponse::Get(Some(popped))) => self.reply(&popped).await,
+            Some(CommandResponse::Get(None)) => self.reply_null_array().await,
+            Some(CommandResponse::WrongType) => write_wrongtype(&mut self.out).await,
+            _ => bail!("internal error waiting for the store"),
+        }
+    }
+
+    async fn lmove(&mut self, source: &[u8], destination: &[u8], from_left: bool, to_left: bool) -> Result<()> {
+        let (source, destination) = (source.to_vec(), destination.to_vec());
+        self.store_tx.send(StoreCommand::LMove { id: self.id, source, destination, from_left, to_left }).await.unwrap();
+        self.write_value_response().await
+    }
+
+    async fn handle_rpoplpush(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 2 {
+            bail!("wrong number of arguments for 'rpoplpush' command")
+        }
+        self.lmove(raw[0], raw[1], false, true).await
+    }
+
+    async fn handle_lmove(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 4 {
+            bail!("wrong number of arguments for 'lmove' command")
+        }
+        let side = |arg: &str| match arg.to_ascii_lowercase().as_str() {
+            "left" => Ok(true),
+            "right" => Ok(false),
+            _ => Err(Error::msg("syntax error")),
+        };
+        let (from_left, to_left) = (side(args[2])?, side(args[3])?);
+
+        self.lmove(raw[0], raw[1], from_left, to_left).await
+    }
+
+    /// BLPOP and BRPOP, depending on `left`
+    async fn handle_blocking_pop(&mut self, args: &[&str], raw: &[&[u8]], left: bool) -> Result<()> {
+        let Some((timeout, _)) = args.split_last().filter(|(_, keys)| !keys.is_empty()) else {
+            let name = if left { "blpop" } else { "brpop" };
+            bail!("wrong number of arguments for '{name}' command")
+        };
+        let timeout = timeout.parse::<f64>().ok()
+            .filter(|timeout| timeout.is_finite())
+            .ok_or_else(|| Error::msg("timeout is not a float or out of range"))?;
+        if timeout < 0.0 {
+            bail!("timeout is negative")
+        }
+
+        let keys = raw[..raw.len() - 1].iter().map(|key| key.to_vec()).collect();
+        let (tx, rx) = oneshot::channel();
+        self.store_tx.send(StoreCommand::BlockPop { keys, left, tx }).await.unwrap();
+        // Giving up drops `rx`, which tells the store to stop waiting
+        let response = if timeout == 0.0 {
+            rx.await.ok()
+        } else {
+            tokio::time::timeout(Duration::from_secs_f64(timeout), rx).await.ok().and_then(Result::ok)
+        };
+
+        match response {
+            Some(CommandResponse::Get(Some(pair))) => self.reply(&pair).await,
+            Some(CommandResponse::WrongType) => write_wrongtype(&mut self.out).await,
+            _ => self.reply_null_array().await,
+        }
+    }
+
+    async fn handle_lrange(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 3 {
+            bail!("wrong number of arguments for 'lrange' command")
+        }
+        let (start, stop) = (parse_integer(args[1])?, parse_integer(args[2])?);
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::LRange { id: self.id, key, start, stop }).await.unwrap();
+        self.write_value_response().await
+    }
+
+    async fn handle_llen(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 1 {
+            bail!("wrong number of arguments for 'llen' command")
+        }
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::LLen { id: self.id, key }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    async fn handle_lindex(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 2 {
+            bail!("wrong number of arguments for 'lindex' command")
+        }
+        let index = parse_integer(args[1])?;
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::LIndex { id: self.id, key, index }).await.unwrap();
+        self.write_value_response().await
+    }
+
+    async fn handle_lpos(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() < 2 {
+            bail!("wrong number of arguments for 'lpos' command")
+        }
+        let options = parse_lpos_options(&args[2..])?;
+
+        let key = raw[0].to_vec();
+        let element = raw[1].to_vec();
+        self.store_tx.send(StoreCommand::LPos { id: self.id, key, element, options }).await.unwrap();
+        self.write_value_response().await
+    }
+
+    async fn handle_lset(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 3 {
+            bail!("wrong number of arguments for 'lset' command")
+        }
+        let index = parse_integer(args[1])?;
+
+        let key = raw[0].to_vec();
+        let value = raw[2].to_vec();
+        self.store_tx.send(StoreCommand::LSet { id: self.id, key, index, value }).await.unwrap();
+        match self.rx.recv().await {
+            Some(CommandResponse::Integer(_)) => write_ok(&mut self.out).await,
+            Some(CommandResponse::WrongType) => write_wrongtype(&mut self.out).await,
+            Some(CommandResponse::Error(message)) => bail!(message),
+            _ => bail!("internal error waiting for the store"),
+        }
+    }
+
+    async fn handle_linsert(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 4 {
+            bail!("wrong number of arguments for 'linsert' command")
+        }
+        let before = match args[1].to_ascii_lowercase().as_str() {
+            "before" => true,
+            "after" => false,
+            _ => bail!("syntax error"),
+        };
+
+        let key = raw[0].to_vec();
+        let (pivot, value) = (raw[2].to_vec(), raw[3].to_vec());
+        self.store_tx.send(StoreCommand::LInsert { id: self.id, key, before, pivot, value }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    async fn handle_lrem(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 3 {
+            bail!("wrong number of arguments for 'lrem' command")
+        }
+        let count = parse_integer(args[1])?;
+
+        let key = raw[0].to_vec();
+        let value = raw[2].to_vec();
+        self.store_tx.send(StoreCommand::LRem { id: self.id, key, count, value }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    async fn handle_hset(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() < 3 || args.len().is_multiple_of(2) {
+            bail!("wrong number of arguments for 'hset' command")
+        }
+
+        let key = raw[0].to_vec();
+        let pairs = raw[1..].chunks(2)
+            .map(|pair| (pair[0].to_vec(), pair[1].to_vec()))
+            .collect();
+        self.store_tx.send(StoreCommand::HSet { id: self.id, key, pairs }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    async fn handle_hget(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 2 {
+            bail!("wrong number of arguments for 'hget' command")
+        }
+
+        let (key, field) = (raw[0].to_vec(), raw[1].to_vec());
+        self.store_tx.send(StoreCommand::HGet { id: self.id, key, field }).await.unwrap();
+        self.write_value_response().await
+    }
+
+    async fn handle_hmget(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() < 2 {
+            bail!("wrong number of arguments for 'hmget' command")
+        }
+
+        let key = raw[0].to_vec();
+        let fields = raw[1..].iter().map(|field| field.to_vec()).collect();
+        self.store_tx.send(StoreCommand::HMGet { id: self.id, key, fields }).await.unwrap();
+        match self.rx.recv().await {
+            Some(CommandResponse::Values(values)) => {
+                let values = values.into_iter()
+                    .map(|value| value.unwrap_or(RedisType::Null))
+                    .collect();
+                self.reply(&RedisType::Array(values)).await
+            }
+            Some(CommandResponse::WrongType) => write_wrongtype(&mut self.out).await,
+            _ => bail!("internal error waiting for the store"),
+        }
+    }
+
+    async fn handle_hsetnx(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 3 {
+            bail!("wrong number of arguments for 'hsetnx' command")
+        }
+
+        let (key, field, value) = (raw[0].to_vec(), raw[1].to_vec(), raw[2].to_vec());
+        self.store_tx.send(StoreCommand::HSetNx { id: self.id, key, field, value }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    async fn handle_hincrby(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 3 {
+            bail!("wrong number of arguments for 'hincrby' command")
+        }
+        let delta = parse_integer(args[2])?;
+
+        let (key, field) = (raw[0].to_vec(), raw[1].to_vec());
+        self.store_tx.send(StoreCommand::HIncrBy { id: self.id, key, field, delta }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    async fn handle_hincrbyfloat(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 3 {
+            bail!("wrong number of arguments for 'hincrbyfloat' command")
+        }
+        let delta = args[2].parse::<f64>().ok()
+            .filter(|delta| delta.is_finite())
+            .ok_or_else(|| Error::msg("value is not a valid float"))?;
+
+        let (key, field) = (raw[0].to_vec(), raw[1].to_vec());
+        self.store_tx.send(StoreCommand::HIncrByFloat { id: self.id, key, field, delta }).await.unwrap();
+        self.write_value_response().await
+    }
+
+    async fn handle_hdel(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() < 2 {
+            bail!("wrong number of arguments for 'hdel' command")
+        }
+
+        let key = raw[0].to_vec();
+        let fields = raw[1..].iter().map(|field| field.to_vec()).collect();
+        self.store_tx.send(StoreCommand::HDel { id: self.id, key, fields }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    /// HEXISTS and HSTRLEN, which take a key and a field
+    async fn handle_hash_field(&mut self, args: &[&str], raw: &[&[u8]], name: &str) -> Result<()> {
+        if args.len() != 2 {
+            bail!("wrong number of arguments for '{name}' command")
+        }
+
+        let (key, field) = (raw[0].to_vec(), raw[1].to_vec());
+        let cmd = match name {
+            "hexists" => StoreCommand::HExists { id: self.id, key, field },
+            _ => StoreCommand::HStrLen { id: self.id, key, field },
+        };
+        self.store_tx.send(cmd).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    async fn handle_hlen(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 1 {
+            bail!("wrong number of arguments for 'hlen' command")
+        }
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::HLen { id: self.id, key }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    /// HGETALL, HKEYS and HVALS. Fields come in no particular order, but
+    /// HKEYS and HVALS agree with HGETALL as long as the hash doesn't change
+    async fn handle_hash_contents(&mut self, args: &[&str], raw: &[&[u8]], name: &str) -> Result<()> {
+        if args.len() != 1 {
+            bail!("wrong number of arguments for '{name}' command")
+        }
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::HGetAll { id: self.id, key }).await.unwrap();
+        let hash = match self.rx.recv().await {
+            Some(CommandResponse::Get(Some(RedisType::Hash(hash)))) => hash,
+            Some(CommandResponse::WrongType) => return write_wrongtype(&mut self.out).await,
+            _ => bail!("internal error waiting for the store"),
+        };
+
+        let reply = match name {
+            "hkeys" => RedisType::Array(hash.into_keys().map(RedisType::from).collect()),
+            "hvals" => RedisType::Array(hash.into_values().map(RedisType::from).collect()),
+            _ => RedisType::Hash(hash),
+        };
+        self.reply(&reply).await
+    }
+
+    /// SADD and SREM, which take a key and some members
+    async fn handle_set_members(&mut self, args: &[&str], raw: &[&[u8]], name: &str) -> Result<()> {
+        if args.len() < 2 {
+            bail!("wrong number of arguments for '{name}' command")
+        }
+
+        let key = raw[0].to_vec();
+        let members = raw[1..].iter().map(|member| member.to_vec()).collect();
+        let cmd = match name {
+            "sadd" => StoreCommand::SAdd { id: self.id, key, members },
+            _ => StoreCommand::SRem { id: self.id, key, members },
+        };
+        self.store_tx.send(cmd).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    /// SISMEMBER, or SMISMEMBER when `multiple` is set
+    async fn handle_sismember(&mut self, args: &[&str], raw: &[&[u8]], multiple: bool) -> Result<()> {
+        if args.len() < 2 || (!multiple && args.len() != 2) {
+            let name = if multiple { "smismember" } else { "sismember" };
+            bail!("wrong number of arguments for '{name}' command")
+        }
+
+        let key = raw[0].to_vec();
+        let members = raw[1..].iter().map(|member| member.to_vec()).collect();
+        self.store_tx.send(StoreCommand::SIsMember { id: self.id, key, members }).await.unwrap();
+        match self.rx.recv().await {
+            Some(CommandResponse::Values(found)) => {
+                let mut found = found.into_iter().map(|value| value.unwrap_or(RedisType::Int(0)));
+                if multiple {
+                    self.reply(&RedisType::Array(found.collect())).await
+                } else {
+                    self.reply(&found.next().unwrap_or(RedisType::Int(0))).await
+                }
+            }
+            Some(CommandResponse::WrongType) => write_wrongtype(&mut self.out).await,
+            _ => bail!("internal error waiting for the store"),
+        }
+    }
+
+    async fn handle_scard(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 1 {
+            bail!("wrong number of arguments for 'scard' command")
+        }
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::SCard { id: self.id, key }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    /// SINTER, SUNION and SDIFF
+    async fn handle_combine(&mut self, args: &[&str], raw: &[&[u8]], name: &str, operation: SetOperation) -> Result<()> {
+        if args.is_empty() {
+            bail!("wrong number of arguments for '{name}' command")
+        }
+
+        let keys = raw.iter().map(|key| key.to_vec()).collect();
+        self.store_tx.send(StoreCommand::Combine { id: self.id, keys, operation }).await.unwrap();
+        self.write_value_response().await
+    }
+
+    async fn handle_zadd(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() < 3 {
+            bail!("wrong number of arguments for 'zadd' command")
+        }
+        let (options, pairs) = parse_zadd(&raw[1..])?;
+
+        let key = raw[0].to_vec();
+        let incr = options.incr;
+        self.store_tx.send(StoreCommand::ZAdd { id: self.id, key, options, pairs }).await.unwrap();
+        if incr {
+            self.write_value_response().await
+        } else {
+            self.write_integer_response().await
+        }
+    }
+
+    async fn handle_zrangebyscore(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() < 3 {
+            bail!("wrong number of arguments for 'zrangebyscore' command")
+        }
+        let range = parse_zrangebyscore(&args[1..])?;
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::ZRange { id: self.id, key, range }).await.unwrap();
+        self.write_value_response().await
+    }
+
+    async fn handle_zrem(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() < 2 {
+            bail!("wrong number of arguments for 'zrem' command")
+        }
+
+        let key = raw[0].to_vec();
+        let members = raw[1..].iter().map(|member| member.to_vec()).collect();
+        self.store_tx.send(StoreCommand::ZRem { id: self.id, key, members }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    async fn handle_zincrby(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 3 {
+            bail!("wrong number of arguments for 'zincrby' command")
+        }
+        let increment = parse_score(args[1])?;
+
+        // Same as ZADD INCR
+        let key = raw[0].to_vec();
+        let options = ZAddOptions { incr: true, ..Default::default() };
+        let pairs = vec![(increment, raw[2].to_vec())];
+        self.store_tx.send(StoreCommand::ZAdd { id: self.id, key, options, pairs }).await.unwrap();
+        self.write_value_response().await
+    }
+
+    async fn handle_zcount(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 3 {
+            bail!("wrong number of arguments for 'zcount' command")
+        }
+        let (min, max) = (parse_score_bound(args[1])?, parse_score_bound(args[2])?);
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::ZCount { id: self.id, key, min, max }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    /// ZPOPMIN and ZPOPMAX
+    async fn handle_zpop(&mut self, args: &[&str], raw: &[&[u8]], max: bool) -> Result<()> {
+        if args.is_empty() || args.len() > 2 {
+            let name = if max { "zpopmax" } else { "zpopmin" };
+            bail!("wrong number of arguments for '{name}' command")
+        }
+        let count = match args.get(1) {
+            Some(count) => usize::try_from(parse_integer(count)?)
+                .map_err(|_| Error::msg("value is out of range, must be positive"))?,
+            None => 1,
+        };
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::ZPop { id: self.id, key, count, max }).await.unwrap();
+        self.write_value_response().await
+    }
+
+    /// ZSCORE, ZRANK and ZREVRANK
+    async fn handle_zmember(&mut self, args: &[&str], raw: &[&[u8]], name: &str) -> Result<()> {
+        if args.len() != 2 {
+            bail!("wrong number of arguments for '{name}' command")
+        }
+
+        let (key, member) = (raw[0].to_vec(), raw[1].to_vec());
+        let command = match name {
+            "zscore" => StoreCommand::ZScore { id: self.id, key, member },
+            _ => StoreCommand::ZRank { id: self.id, key, member, rev: name == "zrevrank" },
+        };
+        self.store_tx.send(command).await.unwrap();
+        self.write_value_response().await
+    }
+
+    async fn handle_zcard(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 1 {
+            bail!("wrong number of arguments for 'zcard' command")
+        }
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::ZCard { id: self.id, key }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    async fn handle_zrange(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() < 3 {
+            bail!("wrong number of arguments for 'zrange' command")
+        }
+        let range = parse_zrange(&raw[1..])?;
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::ZRange { id: self.id, key, range }).await.unwrap();
+        self.write_value_response().await
+    }
+
+    async fn handle_smove(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 3 {
+            bail!("wrong number of arguments for 'smove' command")
+        }
+
+        let (source, destination, member) = (raw[0].to_vec(), raw[1].to_vec(), raw[2].to_vec());
+        self.store_tx.send(StoreCommand::SMove { id: self.id, source, destination, member }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    /// SINTERCARD and ZINTERCARD
+    async fn handle_intercard(&mut self, args: &[&str], raw: &[&[u8]], name: &str) -> Result<()> {
+        if args.len() < 2 {
+            bail!("wrong number of arguments for '{name}' command")
+        }
+        let numkeys = parse_integer(args[0])?;
+        if numkeys <= 0 {
+            bail!("numkeys should be greater than 0")
+        }
+        let numkeys = numkeys as usize;
+        if numkeys > args.len() - 1 {
+            bail!("Number of keys can't be greater than number of args")
+        }
+
+        let limit = match &args[1 + numkeys..] {
+            [] => 0,
+            [option, limit] if option.eq_ignore_ascii_case("limit") => {
+                usize::try_from(parse_integer(limit)?).map_err(|_| Error::msg("LIMIT can't be negative"))?
+            }
+            _ => bail!("syntax error"),
+        };
+
+        let keys = raw[1..=numkeys].iter().map(|key| key.to_vec()).collect();
+        let cmd = match name {
+            "zintercard" => StoreCommand::ZInterCard { id: self.id, keys, limit },
+            _ => StoreCommand::InterCard { id: self.id, keys, limit },
+        };
+        self.store_tx.send(cmd).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    /// SINTERSTORE, SUNIONSTORE and SDIFFSTORE
+    async fn handle_combine_store(&mut self, args: &[&str], raw: &[&[u8]], name: &str, operation: SetOperation) -> Result<()> {
+        if args.len() < 2 {
+            bail!("wrong number of arguments for '{name}' command")
+        }
+
+        let destination = raw[0].to_vec();
+        let keys = raw[1..].iter().map(|key| key.to_vec()).collect();
+        self.store_tx.send(StoreCommand::CombineStore { id: self.id, destination, keys, operation }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    async fn handle_smembers(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 1 {
+            bail!("wrong number of arguments for 'smembers' command")
+        }
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::SMembers { id: self.id, key }).await.unwrap();
+        self.write_value_response().await
+    }
+
+    async fn handle_bitfield(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.is_empty() {
+            bail!("wrong number of arguments for 'bitfield' command")
+        }
+        let ops = parse_bitfield(&args[1..])?;
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::BitField { id: self.id, key, ops }).await.unwrap();
+        match self.rx.recv().await {
+            Some(CommandResponse::Values(values)) => {
+                let values = values.into_iter()
+                    .map(|value| value.unwrap_or(RedisType::Null))
+                    .collect();
+                self.reply(&RedisType::Array(values)).await
+            }
+            Some(CommandResponse::WrongType) => write_wrongtype(&mut self.out).await,
+            _ => bail!("internal error waiting for the store"),
+        }
+    }
+
+    async fn handle_getrange(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 3 {
+            bail!("wrong number of arguments for 'getrange' command")
+        }
+        let (start, end) = (parse_integer(args[1])?, parse_integer(args[2])?);
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::Get { id: self.id, key }).await.unwrap();
+        let value = match self.rx.recv().await {
+            Some(CommandResponse::Get(Some(value))) => match value.string_bytes() {
+                Some(bytes) => bytes,
+                None => return write_wrongtype(&mut self.out).await,
+            },
+            Some(CommandResponse::Get(None)) => vec![],
+            _ => bail!("internal error trying to get the value"),
+        };
+
+        match string_range(start, end, value.len()) {
+            Some((from, to)) => write_bulk_bytes(&mut self.out, &value[from..=to]).await,
+            None => write_string(&mut self.out, "").await,
+        }
+    }
+
+    async fn handle_setrange(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 3 {
+            bail!("wrong number of arguments for 'setrange' command")
+        }
+        let offset = parse_integer(args[1])?;
+        if offset < 0 {
+            bail!("offset is out of range")
+        }
+        let offset = offset as usize;
+        let value = raw[2].to_vec();
+        if offset + value.len() > self.max_bulk_len {
+            bail!("string exceeds maximum allowed size (proto-max-bulk-len)")
+        }
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::SetRange { id: self.id, key, offset, value }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    async fn handle_append(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 2 {
+            bail!("wrong number of arguments for 'append' command")
+        }
+
+        let key = raw[0].to_vec();
+        let value = raw[1].to_vec();
+        self.store_tx.send(StoreCommand::Append { id: self.id, key, value }).await.unwrap();
+        self.write_integer_response().await
+    }
+
+    /// DEL and UNLINK. There's no background deletion, so both are the same
+    async fn handle_del(&mut self, args: &[&str], raw: &[&[u8]], command: &str) -> Result<()> {
+        if args.is_empty() {
+            bail!("wrong number of arguments for '{command}' command")
+        }
+
+        let parts = self.store_tx.by_shard(raw).into_iter()
+            .map(|(shard, indexes)| {
+                let keys = indexes.into_iter().map(|index| raw[index].to_vec()).collect();
+                (shard, StoreCommand::Delete { id: self.id, keys })
+            })
+            .collect::<Vec<_>>();
+        let count = parts.len();
+        self.store_tx.send_parts(parts).await.unwrap();
+        self.write_integer_sum(count).await
+    }
+
+    /// EXISTS and TOUCH. There's no access time to update, so both are the same
+    async fn handle_exists(&mut self, args: &[&str], raw: &[&[u8]], command: &str) -> Result<()> {
+        if args.is_empty() {
+            bail!("wrong number of arguments for '{command}' command")
+        }
+
+        let parts = self.store_tx.by_shard(raw).into_iter()
+            .map(|(shard, indexes)| {
+                let keys = indexes.into_iter().map(|index| raw[index].to_vec()).collect();
+                (shard, StoreCommand::Exists { id: self.id, keys })
+            })
+            .collect::<Vec<_>>();
+        let count = parts.len();
+        self.store_tx.send_parts(parts).await.unwrap();
+        self.write_integer_sum(count).await
+    }
 
-struct Client {
-    id: usize,
-    stream: TcpReader,
-    rx: Receiver<CommandResponse>,
-    store_tx: Sender<StoreCommand>,
-    config_tx: Sender<ConfigCommand>,
-}
+    async fn handle_copy(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        let replace = match args {
+            [_, _] => false,
+            [_, _, option] if option.eq_ignore_ascii_case("replace") => true,
+            [_, _, ..] => bail!("syntax error"),
+            _ => bail!("wrong number of arguments for 'copy' command"),
+        };
+        if args[0] == args[1] {
+            bail!("source and destination objects are the same")
+        }
 
-enum ClientStatus {
-    Normal,
-    Replica,
-}
+        let (source, destination) = (raw[0].to_vec(), raw[1].to_vec());
+        self.store_tx.send(StoreCommand::Copy { id: self.id, source, destination, replace }).await.unwrap();
+        self.write_integer_response().await
+    }
 
-impl Client {
-    async fn send_error_message(&mut self, msg: &str) {
-        let msg = format!("-ERR {}\r\n", msg);
-        let _ = self.stream.write(msg.as_bytes()).await;
+    async fn handle_rename(&mut self, args: &[&str], raw: &[&[u8]], nx: bool) -> Result<()> {
+        if args.len() != 2 {
+            let name = if nx { "renamenx" } else { "rename" };
+            bail!("wrong number of arguments for '{name}' command")
+        }
+
+        let (key, new_key) = (raw[0].to_vec(), raw[1].to_vec());
+        self.store_tx.send(StoreCommand::Rename { id: self.id, key, new_key, nx }).await.unwrap();
+        if nx {
+            return self.write_integer_response().await
+        }
+        match self.rx.recv().await {
+            Some(CommandResponse::Integer(_)) => write_ok(&mut self.out).await,
+            Some(CommandResponse::Error(message)) => bail!(message),
+            _ => bail!("internal error waiting for the store"),
+        }
     }
 
-    /// Respond to a PING command
-    async fn handle_ping(&mut self, args: &[&str]) -> Result<()> {
-        match args.len() {
-            0 => self.stream.write(b"+PONG\r\n").await.map(|_| Ok(()))?,
-            1 => write_string(&mut self.stream, args[0]).await,
-            _ => bail!("wrong number of arguments for 'ping' command") }
+    async fn handle_dbsize(&mut self, args: &[&str]) -> Result<()> {
+        if !args.is_empty() {
+            bail!("wrong number of arguments for 'dbsize' command")
+        }
+
+        self.store_tx.send(StoreCommand::DbSize(self.id)).await.unwrap();
+        self.write_integer_response().await
     }
 
-    /// Respond to an ECHO command
-    async fn handle_echo(&mut self, args: &[&str]) -> Result<()> {
-        match args.len() {
-            1 => write_string(&mut self.stream, args[0]).await,
-            _ => bail!("wrong number of arguments for 'echo' command")
+    async fn handle_flushdb(&mut self, args: &[&str]) -> Result<()> {
+        // There's no background flushing: ASYNC and SYNC do the same
+        match args {
+            [] => {}
+            [mode] if mode.eq_ignore_ascii_case("async") || mode.eq_ignore_ascii_case("sync") => {}
+            _ => bail!("syntax error"),
+        }
+
+        self.store_tx.send(StoreCommand::FlushDb(self.id)).await.unwrap();
+        match self.rx.recv().await {
+            Some(CommandResponse::Integer(_)) => write_ok(&mut self.out).await,
+            _ => bail!("internal error waiting for the store"),
         }
     }
 
-    async fn handle_hello(&mut self, args: &[&str]) -> Result<()> {
-        match args.len() {
-            0 => {
-                HELLO_INFO.get().unwrap().write(&mut self.stream).await
+    async fn handle_randomkey(&mut self, args: &[&str]) -> Result<()> {
+        if !args.is_empty() {
+            bail!("wrong number of arguments for 'randomkey' command")
+        }
+
+        self.store_tx.send(StoreCommand::RandomKey(self.id)).await.unwrap();
+        self.write_get_response().await
+    }
+
+    async fn handle_type(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 1 {
+            bail!("wrong number of arguments for 'type' command")
+        }
+
+        let key = raw[0].to_vec();
+        self.store_tx.send(StoreCommand::Type { id: self.id, key }).await.unwrap();
+        match self.rx.recv().await {
+            Some(CommandResponse::Type(name)) => write_simple_string(&mut self.out, name).await,
+            _ => bail!("internal error trying to get the type"),
+        }
+    }
+
+    async fn handle_object(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.is_empty() {
+            bail!("wrong number of arguments for 'object' command")
+        }
+
+        match args[0].to_lowercase().as_str() {
+            "encoding" => {
+                if args.len() != 2 {
+                    bail!("wrong number of arguments for 'object|encoding' command")
+                }
+                let key = raw[1].to_vec();
+                self.store_tx.send(StoreCommand::ObjectEncoding { id: self.id, key }).await.unwrap();
+                match self.rx.recv().await {
+                    Some(CommandResponse::Encoding(Some(encoding))) => self.reply(&RedisType::from(encoding)).await,
+                    Some(CommandResponse::Encoding(None)) => bail!("no such key"),
+                    _ => bail!("internal error trying to get the encoding"),
+                }
             }
-            // This should be a NOPROTO, we'll deal with that later
-            _ => bail!("wrong number of arguments for 'hello' command")
+            _ => bail!("unknown subcommand '{}'. Try OBJECT HELP", args[0]),
         }
     }
 
-    async fn handle_set(&mut self, args: &[&str]) -> Result<()> {
-        handle_set(&mut self.stream, &self.store_tx, args, true).await
+    async fn handle_client_reply(&mut self, args: &[&str]) -> Result<()> {
+        if args.len() != 1 {
+            bail!("wrong number of arguments for 'client|reply' command")
+        }
+
+        match args[0].to_ascii_lowercase().as_str() {
+            "on" => {
+                self.replies_off = false;
+                self.skip_replies = 0;
+                write_ok(&mut self.out).await
+            }
+            "off" => {
+                self.replies_off = true;
+                Ok(())
+            }
+            // This command and the next one
+            "skip" => {
+                self.skip_replies = 2;
+                Ok(())
+            }
+            _ => bail!("syntax error"),
+        }
     }
 
-    async fn handle_get(&mut self, args: &[&str]) -> Result<()> {
-        match args.len() {
-            1 => {
-                let key = String::from(args[0]);
-                self.store_tx.send(StoreCommand::Get { id: self.id, key }).await.unwrap();
-                if let Some(CommandResponse::Get(resp)) = self.rx.recv().await {
-                    match resp {
-                        Some(RedisType::String(string)) => {
-                            write_string(&mut self.stream, &string).await
-                        }
-                        Some(RedisType::Int(number)) => {
-                            write_integer(&mut self.stream, number).await
-                        }
-                        Some(RedisType::Array(_)) => {
-                            write_wrongtype(&mut self.stream).await
-                        }
-                        Some(RedisType::Timestamp(_)) => todo!(),
-                        None => write_nil(&mut self.stream).await,
-                    }
-                } else {
-                    bail!("internal error trying to get the value")
+    async fn handle_client(&mut self, args: &[&str]) -> Result<()> {
+        if args.is_empty() {
+            bail!("wrong number of arguments for 'client' command")
+        }
+        match args[0].to_lowercase().as_str() {
+            "reply" => self.handle_client_reply(&args[1..]).await,
+            "setname" => {
+                if args.len() != 2 {
+                    bail!("wrong number of arguments for 'client|setname' command")
                 }
-            },
-            _ => bail!("wrong number of arguments for 'get' command")
+                check_client_name(args[1])?;
+                self.name = Some(String::from(args[1])).filter(|name| !name.is_empty());
+                write_ok(&mut self.out).await
+            }
+            "getname" => {
+                if args.len() != 1 {
+                    bail!("wrong number of arguments for 'client|getname' command")
+                }
+                let name = self.name.clone().map_or(RedisType::Null, RedisType::from);
+                self.reply(&name).await
+            }
+            _ => bail!("unknown subcommand '{}'. Try CLIENT HELP", args[0]),
+        }
+    }
+
+    async fn handle_debug(&mut self, args: &[&str]) -> Result<()> {
+        if args.is_empty() {
+            bail!("wrong number of arguments for 'debug' command")
+        }
+
+        match args[0].to_lowercase().as_str() {
+            "quicklist-packed-threshold" => {
+                if args.len() != 2 {
+                    bail!("wrong number of arguments for 'debug|quicklist-packed-threshold' command")
+                }
+                let threshold = parse_memory(args[1])
+                    .ok()
+                    .and_then(|threshold| usize::try_from(threshold).ok())
+                    .filter(|&threshold| threshold <= u32::MAX as usize)
+                    .ok_or_else(|| Error::msg("argument must be a memory value bigger than 1 and smaller than 4gb"))?;
+                self.store_tx.send(StoreCommand::SetPackedThreshold(threshold)).await.unwrap();
+                write_ok(&mut self.out).await
+            }
+            "replicate" => {
+                if args.len() < 2 {
+                    bail!("wrong number of arguments for 'debug|replicate' command")
+                }
+                if !self.get_config_flag("enable-debug-replicate").await {
+                    bail!("DEBUG REPLICATE not allowed. Set 'enable-debug-replicate' to 'yes' to enable it")
+                }
+                let cmd = RedisType::from(args[1..].to_vec());
+                self.store_tx.send(StoreCommand::Replicate(cmd)).await.unwrap();
+                write_ok(&mut self.out).await
+            }
+            _ => bail!("unknown subcommand '{}'. Try DEBUG HELP", args[0]),
+        }
+    }
+
+    /// Write the current data to the RDB file at `dir`/`dbfilename`
+    async fn save(&mut self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.store_tx.send(StoreCommand::Snapshot(tx)).await.unwrap();
+        let (entries, changes) = rx.await?;
+
+        let dir = self.get_config_value("dir").await;
+        let filename = self.get_config_value("dbfilename").await;
+        let (Some(dir), Some(filename)) = (dir, filename) else {
+            bail!("internal error getting the database path")
+        };
+        let path = PathBuf::from(dir).join(filename);
+        let result = save_rdb(&path, &entries).await;
+        self.store_tx.send(StoreCommand::Saved { changes, ok: result.is_ok() }).await.unwrap();
+        if let Err(error) = result {
+            eprintln!("Error saving the database to {}: {error}", path.to_string_lossy());
+            bail!("Background save failed")
+        }
+
+        Ok(())
+    }
+
+    async fn handle_save(&mut self, args: &[&str]) -> Result<()> {
+        if !args.is_empty() {
+            bail!("wrong number of arguments for 'save' command")
+        }
+
+        self.save().await?;
+        write_ok(&mut self.out).await
+    }
+
+    async fn handle_shutdown(&mut self, args: &[&str]) -> Result<()> {
+        // There are no save points, so the data is only saved when asked to
+        let modifiers = ["nosave", "save", "now", "force"];
+        if !args.iter().all(|arg| modifiers.contains(&arg.to_lowercase().as_str())) {
+            bail!("syntax error")
+        }
+        let Some(trigger) = &self.shutdown else {
+            bail!("SHUTDOWN is not allowed from this connection")
+        };
+        let trigger = trigger.clone();
+
+        let save = args.iter().any(|arg| arg.eq_ignore_ascii_case("save"));
+        let force = args.iter().any(|arg| arg.eq_ignore_ascii_case("force"));
+        if save {
+            if let Err(error) = self.save().await {
+                if !force {
+                    bail!("Errors trying to SHUTDOWN. Check logs.")
+                }
+                eprintln!("Shutting down without saving: {error}");
+            }
         }
+
+        // No reply: the connection is closed along with the rest
+        trigger.trigger();
+        Ok(())
     }
 
     async fn handle_config_get(&mut self, args: &[&str]) -> Result<()> {
@@ -135,18 +1499,42 @@ impl Client {
                  self.config_tx.send(ConfigCommand::Get { tx, items: keys }).await.unwrap();
                  // There is going to be an answer, ignore the possible Error (for the time being)
                  let values = rx.await.unwrap();
-                 let redis_values = values.into_iter().map(RedisType::from).collect();
-                 RedisType::Array(redis_values).write(&mut self.stream).await
+                 let pairs = values.chunks(2)
+                     .map(|pair| (RedisType::from(pair[0].as_str()), RedisType::from(pair[1].as_str())))
+                     .collect();
+                 self.reply(&RedisType::Map(pairs)).await
              }
          }
     }
 
+    async fn handle_config_set(&mut self, args: &[&str]) -> Result<()> {
+        if args.is_empty() || !args.len().is_multiple_of(2) {
+            bail!("wrong number of arguments for 'config|set' command")
+        }
+
+        let pairs = args.chunks(2)
+            .map(|pair| (pair[0].to_lowercase(), String::from(pair[1])))
+            .collect::<Vec<_>>();
+        // The store needs to know which keyspace events to publish
+        let notify = pairs.iter()
+            .rfind(|(key, _)| key == "notify-keyspace-events")
+            .map(|(_, flags)| NotifyFlags::parse(flags))
+            .transpose()?;
+        let (tx, rx) = oneshot::channel();
+        self.config_tx.send(ConfigCommand::Set { tx, pairs }).await.unwrap();
+        rx.await??;
+        if let Some(flags) = notify {
+            self.store_tx.send(StoreCommand::SetNotifyFlags(flags)).await.unwrap();
+        }
+        write_ok(&mut self.out).await
+    }
+
     async fn handle_config_help(&mut self, args: &[&str]) -> Result<()> {
         match args.len() {
             0 => {
-                write_array_size(&mut self.stream, HELP_LINES.len()).await?;
+                write_array_size(&mut self.out, HELP_LINES.len()).await?;
                 for arg in HELP_LINES {
-                    write_simple_string(&mut self.stream, arg).await?;
+                    write_simple_string(&mut self.out, arg).await?;
                 }
             }
             _ => {
@@ -162,6 +1550,7 @@ impl Client {
         }
         match args[0].to_lowercase().as_str() {
             "get" => self.handle_config_get(&args[1..]).await?,
+            "set" => self.handle_config_set(&args[1..]).await?,
             "help" => self.handle_config_help(&args[1..]).await?,
             _ => {
                 bail!("unknown subcommand '{}'. Try CONFIG HELP", args[0])
@@ -170,112 +1559,455 @@ impl Client {
         Ok(())
     }
 
-    async fn handle_keys(&mut self, args: &[&str]) -> Result<()> {
+    async fn handle_keys(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
         if args.len() != 1 {
             bail!("wrong number of arguments for 'keys' command")
         }
-        match args[0] {
-            "*" => {
-                self.store_tx.send(StoreCommand::AllKeys(self.id)).await.unwrap();
-                if let Some(CommandResponse::Keys(res)) = self.rx.recv().await {
-                    res.write(&mut self.stream).await?;
-                } else {
-                    bail!("internal error obtaining the keys");
-                }
+        let cmd = StoreCommand::Keys { id: self.id, pattern: raw[0].to_vec() };
+        self.store_tx.send(cmd).await.unwrap();
+        if let Some(CommandResponse::Keys(res)) = self.rx.recv().await {
+            self.reply(&res).await?;
+        } else {
+            bail!("internal error obtaining the keys");
+        }
+        Ok(())
+    }
+
+    async fn handle_scan(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        let Some((cursor, options)) = args.split_first() else {
+            bail!("wrong number of arguments for 'scan' command")
+        };
+        let Ok(cursor) = cursor.parse::<u64>() else { bail!("invalid cursor") };
+
+        let (mut count, mut pattern, mut kind) = (10, None, None);
+        let mut options = options.iter().zip(&raw[1..]);
+        while let Some((option, _)) = options.next() {
+            let Some((arg, raw_arg)) = options.next() else { bail!("syntax error") };
+            match option.to_ascii_lowercase().as_str() {
+                "count" => match parse_integer(arg)? {
+                    count_arg if count_arg >= 1 => count = count_arg as usize,
+                    _ => bail!("syntax error"),
+                },
+                "match" => pattern = Some(raw_arg.to_vec()),
+                "type" => match arg.to_ascii_lowercase().as_str() {
+                    kind_arg @ ("string" | "list" | "hash" | "set" | "zset") => kind = Some(kind_arg.to_string()),
+                    _ => bail!("unknown type name '{arg}'"),
+                },
+                _ => bail!("syntax error"),
+            }
+        }
+
+        self.store_tx.send(StoreCommand::Scan { id: self.id, cursor, count, pattern, kind }).await.unwrap();
+        match self.rx.recv().await {
+            Some(CommandResponse::Scan { cursor, keys }) => {
+                let keys = keys.into_iter().map(RedisType::from).collect();
+                let reply = RedisType::Array(vec![RedisType::from(cursor.to_string()), RedisType::Array(keys)]);
+                self.reply(&reply).await
+            }
+            _ => bail!("internal error scanning the keys"),
+        }
+    }
+
+    async fn handle_info(&mut self, args: &[&str]) -> Result<()> {
+        let sections = args.iter().map(|s| s.to_lowercase()).unique().collect::<Vec<_>>();
+        let mut answer = vec![];
+
+        // The persistence state is kept by the store, the rest by the configuration
+        if sections.is_empty() || sections.iter().any(|section| section == "persistence") {
+            let (tx, rx) = oneshot::channel();
+            self.store_tx.send(StoreCommand::PersistenceInfo(tx)).await.unwrap();
+            answer.push(info::persistence_info(&rx.await?));
+        }
+        if sections.is_empty() {
+            let (tx, rx) = oneshot::channel();
+            self.config_tx.send(ConfigCommand::AllInfo(tx)).await.unwrap();
+            answer.push(rx.await.unwrap());
+        } else {
+            let sections = sections.into_iter().filter(|section| section != "persistence").collect::<Vec<_>>();
+            if !sections.is_empty() {
+                let (tx, rx) = oneshot::channel();
+                self.config_tx.send(ConfigCommand::InfoOn { tx, sections }).await.unwrap();
+                answer.extend(rx.await.unwrap().into_iter().filter(|section| !section.is_empty()));
             }
-            other => {
-                if other.contains('*') {
-                    bail!("general pattern matching unsupported")
+        }
+
+        let answer = if answer.is_empty() { String::new() } else { answer.join("\r\n") + "\r\n" };
+        self.reply(&RedisType::Verbatim { format: *b"txt", text: answer }).await
+    }
+
+    /// Send the (UN)SUBSCRIBE confirmations, keeping track of the number of
+    /// subscriptions left
+    async fn write_subscriptions_response(&mut self) -> Result<()> {
+        match self.rx.recv().await {
+            Some(CommandResponse::Subscriptions { replies, count }) => {
+                self.subscriptions = count;
+                for reply in replies {
+                    self.reply(&reply).await?;
                 }
+                Ok(())
+            }
+            _ => bail!("internal error waiting for the store"),
+        }
+    }
+
+    /// SUBSCRIBE, or PSUBSCRIBE with `pattern`
+    async fn handle_subscribe(&mut self, args: &[&str], raw: &[&[u8]], pattern: bool) -> Result<()> {
+        if args.is_empty() {
+            let name = if pattern { "psubscribe" } else { "subscribe" };
+            bail!("wrong number of arguments for '{name}' command")
+        }
+
+        let names = raw.iter().map(|name| name.to_vec()).collect();
+        let tx = match self.messages_tx.upgrade() {
+            Some(tx) => tx,
+            None => {
+                let (tx, messages) = mpsc::channel(MESSAGE_BUFFER);
+                self.messages_tx = tx.downgrade();
+                self.messages = messages;
+                tx
+            }
+        };
+        self.store_tx.send(StoreCommand::Subscribe { id: self.id, names, pattern, tx }).await.unwrap();
+        self.write_subscriptions_response().await
+    }
+
+    /// UNSUBSCRIBE, or PUNSUBSCRIBE with `pattern`
+    async fn handle_unsubscribe(&mut self, raw: &[&[u8]], pattern: bool) -> Result<()> {
+        let names = raw.iter().map(|name| name.to_vec()).collect();
+        self.store_tx.send(StoreCommand::Unsubscribe { id: self.id, names, pattern }).await.unwrap();
+        self.write_subscriptions_response().await
+    }
+
+    async fn handle_publish(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.len() != 2 {
+            bail!("wrong number of arguments for 'publish' command")
+        }
 
-                let key = String::from(other);
+        let (channel, message) = (raw[0].to_vec(), Bytes::copy_from_slice(raw[1]));
+        self.store_tx.send(StoreCommand::Publish { id: self.id, channel, message }).await.unwrap();
+        self.write_integer_response().await
+    }
 
-                let mut acc = vec![];
-                let cmd = StoreCommand::Get { id: self.id, key: key.clone() };
-                self.store_tx.send(cmd).await.unwrap();
+    async fn handle_pubsub(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.is_empty() {
+            bail!("wrong number of arguments for 'pubsub' command")
+        }
 
-                if let Some(CommandResponse::Get(Some(_))) = self.rx.recv().await {
-                    acc.push(RedisType::String(key));
+        match args[0].to_ascii_lowercase().as_str() {
+            "channels" => {
+                if args.len() > 2 {
+                    bail!("wrong number of arguments for 'pubsub|channels' command")
+                }
+                let pattern = raw.get(1).map(|pattern| pattern.to_vec());
+                self.store_tx.send(StoreCommand::ActiveChannels { id: self.id, pattern }).await.unwrap();
+                self.write_value_response().await
+            }
+            "numsub" => {
+                let channels = raw[1..].iter().map(|channel| channel.to_vec()).collect();
+                self.store_tx.send(StoreCommand::NumSub { id: self.id, channels }).await.unwrap();
+                self.write_value_response().await
+            }
+            "numpat" => {
+                if args.len() != 1 {
+                    bail!("wrong number of arguments for 'pubsub|numpat' command")
                 }
+                self.store_tx.send(StoreCommand::NumPat(self.id)).await.unwrap();
+                self.write_integer_response().await
+            }
+            _ => bail!("unknown subcommand '{}'. Try PUBSUB HELP", args[0]),
+        }
+    }
+
+    async fn handle_multi(&mut self, args: &[&str]) -> Result<()> {
+        if !args.is_empty() {
+            bail!("wrong number of arguments for 'multi' command")
+        }
+        if self.transaction.is_some() {
+            bail!("MULTI calls can not be nested")
+        }
+
+        self.transaction = Some(vec![]);
+        write_ok(&mut self.out).await
+    }
+
+    /// Run the queued commands, unless some watched key changed since WATCH,
+    /// in which case the reply is a null array
+    async fn handle_exec(&mut self, args: &[&str]) -> Result<()> {
+        if !args.is_empty() {
+            bail!("wrong number of arguments for 'exec' command")
+        }
+        let Some(queued) = self.transaction.take() else {
+            bail!("EXEC without MULTI")
+        };
 
-                RedisType::Array(acc).write(&mut self.stream).await?;
+        let watched = std::mem::take(&mut self.watched);
+        let (tx, commands) = mpsc::channel(CLIENT_BUFFER);
+        self.store_tx.send(StoreCommand::Exec { id: self.id, watched, commands }).await.unwrap();
+        match self.rx.recv().await {
+            Some(CommandResponse::Integer(1)) => {}
+            Some(CommandResponse::Integer(_)) => return self.reply_null_array().await,
+            _ => bail!("internal error waiting for the store"),
+        }
+
+        // The store only listens to `tx` until it's dropped, at the end
+        let transaction = self.store_tx.for_transaction(tx);
+        let store_tx = std::mem::replace(&mut self.store_tx, transaction);
+        write_array_size(&mut self.out, queued.len()).await?;
+        for command in &queued {
+            let args = command.iter().map(Vec::as_slice).collect::<Vec<_>>();
+            if let Err(error) = Box::pin(self.dispatch(&args)).await {
+                self.send_error_message(&error.to_string()).await;
             }
         }
+        self.store_tx = store_tx;
+
         Ok(())
     }
 
-    async fn handle_info(&mut self, args: &[&str]) -> Result<()> {
-         let answer = if args.is_empty() {
-             let (tx, rx) = oneshot::channel();
-             self.config_tx.send(ConfigCommand::AllInfo(tx)).await.unwrap();
-             rx.await.unwrap() + "\r\n"
-             // info::all_info(&config) + "\r\n"
-         } else {
-             let (tx, rx) = oneshot::channel();
-             let sections = args.iter().map(|s| s.to_lowercase()).unique().collect();
-
-             self.config_tx.send(ConfigCommand::InfoOn {tx, sections}).await.unwrap();
-             let answer = rx.await.unwrap();
-        
-             if answer.len() > 0 {
-                 answer.join("") + "\r\n"
-             } else {
-                 String::from("")
-             }
-         };
+    async fn handle_discard(&mut self, args: &[&str]) -> Result<()> {
+        if !args.is_empty() {
+            bail!("wrong number of arguments for 'discard' command")
+        }
+        if self.transaction.take().is_none() {
+            bail!("DISCARD without MULTI")
+        }
+
+        self.watched.clear();
+        write_ok(&mut self.out).await
+    }
+
+    async fn handle_watch(&mut self, args: &[&str], raw: &[&[u8]]) -> Result<()> {
+        if args.is_empty() {
+            bail!("wrong number of arguments for 'watch' command")
+        }
+        if self.transaction.is_some() {
+            bail!("WATCH inside MULTI is not allowed")
+        }
+
+        let keys = raw.iter().map(|key| key.to_vec()).collect::<Vec<_>>();
+        self.store_tx.send(StoreCommand::GetVersions { id: self.id, keys: keys.clone() }).await.unwrap();
+        let Some(CommandResponse::Versions(versions)) = self.rx.recv().await else {
+            bail!("internal error waiting for the store")
+        };
+        // Watching a key again keeps the version from the first time
+        for (key, version) in keys.into_iter().zip(versions) {
+            if !self.watched.iter().any(|(watched, _)| *watched == key) {
+                self.watched.push((key, version));
+            }
+        }
+        write_ok(&mut self.out).await
+    }
+
+    async fn handle_unwatch(&mut self, args: &[&str]) -> Result<()> {
+        if !args.is_empty() {
+            bail!("wrong number of arguments for 'unwatch' command")
+        }
 
-         RedisType::from(answer).write(&mut self.stream).await
+        self.watched.clear();
+        write_ok(&mut self.out).await
     }
 
-    async fn handle_replconf(&mut self, _: &[&str]) -> Result<()> {
-        // Trivial implementation. We're ignoring all the REPLCONF details for now
-        write_simple_string(&mut self.stream, "OK").await
+    async fn handle_replconf(&mut self, args: &[&str]) -> Result<()> {
+        // Only the port is kept, to show it in INFO. Everything else is ignored for now
+        if let [option, port] = args {
+            if option.eq_ignore_ascii_case("listening-port") {
+                self.replica_port = Some(port.to_string());
+            }
+        }
+        write_simple_string(&mut self.out, "OK").await
     }
     async fn handle_wait(&mut self, _: &[&str]) -> Result<()> {
         self.store_tx.send(StoreCommand::ReplicaCount(self.id)).await.unwrap();
         if let Some(CommandResponse::ReplicaCount(count)) = self.rx.recv().await {
-            write_integer(&mut self.stream, count as i64).await
+            write_integer(&mut self.out, count as i64).await
         } else {
             let _ = write_simple_error(
-                &mut self.stream,
+                &mut self.out,
                 "internal error retrieving replica count").await;
             bail!("Client: error getting the replica count!")
         }
     }
 
-    async fn handle_psync(&mut self) -> Result<Receiver<Vec<u8>>> {
+    async fn get_config_value(&mut self, key: &str) -> Option<String> {
+        let (tx, rx) = oneshot::channel();
+        self.config_tx.send(ConfigCommand::Get { tx, items: vec![key.to_string()] }).await.unwrap();
+        let mut values = rx.await.unwrap();
+
+        if values.len() == 2 { values.pop() } else { None }
+    }
+
+    async fn get_config_flag(&mut self, key: &str) -> bool {
+        self.get_config_value(key).await.map(|value| value == "yes").unwrap_or(false)
+    }
+
+    async fn handle_psync(&mut self, stream: &mut TcpReader) -> Result<Receiver<Bytes>> {
         let (tx, rx) = oneshot::channel();
         self.config_tx.send(ConfigCommand::ReplicaDigest(tx)).await.unwrap();
         let id = rx.await.unwrap();
+        let diskless = self.get_config_flag("repl-diskless-sync").await;
+
+        let (replica_tx, replica_rx) = mpsc::channel(REPLICA_BUFFER);
+        let (snapshot_tx, snapshot_rx) = oneshot::channel();
+        self.store_tx.send(StoreCommand::InitReplica { tx: replica_tx, snapshot: snapshot_tx }).await.unwrap();
+        let entries = snapshot_rx.await?;
 
-        let (replica_tx, replica_rx) = mpsc::channel(16);
-        self.store_tx.send(StoreCommand::InitReplica(replica_tx)).await.unwrap();
-        write_simple_string(&mut self.stream, &format!("FULLRESYNC {id} 0")).await?;
-        // Empty RDB transfer for the time being. The file was generated using
-        // the official Redis server.
-        let empty_rdb = b"REDIS0010\xff\x00\x00\x00\x00\x00\x00\x00\x00";
-        write_bytes(&mut self.stream, empty_rdb).await?;
+        write_simple_string(stream, &format!("FULLRESYNC {id} 0")).await?;
+        // Replicas may take the replication id from the file as well
+        let metadata = [("repl-id", id.as_str()), ("repl-offset", "0")];
+        if diskless {
+            // The size is not known beforehand. The payload is delimited instead
+            // by a random mark, announced up front and repeated at the end.
+            let mark = eof_mark(&id);
+            stream.write_all(format!("$EOF:{mark}\r\n").as_bytes()).await?;
+            let writer = BufWriter::with_capacity(RDB_CHUNK_SIZE, &mut *stream);
+            write_rdb(writer, &metadata, &entries).await?;
+            stream.write_all(mark.as_bytes()).await?;
+        } else {
+            let rdb = write_rdb(vec![], &metadata, &entries).await?;
+            write_bytes(stream, &rdb).await?;
+        }
 
         Ok(replica_rx)
     }
 
-    pub async fn dispatch(&mut self, cmd_vec: &[&str]) -> Result<ClientStatus> {
-        let name = cmd_vec[0];
-        let args = &cmd_vec[1..];
+    pub(crate) async fn dispatch(&mut self, command: &[&[u8]]) -> Result<ClientStatus> {
+        // Names and options are text. Keys, members, fields and values are
+        // passed along as they came, as `raw`, to the commands that take them
+        let strings = command.iter().map(|arg| String::from_utf8_lossy(arg)).collect::<Vec<_>>();
+        let cmd_vec = strings.iter().map(|arg| arg.as_ref()).collect::<Vec<_>>();
+        // Empty commands are silently ignored
+        let Some((&name, args)) = cmd_vec.split_first() else {
+            return Ok(ClientStatus::Normal)
+        };
+        let raw = &command[1..];
+        if let Some(queued) = self.transaction.as_mut() {
+            if !runs_in_transaction(name) {
+                queued.push(command.iter().map(|arg| arg.to_vec()).collect());
+                write_simple_string(&mut self.out, "QUEUED").await?;
+                return Ok(ClientStatus::Normal)
+            }
+        }
         match name.to_ascii_lowercase().as_str() {
             "ping" => self.handle_ping(args).await?,
-            "echo" => self.handle_echo(args).await?,
+            "echo" => self.handle_echo(raw).await?,
             "hello" => self.handle_hello(args).await?,
-            "set" => self.handle_set(args).await?,
-            "get" => self.handle_get(args).await?,
+            "set" => self.handle_set(args, raw).await?,
+            "get" => self.handle_get(args, raw).await?,
+            "getrange" => self.handle_getrange(args, raw).await?,
+            "getex" => self.handle_getex(args, raw).await?,
+            "getset" => self.handle_getset(args, raw).await?,
+            "setnx" => self.handle_setnx(args, raw).await?,
+            "setex" => self.handle_setex(args, raw, "setex", "ex").await?,
+            "psetex" => self.handle_setex(args, raw, "psetex", "px").await?,
+            "mset" => self.handle_mset(args, raw).await?,
+            "incr" => self.handle_incr(args, raw, "incr", 1).await?,
+            "decr" => self.handle_incr(args, raw, "decr", -1).await?,
+            "incrby" => self.handle_incrby(args, raw, "incrby", 1).await?,
+            "decrby" => self.handle_incrby(args, raw, "decrby", -1).await?,
+            "mget" => self.handle_mget(args, raw).await?,
+            "setrange" => self.handle_setrange(args, raw).await?,
+            "append" => self.handle_append(args, raw).await?,
+            "bitfield" => self.handle_bitfield(args, raw).await?,
+            "lpush" => self.handle_push(args, raw, true, false).await?,
+            "rpush" => self.handle_push(args, raw, false, false).await?,
+            "lpushx" => self.handle_push(args, raw, true, true).await?,
+            "rpushx" => self.handle_push(args, raw, false, true).await?,
+            "lpop" => self.handle_pop(args, raw, true).await?,
+            "rpop" => self.handle_pop(args, raw, false).await?,
+            "lrange" => self.handle_lrange(args, raw).await?,
+            "hset" => self.handle_hset(args, raw).await?,
+            "hget" => self.handle_hget(args, raw).await?,
+            "sadd" => self.handle_set_members(args, raw, "sadd").await?,
+            "srem" => self.handle_set_members(args, raw, "srem").await?,
+            "smembers" => self.handle_smembers(args, raw).await?,
+            "sismember" => self.handle_sismember(args, raw, false).await?,
+            "smismember" => self.handle_sismember(args, raw, true).await?,
+            "scard" => self.handle_scard(args, raw).await?,
+            "sinter" => self.handle_combine(args, raw, "sinter", SetOperation::Intersection).await?,
+            "sunion" => self.handle_combine(args, raw, "sunion", SetOperation::Union).await?,
+            "sdiff" => self.handle_combine(args, raw, "sdiff", SetOperation::Difference).await?,
+            "smove" => self.handle_smove(args, raw).await?,
+            "zadd" => self.handle_zadd(args, raw).await?,
+            "zscore" => self.handle_zmember(args, raw, "zscore").await?,
+            "zrank" => self.handle_zmember(args, raw, "zrank").await?,
+            "zrevrank" => self.handle_zmember(args, raw, "zrevrank").await?,
+            "zcard" => self.handle_zcard(args, raw).await?,
+            "zrange" => self.handle_zrange(args, raw).await?,
+            "zincrby" => self.handle_zincrby(args, raw).await?,
+            "zcount" => self.handle_zcount(args, raw).await?,
+            "zpopmin" => self.handle_zpop(args, raw, false).await?,
+            "zpopmax" => self.handle_zpop(args, raw, true).await?,
+            "zrem" => self.handle_zrem(args, raw).await?,
+            "zintercard" => self.handle_intercard(args, raw, "zintercard").await?,
+            "zrangebyscore" => self.handle_zrangebyscore(args, raw).await?,
+            "sintercard" => self.handle_intercard(args, raw, "sintercard").await?,
+            "sinterstore" => self.handle_combine_store(args, raw, "sinterstore", SetOperation::Intersection).await?,
+            "sunionstore" => self.handle_combine_store(args, raw, "sunionstore", SetOperation::Union).await?,
+            "sdiffstore" => self.handle_combine_store(args, raw, "sdiffstore", SetOperation::Difference).await?,
+            "hmget" => self.handle_hmget(args, raw).await?,
+            "hsetnx" => self.handle_hsetnx(args, raw).await?,
+            "hgetall" => self.handle_hash_contents(args, raw, "hgetall").await?,
+            "hdel" => self.handle_hdel(args, raw).await?,
+            "hincrby" => self.handle_hincrby(args, raw).await?,
+            "hincrbyfloat" => self.handle_hincrbyfloat(args, raw).await?,
+            "hexists" => self.handle_hash_field(args, raw, "hexists").await?,
+            "hstrlen" => self.handle_hash_field(args, raw, "hstrlen").await?,
+            "hlen" => self.handle_hlen(args, raw).await?,
+            "hkeys" => self.handle_hash_contents(args, raw, "hkeys").await?,
+            "hvals" => self.handle_hash_contents(args, raw, "hvals").await?,
+            "blpop" => self.handle_blocking_pop(args, raw, true).await?,
+            "brpop" => self.handle_blocking_pop(args, raw, false).await?,
+            "rpoplpush" => self.handle_rpoplpush(args, raw).await?,
+            "lmove" => self.handle_lmove(args, raw).await?,
+            "llen" => self.handle_llen(args, raw).await?,
+            "lindex" => self.handle_lindex(args, raw).await?,
+            "lset" => self.handle_lset(args, raw).await?,
+            "lpos" => self.handle_lpos(args, raw).await?,
+            "lrem" => self.handle_lrem(args, raw).await?,
+            "linsert" => self.handle_linsert(args, raw).await?,
+            "expire" => self.handle_expire(args, raw, "expire", 1000).await?,
+            "pexpire" => self.handle_expire(args, raw, "pexpire", 1).await?,
+            "expireat" => self.handle_expireat(args, raw, "expireat", 1000).await?,
+            "pexpireat" => self.handle_expireat(args, raw, "pexpireat", 1).await?,
+            "persist" => self.handle_persist(args, raw).await?,
+            "expiretime" => self.handle_expiretime(args, raw, false).await?,
+            "pexpiretime" => self.handle_expiretime(args, raw, true).await?,
+            "type" => self.handle_type(args, raw).await?,
+            "randomkey" => self.handle_randomkey(args).await?,
+            "dbsize" => self.handle_dbsize(args).await?,
+            "flushdb" => self.handle_flushdb(args).await?,
+            "rename" => self.handle_rename(args, raw, false).await?,
+            "copy" => self.handle_copy(args, raw).await?,
+            "del" => self.handle_del(args, raw, "del").await?,
+            "unlink" => self.handle_del(args, raw, "unlink").await?,
+            "exists" => self.handle_exists(args, raw, "exists").await?,
+            "touch" => self.handle_exists(args, raw, "touch").await?,
+            "renamenx" => self.handle_rename(args, raw, true).await?,
+            "object" => self.handle_object(args, raw).await?,
             "config" => self.handle_config(args).await?,
-            "keys" => self.handle_keys(args).await?,
+            "client" => self.handle_client(args).await?,
+            "debug" => self.handle_debug(args).await?,
+            "shutdown" => self.handle_shutdown(args).await?,
+            "save" => self.handle_save(args).await?,
+            "keys" => self.handle_keys(args, raw).await?,
+            "scan" => self.handle_scan(args, raw).await?,
             "info" => self.handle_info(args).await?,
             "replconf" => self.handle_replconf(args).await?,
             "wait" => self.handle_wait(args).await?,
+            "subscribe" => self.handle_subscribe(args, raw, false).await?,
+            "psubscribe" => self.handle_subscribe(args, raw, true).await?,
+            "publish" => self.handle_publish(args, raw).await?,
+            "pubsub" => self.handle_pubsub(args, raw).await?,
+            "unsubscribe" => self.handle_unsubscribe(raw, false).await?,
+            "punsubscribe" => self.handle_unsubscribe(raw, true).await?,
+            "multi" => self.handle_multi(args).await?,
+            "exec" => self.handle_exec(args).await?,
+            "discard" => self.handle_discard(args).await?,
+            "watch" => self.handle_watch(args, raw).await?,
+            "unwatch" => self.handle_unwatch(args).await?,
             "psync" => {
-                if args != &["?", "-1"] {
-                    write_simple_error(&mut self.stream, "ERR Unsupported PSYNC arguments").await?;
+                if args != ["?", "-1"] {
+                    write_simple_error(&mut self.out, "ERR Unsupported PSYNC arguments").await?;
                     bail!("wrong arguments for PSYNC");
                 }
 
@@ -295,65 +2027,262 @@ impl Client {
 }
 
 
-async fn client_replica_loop(mut client: Client) {
-    let mut replica_rx = client.handle_psync().await.unwrap();
+async fn client_replica_loop(mut client: Client, mut stream: TcpReader, mut shutdown: watch::Receiver<bool>) {
+    let mut replica_rx = match client.handle_psync(&mut stream).await {
+        Ok(replica_rx) => replica_rx,
+        Err(error) => { eprintln!("Error during the full resync: {error}"); return },
+    };
 
-    loop {
-        let data = replica_rx.recv().await.unwrap();
+    let replica = ConnectedReplica {
+        ip: stream.get_ref().peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default(),
+        port: client.replica_port.clone().unwrap_or_default(),
+        offset: 0,
+    };
+    let (tx, rx) = oneshot::channel();
+    client.config_tx.send(ConfigCommand::AddReplica { tx, replica }).await.unwrap();
+    let Ok(replica_id) = rx.await else { return };
+
+    // The replica sends its ACKs while we're forwarding commands to it. Each
+    // direction gets its own half of the stream, so that neither interrupts
+    // the other halfway
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let config_tx = client.config_tx.clone();
+    let (max_args, max_bulk_len) = (client.max_args(), client.max_bulk_len());
+
+    let read_acks = async {
+        while let Ok(Some(Command { payload, .. })) = read_command(&mut reader, max_args, max_bulk_len).await {
+            match payload.as_slice() {
+                [command, option, offset] if command.eq_ignore_ascii_case(b"replconf") && option.eq_ignore_ascii_case(b"ack") => {
+                    if let Some(offset) = std::str::from_utf8(offset).ok().and_then(|offset| offset.parse().ok()) {
+                        config_tx.send(ConfigCommand::ReplicaAck { id: replica_id, offset }).await.unwrap();
+                    }
+                }
+                _ => eprintln!("Unexpected command from a replica: {payload:?}"),
+            }
+        }
+    };
+
+    let forward = async {
+        loop {
+            let data = tokio::select! {
+                data = replica_rx.recv() => match data {
+                    Some(data) => data,
+                    None => break,
+                },
+                _ = shutdown.changed() => break,
+            };
+
+            if writer.write_all(&data).await.is_err() {
+                eprintln!("Lost the connection to a replica");
+                break;
+            }
+        }
+    };
 
-        client.stream.write(&data).await.unwrap();
+    tokio::select! {
+        _ = read_acks => {}
+        _ = forward => {}
     }
+    client.config_tx.send(ConfigCommand::RemoveReplica(replica_id)).await.unwrap();
 }
 
-pub async fn client_loop(stream: TcpStream, store_tx: Sender<StoreCommand>, config_tx: Sender<ConfigCommand>) {
-    let addr = stream.local_addr().unwrap();
-    eprintln!("Handling events from {addr}");
-    let stream = BufReader::new(stream);
+// Pending replies are sent as soon as they reach this size, even if more
+// commands are waiting in the buffer
+const PIPELINE_FLUSH_SIZE: usize = 64 * 1024;
 
-    // Send an endpoint to the store so that we can receive responses
-    // to certain commands.
-    let (client_tx, mut client_rx) = mpsc::channel::<CommandResponse>(CLIENT_BUFFER);
+/// Whether the command might wait for a long time (eg. for data to arrive)
+fn is_blocking(cmd_vec: &[&[u8]]) -> bool {
+    cmd_vec.first().is_some_and(|name| [&b"blpop"[..], b"brpop"].contains(&name.to_ascii_lowercase().as_slice()))
+}
 
-    match store_tx.send(StoreCommand::InitClient(client_tx)).await {
-        Err(error) => { eprintln!("Error: {error}"); return },
-        _ => {}
+/// Resolves when the peer closes the connection. Anything it sent before is
+/// left in the buffer
+async fn peer_closed(stream: &mut TcpReader) {
+    if let Ok(buf) = stream.fill_buf().await {
+        if !buf.is_empty() {
+            std::future::pending::<()>().await
+        }
     }
+}
 
-    let client_id = match client_rx.recv().await.unwrap() {
-        CommandResponse::ClientId(id) => id,
-        _ => panic!("Client didn't receive an ID!"),
-    };
+pub async fn client_loop(
+    stream: TcpStream,
+    store_tx: StoreHandle,
+    config_tx: Sender<ConfigCommand>,
+    trigger: ShutdownTrigger,
+) {
+    let addr = stream.local_addr().unwrap();
+    eprintln!("Handling events from {addr}");
+    let mut stream = BufReader::new(stream);
+    let mut shutdown = trigger.subscribe();
 
-    let mut client = Client {
-        id: client_id,
-        stream,
-        rx: client_rx,
-        store_tx,
-        config_tx,
+    let mut client = match Client::new(store_tx, config_tx).await {
+        Ok(client) => client,
+        Err(error) => { eprintln!("Error: {error}"); return },
     };
+    client.shutdown = Some(trigger);
 
     loop {
-        match read_command(&mut client.stream).await {
-            Ok(cnt) => match cnt {
-                Some(Command { payload, .. }) => {
-                    let strs = payload.iter().map(|s| s.as_str()).collect::<Vec<_>>();
-                    match client.dispatch(strs.as_slice()).await {
-                        Err(error) => {
-                            client.send_error_message(&error.to_string()).await;
-                        }
-                        Ok(ClientStatus::Replica) => {
-                            client_replica_loop(client).await;
-                            break;
-                        }
-                        _ => {} // All good
+        let command = tokio::select! {
+            command = read_command(&mut stream, client.max_args(), client.max_bulk_len()) => command,
+            _ = shutdown.changed() => break,
+        };
+
+        match command {
+            // Nothing to do, not even counting it for CLIENT REPLY SKIP
+            Ok(Some(Command { payload, .. })) if payload.is_empty() => {}
+            Ok(Some(Command { payload, .. })) => {
+                match execute(&mut client, &mut stream, &payload, &mut shutdown).await {
+                    Some(ClientStatus::Normal) => {}
+                    Some(ClientStatus::Replica) => {
+                        client_replica_loop(client, stream, shutdown).await;
+                        break;
                     }
+                    None => break,
                 }
-                None => {}
-            },
+                if client.subscriptions > 0 && !subscribed_loop(&mut client, &mut stream, &mut shutdown).await {
+                    break;
+                }
+            }
+            // The client closed the connection
+            Ok(None) => break,
             Err(error) => {
                 client.send_error_message(&error.to_string()).await;
+                let _ = client.flush(&mut stream).await;
                 break;
             }
         }
     }
 }
+
+/// Run a command and queue its replies. They are sent once every command the
+/// client has pipelined so far has run, so that a batch of commands gets
+/// answered in a single write. Returns `None` when the connection has to be
+/// closed
+async fn execute(
+    client: &mut Client,
+    stream: &mut TcpReader,
+    payload: &[Vec<u8>],
+    shutdown: &mut watch::Receiver<bool>,
+) -> Option<ClientStatus> {
+    let args = payload.iter().map(Vec::as_slice).collect::<Vec<_>>();
+    let blocking = is_blocking(&args);
+    // The replies to the commands before this one can't wait for it
+    if blocking {
+        client.flush(stream).await.ok()?;
+    }
+    let start = client.out.len();
+    // Commands that block (eg. waiting for data) are interrupted
+    // when the server shuts down, or when the client goes away
+    let result = tokio::select! {
+        result = client.dispatch(args.as_slice()) => result,
+        _ = shutdown.changed() => return None,
+        _ = peer_closed(stream), if blocking => return None,
+    };
+    let status = match result {
+        Ok(status) => status,
+        Err(error) => {
+            client.send_error_message(&error.to_string()).await;
+            ClientStatus::Normal
+        }
+    };
+    client.apply_reply_mode(start);
+    if stream.buffer().is_empty() || client.out.len() >= PIPELINE_FLUSH_SIZE || matches!(status, ClientStatus::Replica) {
+        client.flush(stream).await.ok()?;
+    }
+
+    Some(status)
+}
+
+/// Whether the command runs right away after MULTI, instead of being queued
+fn runs_in_transaction(name: &str) -> bool {
+    ["multi", "exec", "discard", "watch", "quit", "reset"].contains(&name.to_ascii_lowercase().as_str())
+}
+
+/// Whether a RESP2 client can run the command while subscribed to channels
+fn allowed_when_subscribed(name: &str) -> bool {
+    ["subscribe", "ssubscribe", "psubscribe", "unsubscribe", "sunsubscribe", "punsubscribe", "ping", "quit", "reset"]
+        .contains(&name.to_ascii_lowercase().as_str())
+}
+
+/// Serve a client while it's subscribed to some channel, forwarding the
+/// messages published on them as they arrive. Returns whether the connection
+/// is still open
+async fn subscribed_loop(client: &mut Client, stream: &mut TcpReader, shutdown: &mut watch::Receiver<bool>) -> bool {
+    while client.subscriptions > 0 {
+        // Only wait for the client to send something, without reading it,
+        // because a message may arrive in the middle of a command
+        let closed = tokio::select! {
+            buf = stream.fill_buf() => buf.map_or(true, |buf| buf.is_empty()),
+            message = client.messages.recv() => {
+                // Without a message, the store dropped the client for not
+                // keeping up with them
+                let Some(message) = message else {
+                    return false
+                };
+                if client.flush(stream).await.is_err() {
+                    return false
+                }
+                if let CommandResponse::Message(message) = message {
+                    if message.write_as(stream, client.proto).await.is_err() {
+                        return false
+                    }
+                }
+                continue
+            }
+            _ = shutdown.changed() => return false,
+        };
+        if closed {
+            return false
+        }
+
+        match read_command(stream, client.max_args(), client.max_bulk_len()).await {
+            Ok(Some(Command { payload, .. })) if payload.is_empty() => {}
+            Ok(Some(Command { payload, .. })) => {
+                let name = String::from_utf8_lossy(&payload[0]);
+                if client.proto == Protocol::Resp2 && !allowed_when_subscribed(&name) {
+                    let message = format!(
+                        "Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                        name.to_ascii_lowercase());
+                    client.send_error_message(&message).await;
+                    if client.flush(stream).await.is_err() {
+                        return false
+                    }
+                    continue
+                }
+                if execute(client, stream, &payload, shutdown).await.is_none() {
+                    return false
+                }
+            }
+            Ok(None) => return false,
+            Err(error) => {
+                client.send_error_message(&error.to_string()).await;
+                let _ = client.flush(stream).await;
+                return false
+            }
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::string_range;
+
+    #[test]
+    fn test_string_range() {
+        // "Hello World"
+        assert_eq!(string_range(0, 4, 11), Some((0, 4)));
+        assert_eq!(string_range(-3, -1, 11), Some((8, 10)));
+        assert_eq!(string_range(0, -1, 11), Some((0, 10)));
+        assert_eq!(string_range(5, 100, 11), Some((5, 10)));
+        assert_eq!(string_range(-100, 2, 11), Some((0, 2)));
+        // Redis clamps a negative end to the first byte
+        assert_eq!(string_range(0, -100, 11), Some((0, 0)));
+        assert_eq!(string_range(4, 2, 11), None);
+        assert_eq!(string_range(-1, -5, 11), None);
+        assert_eq!(string_range(20, 30, 11), None);
+        assert_eq!(string_range(0, -1, 0), None);
+    }
+}
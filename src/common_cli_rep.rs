@@ -1,47 +1,219 @@
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{bail, Error, Result};
 
-use tokio::sync::mpsc::Sender;
-
-use crate::io::*;
-use crate::store::StoreCommand;
-use crate::types::RedisType;
-
-pub async fn handle_set(stream: &mut TcpReader, store_tx: &Sender<StoreCommand>, args: &[&str], ack: bool) -> Result<()> {
-    let now = SystemTime::now();
-    match args.len() {
-        2 | 4 => {
-            let duration = if args.len() == 4 {
-                if args[2].to_ascii_lowercase() == "px" {
-                    Some(Duration::from_millis(args[3]
-                            .parse::<u64>()
-                            .map_err(|_| Error::msg("value is not an integer or out of range"))?
-                    ))
-                } else {
-                    bail!("syntax error")
+use crate::store::{ExpireCondition, LPosOptions, SetCondition, SetOptions, SetTtl};
+
+pub fn parse_integer(arg: &str) -> Result<i64> {
+    arg.parse::<i64>()
+        .map_err(|_| Error::msg("value is not an integer or out of range"))
+}
+
+/// Turn an expiration option (EX, PX, EXAT or PXAT) and its argument into
+/// an absolute time
+pub fn parse_expiry(option: &str, arg: &str, command: &str) -> Result<SystemTime> {
+    let amount = parse_integer(arg)?;
+    let invalid = || Error::msg(format!("invalid expire time in '{command}' command"));
+    if amount <= 0 {
+        return Err(invalid())
+    }
+    let amount = amount as u64;
+
+    match option.to_ascii_lowercase().as_str() {
+        "ex" => SystemTime::now().checked_add(Duration::from_secs(amount)).ok_or_else(invalid),
+        "px" => SystemTime::now().checked_add(Duration::from_millis(amount)).ok_or_else(invalid),
+        "exat" => UNIX_EPOCH.checked_add(Duration::from_secs(amount)).ok_or_else(invalid),
+        "pxat" => UNIX_EPOCH.checked_add(Duration::from_millis(amount)).ok_or_else(invalid),
+        _ => bail!("syntax error"),
+    }
+}
+
+/// Parse the optional flags of EXPIRE, EXPIREAT and friends
+pub fn parse_expire_condition(args: &[&str]) -> Result<ExpireCondition> {
+    let mut condition = ExpireCondition::default();
+
+    for arg in args {
+        match arg.to_ascii_lowercase().as_str() {
+            "nx" => condition.nx = true,
+            "xx" => condition.xx = true,
+            "gt" => condition.gt = true,
+            "lt" => condition.lt = true,
+            _ => bail!("Unsupported option {arg}"),
+        }
+    }
+
+    if condition.nx && (condition.xx || condition.gt || condition.lt) {
+        bail!("NX and XX, GT or LT options at the same time are not compatible")
+    }
+    if condition.gt && condition.lt {
+        bail!("GT and LT options at the same time are not compatible")
+    }
+
+    Ok(condition)
+}
+
+/// Parse the options of SET, after the key and the value
+pub fn parse_set_options(args: &[&str]) -> Result<SetOptions> {
+    let mut options = SetOptions::default();
+    let mut ttl_given = false;
+    let mut args = args.iter();
+
+    while let Some(option) = args.next() {
+        match option.to_ascii_lowercase().as_str() {
+            "nx" | "xx" if options.condition.is_some() => bail!("syntax error"),
+            "nx" => options.condition = Some(SetCondition::IfMissing),
+            "xx" => options.condition = Some(SetCondition::IfExists),
+            "get" => options.get = true,
+            _ if ttl_given => bail!("syntax error"),
+            "keepttl" => {
+                options.ttl = SetTtl::Keep;
+                ttl_given = true;
+            }
+            "ex" | "px" | "exat" | "pxat" => {
+                let Some(arg) = args.next() else { bail!("syntax error") };
+                options.ttl = SetTtl::ExpireAt(parse_expiry(option, arg, "set")?);
+                ttl_given = true;
+            }
+            _ => bail!("syntax error"),
+        }
+    }
+
+    Ok(options)
+}
+
+/// Parse the options of LPOS, after the key and the element
+pub fn parse_lpos_options(args: &[&str]) -> Result<LPosOptions> {
+    let mut options = LPosOptions::default();
+    let mut args = args.iter();
+
+    while let Some(option) = args.next() {
+        let Some(arg) = args.next() else { bail!("syntax error") };
+        match option.to_ascii_lowercase().as_str() {
+            "rank" => {
+                options.rank = parse_integer(arg)?;
+                if options.rank == 0 {
+                    bail!("RANK can't be zero: use 1 to start from the first match, 2 from the second ... \
+                           or use negative to start from the end of the list")
+                }
+                if options.rank == i64::MIN {
+                    bail!("value is out of range")
                 }
-            } else {
-                None
-            };
-            let key = String::from(args[0]);
-            let value = RedisType::String(args[1].into());
-            store_tx.send(
-                if let Some(dur) = duration {
-                    let until = now.checked_add(dur).unwrap();
-
-                    StoreCommand::SetEx { key, value, until }
-                } else {
-                    StoreCommand::Set { key, value }
-                }).await.unwrap();
-
-            if ack {
-                write_ok(stream).await
-            } else {
-                Ok(())
             }
+            "count" => {
+                let count = parse_integer(arg)?;
+                options.count = Some(usize::try_from(count).map_err(|_| Error::msg("COUNT can't be negative"))?);
+            }
+            "maxlen" => {
+                let maxlen = parse_integer(arg)?;
+                options.maxlen = usize::try_from(maxlen).map_err(|_| Error::msg("MAXLEN can't be negative"))?;
+            }
+            _ => bail!("syntax error"),
         }
-        _ => bail!("wrong number of arguments for 'set' command")
     }
+
+    Ok(options)
 }
 
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use crate::{
+        common_cli_rep::{parse_expire_condition, parse_expiry, parse_lpos_options, parse_set_options},
+        store::{ExpireCondition, LPosOptions, SetCondition, SetTtl},
+    };
+
+    #[test]
+    fn test_parse_expiry() {
+        let at = parse_expiry("PXAT", "1700000000123", "getex").unwrap();
+        assert_eq!(at, UNIX_EPOCH + Duration::from_millis(1700000000123));
+        let at = parse_expiry("exat", "1700000000", "getex").unwrap();
+        assert_eq!(at, UNIX_EPOCH + Duration::from_secs(1700000000));
+
+        let at = parse_expiry("EX", "100", "getex").unwrap();
+        assert!(at > SystemTime::now() + Duration::from_secs(90));
+
+        assert!(parse_expiry("EX", "0", "getex").is_err());
+        assert!(parse_expiry("PX", "-5", "getex").is_err());
+        assert!(parse_expiry("PX", "ten", "getex").is_err());
+        assert!(parse_expiry("KEEPTTL", "10", "getex").is_err());
+    }
+
+    #[test]
+    fn test_set_expiry_variants() {
+        let expires_at = |args: &[&str]| match parse_set_options(args).unwrap().ttl {
+            SetTtl::ExpireAt(until) => until,
+            other => panic!("unexpected TTL {other:?}"),
+        };
+
+        // Absolute times don't depend on the current time
+        assert_eq!(expires_at(&["EXAT", "4102444800"]), UNIX_EPOCH + Duration::from_secs(4102444800));
+        assert_eq!(expires_at(&["PXAT", "4102444800123"]), UNIX_EPOCH + Duration::from_millis(4102444800123));
+
+        let before = SystemTime::now();
+        let ex = expires_at(&["EX", "100"]);
+        let px = expires_at(&["PX", "100000"]);
+        let after = SystemTime::now();
+        for until in [ex, px] {
+            assert!(until >= before + Duration::from_secs(100) && until <= after + Duration::from_secs(100));
+        }
+
+        assert!(parse_set_options(&["EXAT", "100", "PXAT", "100000"]).is_err());
+        assert!(parse_set_options(&["PX", "100", "EX", "1"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_set_options() {
+        let options = parse_set_options(&[]).unwrap();
+        assert_eq!((options.condition, options.get, options.ttl), (None, false, SetTtl::Discard));
+
+        let options = parse_set_options(&["nx", "GET", "PXAT", "1000"]).unwrap();
+        assert_eq!(options.condition, Some(SetCondition::IfMissing));
+        assert!(options.get);
+        assert_eq!(options.ttl, SetTtl::ExpireAt(UNIX_EPOCH + Duration::from_millis(1000)));
+
+        let options = parse_set_options(&["KEEPTTL", "XX"]).unwrap();
+        assert_eq!((options.condition, options.ttl), (Some(SetCondition::IfExists), SetTtl::Keep));
+
+        for wrong in [
+            &["NX", "XX"][..],
+            &["EX", "10", "PX", "100"],
+            &["KEEPTTL", "EX", "10"],
+            &["EX"],
+            &["EX", "0"],
+            &["FOO"],
+        ] {
+            assert!(parse_set_options(wrong).is_err(), "{wrong:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn test_parse_expire_condition() {
+        assert_eq!(parse_expire_condition(&[]).unwrap(), ExpireCondition::default());
+        assert_eq!(parse_expire_condition(&["nx"]).unwrap(), ExpireCondition { nx: true, ..Default::default() });
+        let xx_lt = ExpireCondition { xx: true, lt: true, ..Default::default() };
+        assert_eq!(parse_expire_condition(&["XX", "lt"]).unwrap(), xx_lt);
+
+        for wrong in [&["NX", "XX"][..], &["GT", "NX"], &["GT", "LT"], &["FOO"]] {
+            assert!(parse_expire_condition(wrong).is_err(), "{wrong:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn test_parse_lpos_options() {
+        assert_eq!(parse_lpos_options(&[]).unwrap(), LPosOptions::default());
+        let options = parse_lpos_options(&["RANK", "-2", "count", "0", "MaxLen", "10"]).unwrap();
+        assert_eq!(options, LPosOptions { rank: -2, count: Some(0), maxlen: 10 });
+
+        for wrong in [
+            &["RANK", "0"][..],
+            &["RANK", "-9223372036854775808"],
+            &["COUNT", "-1"],
+            &["MAXLEN", "-1"],
+            &["COUNT"],
+            &["FOO", "1"],
+        ] {
+            assert!(parse_lpos_options(wrong).is_err(), "{wrong:?} should be rejected");
+        }
+    }
+}
@@ -1,11 +1,12 @@
-use anyhow::{bail, Result};
-use std::collections::HashMap;
+use anyhow::{bail, Error, Result};
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
 use tokio::sync::{mpsc, oneshot};
 
 use crate::{
     info,
-    replica::ReplicaInfo
+    notify::NotifyFlags,
+    replica::{ConnectedReplica, ReplicaInfo},
 };
 
 pub const CMD_BUFFER: usize = 32;
@@ -17,6 +18,20 @@ const ACCEPTABLE_KEYS: &[&str] = &[
     "port",
     "replicaof",
     "master_replid",
+    "repl-diskless-sync",
+    "max-command-args",
+    "proto-max-bulk-len",
+    "maxmemory",
+    "enable-debug-replicate",
+    "notify-keyspace-events",
+    "store-shards",
+    "rdbchecksum",
+];
+
+// Settings holding a number of bytes. They accept human-readable units
+const MEMORY_KEYS: &[&str] = &[
+    "maxmemory",
+    "proto-max-bulk-len",
 ];
 
 const DEFAULT_CONFIG: &[(&str, &str)] = &[
@@ -24,19 +39,62 @@ const DEFAULT_CONFIG: &[(&str, &str)] = &[
     ("dbfilename", "dump.rdb"),
     ("dir", "."),
     ("port", "6379"),
+    ("repl-diskless-sync", "no"),
+    ("max-command-args", "1048576"),
+    ("proto-max-bulk-len", "536870912"),
+    ("maxmemory", "0"),
+    // DEBUG REPLICATE is only meant for tests
+    ("enable-debug-replicate", "no"),
+    ("notify-keyspace-events", ""),
+    // Number of tasks the keyspace is split across. Only read at startup
+    ("store-shards", "1"),
+    // Only about checking the checksum when loading: the files written never have one
+    ("rdbchecksum", "yes"),
 ];
 
 pub enum ConfigCommand {
     Get { tx: oneshot::Sender<Vec<String>>, items: Vec<String> },
+    Set { tx: oneshot::Sender<Result<()>>, pairs: Vec<(String, String)> },
     AllInfo(oneshot::Sender<String>),
     InfoOn { tx: oneshot::Sender<Vec<String>>, sections: Vec<String> },
     ReplicaDigest(oneshot::Sender<String>),
+    /// A replica finished its initial sync. Answers with an id for it
+    AddReplica { tx: oneshot::Sender<usize>, replica: ConnectedReplica },
+    ReplicaAck { id: usize, offset: usize },
+    RemoveReplica(usize),
+}
+
+/// Parse a memory amount like "100mb" into a number of bytes. Follows the
+/// Redis convention: "k", "m" and "g" are powers of 1000, while "kb", "mb"
+/// and "gb" (or "kib", "mib" and "gib") are powers of 1024
+pub fn parse_memory(value: &str) -> Result<u64> {
+    let value = value.to_ascii_lowercase();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let multiplier: u64 = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" | "kib" => 1024,
+        "m" => 1000 * 1000,
+        "mb" | "mib" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" | "gib" => 1024 * 1024 * 1024,
+        _ => bail!("argument must be a memory value"),
+    };
+
+    number.parse::<u64>().ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| Error::msg("argument must be a memory value"))
 }
 
 #[derive(Clone)]
 pub struct Configuration {
     store: HashMap<String, String>,
     replica: ReplicaInfo,
+    // Replicas of this server, by id
+    replicas: BTreeMap<usize, ConnectedReplica>,
+    next_replica_id: usize,
 }
 
 impl Default for Configuration {
@@ -44,6 +102,8 @@ impl Default for Configuration {
         Self {
             store: DEFAULT_CONFIG.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect(),
             replica: ReplicaInfo::new(),
+            replicas: BTreeMap::new(),
+            next_replica_id: 0,
         }
     }
 }
@@ -52,11 +112,25 @@ impl Configuration {
         Self {
             store: HashMap::new(),
             replica: ReplicaInfo::new(),
+            replicas: BTreeMap::new(),
+            next_replica_id: 0,
         }
     }
 
     pub fn update(&mut self, key: String, value: String) -> Result<Option<String>> {
         if ACCEPTABLE_KEYS.contains(&key.as_str()) {
+            // Keep memory values normalized as bytes
+            let value = if MEMORY_KEYS.contains(&key.as_str()) {
+                parse_memory(&value)?.to_string()
+            } else {
+                value
+            };
+            if key == "notify-keyspace-events" {
+                NotifyFlags::parse(&value)?;
+            }
+            if key == "store-shards" && !value.parse::<usize>().is_ok_and(|count| count > 0) {
+                bail!("argument must be a positive number")
+            }
             let current = self.store.remove(key.as_str());
             self.store.insert(key, value);
             Ok(current)
@@ -102,39 +176,69 @@ impl Configuration {
     pub fn replica_info(&self) -> &ReplicaInfo {
         &self.replica
     }
+
+    pub fn connected_replicas(&self) -> impl Iterator<Item = &ConnectedReplica> {
+        self.replicas.values()
+    }
 }
 
-pub async fn config_loop(config: Configuration, mut rx: mpsc::Receiver<ConfigCommand>) {
-    loop {
-        if let Some(cmd) = rx.recv().await {
-            match cmd {
-                ConfigCommand::Get { tx, items } => {
-                    let values = items.into_iter()
-                        .map(|arg| config.get(&arg).and_then(|val| Some(vec![arg, val])))
-                        .flatten()
-                        .flatten()
-                        .collect();
-                    tx.send(values).unwrap();
-                }
-                ConfigCommand::AllInfo(tx) => {
-                    tx.send(info::all_info(&config)).unwrap();
-                }
-                ConfigCommand::InfoOn { tx, sections } => {
-                    tx.send(sections.into_iter()
-                                    .map(|sec| info::info_on(&config, sec.as_str()))
-                                    .collect()).unwrap();
+pub async fn config_loop(mut config: Configuration, mut rx: mpsc::Receiver<ConfigCommand>) {
+    while let Some(cmd) = rx.recv().await {
+        match cmd {
+            ConfigCommand::Get { tx, items } => {
+                let values = items.into_iter()
+                    .filter_map(|arg| config.get(&arg).map(|val| vec![arg, val]))
+                    .flatten()
+                    .collect();
+                tx.send(values).unwrap();
+            }
+            ConfigCommand::Set { tx, pairs } => {
+                // Either all the settings are applied, or none
+                let mut updated = config.clone();
+                let result = pairs.into_iter().try_for_each(|(key, value)| {
+                    updated.update(key.clone(), value)
+                        .map(|_| ())
+                        .map_err(|error| Error::msg(format!(
+                            "CONFIG SET failed (possibly related to argument '{key}') - {error}"
+                        )))
+                });
+                if result.is_ok() {
+                    config = updated;
                 }
-                ConfigCommand::ReplicaDigest(tx) => {
-                    tx.send(config.replica_info().digest_string()).unwrap();
+                let _ = tx.send(result);
+            }
+            ConfigCommand::AllInfo(tx) => {
+                tx.send(info::all_info(&config)).unwrap();
+            }
+            ConfigCommand::InfoOn { tx, sections } => {
+                tx.send(sections.into_iter()
+                                .map(|sec| info::info_on(&config, sec.as_str()))
+                                .collect()).unwrap();
+            }
+            ConfigCommand::ReplicaDigest(tx) => {
+                tx.send(config.replica_info().digest_string()).unwrap();
+            }
+            ConfigCommand::AddReplica { tx, replica } => {
+                let id = config.next_replica_id;
+                config.next_replica_id += 1;
+                config.replicas.insert(id, replica);
+                let _ = tx.send(id);
+            }
+            ConfigCommand::ReplicaAck { id, offset } => {
+                if let Some(replica) = config.replicas.get_mut(&id) {
+                    replica.offset = offset;
                 }
             }
+            ConfigCommand::RemoveReplica(id) => {
+                config.replicas.remove(&id);
+            }
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::config::{Configuration, DEFAULT_CONFIG};
+    use crate::config::{parse_memory, Configuration, DEFAULT_CONFIG};
 
     #[test]
     fn test_default_keys() {
@@ -166,4 +270,33 @@ mod tests {
 
         assert!(config.update(String::from("foo"), String::from("bar")).is_err());
     }
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(parse_memory("1024").unwrap(), 1024);
+        assert_eq!(parse_memory("10b").unwrap(), 10);
+        assert_eq!(parse_memory("1k").unwrap(), 1000);
+        assert_eq!(parse_memory("1kb").unwrap(), 1024);
+        assert_eq!(parse_memory("1KiB").unwrap(), 1024);
+        assert_eq!(parse_memory("100m").unwrap(), 100_000_000);
+        assert_eq!(parse_memory("100mb").unwrap(), 100 * 1024 * 1024);
+        assert_eq!(parse_memory("100MIB").unwrap(), 100 * 1024 * 1024);
+        assert_eq!(parse_memory("2g").unwrap(), 2_000_000_000);
+        assert_eq!(parse_memory("1gb").unwrap(), 1024 * 1024 * 1024);
+        assert_eq!(parse_memory("1gib").unwrap(), 1024 * 1024 * 1024);
+
+        for wrong in ["100xb", "", "mb", "-1mb", "1.5gb", "99999999999999999999gb"] {
+            assert!(parse_memory(wrong).is_err(), "{wrong:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn test_update_memory_key() {
+        let mut config = Configuration::default();
+
+        config.update(String::from("maxmemory"), String::from("100mb")).unwrap();
+        assert_eq!(config.get("maxmemory"), Some(String::from("104857600")));
+        assert!(config.update(String::from("maxmemory"), String::from("100xb")).is_err());
+        assert_eq!(config.get("maxmemory"), Some(String::from("104857600")));
+    }
 }
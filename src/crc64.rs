@@ -0,0 +1,39 @@
+// CRC-64 with the Jones polynomial, the one Redis uses for the checksum of RDB
+// files: reflected input and output, starting from 0 and with no final XOR.
+// This is the polynomial with its bits reversed, as the CRC is reflected
+const POLYNOMIAL: u64 = 0x95ac9329ac4bc9b5;
+
+const TABLE: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = byte as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+};
+
+/// Add `data` to the checksum `crc`, which is 0 for no data
+pub fn crc64(crc: u64, data: &[u8]) -> u64 {
+    data.iter().fold(crc, |crc, &byte| TABLE[((crc ^ byte as u64) & 0xff) as usize] ^ (crc >> 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crc64::crc64;
+
+    #[test]
+    fn test_crc64() {
+        // The check value from the Redis sources
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+        assert_eq!(crc64(0, b""), 0);
+        // It can be worked out piece by piece
+        assert_eq!(crc64(crc64(0, b"1234"), b"56789"), 0xe9c6d914c4b8d9ca);
+    }
+}
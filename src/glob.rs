@@ -0,0 +1,108 @@
+/// Whether `string` matches a glob-style `pattern`, following the rules of
+/// Redis: `*` matches any sequence, `?` any single character, `[...]` any of
+/// the characters in the class (`[^...]` any but them, with ranges like
+/// `a-z`), and `\` escapes the next character
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => string.is_empty(),
+        Some((b'*', rest)) => {
+            let rest = &rest[rest.iter().take_while(|&&c| c == b'*').count()..];
+            rest.is_empty() || (0..=string.len()).any(|start| glob_match(rest, &string[start..]))
+        }
+        Some((b'?', rest)) => !string.is_empty() && glob_match(rest, &string[1..]),
+        Some((b'[', rest)) => match string.split_first() {
+            Some((&c, tail)) => {
+                let (matched, rest) = match_class(rest, c);
+                matched && glob_match(rest, tail)
+            }
+            None => false,
+        },
+        Some((b'\\', [escaped, rest @ ..])) => string.first() == Some(escaped) && glob_match(rest, &string[1..]),
+        Some((literal, rest)) => string.first() == Some(literal) && glob_match(rest, &string[1..]),
+    }
+}
+
+/// Match a character against a class, with `pattern` starting right after the
+/// opening `[`. Returns whether it matches, and what's left of the pattern
+/// after the class
+fn match_class(pattern: &[u8], c: u8) -> (bool, &[u8]) {
+    let (negated, mut pattern) = match pattern.split_first() {
+        Some((b'^', rest)) => (true, rest),
+        _ => (false, pattern),
+    };
+
+    let mut matched = false;
+    loop {
+        match pattern {
+            // An unterminated class ends with the pattern
+            [] => break,
+            [b']', rest @ ..] => {
+                pattern = rest;
+                break
+            }
+            [b'\\', escaped, rest @ ..] => {
+                matched |= *escaped == c;
+                pattern = rest;
+            }
+            // A dash right before the closing bracket is just a dash
+            [start, b'-', end, rest @ ..] if *end != b']' => {
+                let (low, high) = if start <= end { (*start, *end) } else { (*end, *start) };
+                matched |= (low..=high).contains(&c);
+                pattern = rest;
+            }
+            [other, rest @ ..] => {
+                matched |= *other == c;
+                pattern = rest;
+            }
+        }
+    }
+    (matched != negated, pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::glob::glob_match;
+
+    #[test]
+    fn test_glob_match() {
+        let matches = |pattern: &str, string: &str| glob_match(pattern.as_bytes(), string.as_bytes());
+
+        assert!(matches("*", ""));
+        assert!(matches("news.*", "news.sports"));
+        assert!(!matches("news.*", "weather"));
+        assert!(matches("*.*.*", "a.b.c"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(matches("h[a-c]llo", "hbllo"));
+        assert!(matches("h\\*llo", "h*llo"));
+        assert!(!matches("h\\*llo", "hello"));
+    }
+
+    #[test]
+    fn test_glob_match_classes() {
+        let matches = |pattern: &str, string: &str| glob_match(pattern.as_bytes(), string.as_bytes());
+
+        // Ranges work either way round, and can be mixed with single characters
+        assert!(matches("[z-a]", "m"));
+        assert!(matches("[a-c0-9_]", "_"));
+        assert!(!matches("[a-c0-9_]", "d"));
+        assert!(!matches("[^a-c]", "b"));
+        assert!(!matches("[^a]", ""));
+        // A dash that isn't between two characters is just a dash
+        assert!(matches("[a-]", "-"));
+        // Escapes inside classes
+        assert!(matches("[\\]]", "]"));
+        assert!(matches("[\\-]", "-"));
+        assert!(!matches("[\\-]", "\\"));
+        assert!(matches("\\[a]", "[a]"));
+        // An unterminated class goes on until the end of the pattern
+        assert!(matches("h[ae", "ha"));
+        // A trailing backslash matches itself
+        assert!(matches("a\\", "a\\"));
+        assert!(matches("*?", "x"));
+        assert!(!matches("*?", ""));
+    }
+}
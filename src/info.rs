@@ -1,3 +1,5 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use crate::config::Configuration;
 
 const SEPARATOR: &str = "\r\n";
@@ -5,18 +7,50 @@ const SECTIONS: &[(&str, &str)] = &[
     ("replication", "Replication"),
 ];
 
+/// State of the persistence, as tracked by the store
+pub struct PersistenceInfo {
+    pub changes_since_save: u64,
+    pub last_save: SystemTime,
+    pub last_save_ok: bool,
+}
+
+/// The persistence section. Its data comes from the store instead of the
+/// configuration. There's no AOF nor background saving, and the data is
+/// loaded before accepting connections
+pub fn persistence_info(info: &PersistenceInfo) -> String {
+    let last_save = info.last_save.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+    [
+        String::from("# Persistence"),
+        String::from("loading:0"),
+        format!("rdb_changes_since_last_save:{}", info.changes_since_save),
+        String::from("rdb_bgsave_in_progress:0"),
+        format!("rdb_last_save_time:{last_save}"),
+        format!("rdb_last_bgsave_status:{}", if info.last_save_ok { "ok" } else { "err" }),
+        String::from("aof_enabled:0"),
+        String::from("aof_last_bgrewrite_status:ok"),
+    ].join(SEPARATOR)
+}
+
 pub fn info_on(config: &Configuration, section: &str) -> String {
     if section == "replication" {
         let is_replica = config.get("replicaof").is_some();
         let repl_info = config.replica_info();
 
-        vec![
+        let mut lines = vec![
             String::from("# Replication"),
             String::from(if !is_replica { "role:master" } else { "role:slave" }),
-            String::from("connected_slaves:0"),
-            format!("master_replid:{}", repl_info.digest_string()),
-            format!("master_repl_offset:{}", repl_info.offset()),
-        ]
+            format!("connected_slaves:{}", config.connected_replicas().count()),
+        ];
+        for (index, replica) in config.connected_replicas().enumerate() {
+            lines.push(format!(
+                "slave{index}:ip={},port={},state=online,offset={},lag=0",
+                replica.ip, replica.port, replica.offset
+            ));
+        }
+        lines.push(format!("master_replid:{}", repl_info.digest_string()));
+        lines.push(format!("master_repl_offset:{}", repl_info.offset()));
+        lines
     } else {
         vec![]
     }.join(SEPARATOR)
@@ -29,5 +63,5 @@ pub fn all_info(config: &Configuration) -> String {
         tmp.push(info_on(config, key));
     }
 
-    tmp.join(&SEPARATOR)
+    tmp.join(SEPARATOR)
 }
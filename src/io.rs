@@ -1,9 +1,17 @@
+use std::future::Future;
+use std::pin::Pin;
+
 use anyhow::{bail, Error, Result};
-use tokio::io::{AsyncReadExt, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncBufRead, AsyncReadExt, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
 
+use crate::types::{Protocol, RedisType};
+
 pub type TcpReader = BufReader<TcpStream>;
 
+// Longest line accepted, same as Redis' limit for inline commands
+const MAX_LINE_SIZE: usize = 64 * 1024;
+
 #[derive(Debug)]
 pub struct RedisString {
     pub string: String,
@@ -15,88 +23,187 @@ struct BulkLength {
     bytes: usize,
 }
 
+/// Command sent by a client. Arguments are kept as they came, as they may
+/// hold binary data
 pub struct Command {
-    pub payload: Vec<String>,
+    pub payload: Vec<Vec<u8>>,
     pub length: usize,
 }
 
 impl Command {
-    fn new(payload: Vec<String>, length: usize) -> Self {
+    fn new(payload: Vec<Vec<u8>>, length: usize) -> Self {
         Command { payload, length }
     }
 }
 
-pub async fn write_ok(stream: &mut TcpReader) -> Result<()> {
-    stream.write(b"+OK\r\n").await.map(|_| Ok(()))?
+pub async fn write_ok<W: AsyncWrite + Unpin>(stream: &mut W) -> Result<()> {
+    stream.write_all(b"+OK\r\n").await?;
+    Ok(())
 }
 
-pub async fn write_nil(stream: &mut TcpReader) -> Result<()> {
-    stream.write(b"$-1\r\n").await.map(|_| Ok(()))?
+pub async fn write_nil<W: AsyncWrite + Unpin>(stream: &mut W) -> Result<()> {
+    stream.write_all(b"$-1\r\n").await?;
+    Ok(())
 }
 
-pub async fn write_wrongtype(stream: &mut TcpReader) -> Result<()> {
-    stream.write(b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n")
-        .await.map(|_| Ok(()))?
+pub async fn write_null_array<W: AsyncWrite + Unpin>(stream: &mut W) -> Result<()> {
+    stream.write_all(b"*-1\r\n").await?;
+    Ok(())
 }
 
-pub async fn write_simple_error(stream: &mut TcpReader, message: &str) -> Result<()> {
+pub async fn write_wrongtype<W: AsyncWrite + Unpin>(stream: &mut W) -> Result<()> {
+    stream.write_all(b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n").await?;
+    Ok(())
+}
+
+pub async fn write_simple_error<W: AsyncWrite + Unpin>(stream: &mut W, message: &str) -> Result<()> {
     let output = format!("-{message}\r\n");
-    stream.write(output.as_bytes()).await.map(|_| Ok(()))?
+    stream.write_all(output.as_bytes()).await?;
+    Ok(())
 }
 
-pub async fn write_string(stream: &mut TcpReader, string: &str) -> Result<()> {
+pub async fn write_string<W: AsyncWrite + Unpin>(stream: &mut W, string: &str) -> Result<()> {
     let output = format!("${}\r\n{}\r\n", string.len(), string);
-    stream.write(output.as_bytes()).await.map(|_| Ok(()))?
+    stream.write_all(output.as_bytes()).await?;
+    Ok(())
 }
 
-pub async fn write_bytes(stream: &mut TcpReader, bytes: &[u8]) -> Result<()> {
+pub async fn write_bytes<W: AsyncWrite + Unpin>(stream: &mut W, bytes: &[u8]) -> Result<()> {
     let length = format!("${}\r\n", bytes.len());
-    stream.write(length.as_bytes()).await?;
-    stream.write(bytes).await.map(|_| Ok(()))?
+    stream.write_all(length.as_bytes()).await?;
+    stream.write_all(bytes).await?;
+    Ok(())
+}
+
+/// Like `write_bytes`, but terminates the payload as a regular bulk string
+pub async fn write_bulk_bytes<W: AsyncWrite + Unpin>(stream: &mut W, bytes: &[u8]) -> Result<()> {
+    let mut output = format!("${}\r\n", bytes.len()).into_bytes();
+    output.extend_from_slice(bytes);
+    output.extend_from_slice(b"\r\n");
+    stream.write_all(&output).await?;
+    Ok(())
 }
 
-pub async fn write_simple_string(stream: &mut TcpReader, string: &str) -> Result<()> {
+pub async fn write_simple_string<W: AsyncWrite + Unpin>(stream: &mut W, string: &str) -> Result<()> {
     let output = format!("+{string}\r\n");
-    stream.write(output.as_bytes()).await.map(|_| Ok(()))?
+    stream.write_all(output.as_bytes()).await?;
+    Ok(())
 }
 
-pub async fn write_integer(stream: &mut TcpReader, number: i64) -> Result<()> {
+pub async fn write_integer<W: AsyncWrite + Unpin>(stream: &mut W, number: i64) -> Result<()> {
     let output = format!(":{number}\r\n");
-    stream.write(output.as_bytes()).await.map(|_| Ok(()))?
+    stream.write_all(output.as_bytes()).await?;
+    Ok(())
 }
 
-pub async fn write_array_size(stream: &mut TcpReader, size: usize) -> Result<()> {
+pub async fn write_array_size<W: AsyncWrite + Unpin>(stream: &mut W, size: usize) -> Result<()> {
     let size = format!("*{size}\r\n",);
-    stream.write(size.as_bytes()).await.map(|_| Ok(()))?
+    stream.write_all(size.as_bytes()).await?;
+    Ok(())
+}
+
+/// Write a RESP3 attribute: out-of-band data about the reply that follows it.
+/// RESP2 has nothing similar, so nothing is written for RESP2 connections
+pub async fn write_attribute<W>(stream: &mut W, proto: Protocol, attributes: &[(RedisType, RedisType)]) -> Result<()>
+where
+    W: AsyncWrite + Unpin
+{
+    if proto == Protocol::Resp2 {
+        return Ok(())
+    }
+
+    let mut output = format!("|{}\r\n", attributes.len()).into_bytes();
+    for (key, value) in attributes {
+        output.extend_from_slice(&key.encode(proto));
+        output.extend_from_slice(&value.encode(proto));
+    }
+    stream.write_all(&output).await?;
+    Ok(())
+}
+
+/// Like `read_line`, but gives up once the line is longer than `max_size`
+/// instead of buffering whatever the peer sends
+async fn read_line_capped<R>(stream: &mut R, buf: &mut Vec<u8>, max_size: usize) -> Result<usize>
+where
+    R: AsyncBufRead + Unpin
+{
+    loop {
+        let available = stream.fill_buf().await?;
+        if available.is_empty() {
+            break
+        }
+
+        let (chunk, complete) = match available.iter().position(|&byte| byte == b'\n') {
+            Some(end) => (&available[..=end], true),
+            None => (available, false),
+        };
+        let length = chunk.len();
+        buf.extend_from_slice(chunk);
+        stream.consume(length);
+
+        if buf.len() > max_size {
+            bail!("Protocol error: too big inline request")
+        }
+        if complete {
+            break
+        }
+    }
+
+    Ok(buf.len())
 }
 
-pub async fn get_string(stream: &mut TcpReader) -> Result<Option<RedisString>> {
-    let mut buf = String::new();
-    let read_bytes = stream.read_line(&mut buf).await?;
+/// Read a line, without the line terminator. Returns the line, and the number
+/// of bytes read. A bare `\n` is accepted too, as Redis does for inline
+/// commands, but a line cut short by the end of the stream is an error
+async fn get_line<R>(stream: &mut R) -> Result<Option<(Vec<u8>, usize)>>
+where
+    R: AsyncBufRead + Unpin
+{
+    let mut buf = vec![];
+    let read_bytes = read_line_capped(stream, &mut buf, MAX_LINE_SIZE).await?;
 
     if read_bytes == 0 {
         Ok(None)
+    } else if buf.ends_with(b"\r\n") {
+        buf.truncate(read_bytes - 2);
+        Ok(Some((buf, read_bytes)))
+    } else if buf.ends_with(b"\n") {
+        buf.truncate(read_bytes - 1);
+        Ok(Some((buf, read_bytes)))
     } else {
-        Ok(Some(RedisString {
-            string: (&buf[0..read_bytes -2]).to_string(),
-            bytes: read_bytes
-        }))
+        bail!("Protocol error: expected CRLF")
     }
 }
 
-fn format_error<'a>(chr: char) -> String {
+pub async fn get_string<R>(stream: &mut R) -> Result<Option<RedisString>>
+where
+    R: AsyncBufRead + Unpin
+{
+    Ok(get_line(stream).await?.map(|(line, bytes)| RedisString {
+        string: String::from_utf8_lossy(&line).to_string(),
+        bytes,
+    }))
+}
+
+fn format_error(chr: char) -> String {
     format!("Protocol error: expected '$', got '{}'", chr)
 }
 
-async fn read_bulk_length(stream: &mut TcpReader) -> Result<Option<BulkLength>> {
+/// Read the header of a bulk string. Lengths over `max_len` are rejected, so
+/// that nothing gets allocated for them
+async fn read_bulk_length<R>(stream: &mut R, max_len: usize) -> Result<Option<BulkLength>>
+where
+    R: AsyncBufRead + Unpin
+{
     if let Some(RedisString { string, bytes }) = get_string(stream).await? {
         if string.is_empty() {
             bail!(format_error(' '))
         } else if !string.starts_with("$") {
             bail!(format_error(string.chars().next().unwrap()))
         } else {
-            let string_size = string[1..].parse::<usize>()
-                .map_err(|_| Error::msg("Protocol error: invalid bulk length"))?;
+            let string_size = string[1..].parse::<usize>().ok()
+                .filter(|&size| size <= max_len)
+                .ok_or_else(|| Error::msg("Protocol error: invalid bulk length"))?;
             Ok(Some(BulkLength { length: string_size, bytes }))
         }
     } else {
@@ -104,8 +211,11 @@ async fn read_bulk_length(stream: &mut TcpReader) -> Result<Option<BulkLength>>
     }
 }
 
-pub async fn read_bulk_bytes(stream: &mut TcpReader) -> Result<Option<Vec<u8>>> {
-    if let Some(string_size) = read_bulk_length(stream).await? {
+pub async fn read_bulk_bytes<R>(stream: &mut R, max_len: usize) -> Result<Option<Vec<u8>>>
+where
+    R: AsyncBufRead + Unpin
+{
+    if let Some(string_size) = read_bulk_length(stream, max_len).await? {
         let mut buf: Vec<u8> = vec![0; string_size.length];
         stream.read_exact(buf.as_mut_slice()).await?;
         Ok(Some(buf))
@@ -114,29 +224,137 @@ pub async fn read_bulk_bytes(stream: &mut TcpReader) -> Result<Option<Vec<u8>>>
     }
 }
 
-async fn read_bulk_string(stream: &mut TcpReader) -> Result<Option<RedisString>> {
-    if let Some(BulkLength { length: string_size, bytes }) = read_bulk_length(stream).await? {
+/// Read a bulk string, as is. Returns its contents, and the number of bytes
+/// read, including the header and the trailing CRLF
+async fn read_bulk_string<R>(stream: &mut R, max_len: usize) -> Result<Option<(Vec<u8>, usize)>>
+where
+    R: AsyncBufRead + Unpin
+{
+    if let Some(BulkLength { length: string_size, bytes }) = read_bulk_length(stream, max_len).await? {
         let mut buf: Vec<u8> = vec![0; string_size + 2];
         stream.read_exact(buf.as_mut_slice()).await?;
-        let bulk_string = String::from_utf8_lossy(&buf[..string_size]).to_string();
-        Ok(Some(RedisString { string: bulk_string, bytes: bytes + string_size + 2 }))
+        buf.truncate(string_size);
+        Ok(Some((buf, bytes + string_size + 2)))
     } else {
         Ok(None)
     }
 }
 
-pub async fn read_command(stream: &mut TcpReader) -> Result<Option<Command>> {
-    if let Some(text) = get_string(stream).await? {
-        let mut bytes_read = text.bytes;
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+/// Split an inline command into its arguments, the same way Redis does:
+/// arguments are separated by whitespace, and can be quoted. Double quoted
+/// arguments understand escapes like `\n` or `\x41`, single quoted ones
+/// only `\'`. A closing quote must be followed by whitespace or the end of
+/// the line.
+pub fn split_inline(bytes: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let unbalanced = || Error::msg("Protocol error: unbalanced quotes in request");
+    let mut args = vec![];
+    let mut pos = 0;
 
-        let elements = if text.string.starts_with("*") {
-            let chunks = text.string[1..].parse::<usize>()
-                .map_err(|_| Error::msg("Protocol error: invalid multibulk length"))?;
+    loop {
+        while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if pos == bytes.len() {
+            break
+        }
+
+        let mut current = vec![];
+        let mut in_double = false;
+        let mut in_single = false;
+        loop {
+            let Some(&byte) = bytes.get(pos) else {
+                if in_double || in_single {
+                    return Err(unbalanced())
+                }
+                break
+            };
+
+            if in_double {
+                match byte {
+                    b'\\' if pos + 3 < bytes.len() && bytes[pos + 1] == b'x'
+                        && hex_digit(bytes[pos + 2]).is_some() && hex_digit(bytes[pos + 3]).is_some() => {
+                        current.push(hex_digit(bytes[pos + 2]).unwrap() * 16 + hex_digit(bytes[pos + 3]).unwrap());
+                        pos += 3;
+                    }
+                    b'\\' if pos + 1 < bytes.len() => {
+                        pos += 1;
+                        current.push(match bytes[pos] {
+                            b'n' => b'\n',
+                            b'r' => b'\r',
+                            b't' => b'\t',
+                            b'b' => 0x08,
+                            b'a' => 0x07,
+                            other => other,
+                        });
+                    }
+                    b'"' => {
+                        // The closing quote must end the argument
+                        if bytes.get(pos + 1).is_some_and(|next| !next.is_ascii_whitespace()) {
+                            return Err(unbalanced())
+                        }
+                        pos += 1;
+                        break
+                    }
+                    _ => current.push(byte),
+                }
+            } else if in_single {
+                match byte {
+                    b'\\' if bytes.get(pos + 1) == Some(&b'\'') => {
+                        pos += 1;
+                        current.push(b'\'');
+                    }
+                    b'\'' => {
+                        if bytes.get(pos + 1).is_some_and(|next| !next.is_ascii_whitespace()) {
+                            return Err(unbalanced())
+                        }
+                        pos += 1;
+                        break
+                    }
+                    _ => current.push(byte),
+                }
+            } else {
+                match byte {
+                    b' ' | b'\n' | b'\r' | b'\t' | 0 => break,
+                    b'"' => in_double = true,
+                    b'\'' => in_single = true,
+                    _ => current.push(byte),
+                }
+            }
+            pos += 1;
+        }
+
+        args.push(current);
+    }
+
+    Ok(args)
+}
+
+/// Read a command sent by a client, either as a multibulk or inline. Commands
+/// with more than `max_args` elements, or with arguments longer than
+/// `max_bulk_len`, are rejected before reading them.
+pub async fn read_command<R>(stream: &mut R, max_args: usize, max_bulk_len: usize) -> Result<Option<Command>>
+where
+    R: AsyncBufRead + Unpin
+{
+    if let Some((line, mut bytes_read)) = get_line(stream).await? {
+        let elements = if let Some(count) = line.strip_prefix(b"*") {
+            let chunks = std::str::from_utf8(count).ok()
+                .and_then(|count| count.parse::<i64>().ok())
+                .ok_or_else(|| Error::msg("Protocol error: invalid multibulk length"))?;
+            // Empty (*0) and null (*-1) multibulks are empty commands
+            let chunks = chunks.max(0) as usize;
+            if chunks > max_args {
+                bail!("Protocol error: too many arguments")
+            }
             let mut cmd = vec![];
             for _ in 0..chunks {
-                if let Some(cmd_part) = read_bulk_string(stream).await? {
-                    cmd.push(cmd_part.string);
-                    bytes_read += cmd_part.bytes;
+                if let Some((cmd_part, bytes)) = read_bulk_string(stream, max_bulk_len).await? {
+                    cmd.push(cmd_part);
+                    bytes_read += bytes;
                 } else {
                     return Ok(None)
                 }
@@ -144,7 +362,7 @@ pub async fn read_command(stream: &mut TcpReader) -> Result<Option<Command>> {
 
             cmd
         } else {
-            text.string.split_whitespace().map(|s| s.to_string()).collect()
+            split_inline(&line)?
         };
 
         Ok(Some(Command::new(elements, bytes_read)))
@@ -152,3 +370,247 @@ pub async fn read_command(stream: &mut TcpReader) -> Result<Option<Command>> {
         Ok(None)
     }
 }
+
+async fn read_reply_payload<R>(stream: &mut R, length: usize) -> Result<Vec<u8>>
+where
+    R: AsyncBufRead + Unpin
+{
+    let mut buf: Vec<u8> = vec![0; length + 2];
+    stream.read_exact(buf.as_mut_slice()).await?;
+    buf.truncate(length);
+    Ok(buf)
+}
+
+fn parse_reply_number(string: &str) -> Result<i64> {
+    string.parse::<i64>()
+        .map_err(|_| Error::msg(format!("Protocol error: invalid number {string:?}")))
+}
+
+// Replies can be nested, and async functions can't be recursive as-is. Same
+// trick as in rdb.rs: return a boxed future.
+fn read_reply_boxed<'a, R>(stream: &'a mut R) -> Pin<Box<dyn Future<Output=Result<RedisType>> + Send + 'a>>
+where
+    R: AsyncBufRead + Unpin + Send
+{
+    Box::pin(async move {
+        let Some(RedisString { string, .. }) = get_string(stream).await? else {
+            bail!("Connection closed while waiting for a reply")
+        };
+        let Some(kind) = string.chars().next() else {
+            bail!("Protocol error: empty reply")
+        };
+        let rest = &string[kind.len_utf8()..];
+
+        Ok(match kind {
+            '+' | ',' | '(' => RedisType::from(rest),
+            '-' => bail!("{rest}"),
+            ':' => RedisType::Int(parse_reply_number(rest)?),
+            '#' => RedisType::Int(if rest == "t" { 1 } else { 0 }),
+            '_' => RedisType::Null,
+            '$' | '=' | '!' => {
+                let length = parse_reply_number(rest)?;
+                if length < 0 {
+                    return Ok(RedisType::Null)
+                }
+                let payload = read_reply_payload(stream, length as usize).await?;
+                match kind {
+                    // Verbatim strings are prefixed by their format, eg. "txt:"
+                    '=' => RedisType::from(payload.get(4..).unwrap_or_default().to_vec()),
+                    '!' => bail!("{}", String::from_utf8_lossy(&payload)),
+                    _ => RedisType::from(payload),
+                }
+            }
+            '*' | '~' | '>' | '%' | '|' => {
+                let count = parse_reply_number(rest)?;
+                if count < 0 {
+                    return Ok(RedisType::Null)
+                }
+                // Maps and attributes have two elements per entry
+                let count = if kind == '%' || kind == '|' { count * 2 } else { count };
+                let mut elements = vec![];
+                for _ in 0..count {
+                    elements.push(read_reply_boxed(stream).await?);
+                }
+                if kind == '|' {
+                    // Attributes are out-of-band data that precede the actual reply
+                    read_reply_boxed(stream).await?
+                } else {
+                    RedisType::Array(elements)
+                }
+            }
+            other => bail!("Protocol error: unknown reply type '{other}'"),
+        })
+    })
+}
+
+/// Read a reply sent by a server. Understands both RESP2 and RESP3. RESP3 types
+/// without an equivalent are converted to the closest RESP2 form (eg. maps are
+/// turned into flat arrays). Error replies are returned as `Err`.
+pub async fn read_reply<R>(stream: &mut R) -> Result<RedisType>
+where
+    R: AsyncBufRead + Unpin + Send
+{
+    read_reply_boxed(stream).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use tokio::io::AsyncWrite;
+
+    use crate::io::{
+        get_string, read_reply, split_inline, write_array_size, write_attribute, write_bulk_bytes, write_integer, write_ok,
+        write_simple_error, write_string,
+    };
+    use crate::types::{Protocol, RedisType};
+
+    /// Takes only a few bytes on each write, like a congested socket
+    struct ThrottledWriter {
+        written: Vec<u8>,
+        chunk: usize,
+        calls: usize,
+    }
+
+    impl AsyncWrite for ThrottledWriter {
+        fn poll_write(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+            let length = buf.len().min(self.chunk);
+            self.written.extend_from_slice(&buf[..length]);
+            self.calls += 1;
+            Poll::Ready(Ok(length))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    async fn parse(input: &[u8]) -> RedisType {
+        let mut reader = input;
+        read_reply(&mut reader).await.unwrap()
+    }
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn as_strings(value: RedisType) -> Vec<String> {
+        match value {
+            RedisType::Array(array) => array.into_iter().map(|elem| match elem {
+                RedisType::String(bytes) => String::from_utf8_lossy(&bytes).to_string(),
+                RedisType::Int(number) => number.to_string(),
+                other => panic!("Unexpected element {other:?}"),
+            }).collect(),
+            other => panic!("Expected an array, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_resp2_replies() {
+        assert!(matches!(parse(b"+OK\r\n").await, RedisType::String(s) if s == b"OK"));
+        assert!(matches!(parse(b":42\r\n").await, RedisType::Int(42)));
+        assert!(matches!(parse(b"$5\r\nhello\r\n").await, RedisType::String(s) if s == b"hello"));
+        assert!(matches!(parse(b"$-1\r\n").await, RedisType::Null));
+        assert!(matches!(parse(b"*-1\r\n").await, RedisType::Null));
+        assert_eq!(as_strings(parse(b"*2\r\n$1\r\na\r\n:1\r\n").await), strings(&["a", "1"]));
+
+        let mut reader: &[u8] = b"-ERR unknown command\r\n";
+        let error = read_reply(&mut reader).await.unwrap_err();
+        assert_eq!(error.to_string(), "ERR unknown command");
+    }
+
+    #[tokio::test]
+    async fn test_read_resp3_replies() {
+        assert!(matches!(parse(b"_\r\n").await, RedisType::Null));
+        assert!(matches!(parse(b"#t\r\n").await, RedisType::Int(1)));
+        assert!(matches!(parse(b",3.14\r\n").await, RedisType::String(s) if s == b"3.14"));
+        assert!(matches!(parse(b"=9\r\ntxt:hello\r\n").await, RedisType::String(s) if s == b"hello"));
+        assert_eq!(as_strings(parse(b"%1\r\n+proto\r\n:3\r\n").await), strings(&["proto", "3"]));
+        assert_eq!(as_strings(parse(b"~1\r\n+a\r\n").await), strings(&["a"]));
+        assert_eq!(as_strings(parse(b">2\r\n+message\r\n+hi\r\n").await), strings(&["message", "hi"]));
+        // The attribute is skipped, and the reply that follows is returned
+        assert!(matches!(parse(b"|1\r\n+hint\r\n:1\r\n:7\r\n").await, RedisType::Int(7)));
+    }
+
+    #[tokio::test]
+    async fn test_get_string() {
+        let mut reader: &[u8] = b"PING\r\n\n+OK\nx";
+        assert_eq!(get_string(&mut reader).await.unwrap().unwrap().string, "PING");
+        // Bare newlines end a line as well
+        let line = get_string(&mut reader).await.unwrap().unwrap();
+        assert_eq!((line.string.as_str(), line.bytes), ("", 1));
+        assert_eq!(get_string(&mut reader).await.unwrap().unwrap().string, "+OK");
+        // But the stream can't end in the middle of one
+        let error = get_string(&mut reader).await.unwrap_err();
+        assert_eq!(error.to_string(), "Protocol error: expected CRLF");
+        assert!(get_string(&mut reader).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_write_attribute() {
+        let attributes = [(RedisType::from("key-popularity"), RedisType::Map(vec![
+            (RedisType::from("a"), RedisType::from("0.19")),
+        ]))];
+        let reply = RedisType::Array(vec![RedisType::Int(2039123), RedisType::Int(9543892)]);
+
+        let mut output = vec![];
+        write_attribute(&mut output, Protocol::Resp3, &attributes).await.unwrap();
+        assert_eq!(output, b"|1\r\n$14\r\nkey-popularity\r\n%1\r\n$1\r\na\r\n$4\r\n0.19\r\n");
+        reply.write_as(&mut output, Protocol::Resp3).await.unwrap();
+        assert_eq!(as_strings(parse(&output).await), strings(&["2039123", "9543892"]));
+
+        // RESP2 clients never get attributes
+        let mut output = vec![];
+        write_attribute(&mut output, Protocol::Resp2, &attributes).await.unwrap();
+        assert!(output.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_short_writes() {
+        let mut writer = ThrottledWriter { written: vec![], chunk: 3, calls: 0 };
+
+        write_ok(&mut writer).await.unwrap();
+        write_array_size(&mut writer, 2).await.unwrap();
+        write_string(&mut writer, "hello world").await.unwrap();
+        write_integer(&mut writer, -1234).await.unwrap();
+        write_bulk_bytes(&mut writer, b"\x00\xff").await.unwrap();
+        write_simple_error(&mut writer, "ERR no").await.unwrap();
+        RedisType::from(vec!["a", "b"]).write(&mut writer).await.unwrap();
+
+        // Every frame arrives whole, over many writes
+        let expected = b"+OK\r\n*2\r\n$11\r\nhello world\r\n:-1234\r\n$2\r\n\x00\xff\r\n-ERR no\r\n*2\r\n$1\r\na\r\n$1\r\nb\r\n";
+        assert_eq!(writer.written, expected);
+        assert!(writer.calls >= expected.len() / 3);
+    }
+
+    #[test]
+    fn test_split_inline() {
+        let split = |line: &str| split_inline(line.as_bytes())
+            .map(|args| args.into_iter().map(|arg| String::from_utf8(arg).unwrap()).collect::<Vec<_>>());
+
+        assert_eq!(split("  SET  key  ").unwrap(), strings(&["SET", "key"]));
+        assert_eq!(split("").unwrap(), strings(&[]));
+        assert_eq!(split("set key \"\"").unwrap(), strings(&["set", "key", ""]));
+        assert_eq!(split("set '' x").unwrap(), strings(&["set", "", "x"]));
+        assert_eq!(split("set k \"hello world\"").unwrap(), strings(&["set", "k", "hello world"]));
+        assert_eq!(split("set k \"a\\ b\\\"c\\x41\\n\"").unwrap(), strings(&["set", "k", "a b\"cA\n"]));
+        assert_eq!(split("set k 'it\\'s'").unwrap(), strings(&["set", "k", "it's"]));
+        assert_eq!(split("set k 'a\\nb'").unwrap(), strings(&["set", "k", "a\\nb"]));
+        assert_eq!(split("set k\"v\"").unwrap(), strings(&["set", "kv"]));
+        // Escapes may give any byte
+        assert_eq!(split_inline(b"set k \"\\xff\\x00\"").unwrap(), vec![b"set".to_vec(), b"k".to_vec(), vec![0xff, 0]]);
+    }
+
+    #[test]
+    fn test_split_inline_unbalanced_quotes() {
+        for line in ["set k \"value", "set k 'value", "set k \"a\"b", "set k 'a'b", "set k \"a\\\""] {
+            let error = split_inline(line.as_bytes()).unwrap_err();
+            assert_eq!(error.to_string(), "Protocol error: unbalanced quotes in request", "{line}");
+        }
+    }
+}
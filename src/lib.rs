@@ -1,5 +1,6 @@
 
 pub mod config;
+pub mod crc64;
 pub mod rdb;
 pub mod types;
 pub mod io;
@@ -7,4 +8,12 @@ pub mod info;
 pub mod store;
 pub mod client;
 pub mod common_cli_rep;
+pub mod bitfield;
+pub mod zset;
+pub mod pubsub;
+pub mod glob;
+pub mod notify;
 pub mod replica;
+pub mod server;
+pub mod shard;
+pub mod testclient;
@@ -2,21 +2,16 @@ use std::env::{self, Args};
 use std::string::ToString;
 use anyhow::{bail, Result};
 use itertools::Itertools;
-use tokio::net::TcpListener;
-use tokio::sync::mpsc;
 
-use redis_starter_rust::client;
-use redis_starter_rust::config::{config_loop, Configuration, self};
-use redis_starter_rust::store::{store_loop, Store, self};
-use redis_starter_rust::rdb::Rdb;
-use redis_starter_rust::replica::replica_loop;
+use redis_starter_rust::config::Configuration;
+use redis_starter_rust::server::Server;
 
 fn parse_arguments(mut args: Args) -> Result<Vec<(String, String)>> {
     let mut pairs = vec![];
     let _ = args.next(); // Discard the 1st argument (binary path)
     while let Some(arg) = args.next() {
-        if arg.starts_with("--") {
-            if arg == "--replicaof" {
+        if let Some(name) = arg.strip_prefix("--") {
+            if name == "replicaof" {
                 if let Some(address) = args.next() {
                     let split_address = address.split_whitespace().collect_vec();
 
@@ -30,7 +25,7 @@ fn parse_arguments(mut args: Args) -> Result<Vec<(String, String)>> {
                     bail!("--replicaof: Expected an argument")
                 }
             } else if let Some(value) = args.next() {
-                pairs.push(((&arg[2..]).to_string(), value));
+                pairs.push((name.to_string(), value));
             } else {
                 bail!("No value for option {}", arg)
             }
@@ -41,62 +36,18 @@ fn parse_arguments(mut args: Args) -> Result<Vec<(String, String)>> {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    client::init_static_data();
     let mut config = Configuration::default();
     config.bulk_update(parse_arguments(env::args())?)?;
 
-    let db_path = config.get_database_path();
+    let server = Server::builder().config(config).run().await?;
 
-    let listener = TcpListener::bind(config.get_binding_address()?).await?;
-
-    let mut store = Store::default();
-
-    let (store_tx, store_rx) = mpsc::channel(store::CMD_BUFFER);
-
-    // Don't read from the Rdb file if this is a replica
-    if config.is_replica() {
-        // Contact the master server and get the initial
-        // Rdb file
-        let address = config.get("replicaof").unwrap();
-        let cfg2 = config.clone();
-        let stx2 = store_tx.clone();
-        tokio::spawn(async move {
-            replica_loop(address, cfg2, stx2).await;
-        });
-
-        // TODO: Eventually we want to do this right...
-        // todo!();
-    } else {
-        if let Ok(db_path) = db_path {
-            if let Ok(mut rdb) = Rdb::open(db_path.as_path()).await {
-                while let Some(entry) = rdb.read_next_entry().await? {
-                    store.write(&entry.key, entry.value, entry.expires);
-                }
-            } else {
-                eprintln!("Couldn't open database at {}", db_path.to_string_lossy());
-            }
-        }
-    }
-
-    // Spin the Store task
+    let trigger = server.shutdown_trigger();
     tokio::spawn(async move {
-        store_loop(store, store_rx).await;
-    });
-
-    // Spin the Config task
-    let (config_tx, config_rx) = mpsc::channel(config::CMD_BUFFER);
-    tokio::spawn(async move {
-        config_loop(config, config_rx).await;
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("Received SIGINT, shutting down");
+            trigger.trigger();
+        }
     });
 
-    // Start listening for connections
-    loop {
-        let (stream, addr) = listener.accept().await?;
-        eprintln!("Accepted connection from: {}", addr);
-        let stx2 = store_tx.clone();
-        let ctx2 = config_tx.clone();
-        tokio::spawn(async move {
-            client::client_loop(stream, stx2, ctx2).await;
-        });
-    };
+    server.wait().await
 }
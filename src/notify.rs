@@ -0,0 +1,129 @@
+use anyhow::{bail, Result};
+
+/// Classes of keyspace events, which notify-keyspace-events selects
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventClass {
+    Generic,
+    String,
+    List,
+    Set,
+    Hash,
+    SortedSet,
+    Expired,
+    Evicted,
+    Stream,
+    KeyMiss,
+    Module,
+    NewKey,
+}
+
+impl EventClass {
+    fn bit(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+// Classes included in 'A'. Key misses and new keys have to be asked for
+const ALL_CLASSES: &str = "g$lshzxetd";
+
+/// Which keyspace events get published, as set with notify-keyspace-events
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NotifyFlags {
+    // On __keyspace@<db>__:<key>, with the event as the message
+    keyspace: bool,
+    // On __keyevent@<db>__:<event>, with the key as the message
+    keyevent: bool,
+    classes: u16,
+}
+
+impl NotifyFlags {
+    /// Parse the flags the way Redis does. An empty string disables the
+    /// notifications
+    pub fn parse(flags: &str) -> Result<Self> {
+        let mut parsed = NotifyFlags::default();
+
+        for flag in flags.chars() {
+            let class = match flag {
+                'K' => { parsed.keyspace = true; continue }
+                'E' => { parsed.keyevent = true; continue }
+                'A' => {
+                    parsed.classes |= NotifyFlags::parse(ALL_CLASSES)?.classes;
+                    continue
+                }
+                'g' => EventClass::Generic,
+                '$' => EventClass::String,
+                'l' => EventClass::List,
+                's' => EventClass::Set,
+                'h' => EventClass::Hash,
+                'z' => EventClass::SortedSet,
+                'x' => EventClass::Expired,
+                'e' => EventClass::Evicted,
+                't' => EventClass::Stream,
+                'm' => EventClass::KeyMiss,
+                'd' => EventClass::Module,
+                'n' => EventClass::NewKey,
+                _ => bail!("Invalid event class character. Use 'Ag$lshzxeKEtmdn'."),
+            };
+            parsed.classes |= class.bit();
+        }
+        Ok(parsed)
+    }
+
+    pub fn enabled(&self, class: EventClass) -> bool {
+        (self.keyspace || self.keyevent) && self.classes & class.bit() != 0
+    }
+
+    /// Channels an event has to be published on, with the message for each.
+    /// The key goes into the keyspace channel name as it is, binary or not
+    pub fn channels(&self, event: &str, key: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut channels = vec![];
+        if self.keyspace {
+            channels.push(([b"__keyspace@0__:", key].concat(), event.as_bytes().to_vec()));
+        }
+        if self.keyevent {
+            channels.push((format!("__keyevent@0__:{event}").into_bytes(), key.to_vec()));
+        }
+        channels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::notify::{EventClass, NotifyFlags};
+
+    #[test]
+    fn test_parse_flags() {
+        let flags = NotifyFlags::parse("Kl$").unwrap();
+        assert!(flags.enabled(EventClass::List));
+        assert!(flags.enabled(EventClass::String));
+        assert!(!flags.enabled(EventClass::Generic));
+
+        // All classes except key misses and new keys
+        let flags = NotifyFlags::parse("EA").unwrap();
+        assert!(flags.enabled(EventClass::Expired));
+        assert!(flags.enabled(EventClass::Module));
+        assert!(!flags.enabled(EventClass::KeyMiss));
+        assert!(!flags.enabled(EventClass::NewKey));
+
+        // Nothing is published without K or E
+        assert!(!NotifyFlags::parse("A").unwrap().enabled(EventClass::Generic));
+        assert!(!NotifyFlags::parse("").unwrap().enabled(EventClass::Generic));
+        assert!(NotifyFlags::parse("KQ").is_err());
+    }
+
+    #[test]
+    fn test_channels() {
+        let both = NotifyFlags::parse("KEg").unwrap();
+        assert_eq!(both.channels("del", b"key"), vec![
+            (b"__keyspace@0__:key".to_vec(), b"del".to_vec()),
+            (b"__keyevent@0__:del".to_vec(), b"key".to_vec()),
+        ]);
+
+        // Binary keys aren't mangled on their way into the channel name
+        let keyspace = NotifyFlags::parse("Kg").unwrap();
+        assert_eq!(keyspace.channels("del", b"\xffkey"), vec![(b"__keyspace@0__:\xffkey".to_vec(), b"del".to_vec())]);
+
+        let keyevent = NotifyFlags::parse("Eg").unwrap();
+        assert_eq!(keyevent.channels("del", b"key"), vec![(b"__keyevent@0__:del".to_vec(), b"key".to_vec())]);
+    }
+}
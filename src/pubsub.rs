@@ -0,0 +1,414 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+use bytes::Bytes;
+use itertools::Itertools;
+use tokio::{io::{AsyncWrite, AsyncWriteExt}, sync::mpsc::Sender};
+
+use crate::{
+    glob::glob_match,
+    store::CommandResponse,
+    types::{Protocol, RedisType},
+};
+
+/// Message delivered to a subscriber: the parts saying what it is and
+/// where it comes from, followed by the message as it was published. The
+/// published message is shared by all the subscribers getting it, instead
+/// of being copied for each of them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    header: Vec<Vec<u8>>,
+    payload: Bytes,
+}
+
+impl Message {
+    fn new(header: &[&[u8]], payload: &Bytes) -> Self {
+        Message {
+            header: header.iter().map(|&part| part.to_vec()).collect(),
+            payload: payload.clone(),
+        }
+    }
+
+    /// Write as a push, or as an array for RESP2. The payload goes straight
+    /// from the shared buffer to the stream
+    pub async fn write_as<W: AsyncWrite + Unpin>(&self, stream: &mut W, proto: Protocol) -> Result<()> {
+        let kind = match proto {
+            Protocol::Resp2 => '*',
+            Protocol::Resp3 => '>',
+        };
+        let mut head = format!("{kind}{}\r\n", self.header.len() + 1).into_bytes();
+        for part in &self.header {
+            head.extend_from_slice(&RedisType::from(part.as_slice()).encode(proto));
+        }
+        head.extend_from_slice(format!("${}\r\n", self.payload.len()).as_bytes());
+
+        stream.write_all(&head).await?;
+        stream.write_all(&self.payload).await?;
+        stream.write_all(b"\r\n").await?;
+        Ok(())
+    }
+}
+
+/// Who is subscribed to what, for either channels or patterns
+#[derive(Default)]
+struct Registry {
+    subscribers: HashMap<Vec<u8>, HashSet<usize>>,
+    subscriptions: HashMap<usize, HashSet<Vec<u8>>>,
+}
+
+impl Registry {
+    fn count(&self, id: usize) -> usize {
+        self.subscriptions.get(&id).map_or(0, HashSet::len)
+    }
+
+    fn add(&mut self, id: usize, name: &[u8]) {
+        self.subscribers.entry(name.to_vec()).or_default().insert(id);
+        self.subscriptions.entry(id).or_default().insert(name.to_vec());
+    }
+
+    fn remove(&mut self, id: usize, name: &[u8]) {
+        if let Some(subscribers) = self.subscribers.get_mut(name) {
+            subscribers.remove(&id);
+            if subscribers.is_empty() {
+                self.subscribers.remove(name);
+            }
+        }
+        if let Some(subscriptions) = self.subscriptions.get_mut(&id) {
+            subscriptions.remove(name);
+            if subscriptions.is_empty() {
+                self.subscriptions.remove(&id);
+            }
+        }
+    }
+
+    /// Everything a client is subscribed to, sorted
+    fn of_client(&self, id: usize) -> Vec<Vec<u8>> {
+        self.subscriptions.get(&id).map(|names| names.iter().cloned().sorted().collect()).unwrap_or_default()
+    }
+}
+
+/// Channels and patterns, and the clients subscribed to them. Messages are
+/// sent to each client through its own endpoint, separate from the one used
+/// to answer its commands, so that they may arrive at any time
+#[derive(Default)]
+pub struct PubSub {
+    channels: Registry,
+    patterns: Registry,
+    endpoints: HashMap<usize, Sender<CommandResponse>>,
+}
+
+impl PubSub {
+    /// Number of channels and patterns a client is subscribed to
+    pub fn subscription_count(&self, id: usize) -> usize {
+        self.channels.count(id) + self.patterns.count(id)
+    }
+
+    fn registry(&mut self, pattern: bool) -> &mut Registry {
+        if pattern { &mut self.patterns } else { &mut self.channels }
+    }
+
+    /// Subscribe a client to some channels, or patterns. Returns the
+    /// confirmation for each of them, with the number of subscriptions of the
+    /// client at that point
+    pub fn subscribe(&mut self, id: usize, tx: Sender<CommandResponse>, names: &[Vec<u8>], pattern: bool) -> Vec<RedisType> {
+        self.endpoints.insert(id, tx);
+        let kind = if pattern { "psubscribe" } else { "subscribe" };
+
+        let mut replies = vec![];
+        for name in names {
+            self.registry(pattern).add(id, name);
+            replies.push(RedisType::Push(vec![
+                RedisType::from(kind),
+                RedisType::from(name.as_slice()),
+                RedisType::Int(self.subscription_count(id) as i64),
+            ]));
+        }
+        replies
+    }
+
+    /// Unsubscribe a client from some channels, or patterns, or from all of
+    /// them if none is given. Returns the confirmation for each of them, with
+    /// the number of subscriptions of the client left at that point
+    pub fn unsubscribe(&mut self, id: usize, names: &[Vec<u8>], pattern: bool) -> Vec<RedisType> {
+        let kind = if pattern { "punsubscribe" } else { "unsubscribe" };
+        let names = match names {
+            [] => self.registry(pattern).of_client(id),
+            names => names.to_vec(),
+        };
+
+        let mut replies = vec![];
+        for name in &names {
+            self.registry(pattern).remove(id, name);
+            replies.push(RedisType::Push(vec![
+                RedisType::from(kind),
+                RedisType::from(name.as_slice()),
+                RedisType::Int(self.subscription_count(id) as i64),
+            ]));
+        }
+        // There's always a confirmation, even with nothing to unsubscribe from
+        if replies.is_empty() {
+            replies.push(RedisType::Push(vec![
+                RedisType::from(kind),
+                RedisType::Null,
+                RedisType::Int(self.subscription_count(id) as i64),
+            ]));
+        }
+        if self.subscription_count(id) == 0 {
+            self.endpoints.remove(&id);
+        }
+        replies
+    }
+
+    /// Forget about the clients that went away without unsubscribing
+    fn prune(&mut self) {
+        let gone = self.endpoints.iter()
+            .filter(|(_, tx)| tx.is_closed())
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for id in gone {
+            self.remove_client(id);
+        }
+    }
+
+    /// Channels with subscribers, optionally only those matching a pattern
+    pub fn active_channels(&mut self, pattern: Option<&[u8]>) -> Vec<Vec<u8>> {
+        self.prune();
+        self.channels.subscribers.keys()
+            .filter(|channel| pattern.is_none_or(|pattern| glob_match(pattern, channel)))
+            .cloned()
+            .sorted()
+            .collect()
+    }
+
+    /// Number of subscribers of a channel, not counting patterns
+    pub fn subscriber_count(&mut self, channel: &[u8]) -> usize {
+        self.prune();
+        self.channels.subscribers.get(channel).map_or(0, HashSet::len)
+    }
+
+    /// Number of patterns with subscribers
+    pub fn pattern_count(&mut self) -> usize {
+        self.prune();
+        self.patterns.subscribers.len()
+    }
+
+    /// Forget about a client that went away
+    pub fn remove_client(&mut self, id: usize) {
+        for pattern in [false, true] {
+            for name in self.registry(pattern).of_client(id) {
+                self.registry(pattern).remove(id, &name);
+            }
+        }
+        self.endpoints.remove(&id);
+    }
+
+    /// Send a message to the subscribers of a channel, and to those of the
+    /// patterns that match it. Returns how many messages were delivered.
+    /// Subscribers that fell so far behind that their buffer is full are
+    /// dropped, rather than holding up the store for them
+    pub fn publish(&mut self, channel: &[u8], message: Bytes) -> usize {
+        let mut deliveries = vec![];
+        for &id in self.channels.subscribers.get(channel).into_iter().flatten() {
+            deliveries.push((id, Message::new(&[b"message", channel], &message)));
+        }
+        for (pattern, subscribers) in &self.patterns.subscribers {
+            if glob_match(pattern, channel) {
+                for &id in subscribers {
+                    deliveries.push((id, Message::new(&[b"pmessage", pattern, channel], &message)));
+                }
+            }
+        }
+
+        let mut received = 0;
+        for (id, message) in deliveries {
+            let delivered = match self.endpoints.get(&id) {
+                Some(tx) => tx.try_send(CommandResponse::Message(message)).is_ok(),
+                None => false,
+            };
+            if delivered {
+                received += 1;
+            } else {
+                self.remove_client(id);
+            }
+        }
+        received
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::sync::mpsc::{self, Receiver};
+
+    use crate::{
+        pubsub::{Message, PubSub},
+        store::CommandResponse,
+        types::{Protocol, RedisType},
+    };
+
+    fn push(parts: &[&str]) -> Vec<u8> {
+        RedisType::Push(parts.iter().map(|&part| RedisType::from(part)).collect()).encode(Protocol::Resp3)
+    }
+
+    async fn next_message(rx: &mut Receiver<CommandResponse>) -> Message {
+        match rx.recv().await {
+            Some(CommandResponse::Message(message)) => message,
+            _ => panic!("expected a message"),
+        }
+    }
+
+    async fn encoded(message: &Message, proto: Protocol) -> Vec<u8> {
+        let mut output = vec![];
+        message.write_as(&mut output, proto).await.unwrap();
+        output
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let mut pubsub = PubSub::default();
+        let (tx, mut rx) = mpsc::channel(4);
+        let (gone_tx, gone_rx) = mpsc::channel(4);
+
+        let replies = pubsub.subscribe(0, tx, &[b"news".to_vec(), b"news".to_vec(), b"sports".to_vec()], false);
+        assert_eq!(replies[1], RedisType::Push(vec![RedisType::from("subscribe"), RedisType::from("news"), RedisType::Int(1)]));
+        assert_eq!(replies[2], RedisType::Push(vec![RedisType::from("subscribe"), RedisType::from("sports"), RedisType::Int(2)]));
+        pubsub.subscribe(1, gone_tx, &[b"news".to_vec()], false);
+        drop(gone_rx);
+
+        // Clients that went away are dropped when publishing
+        assert_eq!(pubsub.publish(b"news", Bytes::from_static(b"hello")), 1);
+        assert_eq!(pubsub.subscription_count(1), 0);
+        assert_eq!(encoded(&next_message(&mut rx).await, Protocol::Resp3).await, push(&["message", "news", "hello"]));
+        assert_eq!(pubsub.publish(b"weather", Bytes::from_static(b"sunny")), 0);
+
+        pubsub.remove_client(0);
+        assert_eq!(pubsub.publish(b"sports", Bytes::from_static(b"goal")), 0);
+    }
+
+    #[tokio::test]
+    async fn test_publish_shares_payload() {
+        let mut pubsub = PubSub::default();
+        let (tx, mut rx) = mpsc::channel(4);
+        let (other_tx, mut other_rx) = mpsc::channel(4);
+
+        pubsub.subscribe(0, tx, &[b"news".to_vec()], false);
+        pubsub.subscribe(1, other_tx, &[b"news".to_vec()], false);
+
+        let payload = Bytes::from(vec![b'x'; 64 * 1024]);
+        assert_eq!(pubsub.publish(b"news", payload.clone()), 2);
+        let (message, other) = (next_message(&mut rx).await, next_message(&mut other_rx).await);
+        assert_eq!(message.payload.as_ptr(), payload.as_ptr());
+        assert_eq!(other.payload.as_ptr(), payload.as_ptr());
+
+        // RESP2 clients get it as a plain array
+        let mut expected = b"*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$65536\r\n".to_vec();
+        expected.extend_from_slice(&payload);
+        expected.extend_from_slice(b"\r\n");
+        assert_eq!(encoded(&message, Protocol::Resp2).await, expected);
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscriber() {
+        let mut pubsub = PubSub::default();
+        let (tx, mut rx) = mpsc::channel(2);
+        let (slow_tx, mut slow_rx) = mpsc::channel(1);
+
+        pubsub.subscribe(0, tx, &[b"news".to_vec()], false);
+        pubsub.subscribe(1, slow_tx, &[b"news".to_vec()], false);
+        assert_eq!(pubsub.publish(b"news", Bytes::from_static(b"first")), 2);
+
+        // The one that didn't make room for the second message is gone
+        assert_eq!(pubsub.publish(b"news", Bytes::from_static(b"second")), 1);
+        assert_eq!(pubsub.subscription_count(1), 0);
+        assert_eq!(encoded(&next_message(&mut slow_rx).await, Protocol::Resp3).await, push(&["message", "news", "first"]));
+        assert!(slow_rx.recv().await.is_none());
+        assert_eq!(encoded(&next_message(&mut rx).await, Protocol::Resp3).await, push(&["message", "news", "first"]));
+        assert_eq!(encoded(&next_message(&mut rx).await, Protocol::Resp3).await, push(&["message", "news", "second"]));
+    }
+
+    #[tokio::test]
+    async fn test_binary_channels() {
+        let mut pubsub = PubSub::default();
+        let (tx, mut rx) = mpsc::channel(4);
+
+        // Names aren't mangled into text, so these two are different channels
+        pubsub.subscribe(0, tx.clone(), &[b"news\xff".to_vec()], false);
+        pubsub.subscribe(0, tx, &[b"*\xff".to_vec()], true);
+        assert_eq!(pubsub.publish(b"news\xfe", Bytes::from_static(b"hello")), 0);
+        assert_eq!(pubsub.publish(b"news\xff", Bytes::from_static(b"hello")), 2);
+        assert_eq!(pubsub.active_channels(None), vec![b"news\xff".to_vec()]);
+
+        let mut expected = b">3\r\n$7\r\nmessage\r\n$5\r\nnews\xff\r\n".to_vec();
+        expected.extend_from_slice(b"$5\r\nhello\r\n");
+        assert_eq!(encoded(&next_message(&mut rx).await, Protocol::Resp3).await, expected);
+        assert_eq!(next_message(&mut rx).await.header, vec![b"pmessage".to_vec(), b"*\xff".to_vec(), b"news\xff".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribe() {
+        let mut pubsub = PubSub::default();
+        let (tx, _rx) = mpsc::channel(4);
+        let channels = |names: &[&str]| names.iter().map(|&name| name.as_bytes().to_vec()).collect::<Vec<_>>();
+        let confirmation = |channel: Option<&str>, count| RedisType::Push(vec![
+            RedisType::from("unsubscribe"),
+            channel.map_or(RedisType::Null, RedisType::from),
+            RedisType::Int(count),
+        ]);
+
+        pubsub.subscribe(0, tx, &channels(&["a", "b", "c"]), false);
+        assert_eq!(pubsub.unsubscribe(0, &channels(&["b", "d"]), false), vec![confirmation(Some("b"), 2), confirmation(Some("d"), 2)]);
+        assert_eq!(pubsub.publish(b"b", Bytes::from_static(b"hello")), 0);
+
+        // Without channels, it unsubscribes from all of them
+        assert_eq!(pubsub.unsubscribe(0, &[], false), vec![confirmation(Some("a"), 1), confirmation(Some("c"), 0)]);
+        assert_eq!(pubsub.unsubscribe(0, &[], false), vec![confirmation(None, 0)]);
+        assert_eq!(pubsub.publish(b"a", Bytes::from_static(b"hello")), 0);
+    }
+
+    #[tokio::test]
+    async fn test_publish_to_patterns() {
+        let mut pubsub = PubSub::default();
+        let (tx, mut rx) = mpsc::channel(4);
+
+        let replies = pubsub.subscribe(0, tx.clone(), &[b"news.*".to_vec()], true);
+        assert_eq!(replies, vec![RedisType::Push(vec![RedisType::from("psubscribe"), RedisType::from("news.*"), RedisType::Int(1)])]);
+        pubsub.subscribe(0, tx, &[b"news.sports".to_vec()], false);
+
+        // Matching both the channel and a pattern means getting it twice
+        assert_eq!(pubsub.publish(b"news.sports", Bytes::from_static(b"goal")), 2);
+        assert_eq!(encoded(&next_message(&mut rx).await, Protocol::Resp3).await, push(&["message", "news.sports", "goal"]));
+        assert_eq!(encoded(&next_message(&mut rx).await, Protocol::Resp3).await, push(&["pmessage", "news.*", "news.sports", "goal"]));
+        assert_eq!(pubsub.publish(b"weather", Bytes::from_static(b"sunny")), 0);
+
+        pubsub.unsubscribe(0, &[], true);
+        assert_eq!(pubsub.subscription_count(0), 1);
+        assert_eq!(pubsub.publish(b"news.art", Bytes::from_static(b"painting")), 0);
+    }
+
+    #[tokio::test]
+    async fn test_introspection() {
+        let mut pubsub = PubSub::default();
+        let (tx, _rx) = mpsc::channel(4);
+        let (gone_tx, gone_rx) = mpsc::channel(4);
+        let names = |names: &[&str]| names.iter().map(|&name| name.as_bytes().to_vec()).collect::<Vec<_>>();
+
+        pubsub.subscribe(0, tx.clone(), &names(&["news.art", "news.sports", "weather"]), false);
+        pubsub.subscribe(0, tx, &names(&["news.*", "*"]), true);
+        pubsub.subscribe(1, gone_tx.clone(), &names(&["news.art", "gone"]), false);
+        pubsub.subscribe(1, gone_tx, &names(&["news.*"]), true);
+
+        assert_eq!(pubsub.active_channels(None), names(&["gone", "news.art", "news.sports", "weather"]));
+        assert_eq!(pubsub.subscriber_count(b"news.art"), 2);
+        assert_eq!(pubsub.pattern_count(), 2);
+
+        // Channels are left without subscribers when their clients go away
+        drop(gone_rx);
+        assert_eq!(pubsub.active_channels(Some(b"news.*")), names(&["news.art", "news.sports"]));
+        assert_eq!(pubsub.active_channels(None), names(&["news.art", "news.sports", "weather"]));
+        assert_eq!(pubsub.subscriber_count(b"news.art"), 1);
+        assert_eq!(pubsub.subscriber_count(b"gone"), 0);
+
+        pubsub.unsubscribe(0, &names(&["*"]), true);
+        assert_eq!(pubsub.pattern_count(), 1);
+    }
+}
@@ -1,26 +1,51 @@
 use anyhow::{bail, Result};
-use std::collections::HashMap;
+use itertools::Itertools;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::future::Future;
-use std::io::SeekFrom;
 use std::path::Path;
 use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::fs::File;
-use tokio::io::{AsyncBufRead, AsyncReadExt, AsyncSeekExt, BufReader};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
+use crate::crc64::crc64;
 use crate::types::RedisType;
+use crate::zset::{format_score, parse_score, SortedSet};
 
-#[derive(Debug)]
+const RDB_VERSION: &[u8] = b"0011";
+
+#[derive(Debug, Clone)]
 pub struct RedisFileEntry {
-    pub key: String,
+    pub key: Vec<u8>,
     pub value: RedisType,
     pub expires: Option<SystemTime>,
 }
 
-pub struct Rdb {
-    file: Box<dyn AsyncBufRead + Unpin>,
+pub struct Rdb<'a> {
+    file: Checksummed<Box<dyn AsyncBufRead + Unpin + Send + 'a>>,
     version: u16,
     metadata: HashMap<String, String>,
-    db0_offset: u64,
+    finished: bool,
+    // Opcode read along with the header, that still needs to be dealt with
+    pending: Option<u8>,
+    // Database the entries being read belong to
+    db: usize,
+    verify_checksum: bool,
+}
+
+/// Reader that keeps the checksum of everything read through it
+struct Checksummed<R> {
+    inner: R,
+    crc: u64,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Checksummed<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let start = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.crc = crc64(self.crc, &buf.filled()[start..]);
+        result
+    }
 }
 
 #[derive(Debug)]
@@ -31,7 +56,7 @@ enum EncodedLength {
 
 async fn read_length_encoded<Buf>(file: &mut Buf) -> Result<EncodedLength>
 where
-    Buf: AsyncBufRead + Unpin
+    Buf: AsyncRead + Unpin
 {
     let first_byte = file.read_u8().await?;
     let (length_type, remaining_bits) = ((first_byte >> 6), first_byte & 0x3f);
@@ -55,93 +80,501 @@ where
 //       - a length-encoded compressed length (`clen`)
 //       - a length-encoded uncompressed length
 //       - `clen` bytes of compressed string
-async fn read_string<Buf>(file: &mut Buf) -> Result<String>
+async fn read_bytes<Buf>(file: &mut Buf) -> Result<Vec<u8>>
 where
-    Buf: AsyncBufRead + Unpin
+    Buf: AsyncRead + Unpin
 {
     Ok(match read_length_encoded(file).await? {
         EncodedLength::Int(length) => {
-            let mut string = String::with_capacity(length as usize);
-            file.take(length as u64).read_to_string(&mut string).await?;
-            string
-        }
-        EncodedLength::Special(0) => file.read_i8().await?.to_string(),
-        EncodedLength::Special(1) => file.read_i16().await?.to_string(),
-        EncodedLength::Special(2) => file.read_i32().await?.to_string(),
-        EncodedLength::Special(3) => { bail!("Unimplemented: reading compressed string")}
+            let mut bytes = Vec::with_capacity(length as usize);
+            file.take(length as u64).read_to_end(&mut bytes).await?;
+            bytes
+        }
+        EncodedLength::Special(0) => file.read_i8().await?.to_string().into_bytes(),
+        EncodedLength::Special(1) => file.read_i16().await?.to_string().into_bytes(),
+        EncodedLength::Special(2) => file.read_i32().await?.to_string().into_bytes(),
+        EncodedLength::Special(3) => {
+            let compressed_length = read_length(file).await?;
+            let length = read_length(file).await?;
+            let mut compressed = Vec::with_capacity(compressed_length);
+            file.take(compressed_length as u64).read_to_end(&mut compressed).await?;
+            lzf_decompress(&compressed, length)?
+        }
         _ => { bail!("Unknown encoding")}
     })
 }
 
-impl Rdb {
+/// A length-encoded size, which can't have a special encoding
+async fn read_length<Buf>(file: &mut Buf) -> Result<usize>
+where
+    Buf: AsyncRead + Unpin
+{
+    match read_length_encoded(file).await? {
+        EncodedLength::Int(length) => Ok(length as usize),
+        EncodedLength::Special(encoding) => bail!("Unexpected special encoding {encoding} for a length"),
+    }
+}
+
+// LZF compression
+//   The compressed data is a sequence of chunks, each starting with a control byte.
+//   If its 3 higher bits are 0, the rest tell how many literal bytes follow, minus 1.
+//   Otherwise, it's a back reference: those 3 bits are the length of the match minus 2
+//   (when it's 7, the next byte is added to it) and the 5 lower bits, followed by another
+//   byte, are how far back the match starts in the output, minus 1. Matches can overlap
+//   with the bytes being copied.
+fn lzf_decompress(input: &[u8], length: usize) -> Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(length);
+    let mut input = input.iter().copied();
+
+    while let Some(control) = input.next() {
+        if control < 1 << 5 {
+            let literal = usize::from(control) + 1;
+            output.extend(input.by_ref().take(literal));
+            if output.len() > length {
+                break
+            }
+            continue
+        }
+
+        let mut match_length = usize::from(control >> 5);
+        if match_length == 7 {
+            let Some(extra) = input.next() else { bail!("Corrupt compressed string: truncated back reference") };
+            match_length += usize::from(extra);
+        }
+        let Some(low) = input.next() else { bail!("Corrupt compressed string: truncated back reference") };
+        let distance = (usize::from(control & 0x1f) << 8 | usize::from(low)) + 1;
+        let Some(start) = output.len().checked_sub(distance) else {
+            bail!("Corrupt compressed string: back reference out of bounds")
+        };
+        for index in start..start + match_length + 2 {
+            output.push(output[index]);
+        }
+    }
+
+    if output.len() != length {
+        bail!("Corrupt compressed string: expected {length} bytes, got {}", output.len())
+    }
+    Ok(output)
+}
+
+/// Same as `read_bytes`, for strings that are expected to be text (eg. the
+/// auxiliary fields)
+async fn read_string<Buf>(file: &mut Buf) -> Result<String>
+where
+    Buf: AsyncRead + Unpin
+{
+    Ok(String::from_utf8_lossy(&read_bytes(file).await?).to_string())
+}
+
+/// Split off the first `count` bytes of `blob`
+fn take<'b>(blob: &mut &'b [u8], count: usize) -> Result<&'b [u8]> {
+    let Some((taken, rest)) = blob.split_at_checked(count) else { bail!("Corrupt encoded value: too short") };
+    *blob = rest;
+    Ok(taken)
+}
+
+// Ziplist encoding
+//   Used by versions older than 7 for small aggregates, and for the nodes of quicklists.
+//   A header with the size in bytes (32 bits), the offset of the last entry (32 bits) and
+//   the number of entries (16 bits), all little endian, then the entries and a final 0xFF.
+//   Each entry starts with the length of the previous one (1 byte, or 0xFE and 4 more),
+//   followed by the encoding:
+//     00pppppp                    - a string of up to 63 bytes
+//     01pppppp qqqqqqqq           - a string of up to 16383 bytes (big endian)
+//     10000000 + 4 bytes          - a longer string (big endian length)
+//     11000000 / 11010000 / 11100000 - a 16/32/64 bit integer
+//     11110000                    - a 24 bit integer
+//     11111110                    - an 8 bit integer
+//     1111xxxx                    - an integer from 0 to 12, as xxxx - 1
+//   Integers are little endian
+fn read_ziplist(mut blob: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let blob = &mut blob;
+    take(blob, 8)?;
+    let count = u16::from_le_bytes(take(blob, 2)?.try_into()?);
+
+    let mut elements = Vec::with_capacity(count as usize);
+    loop {
+        let previous = take(blob, 1)?[0];
+        if previous == 0xFF {
+            break
+        }
+        if previous == 0xFE {
+            take(blob, 4)?;
+        }
+
+        let encoding = take(blob, 1)?[0];
+        let element = match encoding >> 6 {
+            0 => take(blob, (encoding & 0x3F) as usize)?.to_vec(),
+            1 => {
+                let length = ((encoding & 0x3F) as usize) << 8 | take(blob, 1)?[0] as usize;
+                take(blob, length)?.to_vec()
+            }
+            2 => {
+                let length = u32::from_be_bytes(take(blob, 4)?.try_into()?);
+                take(blob, length as usize)?.to_vec()
+            }
+            _ => {
+                let integer = match encoding {
+                    0xC0 => i16::from_le_bytes(take(blob, 2)?.try_into()?) as i64,
+                    0xD0 => i32::from_le_bytes(take(blob, 4)?.try_into()?) as i64,
+                    0xE0 => i64::from_le_bytes(take(blob, 8)?.try_into()?),
+                    // Sign extended from the highest byte
+                    0xF0 => {
+                        let bytes = take(blob, 3)?;
+                        i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) as i64 >> 8
+                    }
+                    0xFE => take(blob, 1)?[0] as i8 as i64,
+                    0xF1..=0xFD => (encoding & 0x0F) as i64 - 1,
+                    _ => bail!("Corrupt ziplist: unknown encoding {encoding:#x}"),
+                };
+                integer.to_string().into_bytes()
+            }
+        };
+        elements.push(element);
+    }
+
+    if elements.len() != count as usize && count != u16::MAX {
+        bail!("Corrupt ziplist: expected {count} entries, found {}", elements.len())
+    }
+    Ok(elements)
+}
+
+// Listpack encoding
+//   Used since version 7 for small aggregates, and for the nodes of quicklists. A header
+//   with the size in bytes (32 bits) and the number of entries (16 bits), both little
+//   endian, then the entries and a final 0xFF. Each entry starts with the encoding:
+//     0xxxxxxx                    - an integer from 0 to 127
+//     10xxxxxx                    - a string of up to 63 bytes
+//     110xxxxx yyyyyyyy           - a 13 bit integer (big endian)
+//     1110xxxx yyyyyyyy           - a string of up to 4095 bytes (big endian length)
+//     11110000 + 4 bytes          - a longer string (little endian length)
+//     11110001 / 11110010 / 11110011 / 11110100 - a 16/24/32/64 bit integer
+//   followed by the data, and then by the size of the encoding and the data (the
+//   "back length", to walk the list backwards) using 7 bits out of each byte
+fn read_listpack(mut blob: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let blob = &mut blob;
+    take(blob, 4)?;
+    let count = u16::from_le_bytes(take(blob, 2)?.try_into()?);
+
+    let mut elements = Vec::with_capacity(count as usize);
+    loop {
+        let before = blob.len();
+        let encoding = take(blob, 1)?[0];
+        let element = match encoding {
+            0xFF => break,
+            0x00..=0x7F => encoding.to_string().into_bytes(),
+            0x80..=0xBF => take(blob, (encoding & 0x3F) as usize)?.to_vec(),
+            0xC0..=0xDF => {
+                let unsigned = ((encoding & 0x1F) as i64) << 8 | take(blob, 1)?[0] as i64;
+                // Negative numbers are in two's complement
+                let integer = if unsigned >= 1 << 12 { unsigned - (1 << 13) } else { unsigned };
+                integer.to_string().into_bytes()
+            }
+            0xE0..=0xEF => {
+                let length = ((encoding & 0x0F) as usize) << 8 | take(blob, 1)?[0] as usize;
+                take(blob, length)?.to_vec()
+            }
+            0xF0 => {
+                let length = u32::from_le_bytes(take(blob, 4)?.try_into()?);
+                take(blob, length as usize)?.to_vec()
+            }
+            0xF1 => i16::from_le_bytes(take(blob, 2)?.try_into()?).to_string().into_bytes(),
+            0xF2 => {
+                // Sign extended from the highest byte
+                let bytes = take(blob, 3)?;
+                (i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8).to_string().into_bytes()
+            }
+            0xF3 => i32::from_le_bytes(take(blob, 4)?.try_into()?).to_string().into_bytes(),
+            0xF4 => i64::from_le_bytes(take(blob, 8)?.try_into()?).to_string().into_bytes(),
+            _ => bail!("Corrupt listpack: unknown encoding {encoding:#x}"),
+        };
+
+        let length = before - blob.len();
+        let back_length = match length {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        take(blob, back_length)?;
+        elements.push(element);
+    }
+
+    if elements.len() != count as usize && count != u16::MAX {
+        bail!("Corrupt listpack: expected {count} entries, found {}", elements.len())
+    }
+    Ok(elements)
+}
+
+// Intset encoding
+//   Used for small sets made only of integers. The size of the integers in bytes (2, 4
+//   or 8) and how many there are, both as 32 bit little endian numbers, followed by the
+//   integers, sorted and little endian as well
+fn read_intset(mut blob: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let blob = &mut blob;
+    let size = u32::from_le_bytes(take(blob, 4)?.try_into()?) as usize;
+    let count = u32::from_le_bytes(take(blob, 4)?.try_into()?) as usize;
+
+    let mut elements = Vec::with_capacity(count);
+    for _ in 0..count {
+        let bytes = take(blob, size)?;
+        let integer = match size {
+            2 => i16::from_le_bytes(bytes.try_into()?) as i64,
+            4 => i32::from_le_bytes(bytes.try_into()?) as i64,
+            8 => i64::from_le_bytes(bytes.try_into()?),
+            _ => bail!("Corrupt intset: unknown integer size {size}"),
+        };
+        elements.push(integer.to_string().into_bytes());
+    }
+    Ok(elements)
+}
+
+#[derive(Clone, Copy)]
+enum Aggregate {
+    List,
+    Set,
+    Hash,
+    ZSet,
+}
+
+/// Put together a value of an aggregate type out of its elements, all in a
+/// row: the members of lists and sets, or pairs of field and value for hashes
+/// and of member and score for sorted sets
+fn aggregate(kind: Aggregate, key: &str, elements: Vec<Vec<u8>>) -> Result<RedisType> {
+    if matches!(kind, Aggregate::Hash | Aggregate::ZSet) && !elements.len().is_multiple_of(2) {
+        bail!("Reading entry: odd number of elements for key {key}")
+    }
+
+    Ok(match kind {
+        Aggregate::List => RedisType::List(elements.into()),
+        Aggregate::Set => {
+            let count = elements.len();
+            let members = elements.into_iter().collect::<HashSet<_>>();
+            if members.len() != count {
+                bail!("Reading entry: set {key} has repeated members")
+            }
+            RedisType::Set(members)
+        }
+        Aggregate::Hash => {
+            let count = elements.len();
+            let fields = elements.into_iter().tuples().collect::<HashMap<_, _>>();
+            if fields.len() * 2 != count {
+                bail!("Reading entry: hash {key} has repeated fields")
+            }
+            RedisType::Hash(fields)
+        }
+        Aggregate::ZSet => {
+            let mut zset = SortedSet::default();
+            for (member, score) in elements.into_iter().tuples() {
+                let score = String::from_utf8_lossy(&score);
+                let Ok(score) = parse_score(&score) else { bail!("Reading entry: invalid score {score} for key {key}") };
+                if !zset.insert(&member, score) {
+                    bail!("Reading entry: sorted set {key} has repeated members")
+                }
+            }
+            RedisType::ZSet(zset)
+        }
+    })
+}
+
+impl Rdb<'static> {
     pub async fn open(path: &Path) -> Result<Self> {
-        let mut file = BufReader::new(File::open(path).await?);
+        let file = BufReader::new(File::open(path).await?);
+
+        match Rdb::from_reader(file).await {
+            Ok(rdb) => Ok(rdb),
+            Err(error) => bail!("{}: {error}", path.to_string_lossy()),
+        }
+    }
+}
+
+impl<'a> Rdb<'a> {
+    /// Read a database from any source, eg. a replication stream. Entries are
+    /// read on demand, the contents are never loaded in memory as a whole.
+    pub async fn from_reader<R>(mut file: R) -> Result<Self>
+    where
+        R: AsyncBufRead + Unpin + Send + 'a
+    {
         let mut magic =[0; 9];
-        let mut metadata = HashMap::new();
 
         file.read_exact(&mut magic).await?;
         if &magic[0..5] != b"REDIS" {
-            bail!("Not a Redis database: {}", path.to_string_lossy())
+            bail!("Not a Redis database")
         }
+        let version = String::from_utf8_lossy(&magic[5..]).parse::<u16>()?;
+
+        let mut rdb = Self {
+            file: Checksummed { inner: Box::new(file), crc: 0 },
+            version,
+            metadata: HashMap::new(),
+            finished: false,
+            pending: None,
+            db: 0,
+            verify_checksum: true,
+        };
+        rdb.file.crc = crc64(0, &magic);
 
-        while let Ok(first)= file.read_u8().await {
-            match first {
+        loop {
+            match rdb.file.read_u8().await? {
                 0xFA => {
                     // Read an auxiliary field
-                    let (key, value) = (read_string(&mut file).await?, read_string(&mut file).await?);
-                    metadata.insert(key, value);
+                    let (key, value) = (read_string(&mut rdb.file).await?, read_string(&mut rdb.file).await?);
+                    rdb.metadata.insert(key, value);
                 }
-                0xFE => {
-                    // DB marker
-                    if file.read_u8().await? != 0 {
-                        bail!("Corrupt file. Couldn't find the marker for DB 0");
-                    }
-                    break;
+                // The databases (or the end of the file, if there are none) are
+                // read along with the entries. The checksum in particular, so that its
+                // verification can be turned off before that
+                opcode @ (0xFE | 0xFF) => {
+                    rdb.pending = Some(opcode);
+                    break
                 }
                 byte => {
-                    let offset = file.seek(SeekFrom::Current(0)).await?;
-                    bail!("Unknown byte {byte:#x} at offset {offset}");
+                    bail!("Unknown byte {byte:#x} in the header");
                 }
             }
         }
 
-        if file.read_u8().await? != 0xFB {
-            bail!("Corrupt file. Couldn't find the marker for DB 0's hash size info");
-        }
-
-        let _hash_table_size = read_length_encoded(&mut file).await?;
-        let _expire_hash_table_size = read_length_encoded(&mut file).await?;
-        let current_offset = file.seek(SeekFrom::Current(0)).await?;
-
-        Ok(Self {
-            file: Box::new(file),
-            version: String::from_utf8_lossy(&magic[5..]).parse::<u16>()?,
-            metadata,
-            db0_offset: current_offset,
-        })
+        Ok(rdb)
     }
 
     pub fn print_debug_info(&self) {
         eprintln!("RDB Version: {}", self.version);
-        eprintln!("Offset to DB 0: {}", self.db0_offset);
         eprintln!("Metadata:\n{:#?}", self.metadata);
     }
 
-    fn priv_next_entry(&mut self) -> Pin<Box<dyn Future<Output=Result<Option<RedisFileEntry>>> + '_>> {
+    pub fn metadata(&self) -> &HashMap<String, String> {
+        &self.metadata
+    }
+
+    /// Whether to check that the file is not corrupt against its checksum,
+    /// once all of it is read. On by default
+    pub fn set_verify_checksum(&mut self, verify: bool) {
+        self.verify_checksum = verify;
+    }
+
+    async fn read_checksum(&mut self) -> Result<()> {
+        self.finished = true;
+        // Versions older than 5 don't have a checksum
+        if self.version >= 5 {
+            let expected = self.file.crc;
+            let checksum = self.file.read_u64_le().await?;
+            // A zero checksum means "checksum disabled"
+            if self.verify_checksum && checksum != 0 && checksum != expected {
+                bail!("Bad RDB checksum")
+            }
+        }
+        Ok(())
+    }
+
+    /// The `count` strings making up a list, set or hash stored without any
+    /// special encoding
+    async fn read_elements(&mut self, key: &str, count: usize) -> Result<Vec<Vec<u8>>> {
+        let mut elements = Vec::with_capacity(count);
+        for read in 0..count {
+            match read_bytes(&mut self.file).await {
+                Ok(element) => elements.push(element),
+                Err(error) => bail!("Reading entry: expected {count} elements for key {key}, could only read {read}: {error}"),
+            }
+        }
+        Ok(elements)
+    }
+
+    /// The `count` members of a sorted set stored without any special
+    /// encoding, each followed by its score. Scores are doubles with
+    /// `binary`, or strings otherwise
+    async fn read_scored(&mut self, key: &str, count: usize, binary: bool) -> Result<Vec<Vec<u8>>> {
+        let mut elements = Vec::with_capacity(count * 2);
+        for _ in 0..count {
+            elements.push(read_bytes(&mut self.file).await?);
+            let score = match binary {
+                true => self.file.read_f64_le().await?,
+                // The length of the string, or one of the special values
+                false => match self.file.read_u8().await? {
+                    253 => bail!("Reading entry: invalid score nan for key {key}"),
+                    254 => f64::INFINITY,
+                    255 => f64::NEG_INFINITY,
+                    length => {
+                        let mut score = vec![0; length as usize];
+                        self.file.read_exact(&mut score).await?;
+                        elements.push(score);
+                        continue
+                    }
+                },
+            };
+            elements.push(format_score(score).into_bytes());
+        }
+        Ok(elements)
+    }
+
+    fn priv_next_entry(&mut self) -> Pin<Box<dyn Future<Output=Result<Option<RedisFileEntry>>> + Send + '_>> {
         Box::pin(async move {
-            let first = self.file.read_u8().await?;
+            let first = match self.pending.take() {
+                Some(opcode) => opcode,
+                None => self.file.read_u8().await?,
+            };
 
             Ok(match first {
-                0..=14 => {
-                    let key = read_string(&mut self.file).await?;
-                    match first {
-                        0 => Some(RedisFileEntry {
-                            key,
-                            value: RedisType::String(read_string(&mut self.file).await?),
-                            expires: None,
-                        }),
+                0..=20 => {
+                    let raw_key = read_bytes(&mut self.file).await?;
+                    // Keys may be binary. This is only to name them in errors
+                    let key = String::from_utf8_lossy(&raw_key).to_string();
+                    let value = match first {
+                        0 => RedisType::String(read_bytes(&mut self.file).await?),
+                        1 | 2 | 4 => {
+                            let (kind, per_entry) = match first {
+                                1 => (Aggregate::List, 1),
+                                2 => (Aggregate::Set, 1),
+                                _ => (Aggregate::Hash, 2),
+                            };
+                            let count = read_length(&mut self.file).await? * per_entry;
+                            aggregate(kind, &key, self.read_elements(&key, count).await?)?
+                        }
+                        3 | 5 => {
+                            let count = read_length(&mut self.file).await?;
+                            aggregate(Aggregate::ZSet, &key, self.read_scored(&key, count, first == 5).await?)?
+                        }
+                        11 => aggregate(Aggregate::Set, &key, read_intset(&read_bytes(&mut self.file).await?)?)?,
+                        10 | 12 | 13 => {
+                            let kind = [Aggregate::List, Aggregate::ZSet, Aggregate::Hash][first as usize - 10];
+                            aggregate(kind, &key, read_ziplist(&read_bytes(&mut self.file).await?)?)?
+                        }
+                        16 | 17 | 20 => {
+                            let kind = match first {
+                                16 => Aggregate::Hash,
+                                17 => Aggregate::ZSet,
+                                _ => Aggregate::Set,
+                            };
+                            aggregate(kind, &key, read_listpack(&read_bytes(&mut self.file).await?)?)?
+                        }
+                        14 => {
+                            // A list of ziplists
+                            let nodes = read_length(&mut self.file).await?;
+                            let mut elements = VecDeque::new();
+                            for _ in 0..nodes {
+                                elements.extend(read_ziplist(&read_bytes(&mut self.file).await?)?);
+                            }
+                            RedisType::List(elements)
+                        }
+                        18 => {
+                            // A list of nodes that are either listpacks, or a
+                            // single large element stored as is
+                            let nodes = read_length(&mut self.file).await?;
+                            let mut elements = VecDeque::new();
+                            for _ in 0..nodes {
+                                let container = read_length(&mut self.file).await?;
+                                let node = read_bytes(&mut self.file).await?;
+                                match container {
+                                    1 => elements.push_back(node),
+                                    2 => elements.extend(read_listpack(&node)?),
+                                    _ => bail!("Reading entry: unknown quicklist container {container} for key: {key}"),
+                                }
+                            }
+                            RedisType::List(elements)
+                        }
                         _ => bail!("Reading entry: unsupported data type {first} for key: {key}")
-                    }
+                    };
+                    Some(RedisFileEntry { key: raw_key, value, expires: None })
                 }
                 0xFC|0xFD => {
                     let expires_at = if first == 0xFC {
@@ -159,10 +592,21 @@ impl Rdb {
                             rec
                         })
                 }
-                0xFF|0xFE => {
-                    // End of File, next DB. We support reading only from DB 0
+                0xFF => {
+                    self.read_checksum().await?;
                     None
                 }
+                0xFE => {
+                    // The entries that follow belong to another database
+                    self.db = read_length(&mut self.file).await?;
+                    self.priv_next_entry().await?
+                }
+                0xFB => {
+                    // Hint about the size of the current database. Not needed
+                    let _hash_table_size = read_length(&mut self.file).await?;
+                    let _expire_hash_table_size = read_length(&mut self.file).await?;
+                    self.priv_next_entry().await?
+                }
                 unknown => {
                     bail!("Reading entry: unrecognized code '{unknown:#x}'")
                 }
@@ -170,7 +614,465 @@ impl Rdb {
         })
     }
 
-    pub async fn read_next_entry(&mut self) -> Result<Option<RedisFileEntry>> {
-        self.priv_next_entry().await
+    /// Next entry in the file, along with the index of the database it
+    /// belongs to, or `None` once there are no more
+    pub async fn read_next_entry(&mut self) -> Result<Option<(usize, RedisFileEntry)>> {
+        if self.finished {
+            return Ok(None)
+        }
+        let entry = self.priv_next_entry().await?;
+        Ok(entry.map(|entry| (self.db, entry)))
     }
-}
\ No newline at end of file
+}
+
+fn encode_length(length: usize) -> Vec<u8> {
+    if length < 1 << 6 {
+        vec![length as u8]
+    } else if length < 1 << 14 {
+        vec![0x40 | (length >> 8) as u8, length as u8]
+    } else {
+        let mut encoded = vec![0x80];
+        encoded.extend((length as u32).to_be_bytes());
+        encoded
+    }
+}
+
+fn encode_string(string: &[u8]) -> Vec<u8> {
+    let mut encoded = encode_length(string.len());
+    encoded.extend(string);
+    encoded
+}
+
+/// Type and encoding of a value. Aggregates are written without any of the
+/// compact encodings: lists, sets and hashes as a count followed by their
+/// strings, and sorted sets with binary scores (ZSET_2)
+fn encode_value(value: &RedisType) -> Option<(u8, Vec<u8>)> {
+    Some(match value {
+        RedisType::List(elements) => {
+            let mut encoded = encode_length(elements.len());
+            elements.iter().for_each(|element| encoded.extend(encode_string(element)));
+            (1, encoded)
+        }
+        RedisType::Set(members) => {
+            let mut encoded = encode_length(members.len());
+            members.iter().for_each(|member| encoded.extend(encode_string(member)));
+            (2, encoded)
+        }
+        RedisType::Hash(fields) => {
+            let mut encoded = encode_length(fields.len());
+            for (field, value) in fields {
+                encoded.extend(encode_string(field));
+                encoded.extend(encode_string(value));
+            }
+            (4, encoded)
+        }
+        RedisType::ZSet(zset) => {
+            let mut encoded = encode_length(zset.len());
+            for (member, score) in zset.iter() {
+                encoded.extend(encode_string(member));
+                encoded.extend(score.to_le_bytes());
+            }
+            (5, encoded)
+        }
+        other => (0, encode_string(&other.string_bytes()?)),
+    })
+}
+
+/// Writes a database in RDB format. The output is not buffered, wrap `file`
+/// in a `BufWriter` if needed.
+pub struct RdbWriter<W> {
+    file: W,
+}
+
+impl<W> RdbWriter<W>
+where
+    W: AsyncWrite + Unpin
+{
+    /// Start the file, writing the header and the auxiliary fields
+    pub async fn new(mut file: W, metadata: &[(&str, &str)]) -> Result<Self> {
+        file.write_all(b"REDIS").await?;
+        file.write_all(RDB_VERSION).await?;
+        for (key, value) in metadata {
+            file.write_u8(0xFA).await?;
+            file.write_all(&encode_string(key.as_bytes())).await?;
+            file.write_all(&encode_string(value.as_bytes())).await?;
+        }
+
+        Ok(RdbWriter { file })
+    }
+
+    /// Start DB 0. Needs to be called before writing any entry
+    pub async fn start_database(&mut self, size: usize, expires_size: usize) -> Result<()> {
+        self.file.write_all(&[0xFE, 0x00, 0xFB]).await?;
+        self.file.write_all(&encode_length(size)).await?;
+        self.file.write_all(&encode_length(expires_size)).await?;
+        Ok(())
+    }
+
+    pub async fn write_entry(&mut self, entry: &RedisFileEntry) -> Result<()> {
+        if let Some(expires) = entry.expires {
+            let millis = expires.duration_since(UNIX_EPOCH)?.as_millis() as u64;
+            self.file.write_u8(0xFC).await?;
+            self.file.write_all(&millis.to_le_bytes()).await?;
+        }
+
+        let Some((kind, value)) = encode_value(&entry.value) else {
+            bail!("Writing entry: unsupported value {:?} for key: {}", entry.value, String::from_utf8_lossy(&entry.key))
+        };
+        self.file.write_u8(kind).await?;
+        self.file.write_all(&encode_string(&entry.key)).await?;
+        self.file.write_all(&value).await?;
+        Ok(())
+    }
+
+    /// Write the end of file marker and return the underlying writer
+    pub async fn finish(mut self) -> Result<W> {
+        self.file.write_u8(0xFF).await?;
+        // A zero checksum means "checksum disabled"
+        self.file.write_all(&[0; 8]).await?;
+        self.file.flush().await?;
+        Ok(self.file)
+    }
+}
+
+/// Write a whole database, made of `entries`, to `file`
+pub async fn write_rdb<W>(file: W, metadata: &[(&str, &str)], entries: &[RedisFileEntry]) -> Result<W>
+where
+    W: AsyncWrite + Unpin
+{
+    let mut writer = RdbWriter::new(file, metadata).await?;
+
+    if !entries.is_empty() {
+        let expires_size = entries.iter().filter(|entry| entry.expires.is_some()).count();
+        writer.start_database(entries.len(), expires_size).await?;
+        for entry in entries {
+            writer.write_entry(entry).await?;
+        }
+    }
+
+    writer.finish().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use crate::crc64::crc64;
+    use crate::rdb::{encode_string, lzf_decompress, read_listpack, read_ziplist, write_rdb, Rdb, RedisFileEntry};
+    use crate::types::RedisType;
+    use crate::zset::SortedSet;
+
+    async fn read_all(data: &[u8]) -> Vec<RedisFileEntry> {
+        let mut rdb = Rdb::from_reader(data).await.unwrap();
+        let mut entries = vec![];
+        while let Some((_, entry)) = rdb.read_next_entry().await.unwrap() {
+            entries.push(entry);
+        }
+        entries
+    }
+
+    #[tokio::test]
+    async fn test_empty_round_trip() {
+        let data = write_rdb(vec![], &[("redis-ver", "7.2.0")], &[]).await.unwrap();
+        let rdb = Rdb::from_reader(data.as_slice()).await.unwrap();
+
+        assert_eq!(rdb.metadata().get("redis-ver"), Some(&String::from("7.2.0")));
+        assert!(read_all(&data).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_entries_round_trip() {
+        let expires = UNIX_EPOCH + Duration::from_millis(4102444800000);
+        let long_value = "x".repeat(20000);
+        let entries = vec![
+            RedisFileEntry { key: "short".into(), value: RedisType::from("value"), expires: None },
+            RedisFileEntry { key: "long".into(), value: RedisType::from(long_value.as_str()), expires: None },
+            RedisFileEntry { key: "expiring".into(), value: RedisType::from("soon"), expires: Some(expires) },
+        ];
+
+        let data = write_rdb(vec![], &[], &entries).await.unwrap();
+        let read = read_all(&data).await;
+
+        assert_eq!(read.len(), 3);
+        for (written, read) in entries.iter().zip(read.iter()) {
+            assert_eq!(written.key, read.key);
+            assert_eq!(written.expires, read.expires);
+            match (&written.value, &read.value) {
+                (RedisType::String(w), RedisType::String(r)) => assert_eq!(w, r),
+                other => panic!("Unexpected values: {other:?}"),
+            }
+        }
+        assert!(read[2].expires.unwrap() > SystemTime::now());
+    }
+
+    #[tokio::test]
+    async fn test_expiry_keeps_milliseconds() {
+        // Sub-millisecond precision is lost, but nothing else
+        let expires = SystemTime::now() + Duration::from_millis(1500);
+        let truncated = UNIX_EPOCH + Duration::from_millis(expires.duration_since(UNIX_EPOCH).unwrap().as_millis() as u64);
+        let entries = vec![
+            RedisFileEntry { key: "foo".into(), value: RedisType::from("bar"), expires: Some(expires) },
+        ];
+
+        let data = write_rdb(vec![], &[], &entries).await.unwrap();
+        assert_eq!(read_all(&data).await[0].expires, Some(truncated));
+    }
+
+    #[tokio::test]
+    async fn test_aggregates_round_trip() {
+        let expires = UNIX_EPOCH + Duration::from_millis(4102444800000);
+        let mut zset = SortedSet::default();
+        zset.insert(b"low", f64::NEG_INFINITY);
+        zset.insert(b"mid", 0.1);
+        zset.insert(b"high", 1e300);
+        let entries = vec![
+            RedisFileEntry { key: "list".into(), value: list(&["a", "", "a"]), expires: None },
+            RedisFileEntry { key: "set".into(), value: RedisType::Set(["a", "b"].map(|member| member.as_bytes().to_vec()).into()), expires: None },
+            RedisFileEntry {
+                key: "hash".into(),
+                value: RedisType::Hash([(b"field".to_vec(), b"value".to_vec())].into()),
+                expires: Some(expires),
+            },
+            RedisFileEntry { key: "zset".into(), value: RedisType::ZSet(zset), expires: None },
+        ];
+
+        let data = write_rdb(vec![], &[], &entries).await.unwrap();
+        let read = read_all(&data).await;
+
+        assert_eq!(read.len(), entries.len());
+        for (written, read) in entries.iter().zip(read.iter()) {
+            assert_eq!(written.key, read.key);
+            assert_eq!(written.value, read.value);
+            assert_eq!(written.expires, read.expires);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plain_zsets() {
+        // Scores as strings, with the special length for infinity
+        let entries = read_all(&with_entry(&[], 3, "zset", b"\x02\x01a\x031.5\x01b\xFE")).await;
+        let mut expected = SortedSet::default();
+        expected.insert(b"a", 1.5);
+        expected.insert(b"b", f64::INFINITY);
+        assert_eq!(entries[0].value, RedisType::ZSet(expected));
+
+        let nan = with_entry(&[], 3, "zset", b"\x01\x01a\xFD");
+        let mut rdb = Rdb::from_reader(nan.as_slice()).await.unwrap();
+        assert!(rdb.read_next_entry().await.unwrap_err().to_string().contains("invalid score"));
+    }
+
+    #[test]
+    fn test_lzf_decompress() {
+        // One literal, and a back reference to it overlapping with itself
+        assert_eq!(lzf_decompress(&[0x00, b'a', 0xE0, 0x0A, 0x00], 20).unwrap(), vec![b'a'; 20]);
+        // A back reference further away, followed by more literals
+        let compressed = b"\x05hello \x20\x05\x00!";
+        assert_eq!(lzf_decompress(compressed, 10).unwrap(), b"hello hel!");
+        assert!(lzf_decompress(compressed, 11).is_err());
+        assert!(lzf_decompress(&[0x20, 0x00], 2).is_err());
+        assert!(lzf_decompress(&[0x00, b'a', 0xE0], 20).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_compressed_string() {
+        // A key set to "abc" 8 times, compressed the way Redis does with rdbcompression on
+        let mut data = b"REDIS0011\xFE\x00\xFB\x01\x00\x00\x03key".to_vec();
+        data.extend([0xC3, 0x07, 0x18, 0x02, b'a', b'b', b'c', 0xE0, 0x0C, 0x02]);
+        data.extend([0xFF, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let entries = read_all(&data).await;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].value, RedisType::from("abc".repeat(8).as_str()));
+    }
+
+    /// A database with a single entry, of the given type and encoded value
+    fn with_entry(expiry: &[u8], kind: u8, key: &str, value: &[u8]) -> Vec<u8> {
+        let mut data = b"REDIS0011\xFE\x00\xFB\x01\x00".to_vec();
+        data.extend(expiry);
+        data.push(kind);
+        data.push(key.len() as u8);
+        data.extend(key.as_bytes());
+        data.extend(value);
+        data.extend([0xFF, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data
+    }
+
+    fn list(elements: &[&str]) -> RedisType {
+        RedisType::List(elements.iter().map(|element| element.as_bytes().to_vec()).collect())
+    }
+
+    #[test]
+    fn test_read_ziplist() {
+        // "a", then 5, -2, -1 and 100 with all the integer encodings that fit them
+        let entries = [0x00, 0x01, b'a', 0x03, 0xF6, 0x02, 0xC0, 0xFE, 0xFF, 0x04, 0xF0, 0xFF, 0xFF, 0xFF, 0x05, 0xFE, 100, 0xFF];
+        let mut ziplist = vec![28, 0, 0, 0, 22, 0, 0, 0, 5, 0];
+        ziplist.extend(entries);
+        let elements = read_ziplist(&ziplist).unwrap();
+        assert_eq!(elements, vec![b"a".to_vec(), b"5".to_vec(), b"-2".to_vec(), b"-1".to_vec(), b"100".to_vec()]);
+
+        ziplist[8] = 6;
+        assert!(read_ziplist(&ziplist).is_err());
+        assert!(read_ziplist(&ziplist[..20]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lists() {
+        // A plain list, with an expiration time
+        let expiry = [0xFC, 0x00, 0x20, 0x51, 0xA6, 0x3C, 0x01, 0x00, 0x00];
+        let entries = read_all(&with_entry(&expiry, 1, "plain", b"\x03\x01a\x00\xC0\x07")).await;
+        assert_eq!(entries[0].key, b"plain");
+        assert_eq!(entries[0].value, list(&["a", "", "7"]));
+        assert_eq!(entries[0].expires, Some(UNIX_EPOCH + Duration::from_millis(1360000000000)));
+
+        // A quicklist with two nodes
+        let mut nodes = vec![0x02, 0x10, 16, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0x00, 0x01, b'a', 0x03, 0xF2, 0xFF];
+        nodes.extend([0x0D, 13, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0x00, 0xF1, 0xFF]);
+        let entries = read_all(&with_entry(&[], 14, "quick", &nodes)).await;
+        assert_eq!(entries[0].value, list(&["a", "1", "0"]));
+        assert_eq!(entries[0].expires, None);
+    }
+
+    #[tokio::test]
+    async fn test_hashes_and_sets() {
+        let entries = read_all(&with_entry(&[], 2, "set", b"\x03\x01a\x01b\xC0\x0C")).await;
+        assert_eq!(entries[0].value, RedisType::Set(["a", "b", "12"].map(|member| member.as_bytes().to_vec()).into()));
+
+        let entries = read_all(&with_entry(&[], 4, "hash", b"\x02\x05field\x05value\x03age\xC0\x2A")).await;
+        let fields = [("field", "value"), ("age", "42")].map(|(field, value)| (field.as_bytes().to_vec(), value.as_bytes().to_vec()));
+        assert_eq!(entries[0].value, RedisType::Hash(fields.into()));
+
+        // Counts that don't match what's there
+        let repeated = with_entry(&[], 2, "set", b"\x02\x01a\x01a");
+        let mut rdb = Rdb::from_reader(repeated.as_slice()).await.unwrap();
+        assert!(rdb.read_next_entry().await.unwrap_err().to_string().contains("repeated members"));
+        let mut rdb = Rdb::from_reader(&b"REDIS0011\xFE\x00\xFB\x01\x00\x04\x04hash\x02\x01a\x01b\x01c"[..]).await.unwrap();
+        let error = rdb.read_next_entry().await.unwrap_err().to_string();
+        assert!(error.contains("expected 4 elements for key hash, could only read 3"), "{error}");
+    }
+
+    /// A listpack made of short entries, given with their encoding
+    fn listpack(entries: &[&[u8]]) -> Vec<u8> {
+        let mut body = vec![];
+        for entry in entries {
+            body.extend(*entry);
+            body.push(entry.len() as u8);
+        }
+        body.push(0xFF);
+
+        let mut listpack = ((body.len() + 6) as u32).to_le_bytes().to_vec();
+        listpack.extend((entries.len() as u16).to_le_bytes());
+        listpack.extend(body);
+        encode_string(&listpack)
+    }
+
+    #[test]
+    fn test_read_listpack() {
+        // "a", 5, -2, 300, "x" 70 times, -1000, -70000, 2^31 - 1 and -2^40
+        let mut blob = vec![0x73, 0, 0, 0, 9, 0, 0x81, b'a', 0x02, 0x05, 0x01, 0xDF, 0xFE, 0x02, 0xC1, 0x2C, 0x02, 0xE0, 0x46];
+        blob.extend([b'x'; 70]);
+        blob.extend([0x48, 0xF1, 0x18, 0xFC, 0x03, 0xF2, 0x90, 0xEE, 0xFE, 0x04, 0xF3, 0xFF, 0xFF, 0xFF, 0x7F, 0x05]);
+        blob.extend([0xF4, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0x09, 0xFF]);
+
+        let expected = ["a", "5", "-2", "300", &"x".repeat(70), "-1000", "-70000", "2147483647", "-1099511627776"];
+        assert_eq!(read_listpack(&blob).unwrap(), expected.map(|element| element.as_bytes().to_vec()));
+
+        blob[4] = 10;
+        assert!(read_listpack(&blob).is_err());
+        assert!(read_listpack(&blob[..50]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_compact_encodings() {
+        let hash = listpack(&[b"\x85field", b"\x85value", b"\x83age", b"\x2A"]);
+        let entries = read_all(&with_entry(&[], 16, "hash", &hash)).await;
+        let fields = [("field", "value"), ("age", "42")].map(|(field, value)| (field.as_bytes().to_vec(), value.as_bytes().to_vec()));
+        assert_eq!(entries[0].value, RedisType::Hash(fields.into()));
+
+        let zset = listpack(&[b"\x81a", b"\x01", b"\x81b", b"\x831.5"]);
+        let entries = read_all(&with_entry(&[], 17, "zset", &zset)).await;
+        let mut expected = SortedSet::default();
+        expected.insert(b"a", 1.0);
+        expected.insert(b"b", 1.5);
+        assert_eq!(entries[0].value, RedisType::ZSet(expected));
+
+        let set = listpack(&[b"\x81a", b"\x07"]);
+        let entries = read_all(&with_entry(&[], 20, "set", &set)).await;
+        assert_eq!(entries[0].value, RedisType::Set(["a", "7"].map(|member| member.as_bytes().to_vec()).into()));
+
+        // Integers only, 16 bits each
+        let intset = encode_string(&[2, 0, 0, 0, 2, 0, 0, 0, 0xFF, 0xFF, 0x10, 0x00]);
+        let entries = read_all(&with_entry(&[], 11, "intset", &intset)).await;
+        assert_eq!(entries[0].value, RedisType::Set(["-1", "16"].map(|member| member.as_bytes().to_vec()).into()));
+
+        // A quicklist with a packed node, and a plain one
+        let mut nodes = vec![0x02, 0x02];
+        nodes.extend(listpack(&[b"\x81a", b"\x81b"]));
+        nodes.extend([0x01, 0x05]);
+        nodes.extend(b"large");
+        let entries = read_all(&with_entry(&[], 18, "list", &nodes)).await;
+        assert_eq!(entries[0].value, list(&["a", "b", "large"]));
+
+        // A hash with a field missing its value
+        let broken = with_entry(&[], 16, "hash", &listpack(&[b"\x85field"]));
+        let mut rdb = Rdb::from_reader(broken.as_slice()).await.unwrap();
+        assert!(rdb.read_next_entry().await.unwrap_err().to_string().contains("odd number of elements"));
+    }
+
+    async fn try_read_all(data: &[u8], verify: bool) -> anyhow::Result<Vec<RedisFileEntry>> {
+        let mut rdb = Rdb::from_reader(data).await?;
+        rdb.set_verify_checksum(verify);
+        let mut entries = vec![];
+        while let Some((_, entry)) = rdb.read_next_entry().await? {
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    #[tokio::test]
+    async fn test_checksum() {
+        let with_checksum = |mut data: Vec<u8>| {
+            let body = data.len() - 8;
+            let checksum = crc64(0, &data[..body]);
+            data[body..].copy_from_slice(&checksum.to_le_bytes());
+            data
+        };
+
+        let data = with_checksum(with_entry(&[], 0, "key", b"\x05value"));
+        assert_eq!(try_read_all(&data, true).await.unwrap()[0].value, RedisType::from("value"));
+        let empty = with_checksum(b"REDIS0011\xFA\x03ver\x017\xFF\x00\x00\x00\x00\x00\x00\x00\x00".to_vec());
+        assert!(try_read_all(&empty, true).await.unwrap().is_empty());
+
+        // A bit flipped somewhere in the value
+        let mut corrupt = data.clone();
+        corrupt[20] ^= 0x01;
+        let error = try_read_all(&corrupt, true).await.unwrap_err();
+        assert_eq!(error.to_string(), "Bad RDB checksum");
+        assert_eq!(try_read_all(&corrupt, false).await.unwrap()[0].value, RedisType::from("walue"));
+        let mut corrupt = empty.clone();
+        corrupt[12] = b'8';
+        assert!(try_read_all(&corrupt, true).await.is_err());
+        assert!(try_read_all(&corrupt, false).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_multiple_databases() {
+        // DB 0 and DB 3, each with its size hint, and a TTL in the latter
+        let mut data = b"REDIS0011\xFA\x03ver\x017\xFE\x00\xFB\x01\x00\x00\x01a\x01x".to_vec();
+        data.extend(b"\xFE\x03\xFB\x02\x01\x00\x01b\x01y\xFC\x00\x20\x51\xA6\x3C\x01\x00\x00\x00\x01c\x01z");
+        data.extend([0xFF, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut rdb = Rdb::from_reader(data.as_slice()).await.unwrap();
+        let mut entries = vec![];
+        while let Some((db, entry)) = rdb.read_next_entry().await.unwrap() {
+            entries.push((db, entry.key, entry.expires));
+        }
+        let expires = UNIX_EPOCH + Duration::from_millis(1360000000000);
+        assert_eq!(entries, vec![(0, "a".into(), None), (3, "b".into(), None), (3, "c".into(), Some(expires))]);
+        assert!(rdb.read_next_entry().await.unwrap().is_none());
+
+        // The size hint is optional
+        let data = b"REDIS0011\xFE\x01\x00\x01a\x01x\xFF\x00\x00\x00\x00\x00\x00\x00\x00";
+        let mut rdb = Rdb::from_reader(&data[..]).await.unwrap();
+        assert!(matches!(rdb.read_next_entry().await.unwrap(), Some((1, entry)) if entry.key == b"a"));
+    }
+}
@@ -1,19 +1,21 @@
 use std::time::Duration;
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Error, Result};
 use sha1::{Sha1, Digest};
 
 use tokio::{
-    io::BufReader,
+    io::{AsyncReadExt, BufReader},
     net::TcpStream,
-    sync::mpsc::Sender,
+    sync::{mpsc::Sender, watch},
     time::timeout,
 };
 
 use crate::{
-    common_cli_rep::handle_set,
-    config::Configuration,
+    client::Client,
+    config::{ConfigCommand, Configuration},
     io::*,
+    rdb::{Rdb, RedisFileEntry},
+    shard::StoreHandle,
     store::StoreCommand,
     types::RedisType,
 };
@@ -24,13 +26,19 @@ pub struct ReplicaInfo {
     offset: usize,
 }
 
-impl ReplicaInfo {
-    pub fn new() -> Self {
+impl Default for ReplicaInfo {
+    fn default() -> Self {
         ReplicaInfo {
             hasher: Sha1::new(),
             offset: 0,
         }
     }
+}
+
+impl ReplicaInfo {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
     pub fn digest_string(&self) -> String {
         let cl = self.hasher.clone();
@@ -44,11 +52,37 @@ impl ReplicaInfo {
     }
 }
 
+/// A replica connected to this server, as seen by the master
+#[derive(Clone)]
+pub struct ConnectedReplica {
+    pub ip: String,
+    pub port: String,
+    // Last offset acknowledged by the replica
+    pub offset: usize,
+}
+
 static TIMEOUT: Duration = Duration::from_millis(1000);
+// Like Redis, replicas acknowledge their offset every second, even if not asked
+static ACK_PERIOD: Duration = Duration::from_secs(1);
+
+async fn load_entries(rdb: &mut Rdb<'_>, store_tx: &StoreHandle) -> Result<()> {
+    while let Some((db, RedisFileEntry { key, value, expires })) = rdb.read_next_entry().await? {
+        // There's only DB 0 for now
+        if db != 0 {
+            continue
+        }
+        store_tx.send(StoreCommand::Load { key, value, expires }).await?;
+    }
+
+    Ok(())
+}
 
 struct Replica {
     stream: TcpReader,
-    store_tx: Sender<StoreCommand>,
+    store_tx: StoreHandle,
+    // Runs the commands propagated by the master, as if they came
+    // from a regular client. The replies are discarded
+    client: Client,
     total_bytes: usize,
 }
 
@@ -75,8 +109,8 @@ impl Replica {
 
         cmd.write(&mut self.stream).await?;
         match timeout(TIMEOUT, get_string(&mut self.stream)).await {
-            Ok(Ok(Some(RedisString { string, .. }))) => {
-                if string != "+OK" { bail !("expected OK at first REPLCONF") }
+            Ok(Ok(Some(RedisString { string, .. }))) if string != "+OK" => {
+                bail!("expected OK at first REPLCONF")
             }
             Ok(Err(_)) => eprintln!("Error when reading the answer for the first REPLCONF"),
             Err(_) => eprintln!("Timeout when waiting for an answer for the first REPLCONF"),
@@ -88,8 +122,8 @@ impl Replica {
 
         cmd.write(&mut self.stream).await?;
         match timeout(TIMEOUT, get_string(&mut self.stream)).await {
-            Ok(Ok(Some(RedisString { string, .. }))) => {
-                if string != "+OK" { bail !("expected OK at second REPLCONF") }
+            Ok(Ok(Some(RedisString { string, .. }))) if string != "+OK" => {
+                bail!("expected OK at second REPLCONF")
             }
             Ok(Err(_)) => eprintln!("Error when reading the answer for the second REPLCONF"),
             Err(_) => eprintln!("Timeout when waiting for an answer for the second REPLCONF"),
@@ -109,8 +143,7 @@ impl Replica {
                     bail !("expected FULLRESYNC at initial PSYNC. Got: {string:?}")
                 }
                 else {
-                    // Read the transmitted RDB file
-                    let _rdb = read_bulk_bytes(&mut self.stream).await?;
+                    self.load_rdb().await?;
                 }
             }
             Ok(Err(_)) => eprintln!("Error when reading the answer PSYNC"),
@@ -121,6 +154,41 @@ impl Replica {
         Ok(())
     }
 
+    /// Read the RDB file sent by the master, loading the entries into the
+    /// store as they arrive.
+    async fn load_rdb(&mut self) -> Result<()> {
+        let Some(RedisString { string, .. }) = get_string(&mut self.stream).await? else {
+            bail!("connection closed before receiving the RDB file")
+        };
+
+        if let Some(mark) = string.strip_prefix("$EOF:") {
+            // Diskless transfer: the file is followed by the same mark
+            let mut rdb = Rdb::from_reader(&mut self.stream).await?;
+            load_entries(&mut rdb, &self.store_tx).await?;
+            drop(rdb);
+
+            let mut trailer = vec![0; mark.len()];
+            self.stream.read_exact(&mut trailer).await?;
+            if trailer != mark.as_bytes() {
+                bail!("the RDB file is not followed by the expected EOF mark")
+            }
+        } else if let Some(length) = string.strip_prefix('$') {
+            let length = length.parse::<u64>()
+                .map_err(|_| Error::msg("Protocol error: invalid bulk length"))?;
+            let mut payload = (&mut self.stream).take(length);
+            let mut rdb = Rdb::from_reader(&mut payload).await?;
+            load_entries(&mut rdb, &self.store_tx).await?;
+            drop(rdb);
+
+            // Skip whatever the reader didn't need
+            tokio::io::copy(&mut payload, &mut tokio::io::sink()).await?;
+        } else {
+            bail!("expected the RDB file. Got: {string:?}")
+        }
+
+        Ok(())
+    }
+
     async fn handshake(&mut self, config: &Configuration) -> Result<()> {
         if let Err(error) = self.ping().await {
             eprintln!("Replica handshake error at PING: {error}");
@@ -138,21 +206,21 @@ impl Replica {
         Ok(())
     }
 
-    async fn handle_set(&mut self, args: &[&str]) -> Result<()> {
-        handle_set(&mut self.stream, &self.store_tx, args, false).await
+    async fn send_ack(&mut self) -> Result<()> {
+        RedisType::from(vec![
+            "REPLCONF",
+            "ACK",
+            self.total_bytes.to_string().as_str()
+        ]).write(&mut self.stream)
+          .await
     }
 
     async fn handle_replconf(&mut self, args: &[&str]) -> Result<()> {
         match args.len() {
             2 => {
-                if args[0].to_ascii_lowercase() == "getack" {
+                if args[0].eq_ignore_ascii_case("getack") {
                     if args[1] == "*" {
-                        RedisType::from(vec![
-                            "REPLCONF",
-                            "ACK",
-                            self.total_bytes.to_string().as_str()
-                        ]).write(&mut self.stream)
-                          .await
+                        self.send_ack().await
                     } else {
                         bail!("unsupported argument {:?} for REPLCONF GETACK", args[1]);
                     }
@@ -164,29 +232,33 @@ impl Replica {
         }
     }
 
-    async fn dispatch(&mut self, cmd_vec: &[&str]) -> Result<()> {
-        let name = cmd_vec[0];
-        let args = &cmd_vec[1..];
-        match name.to_ascii_lowercase().as_str() {
-            "set" => self.handle_set(args).await,
-            "replconf" => self.handle_replconf(args).await,
-            "ping" => {
-                Ok(())
-            }
-            _ => {
-                eprintln!("Replica: got unsupported command {name:?}");
-                let args = cmd_vec[1..]
-                    .iter()
-                    .map(|s| format!("'{}'", *s))
-                    .collect::<Vec<_>>()
-                    .join(" ");
-                bail!("Replica: unknown command '{}', with args beginning with: {}", name, args)
-            }
+    async fn dispatch(&mut self, command: &[&[u8]]) -> Result<()> {
+        let strings = command.iter().map(|arg| String::from_utf8_lossy(arg)).collect::<Vec<_>>();
+        let cmd_vec = strings.iter().map(|arg| arg.as_ref()).collect::<Vec<_>>();
+        let Some((&name, args)) = cmd_vec.split_first() else {
+            return Ok(())
+        };
+        if name.eq_ignore_ascii_case("replconf") {
+            return self.handle_replconf(args).await
         }
+
+        // The master doesn't expect an answer for anything else
+        let result = self.client.dispatch(command).await;
+        self.client.discard_replies();
+        if let Err(error) = &result {
+            eprintln!("Replica: error running {name:?}: {error}");
+        }
+        result.map(|_| ())
     }
 }
 
-pub async fn replica_loop(address: String, config: Configuration, store_tx: Sender<StoreCommand>) {
+pub async fn replica_loop(
+    address: String,
+    config: Configuration,
+    store_tx: StoreHandle,
+    config_tx: Sender<ConfigCommand>,
+    mut shutdown: watch::Receiver<bool>,
+) {
     let stream = match TcpStream::connect(address.clone()).await {
         Ok(stream) => stream,
         Err(error) => {
@@ -196,29 +268,53 @@ pub async fn replica_loop(address: String, config: Configuration, store_tx: Send
         }
     };
 
+    let client = match Client::new(store_tx.clone(), config_tx).await {
+        Ok(client) => client,
+        Err(error) => {
+            eprintln!("Replica setup: {error}");
+            return
+        }
+    };
+
     let mut replica = Replica {
         stream: BufReader::new(stream),
         store_tx,
+        client,
         total_bytes: 0,
     };
 
-    if let Err(_) = replica.handshake(&config).await {
+    if replica.handshake(&config).await.is_err() {
         eprintln!("Replica setup: error when trying to handshake");
         return
     }
 
+    let mut ack_timer = tokio::time::interval(ACK_PERIOD);
+    ack_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
     loop {
-        match read_command(&mut replica.stream).await {
-            Ok(cnt) => match cnt {
-                Some(Command { payload, length } ) => {
-                    eprintln!("Replica: get {length} bytes with command {payload:?}");
-                    let strs = payload.iter().map(|s| s.as_str()).collect::<Vec<_>>();
-                    // Don't do error handling right now
-                    let _ = replica.dispatch(strs.as_slice()).await;
-                    replica.total_bytes += length;
+        let command = tokio::select! {
+            command = read_command(&mut replica.stream, replica.client.max_args(), replica.client.max_bulk_len()) => command,
+            _ = ack_timer.tick() => {
+                if let Err(error) = replica.send_ack().await {
+                    eprintln!("Replica: error sending ACK: {error}");
                 }
-                None => {},
-            },
+                continue
+            }
+            _ = shutdown.changed() => break,
+        };
+
+        match command {
+            Ok(Some(Command { payload, length })) => {
+                eprintln!("Replica: get {length} bytes with command {payload:?}");
+                let args = payload.iter().map(Vec::as_slice).collect::<Vec<_>>();
+                // Don't do error handling right now
+                let _ = replica.dispatch(args.as_slice()).await;
+                replica.total_bytes += length;
+            }
+            Ok(None) => {
+                eprintln!("Replica: the master closed the connection");
+                break;
+            }
             Err(error) => {
                 eprintln!("Replica: {error}");
             }
@@ -0,0 +1,246 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::Result;
+
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc::{self, Sender}, watch},
+    task::{JoinHandle, JoinSet},
+};
+
+use crate::{
+    client,
+    config::{self, config_loop, ConfigCommand, Configuration},
+    notify::NotifyFlags,
+    rdb::Rdb,
+    replica::replica_loop,
+    shard::{spawn_shards, StoreHandle},
+    store::{self, expire_loop, store_loop, Store},
+};
+
+/// Entry point to run the server, either from `main` or embedded in another
+/// application:
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use redis_starter_rust::server::Server;
+///
+/// let server = Server::builder().bind("127.0.0.1:0").run().await?;
+/// println!("Listening on {}", server.local_addr());
+/// server.shutdown().await
+/// # }
+/// ```
+pub struct Server;
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+}
+
+#[derive(Default)]
+pub struct ServerBuilder {
+    config: Configuration,
+    store: Option<Store>,
+    address: Option<String>,
+}
+
+impl ServerBuilder {
+    pub fn config(mut self, config: Configuration) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Start with a pre-populated store. When given, the RDB file is not loaded
+    pub fn store(mut self, store: Store) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Listen on `address` instead of the one from the configuration. Use
+    /// port 0 to get an ephemeral port (see `ServerHandle::local_addr`)
+    pub fn bind(mut self, address: &str) -> Self {
+        self.address = Some(String::from(address));
+        self
+    }
+
+    /// Bind the listener and spawn all the server tasks. Returns as soon as the
+    /// server is ready to accept connections.
+    pub async fn run(self) -> Result<ServerHandle> {
+        let config = self.config;
+        let address = match self.address {
+            Some(address) => address,
+            None => config.get_binding_address()?,
+        };
+        let listener = TcpListener::bind(address).await?;
+        let local_addr = listener.local_addr()?;
+
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let trigger = ShutdownTrigger(Arc::new(shutdown_tx));
+        let mut tasks = JoinSet::new();
+
+        let mut store = match self.store {
+            Some(store) => store,
+            None => load_store(&config).await?,
+        };
+        let notify = config.get("notify-keyspace-events").unwrap_or_default();
+        store.set_notify_flags(NotifyFlags::parse(&notify)?);
+
+        let (config_tx, config_rx) = mpsc::channel(config::CMD_BUFFER);
+
+        // Spin the Store task, or one per shard along with the router that
+        // takes care of the commands spanning several of them
+        let shards = config.get("store-shards").and_then(|count| count.parse().ok()).unwrap_or(1);
+        let store_tx = if shards > 1 {
+            spawn_shards(store, shards)
+        } else {
+            let (store_tx, store_rx) = mpsc::channel(store::CMD_BUFFER);
+            tokio::spawn(async move {
+                store_loop(store, store_rx).await;
+            });
+            StoreHandle::new(store_tx)
+        };
+
+        // Don't read from the Rdb file if this is a replica
+        if config.is_replica() {
+            // Contact the master server and get the initial
+            // Rdb file
+            let address = config.get("replicaof").unwrap();
+            let cfg2 = config.clone();
+            let stx2 = store_tx.clone();
+            let ctx2 = config_tx.clone();
+            let srx2 = shutdown_rx.clone();
+            tasks.spawn(async move {
+                replica_loop(address, cfg2, stx2, ctx2, srx2).await;
+            });
+        }
+
+        // Keys with a TTL are removed even if nobody asks for them. Replicas
+        // leave it to the master, which sends them a DEL for each one
+        if !config.is_replica() {
+            let stx2 = store_tx.clone();
+            let srx2 = shutdown_rx.clone();
+            tokio::spawn(async move {
+                expire_loop(stx2, srx2).await;
+            });
+        }
+
+        // Spin the Config task
+        tokio::spawn(async move {
+            config_loop(config, config_rx).await;
+        });
+
+        let trigger2 = trigger.clone();
+        let task = tokio::spawn(async move {
+            accept_loop(listener, store_tx, config_tx, trigger2, shutdown_rx, tasks).await;
+        });
+
+        Ok(ServerHandle { local_addr, trigger, task })
+    }
+}
+
+/// Asks the server to shut down. Can be cloned and used from other tasks
+/// (eg. to shut down on a signal, or from the SHUTDOWN command)
+#[derive(Clone)]
+pub struct ShutdownTrigger(Arc<watch::Sender<bool>>);
+
+impl ShutdownTrigger {
+    pub fn trigger(&self) {
+        let _ = self.0.send(true);
+    }
+
+    /// Signal that changes when the server is shutting down
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.0.subscribe()
+    }
+}
+
+/// Handle to a running server. Dropping it leaves the server running: use
+/// `shutdown`, or a `ShutdownTrigger`, to stop it.
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    trigger: ShutdownTrigger,
+    task: JoinHandle<()>,
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn shutdown_trigger(&self) -> ShutdownTrigger {
+        self.trigger.clone()
+    }
+
+    /// Stop accepting connections, close the open ones and wait until all
+    /// of them are done.
+    pub async fn shutdown(self) -> Result<()> {
+        self.trigger.trigger();
+        self.task.await?;
+        Ok(())
+    }
+
+    /// Wait until the server stops on its own (eg. after a SHUTDOWN command)
+    pub async fn wait(self) -> Result<()> {
+        self.task.await?;
+        Ok(())
+    }
+}
+
+async fn load_store(config: &Configuration) -> Result<Store> {
+    let mut store = Store::default();
+
+    if config.is_replica() {
+        return Ok(store)
+    }
+
+    if let Ok(db_path) = config.get_database_path() {
+        if let Ok(mut rdb) = Rdb::open(db_path.as_path()).await {
+            rdb.set_verify_checksum(config.get("rdbchecksum").is_some_and(|value| value == "yes"));
+            // There's only DB 0 for now, entries in other databases are left out
+            while let Some((db, entry)) = rdb.read_next_entry().await? {
+                if db == 0 {
+                    store.write(&entry.key, entry.value, entry.expires);
+                }
+            }
+        } else {
+            eprintln!("Couldn't open database at {}", db_path.to_string_lossy());
+        }
+    }
+    // Loading doesn't count as a change
+    store.mark_saved();
+
+    Ok(store)
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    store_tx: StoreHandle,
+    config_tx: Sender<ConfigCommand>,
+    trigger: ShutdownTrigger,
+    mut shutdown: watch::Receiver<bool>,
+    mut tasks: JoinSet<()>,
+) {
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, addr)) => {
+                    eprintln!("Accepted connection from: {}", addr);
+                    let stx2 = store_tx.clone();
+                    let ctx2 = config_tx.clone();
+                    let trigger2 = trigger.clone();
+                    tasks.spawn(async move {
+                        client::client_loop(stream, stx2, ctx2, trigger2).await;
+                    });
+                }
+                Err(error) => eprintln!("Error accepting a connection: {error}"),
+            },
+            // Reap the finished connections as we go
+            Some(_) = tasks.join_next(), if !tasks.is_empty() => {}
+            _ = shutdown.changed() => break,
+        }
+    }
+
+    // Every task is watching the shutdown signal as well
+    while tasks.join_next().await.is_some() {}
+}
@@ -0,0 +1,561 @@
+use std::{
+    hash::{BuildHasher, DefaultHasher, Hash, Hasher, RandomState},
+    sync::{Arc, Mutex},
+};
+
+use itertools::Itertools;
+use tokio::sync::{
+    mpsc::{self, error::SendError, Receiver, Sender},
+    oneshot, OwnedRwLockWriteGuard, RwLock,
+};
+
+use crate::{
+    info::PersistenceInfo,
+    store::{scan_position, shard_loop, CommandResponse, KeyState, Store, StoreCommand, CMD_BUFFER},
+    types::RedisType,
+};
+
+// The router talks to the shards as one more client, with this id
+const ROUTER_ID: usize = usize::MAX;
+
+/// Shard, out of `count`, that holds `key`
+pub fn shard_of(key: &[u8], count: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % count as u64) as usize
+}
+
+/// Way to the store for the clients. Commands on keys of a single shard go
+/// straight to it, and the rest through the router, which is the only one
+/// there is without shards
+#[derive(Clone)]
+pub struct StoreHandle {
+    tx: Sender<StoreCommand>,
+    shards: Vec<Sender<StoreCommand>>,
+    // Read to send a command straight to a shard. The router holds it for
+    // writing while it runs something across shards, so that nothing gets in
+    // between. A transaction has it already, so its handle goes without
+    gate: Option<Arc<RwLock<()>>>,
+}
+
+impl StoreHandle {
+    pub fn new(tx: Sender<StoreCommand>) -> Self {
+        StoreHandle { tx, shards: vec![], gate: None }
+    }
+
+    /// Handle for the commands of a transaction, that the router takes from
+    /// `tx` until it's dropped
+    pub fn for_transaction(&self, tx: Sender<StoreCommand>) -> Self {
+        StoreHandle { tx, shards: self.shards.clone(), gate: None }
+    }
+
+    /// Positions of some keys, grouped by the shard holding them
+    pub fn by_shard(&self, keys: &[&[u8]]) -> Vec<(usize, Vec<usize>)> {
+        let count = self.shards.len().max(1);
+        keys.iter().enumerate()
+            .into_group_map_by(|(_, key)| shard_of(key, count))
+            .into_iter()
+            .map(|(shard, keys)| (shard, keys.into_iter().map(|(index, _)| index).collect()))
+            .sorted()
+            .collect()
+    }
+
+    async fn deliver(&self, shard: usize, cmd: StoreCommand) -> Result<(), SendError<StoreCommand>> {
+        match self.shards.get(shard) {
+            Some(tx) => tx.send(cmd).await,
+            None => self.tx.send(cmd).await,
+        }
+    }
+
+    /// Send a command straight to a shard
+    async fn send_to(&self, shard: usize, cmd: StoreCommand) -> Result<(), SendError<StoreCommand>> {
+        let _open = match &self.gate {
+            Some(gate) => Some(gate.read().await),
+            None => None,
+        };
+        self.deliver(shard, cmd).await
+    }
+
+    /// Send each shard, as returned by `by_shard`, its part of a command on
+    /// keys spread over several. The gate stays closed until all of them
+    /// are queued, so that every shard serves its part in the same place
+    /// with respect to the rest of commands, as if it were a single one
+    pub async fn send_parts(&self, mut parts: Vec<(usize, StoreCommand)>) -> Result<(), SendError<StoreCommand>> {
+        if parts.len() == 1 {
+            let (shard, cmd) = parts.remove(0);
+            return self.send_to(shard, cmd).await
+        }
+        let _closed = match &self.gate {
+            Some(gate) => Some(gate.write().await),
+            None => None,
+        };
+        for (shard, cmd) in parts {
+            self.deliver(shard, cmd).await?;
+        }
+        Ok(())
+    }
+
+    /// Send a command to the shard holding its keys, or to the router if
+    /// they're spread over several, or it doesn't work on keys
+    pub async fn send(&self, cmd: StoreCommand) -> Result<(), SendError<StoreCommand>> {
+        let shard = match keys_of(&cmd) {
+            Some(keys) if !self.shards.is_empty() => {
+                keys.iter().map(|key| shard_of(key, self.shards.len())).all_equal_value().ok()
+            }
+            _ => None,
+        };
+        match shard {
+            Some(shard) => self.send_to(shard, cmd).await,
+            None => self.tx.send(cmd).await,
+        }
+    }
+}
+
+/// Split the store into `count` shards, each one served by its own task, and
+/// spawn the router that takes care of the commands that aren't meant for a
+/// single one
+pub fn spawn_shards(store: Store, count: usize) -> StoreHandle {
+    let mut shards = vec![];
+    // The first shard keeps the subscriptions, so the others send their
+    // keyspace notifications to it
+    let mut hub = None;
+    for shard in store.split(count) {
+        let (tx, shard_rx) = mpsc::channel(CMD_BUFFER);
+        tokio::spawn(shard_loop(shard, shard_rx, hub.clone()));
+        hub.get_or_insert(tx.clone());
+        shards.push(tx);
+    }
+
+    let (tx, rx) = mpsc::channel(CMD_BUFFER);
+    let gate = Arc::new(RwLock::new(()));
+    tokio::spawn(router_loop(shards.clone(), gate.clone(), rx));
+
+    StoreHandle { tx, shards, gate: Some(gate) }
+}
+
+/// Keys a command works on, or `None` if it doesn't work on keys. Commands
+/// that work on the whole keyspace, or on none of the shards in particular,
+/// are taken care of by the router before asking
+fn keys_of(cmd: &StoreCommand) -> Option<Vec<&[u8]>> {
+    let keys = match cmd {
+        StoreCommand::Set { key, .. }
+        | StoreCommand::SetEx { key, .. }
+        | StoreCommand::Load { key, .. }
+        | StoreCommand::SetWith { key, .. }
+        | StoreCommand::Get { key, .. }
+        | StoreCommand::GetEx { key, .. }
+        | StoreCommand::Expire { key, .. }
+        | StoreCommand::Persist { key, .. }
+        | StoreCommand::ExpireTime { key, .. }
+        | StoreCommand::SetRange { key, .. }
+        | StoreCommand::Append { key, .. }
+        | StoreCommand::Push { key, .. }
+        | StoreCommand::Pop { key, .. }
+        | StoreCommand::LRange { key, .. }
+        | StoreCommand::HSet { key, .. }
+        | StoreCommand::HGet { key, .. }
+        | StoreCommand::HMGet { key, .. }
+        | StoreCommand::HSetNx { key, .. }
+        | StoreCommand::HIncrBy { key, .. }
+        | StoreCommand::HIncrByFloat { key, .. }
+        | StoreCommand::HDel { key, .. }
+        | StoreCommand::HExists { key, .. }
+        | StoreCommand::HLen { key, .. }
+        | StoreCommand::HStrLen { key, .. }
+        | StoreCommand::HGetAll { key, .. }
+        | StoreCommand::SAdd { key, .. }
+        | StoreCommand::SRem { key, .. }
+        | StoreCommand::SMembers { key, .. }
+        | StoreCommand::SIsMember { key, .. }
+        | StoreCommand::SCard { key, .. }
+        | StoreCommand::ZAdd { key, .. }
+        | StoreCommand::ZScore { key, .. }
+        | StoreCommand::ZRank { key, .. }
+        | StoreCommand::ZCard { key, .. }
+        | StoreCommand::ZRem { key, .. }
+        | StoreCommand::ZCount { key, .. }
+        | StoreCommand::ZPop { key, .. }
+        | StoreCommand::ZRange { key, .. }
+        | StoreCommand::LLen { key, .. }
+        | StoreCommand::LPos { key, .. }
+        | StoreCommand::LIndex { key, .. }
+        | StoreCommand::LSet { key, .. }
+        | StoreCommand::LInsert { key, .. }
+        | StoreCommand::LRem { key, .. }
+        | StoreCommand::BitField { key, .. }
+        | StoreCommand::IncrBy { key, .. }
+        | StoreCommand::GetSet { key, .. }
+        | StoreCommand::SetNx { key, .. }
+        | StoreCommand::Type { key, .. }
+        | StoreCommand::ObjectEncoding { key, .. } => vec![key.as_slice()],
+        StoreCommand::MGet { keys, .. }
+        | StoreCommand::MGetPart { keys, .. }
+        | StoreCommand::Combine { keys, .. }
+        | StoreCommand::InterCard { keys, .. }
+        | StoreCommand::ZInterCard { keys, .. }
+        | StoreCommand::Delete { keys, .. }
+        | StoreCommand::Exists { keys, .. } => keys.iter().map(Vec::as_slice).collect(),
+        StoreCommand::MSet { pairs } => pairs.iter().map(|(key, _)| key.as_slice()).collect(),
+        StoreCommand::LMove { source, destination, .. }
+        | StoreCommand::SMove { source, destination, .. }
+        | StoreCommand::Copy { source, destination, .. } => vec![source.as_slice(), destination.as_slice()],
+        StoreCommand::Rename { key, new_key, .. } => vec![key.as_slice(), new_key.as_slice()],
+        StoreCommand::CombineStore { destination, keys, .. } => {
+            std::iter::once(destination).chain(keys).map(Vec::as_slice).collect()
+        }
+        _ => return None,
+    };
+    Some(keys)
+}
+
+/// Takes the commands that the clients couldn't send straight to a shard.
+/// Those working on keys of a single shard are simply passed along. The rest
+/// are run here, asking each shard for its part. Each command is done with
+/// before the next one is routed, so that they don't interleave
+struct Router {
+    shards: Vec<Sender<StoreCommand>>,
+    gate: Arc<RwLock<()>>,
+    // Held while a transaction runs
+    exclusive: Option<OwnedRwLockWriteGuard<()>>,
+    clients: Vec<Sender<CommandResponse>>,
+    // Answers of the shards to the router
+    responses: Receiver<CommandResponse>,
+    // Changes held by each shard when the last snapshot was taken
+    snapshot_changes: Vec<u64>,
+}
+
+impl Router {
+    fn shard_of(&self, key: &[u8]) -> usize {
+        shard_of(key, self.shards.len())
+    }
+
+    async fn send(&self, shard: usize, cmd: StoreCommand) {
+        let _ = self.shards[shard].send(cmd).await;
+    }
+
+    async fn broadcast(&self, cmd: impl Fn() -> StoreCommand) {
+        for shard in 0..self.shards.len() {
+            self.send(shard, cmd()).await;
+        }
+    }
+
+    /// Send a command to a shard, and wait for its answer
+    async fn ask(&mut self, shard: usize, cmd: StoreCommand) -> Option<CommandResponse> {
+        self.send(shard, cmd).await;
+        self.responses.recv().await
+    }
+
+    /// Send a command that answers through a oneshot channel, and wait for it
+    async fn request<T>(&self, shard: usize, cmd: impl FnOnce(oneshot::Sender<T>) -> StoreCommand) -> Option<T> {
+        let (tx, rx) = oneshot::channel();
+        self.send(shard, cmd(tx)).await;
+        rx.await.ok()
+    }
+
+    async fn respond(&self, id: usize, response: CommandResponse) {
+        if let Some(client) = self.clients.get(id) {
+            let _ = client.send(response).await;
+        }
+    }
+
+    /// Wait until every shard has served the commands sent so far
+    async fn sync(&self) {
+        for shard in 0..self.shards.len() {
+            self.request(shard, StoreCommand::Sync).await;
+        }
+    }
+
+    /// Keep the clients from sending commands straight to the shards until
+    /// the guard is dropped. The commands they sent before are served first,
+    /// as they're ahead in the queues of the shards
+    async fn close_gate(&self) -> Option<OwnedRwLockWriteGuard<()>> {
+        match self.exclusive {
+            Some(_) => None,
+            None => Some(self.gate.clone().write_owned().await),
+        }
+    }
+
+    /// Run a command on keys spread over several shards. The keys are moved
+    /// to the shard of the first one for the command, and sent back home
+    /// afterwards, with the gate closed so that nobody misses them
+    async fn across(&mut self, cmd: StoreCommand, keys: Vec<Vec<u8>>) {
+        let _closed = self.close_gate().await;
+        let keys = keys.into_iter().unique().collect::<Vec<_>>();
+        let home = self.shard_of(&keys[0]);
+        let away = keys.into_iter().filter(|key| self.shard_of(key) != home).collect::<Vec<_>>();
+
+        let by_shard = away.iter().cloned().into_group_map_by(|key| self.shard_of(key));
+        for (shard, keys) in by_shard {
+            let states = self.request(shard, |tx| StoreCommand::Lend { keys, tx }).await.unwrap_or_default();
+            self.send(home, StoreCommand::Adopt(states)).await;
+        }
+
+        self.send(home, cmd).await;
+
+        let states = self.request(home, |tx| StoreCommand::Lend { keys: away, tx }).await.unwrap_or_default();
+        let by_shard = states.into_iter().into_group_map_by(|state: &KeyState| self.shard_of(state.key()));
+        for (shard, states) in by_shard {
+            self.send(shard, StoreCommand::Adopt(states)).await;
+        }
+    }
+
+    /// Versions of some keys, wherever they are
+    async fn versions(&mut self, keys: &[Vec<u8>]) -> Vec<u64> {
+        let mut versions = vec![0; keys.len()];
+        let by_shard = keys.iter().enumerate().into_group_map_by(|(_, key)| self.shard_of(key));
+        for (shard, indexed) in by_shard {
+            let (indexes, keys): (Vec<usize>, Vec<Vec<u8>>) = indexed.into_iter()
+                .map(|(index, key)| (index, key.clone()))
+                .unzip();
+            if let Some(CommandResponse::Versions(found)) = self.ask(shard, StoreCommand::GetVersions { id: ROUTER_ID, keys }).await {
+                for (index, version) in indexes.into_iter().zip(found) {
+                    versions[index] = version;
+                }
+            }
+        }
+        versions
+    }
+
+    /// BLPOP and BRPOP. The shards are tried in the order of the keys, so
+    /// that the first list with elements is the one popped. Then the client
+    /// waits on all of them, unless `wait` is false
+    async fn block_pop(&mut self, keys: Vec<Vec<u8>>, left: bool, tx: oneshot::Sender<CommandResponse>, wait: bool) {
+        let waiter = Arc::new(Mutex::new(Some(tx)));
+        let by_shard = keys.iter().cloned().into_group_map_by(|key| self.shard_of(key));
+        let order = keys.iter().map(|key| self.shard_of(key)).unique().collect::<Vec<_>>();
+
+        for shard in order {
+            let keys = by_shard[&shard].clone();
+            self.send(shard, StoreCommand::BlockPopShared { keys, left, waiter: waiter.clone() }).await;
+            self.request(shard, StoreCommand::Sync).await;
+            if waiter.lock().unwrap().is_none() {
+                return
+            }
+        }
+
+        if !wait {
+            if let Some(tx) = waiter.lock().unwrap().take() {
+                let _ = tx.send(CommandResponse::Get(None));
+            }
+        }
+    }
+
+    async fn route(&mut self, cmd: StoreCommand, transaction: &mut Option<Receiver<StoreCommand>>) {
+        match cmd {
+            StoreCommand::InitClient(tx) => {
+                let id = self.clients.len();
+                self.clients.push(tx.clone());
+                self.broadcast(|| StoreCommand::AddClient { id, tx: tx.clone() }).await;
+                let _ = tx.send(CommandResponse::ClientId(id)).await;
+            }
+            StoreCommand::SetNotifyFlags(flags) => {
+                self.broadcast(|| StoreCommand::SetNotifyFlags(flags)).await;
+            }
+            StoreCommand::SetPackedThreshold(threshold) => {
+                self.broadcast(|| StoreCommand::SetPackedThreshold(threshold)).await;
+            }
+            StoreCommand::ExpireCycle => {
+                self.broadcast(|| StoreCommand::ExpireCycle).await;
+            }
+            StoreCommand::GetVersions { id, keys } => {
+                let versions = self.versions(&keys).await;
+                self.respond(id, CommandResponse::Versions(versions)).await
+            }
+            StoreCommand::Exec { id, watched, commands } => {
+                let closed = self.close_gate().await;
+                let (keys, expected): (Vec<Vec<u8>>, Vec<u64>) = watched.into_iter().unzip();
+                let unchanged = self.versions(&keys).await == expected;
+                if unchanged {
+                    *transaction = Some(commands);
+                    self.exclusive = closed;
+                }
+                self.respond(id, CommandResponse::Integer(unchanged as i64)).await
+            }
+            StoreCommand::InitReplica { tx, snapshot } => {
+                let mut entries = vec![];
+                for shard in 0..self.shards.len() {
+                    let part = self.request(shard, |snapshot| StoreCommand::InitReplica { tx: tx.clone(), snapshot }).await;
+                    entries.extend(part.unwrap_or_default());
+                }
+                let _ = snapshot.send(entries);
+            }
+            StoreCommand::Snapshot(tx) => {
+                let mut entries = vec![];
+                self.snapshot_changes.clear();
+                for shard in 0..self.shards.len() {
+                    let (part, changes) = self.request(shard, StoreCommand::Snapshot).await.unwrap_or_default();
+                    entries.extend(part);
+                    self.snapshot_changes.push(changes);
+                }
+                let _ = tx.send((entries, self.snapshot_changes.iter().sum()));
+            }
+            StoreCommand::Saved { ok, .. } => {
+                for shard in 0..self.shards.len() {
+                    let changes = self.snapshot_changes.get(shard).copied().unwrap_or_default();
+                    self.send(shard, StoreCommand::Saved { changes, ok }).await;
+                }
+            }
+            StoreCommand::PersistenceInfo(tx) => {
+                let mut parts = vec![];
+                for shard in 0..self.shards.len() {
+                    parts.extend(self.request(shard, StoreCommand::PersistenceInfo).await);
+                }
+                let _ = tx.send(PersistenceInfo {
+                    changes_since_save: parts.iter().map(|part| part.changes_since_save).sum(),
+                    last_save: parts.iter().map(|part| part.last_save).max().unwrap(),
+                    last_save_ok: parts.iter().all(|part| part.last_save_ok),
+                });
+            }
+            StoreCommand::BlockPop { keys, left, tx } => {
+                // Nobody else gets to push during a transaction, so there's no point in waiting
+                self.block_pop(keys, left, tx, transaction.is_none()).await
+            }
+            StoreCommand::Replicate(cmd) => {
+                // Whatever the shards were replicating goes first
+                self.sync().await;
+                self.send(0, StoreCommand::Replicate(cmd)).await;
+            }
+            StoreCommand::DbSize(id) => {
+                let mut size = 0;
+                for shard in 0..self.shards.len() {
+                    if let Some(CommandResponse::Integer(count)) = self.ask(shard, StoreCommand::DbSize(ROUTER_ID)).await {
+                        size += count;
+                    }
+                }
+                self.respond(id, CommandResponse::Integer(size)).await
+            }
+            StoreCommand::Keys { id, pattern } => {
+                let mut keys = vec![];
+                for shard in 0..self.shards.len() {
+                    let cmd = StoreCommand::Keys { id: ROUTER_ID, pattern: pattern.clone() };
+                    if let Some(CommandResponse::Keys(RedisType::Array(part))) = self.ask(shard, cmd).await {
+                        keys.extend(part);
+                    }
+                }
+                self.respond(id, CommandResponse::Keys(RedisType::Array(keys))).await
+            }
+            StoreCommand::Scan { id, cursor, count, pattern, kind } => {
+                // Each shard takes its own step from the same cursor. The
+                // scan goes on from the nearest place any of them stopped at,
+                // and the keys past that are found again in the next step
+                let mut next = None;
+                let mut keys = vec![];
+                for shard in 0..self.shards.len() {
+                    let cmd = StoreCommand::Scan { id: ROUTER_ID, cursor, count, pattern: pattern.clone(), kind: kind.clone() };
+                    if let Some(CommandResponse::Scan { cursor, keys: part }) = self.ask(shard, cmd).await {
+                        if cursor != 0 {
+                            next = Some(next.map_or(cursor, |next: u64| next.min(cursor)));
+                        }
+                        keys.push(part);
+                    }
+                }
+                let keys = keys.into_iter()
+                    .flatten()
+                    .filter(|key| next.is_none_or(|next| scan_position(key) < next))
+                    .collect();
+                self.respond(id, CommandResponse::Scan { cursor: next.unwrap_or(0), keys }).await
+            }
+            StoreCommand::RandomKey(id) => {
+                // Start from a random shard, moving on to the next ones if it's empty
+                let count = self.shards.len();
+                let start = RandomState::new().hash_one(id) as usize % count;
+                let mut key = None;
+                for shard in (0..count).map(|offset| (start + offset) % count) {
+                    if let Some(CommandResponse::Get(Some(found))) = self.ask(shard, StoreCommand::RandomKey(ROUTER_ID)).await {
+                        key = Some(found);
+                        break
+                    }
+                }
+                self.respond(id, CommandResponse::Get(key)).await
+            }
+            StoreCommand::FlushDb(id) => {
+                // Only the first shard propagates it, once the rest are done
+                for shard in 1..self.shards.len() {
+                    self.request(shard, StoreCommand::Flush).await;
+                }
+                self.send(0, StoreCommand::FlushDb(id)).await;
+            }
+            cmd => {
+                let target = match keys_of(&cmd) {
+                    // Pub/sub and the like
+                    None => Ok(0),
+                    Some(keys) if keys.is_empty() => Ok(0),
+                    Some(keys) => match keys.iter().map(|key| self.shard_of(key)).unique().exactly_one() {
+                        Ok(shard) => Ok(shard),
+                        Err(_) => Err(keys.into_iter().map(<[u8]>::to_vec).collect::<Vec<_>>()),
+                    },
+                };
+                match target {
+                    Ok(shard) => self.send(shard, cmd).await,
+                    Err(keys) => self.across(cmd, keys).await,
+                }
+            }
+        }
+    }
+}
+
+async fn router_loop(shards: Vec<Sender<StoreCommand>>, gate: Arc<RwLock<()>>, mut rx: Receiver<StoreCommand>) {
+    let (tx, responses) = mpsc::channel(CMD_BUFFER);
+    let mut router = Router { shards, gate, exclusive: None, clients: vec![], responses, snapshot_changes: vec![] };
+    router.broadcast(|| StoreCommand::AddClient { id: ROUTER_ID, tx: tx.clone() }).await;
+    // Commands of the transaction being executed, if any
+    let mut transaction: Option<Receiver<StoreCommand>> = None;
+
+    loop {
+        let cmd = match &mut transaction {
+            Some(commands) => match commands.recv().await {
+                Some(cmd) => cmd,
+                None => {
+                    transaction = None;
+                    router.exclusive = None;
+                    continue
+                }
+            },
+            None => match rx.recv().await {
+                Some(cmd) => cmd,
+                None => break,
+            },
+        };
+
+        router.route(cmd, &mut transaction).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use crate::{shard::{shard_of, StoreHandle}, store::Store, types::RedisType};
+
+    #[test]
+    fn test_split() {
+        let mut store = Store::default();
+        for key in ["a", "b", "c", "d", "e", "f"] {
+            store.write(key.as_bytes(), RedisType::from(key), None);
+        }
+        let version = store.version(b"a");
+
+        let shards = store.split(3);
+        assert_eq!(shards.iter().map(Store::size).sum::<usize>(), 6);
+        for key in ["a", "b", "c", "d", "e", "f"] {
+            let shard = &shards[shard_of(key.as_bytes(), 3)];
+            assert!(shard.iter().any(|(found, _, _)| found == key.as_bytes()));
+        }
+        // Versions go along with the keys
+        assert_eq!(shards[shard_of(b"a", 3)].version(b"a"), version);
+    }
+
+    #[test]
+    fn test_by_shard() {
+        let (tx, _rx) = mpsc::channel(1);
+        let keys: [&[u8]; 4] = [b"a", b"b", b"c", b"a"];
+        assert_eq!(StoreHandle::new(tx.clone()).by_shard(&keys), vec![(0, vec![0, 1, 2, 3])]);
+
+        let sharded = StoreHandle { tx: tx.clone(), shards: vec![tx; 3], gate: None };
+        let groups = sharded.by_shard(&keys);
+        assert_eq!(groups.iter().map(|(_, indexes)| indexes.len()).sum::<usize>(), 4);
+        for (shard, indexes) in groups {
+            assert!(indexes.iter().all(|&index| shard_of(keys[index], 3) == shard));
+        }
+    }
+}
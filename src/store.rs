@@ -1,12 +1,28 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
+    hash::{BuildHasher, DefaultHasher, Hash, Hasher, RandomState},
     path::PathBuf,
-    time::{SystemTime, UNIX_EPOCH},
+    sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use tokio::sync::mpsc::{Sender, Receiver};
+use bytes::Bytes;
+use tokio::{
+    sync::{mpsc::{Sender, Receiver}, oneshot, watch},
+    time::{interval, MissedTickBehavior},
+};
 
-use crate::types::RedisType;
+use crate::{
+    bitfield::{self, BitfieldOp},
+    glob::glob_match,
+    info::PersistenceInfo,
+    notify::{EventClass, NotifyFlags},
+    rdb::RedisFileEntry,
+    pubsub::{Message, PubSub},
+    shard::{shard_of, StoreHandle},
+    types::RedisType,
+    zset::{format_score, Added, ScoreBound, SortedSet, ZAddOptions, ZRange},
+};
 
 pub const CMD_BUFFER: usize = 1024;
 
@@ -14,141 +30,3992 @@ pub enum CommandResponse {
     RdbFile(PathBuf),
     ClientId(usize),
     Get(Option<RedisType>),
+    Values(Vec<Option<RedisType>>),
     Keys(RedisType),
     ReplicaCount(usize),
+    Integer(i64),
+    WrongType,
+    Error(String),
+    Type(&'static str),
+    /// Internal encoding of a value, as reported by OBJECT ENCODING. `None` if
+    /// the key doesn't exist
+    Encoding(Option<&'static str>),
+    /// Confirmations for (UN)SUBSCRIBE, one per channel, and the number of
+    /// subscriptions the client is left with
+    Subscriptions { replies: Vec<RedisType>, count: usize },
+    /// Message published on a channel the client is subscribed to
+    Message(Message),
+    /// Current version of some keys, for WATCH
+    Versions(Vec<u64>),
+    /// Keys found by a step of SCAN, and the cursor for the next one
+    Scan { cursor: u64, keys: Vec<Vec<u8>> },
+}
+
+/// Only write if the key is missing (NX) or if it exists (XX)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetCondition {
+    IfMissing,
+    IfExists,
+}
+
+/// What happens to the TTL of a key when it's overwritten
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SetTtl {
+    #[default]
+    Discard,
+    Keep,
+    ExpireAt(SystemTime),
+}
+
+/// Options for SET
+#[derive(Debug, Default, PartialEq)]
+pub struct SetOptions {
+    pub condition: Option<SetCondition>,
+    pub get: bool,
+    pub ttl: SetTtl,
+}
+
+/// Flags of EXPIRE and friends: only change the TTL if the key has none (NX),
+/// if it has one (XX), or if the new one is greater (GT) or less (LT) than the
+/// current one. Keys without TTL count as having an infinite one
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ExpireCondition {
+    pub nx: bool,
+    pub xx: bool,
+    pub gt: bool,
+    pub lt: bool,
+}
+
+impl ExpireCondition {
+    fn allows(&self, current: Option<SystemTime>, until: SystemTime) -> bool {
+        match current {
+            Some(current) => !self.nx && (!self.gt || until > current) && (!self.lt || until < current),
+            None => !self.xx && !self.gt,
+        }
+    }
+}
+
+/// Options for LPOS. `rank` is never zero, and a `maxlen` of zero means no
+/// limit
+#[derive(Debug, PartialEq)]
+pub struct LPosOptions {
+    pub rank: i64,
+    pub count: Option<usize>,
+    pub maxlen: usize,
+}
+
+impl Default for LPosOptions {
+    fn default() -> Self {
+        LPosOptions { rank: 1, count: None, maxlen: 0 }
+    }
+}
+
+/// How SINTER, SUNION, SDIFF and their STORE variants combine sets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetOperation {
+    Intersection,
+    Union,
+    Difference,
+}
+
+/// Change to the TTL of a key
+pub enum TtlChange {
+    ExpireAt(SystemTime),
+    Persist,
 }
 
 pub enum StoreCommand {
     InitClient(Sender<CommandResponse>),
-    InitReplica(Sender<Vec<u8>>),
-    Set { key: String, value: RedisType },
-    SetEx { key: String, value: RedisType, until: SystemTime },
-    Get { id: usize, key: String },
-    AllKeys(usize),
+    /// New value of notify-keyspace-events
+    SetNotifyFlags(NotifyFlags),
+    /// Versions of some keys, to be checked on EXEC
+    GetVersions { id: usize, keys: Vec<Vec<u8>> },
+    /// EXEC. Answers with 1 if none of the `watched` keys changed from their
+    /// version, in which case the store serves only `commands` until the
+    /// client closes it, so that the transaction runs without interruptions.
+    /// Answers with 0 otherwise
+    Exec { id: usize, watched: Vec<(Vec<u8>, u64)>, commands: Receiver<StoreCommand> },
+    /// SUBSCRIBE, or PSUBSCRIBE with `pattern`. Messages are sent through `tx`
+    Subscribe { id: usize, names: Vec<Vec<u8>>, pattern: bool, tx: Sender<CommandResponse> },
+    /// UNSUBSCRIBE, or PUNSUBSCRIBE with `pattern`. Without names, from
+    /// everything
+    Unsubscribe { id: usize, names: Vec<Vec<u8>>, pattern: bool },
+    /// PUBSUB CHANNELS. Answers with the channels that have subscribers
+    ActiveChannels { id: usize, pattern: Option<Vec<u8>> },
+    /// PUBSUB NUMSUB. Answers with each channel and its number of subscribers
+    NumSub { id: usize, channels: Vec<Vec<u8>> },
+    /// PUBSUB NUMPAT
+    NumPat(usize),
+    /// Answers with the number of clients that got the message
+    Publish { id: usize, channel: Vec<u8>, message: Bytes },
+    /// Register a replica. The store answers with a snapshot of the current
+    /// data, taken right before any further command is replicated
+    InitReplica { tx: Sender<Bytes>, snapshot: oneshot::Sender<Vec<RedisFileEntry>> },
+    /// Copy of the current data, eg. to write it to disk, along with the number
+    /// of changes it includes
+    Snapshot(oneshot::Sender<(Vec<RedisFileEntry>, u64)>),
+    /// Result of saving a snapshot that included `changes` changes
+    Saved { changes: u64, ok: bool },
+    PersistenceInfo(oneshot::Sender<PersistenceInfo>),
+    Set { key: Vec<u8>, value: RedisType },
+    SetEx { key: Vec<u8>, value: RedisType, until: SystemTime },
+    /// Value of any type read from an RDB file. It isn't propagated: whoever
+    /// is connected to us gets the data when they sync
+    Load { key: Vec<u8>, value: RedisType, expires: Option<SystemTime> },
+    /// SET with all its options. Answers with the previous value if `options.get`
+    /// is set, or with whether the value was written otherwise
+    SetWith { id: usize, key: Vec<u8>, value: RedisType, options: SetOptions },
+    Get { id: usize, key: Vec<u8> },
+    MSet { pairs: Vec<(Vec<u8>, RedisType)> },
+    MGet { id: usize, keys: Vec<Vec<u8>> },
+    /// MGET for the keys of a single shard. It answers through `tx`, so that
+    /// the parts sent to several shards can be told apart
+    MGetPart { keys: Vec<Vec<u8>>, tx: oneshot::Sender<Vec<Option<RedisType>>> },
+    GetEx { id: usize, key: Vec<u8>, ttl: Option<TtlChange> },
+    Expire { id: usize, key: Vec<u8>, until: SystemTime, condition: ExpireCondition },
+    Persist { id: usize, key: Vec<u8> },
+    ExpireTime { id: usize, key: Vec<u8> },
+    SetRange { id: usize, key: Vec<u8>, offset: usize, value: Vec<u8> },
+    Append { id: usize, key: Vec<u8>, value: Vec<u8> },
+    /// LPUSH (`left`) and RPUSH, or LPUSHX and RPUSHX if `must_exist` is set.
+    /// Answers with the length of the list
+    Push { id: usize, key: Vec<u8>, values: Vec<Vec<u8>>, left: bool, must_exist: bool },
+    /// LPOP (`left`) and RPOP. Without `count` answers with a single element,
+    /// with it, with a list of up to `count` elements
+    Pop { id: usize, key: Vec<u8>, count: Option<usize>, left: bool },
+    /// LMOVE and RPOPLPUSH: pop from the head (`from_left`) or the tail of
+    /// `source`, and push to the head (`to_left`) or the tail of `destination`.
+    /// Answers with the element moved
+    LMove { id: usize, source: Vec<u8>, destination: Vec<u8>, from_left: bool, to_left: bool },
+    /// BLPOP (`left`) and BRPOP. The answer is sent as soon as any of the
+    /// lists has elements, or right away if it's not a list
+    BlockPop { keys: Vec<Vec<u8>>, left: bool, tx: oneshot::Sender<CommandResponse> },
+    LRange { id: usize, key: Vec<u8>, start: i64, stop: i64 },
+    /// Answers with the number of fields added
+    HSet { id: usize, key: Vec<u8>, pairs: Vec<(Vec<u8>, Vec<u8>)> },
+    HGet { id: usize, key: Vec<u8>, field: Vec<u8> },
+    HMGet { id: usize, key: Vec<u8>, fields: Vec<Vec<u8>> },
+    /// Answers with whether the field was set
+    HSetNx { id: usize, key: Vec<u8>, field: Vec<u8>, value: Vec<u8> },
+    /// HINCRBY. Answers with the new value
+    HIncrBy { id: usize, key: Vec<u8>, field: Vec<u8>, delta: i64 },
+    /// HINCRBYFLOAT. Answers with the new value, as a string
+    HIncrByFloat { id: usize, key: Vec<u8>, field: Vec<u8>, delta: f64 },
+    /// Answers with the number of fields removed
+    HDel { id: usize, key: Vec<u8>, fields: Vec<Vec<u8>> },
+    HExists { id: usize, key: Vec<u8>, field: Vec<u8> },
+    HLen { id: usize, key: Vec<u8> },
+    HStrLen { id: usize, key: Vec<u8>, field: Vec<u8> },
+    /// Answers with the whole hash. Missing keys are empty hashes
+    HGetAll { id: usize, key: Vec<u8> },
+    /// Answers with the number of members added
+    SAdd { id: usize, key: Vec<u8>, members: Vec<Vec<u8>> },
+    /// Answers with the number of members removed
+    SRem { id: usize, key: Vec<u8>, members: Vec<Vec<u8>> },
+    /// Answers with the whole set. Missing keys are empty sets
+    SMembers { id: usize, key: Vec<u8> },
+    /// SISMEMBER and SMISMEMBER. Answers with whether each member is in the set
+    SIsMember { id: usize, key: Vec<u8>, members: Vec<Vec<u8>> },
+    SCard { id: usize, key: Vec<u8> },
+    /// SINTER, SUNION and SDIFF. Answers with the resulting set
+    Combine { id: usize, keys: Vec<Vec<u8>>, operation: SetOperation },
+    /// Answers with the number of members added (or changed, with CH), or
+    /// with the new score with INCR
+    ZAdd { id: usize, key: Vec<u8>, options: ZAddOptions, pairs: Vec<(f64, Vec<u8>)> },
+    ZScore { id: usize, key: Vec<u8>, member: Vec<u8> },
+    /// Answers with the position of the member, counting from the highest
+    /// score with `rev`
+    ZRank { id: usize, key: Vec<u8>, member: Vec<u8>, rev: bool },
+    ZCard { id: usize, key: Vec<u8> },
+    ZRem { id: usize, key: Vec<u8>, members: Vec<Vec<u8>> },
+    ZCount { id: usize, key: Vec<u8>, min: ScoreBound, max: ScoreBound },
+    /// Answers with the popped members and their scores
+    ZPop { id: usize, key: Vec<u8>, count: usize, max: bool },
+    /// Answers with the members in the range, and their scores if requested
+    ZRange { id: usize, key: Vec<u8>, range: ZRange },
+    /// Answers with whether the member was moved
+    SMove { id: usize, source: Vec<u8>, destination: Vec<u8>, member: Vec<u8> },
+    /// Answers with the size of the intersection, counting up to `limit`
+    /// members unless it's 0
+    InterCard { id: usize, keys: Vec<Vec<u8>>, limit: usize },
+    /// Same as `InterCard`, for sorted sets
+    ZInterCard { id: usize, keys: Vec<Vec<u8>>, limit: usize },
+    /// SINTERSTORE, SUNIONSTORE and SDIFFSTORE. Answers with the size of the
+    /// resulting set
+    CombineStore { id: usize, destination: Vec<u8>, keys: Vec<Vec<u8>>, operation: SetOperation },
+    LLen { id: usize, key: Vec<u8> },
+    LPos { id: usize, key: Vec<u8>, element: Vec<u8>, options: LPosOptions },
+    LIndex { id: usize, key: Vec<u8>, index: i64 },
+    LSet { id: usize, key: Vec<u8>, index: i64, value: Vec<u8> },
+    /// Answers with the new length, 0 if the key is missing or -1 if `pivot`
+    /// wasn't found
+    LInsert { id: usize, key: Vec<u8>, before: bool, pivot: Vec<u8>, value: Vec<u8> },
+    /// Answers with the number of elements removed
+    LRem { id: usize, key: Vec<u8>, count: i64, value: Vec<u8> },
+    BitField { id: usize, key: Vec<u8>, ops: Vec<BitfieldOp> },
+    IncrBy { id: usize, key: Vec<u8>, delta: i64 },
+    GetSet { id: usize, key: Vec<u8>, value: RedisType },
+    SetNx { id: usize, key: Vec<u8>, value: RedisType },
+    Type { id: usize, key: Vec<u8> },
+    Copy { id: usize, source: Vec<u8>, destination: Vec<u8>, replace: bool },
+    /// DEL and UNLINK. Answers with the number of keys removed
+    Delete { id: usize, keys: Vec<Vec<u8>> },
+    /// EXISTS and TOUCH. Answers with the number of keys found
+    Exists { id: usize, keys: Vec<Vec<u8>> },
+    /// RENAME, or RENAMENX when `nx` is set
+    Rename { id: usize, key: Vec<u8>, new_key: Vec<u8>, nx: bool },
+    ObjectEncoding { id: usize, key: Vec<u8> },
+    /// Send a command to the replicas, without running it (DEBUG REPLICATE)
+    Replicate(RedisType),
+    /// Size above which list elements are not packed (DEBUG QUICKLIST-PACKED-THRESHOLD)
+    SetPackedThreshold(usize),
+    RandomKey(usize),
+    DbSize(usize),
+    FlushDb(usize),
+    /// Keys that haven't expired matching a glob-style pattern (KEYS)
+    Keys { id: usize, pattern: Vec<u8> },
+    /// One step of SCAN, optionally keeping only the keys that match a
+    /// pattern or hold a type of value
+    Scan { id: usize, cursor: u64, count: usize, pattern: Option<Vec<u8>>, kind: Option<String> },
     ReplicaCount(usize),
+    /// Register a client with the id given by the router of a sharded store
+    AddClient { id: usize, tx: Sender<CommandResponse> },
+    /// Take some keys out of this shard, to move them to another one
+    Lend { keys: Vec<Vec<u8>>, tx: oneshot::Sender<Vec<KeyState>> },
+    /// Take in keys lent by another shard
+    Adopt(Vec<KeyState>),
+    /// Answers once every command sent before has been served
+    Sync(oneshot::Sender<()>),
+    /// Remove every key, without propagating it. Answers once done
+    Flush(oneshot::Sender<()>),
+    /// Keyspace notifications raised by another shard, to be published here
+    Notify(Vec<(Vec<u8>, Vec<u8>)>),
+    /// BlockPop for some of the keys, with a `waiter` that other shards may
+    /// serve as well. Only the first one to pop something gets to answer
+    BlockPopShared { keys: Vec<Vec<u8>>, left: bool, waiter: Waiter },
+    /// Remove some of the keys that expired without anyone noticing
+    ExpireCycle,
+}
+
+/// Where the answer to BLPOP or BRPOP goes. It can be shared by several
+/// shards: whoever takes the sender out is the one that answers
+pub type Waiter = Arc<Mutex<Option<oneshot::Sender<CommandResponse>>>>;
+
+/// Everything the store keeps about a key, to move it to another shard
+pub struct KeyState {
+    key: Vec<u8>,
+    value: Option<StoreValue>,
+    raw: bool,
+    version: Option<u64>,
+}
+
+impl KeyState {
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
 }
 
+#[derive(Clone)]
 enum StoreValue {
     Permanent(RedisType),
     Expirable { value: RedisType, until: SystemTime },
 }
 
+impl StoreValue {
+    fn value(&self) -> &RedisType {
+        match self {
+            StoreValue::Permanent(value) => value,
+            StoreValue::Expirable { value, .. } => value,
+        }
+    }
+
+    fn value_mut(&mut self) -> &mut RedisType {
+        match self {
+            StoreValue::Permanent(value) => value,
+            StoreValue::Expirable { value, .. } => value,
+        }
+    }
+
+    fn expires(&self) -> Option<SystemTime> {
+        match self {
+            StoreValue::Permanent(_) => None,
+            StoreValue::Expirable { until, .. } => Some(*until),
+        }
+    }
+
+    fn set_expiry(&mut self, expiry: Option<SystemTime>) {
+        let value = match self {
+            StoreValue::Permanent(value) => std::mem::replace(value, RedisType::Null),
+            StoreValue::Expirable { value, .. } => std::mem::replace(value, RedisType::Null),
+        };
+
+        *self = match expiry {
+            Some(until) => StoreValue::Expirable { value, until },
+            None => StoreValue::Permanent(value),
+        };
+    }
+}
+
+/// Keys with a TTL, for the active expiry to pick from at random without
+/// going through the whole keyspace
 #[derive(Default)]
+struct Volatile {
+    keys: Vec<Vec<u8>>,
+    // Where each key is in `keys`
+    positions: HashMap<Vec<u8>, usize>,
+}
+
+impl Volatile {
+    fn insert(&mut self, key: &[u8]) {
+        if !self.positions.contains_key(key) {
+            self.positions.insert(key.to_vec(), self.keys.len());
+            self.keys.push(key.to_vec());
+        }
+    }
+
+    /// Take a key out, putting the last one in its place
+    fn remove(&mut self, key: &[u8]) {
+        let Some(position) = self.positions.remove(key) else {
+            return
+        };
+        self.keys.swap_remove(position);
+        if let Some(moved) = self.keys.get(position) {
+            self.positions.insert(moved.clone(), position);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.keys.clear();
+        self.positions.clear();
+    }
+
+    fn len(&self) -> usize {
+        self.keys.len()
+    }
+}
+
+// Same default as Redis: 1GB
+const DEFAULT_PACKED_THRESHOLD: usize = 1 << 30;
+
+// Same default as Redis (list-max-listpack-size -2): lists up to 8KB are
+// kept in a single listpack
+const LISTPACK_SIZE_LIMIT: usize = 8 * 1024;
+
+// Strings up to this size are allocated along with their object header
+const EMBSTR_SIZE_LIMIT: usize = 44;
+
+// The active expiry runs 10 times per second, as with the default hz in Redis.
+// Each round looks at this many keys with a TTL, and there's another round
+// if more than a quarter of them had expired, for as long as there's time
+pub const ACTIVE_EXPIRE_PERIOD: Duration = Duration::from_millis(100);
+const ACTIVE_EXPIRE_SAMPLE: usize = 20;
+const ACTIVE_EXPIRE_BUDGET: Duration = Duration::from_millis(25);
+
+/// Whether Redis would store the string as an integer: it must read back
+/// exactly the same, so "+1", "007" or " 7" don't count
+fn is_integer_string(bytes: &[u8]) -> bool {
+    bytes.len() <= 20 && std::str::from_utf8(bytes).ok()
+        .and_then(|string| string.parse::<i64>().ok())
+        .is_some_and(|number| number.to_string().as_bytes() == bytes)
+}
+
+/// Resolve the inclusive `start`..`stop` range used by LRANGE into valid
+/// indices for a list of `length` elements. Unlike GETRANGE, a `stop` before
+/// the head of the list means an empty range. Returns `None` when the range
+/// is empty
+pub(crate) fn list_range(start: i64, stop: i64, length: usize) -> Option<(usize, usize)> {
+    let length = length as i64;
+    let start = if start < 0 { length + start } else { start }.max(0);
+    let stop = if stop < 0 { length + stop } else { stop };
+
+    if start > stop || start >= length {
+        None
+    } else {
+        Some((start as usize, stop.min(length - 1) as usize))
+    }
+}
+
+/// Position of the element at `index` in a list of `length` elements, if
+/// there's one. Negative indices count from the tail
+fn list_index(index: i64, length: usize) -> Option<usize> {
+    let index = if index < 0 { length as i64 + index } else { index };
+    (0..length as i64).contains(&index).then_some(index as usize)
+}
+
 pub struct Store {
-    data: HashMap<String, StoreValue>,
+    data: HashMap<Vec<u8>, StoreValue>,
+    // The keys in `data` that have a TTL
+    volatile: Volatile,
+    // Strings modified in place (APPEND, SETRANGE). Redis keeps those as "raw",
+    // no matter their content
+    raw: HashSet<Vec<u8>>,
+    // List elements bigger than this get a quicklist node of their own
+    packed_threshold: usize,
+    // Number of changes since the last save
+    dirty: u64,
+    last_save: SystemTime,
+    last_save_ok: bool,
+    // Keyspace notifications: which ones are enabled, and the ones waiting
+    // to be published, as (channel, message)
+    notify_flags: NotifyFlags,
+    notifications: Vec<(Vec<u8>, Vec<u8>)>,
+    // Version of each key that has ever changed, for WATCH. Taken from a
+    // counter that only goes up, so that a key that is removed and written
+    // again doesn't get an old version back
+    versions: HashMap<Vec<u8>, u64>,
+    // Shared by all the shards, if the store is split
+    last_version: Arc<AtomicU64>,
+}
+
+/// Name of a type of value, as reported by TYPE
+fn type_name(value: &RedisType) -> &'static str {
+    match value {
+        RedisType::String(_) | RedisType::Int(_) => "string",
+        RedisType::Array(_) | RedisType::List(_) => "list",
+        RedisType::Map(_) | RedisType::Hash(_) => "hash",
+        RedisType::Set(_) => "set",
+        RedisType::ZSet(_) => "zset",
+        RedisType::Timestamp(_)
+        | RedisType::Push(_)
+        | RedisType::Verbatim { .. }
+        | RedisType::Double(_)
+        | RedisType::Bool(_)
+        | RedisType::Null => "none",
+    }
+}
+
+/// Position of a key in the order followed by SCAN. It only depends on the
+/// key, so that it doesn't change as other keys come and go, and so that all
+/// the shards of a split store agree on it
+pub fn scan_position(key: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Store {
+            data: HashMap::new(),
+            volatile: Volatile::default(),
+            raw: HashSet::new(),
+            packed_threshold: DEFAULT_PACKED_THRESHOLD,
+            dirty: 0,
+            last_save: SystemTime::now(),
+            last_save_ok: true,
+            notify_flags: NotifyFlags::default(),
+            notifications: vec![],
+            versions: HashMap::new(),
+            last_version: Arc::new(AtomicU64::new(0)),
+        }
+    }
 }
 
 impl Store {
-    pub fn write(&mut self, key: &str, value: RedisType, maybe_until: Option<SystemTime>) {
+    pub fn write(&mut self, key: &[u8], value: RedisType, maybe_until: Option<SystemTime>) {
         let store_val = match maybe_until {
             Some(until) => StoreValue::Expirable { value, until },
             None        => StoreValue::Permanent(value),
         };
 
-        self.data.insert(key.to_string(), store_val);
+        self.raw.remove(key);
+        self.insert_entry(key, store_val);
+        self.dirty += 1;
+        self.touch(key);
     }
 
-    pub fn read(&mut self, key: &str) -> Option<RedisType> {
-        if let Some(val) = self.data.get(key) {
-            match val {
-                StoreValue::Permanent(value) => Some(value.clone()),
-                StoreValue::Expirable { value, until } => {
-                    if SystemTime::now() < *until {
-                        Some(value.clone())
-                    } else {
-                        self.data.remove(key);
-                        None
-                    }
-                }
+    /// Put in an entry, keeping track of whether it has a TTL
+    fn insert_entry(&mut self, key: &[u8], value: StoreValue) {
+        self.track_expiry(key, value.expires());
+        self.data.insert(key.to_vec(), value);
+    }
+
+    fn remove_entry(&mut self, key: &[u8]) -> Option<StoreValue> {
+        self.volatile.remove(key);
+        self.data.remove(key)
+    }
+
+    /// Keep the index of keys with a TTL up to date after changing the
+    /// expiration time of a key in place
+    fn track_expiry(&mut self, key: &[u8], expiry: Option<SystemTime>) {
+        match expiry {
+            Some(_) => self.volatile.insert(key),
+            None => self.volatile.remove(key),
+        }
+    }
+
+    /// Give a key a new version, which makes the transactions watching it fail
+    fn touch(&mut self, key: &[u8]) {
+        let version = self.last_version.fetch_add(1, Ordering::Relaxed) + 1;
+        self.versions.insert(key.to_vec(), version);
+    }
+
+    /// Version of a key, as it was last touched. 0 for keys that never changed
+    pub fn version(&self, key: &[u8]) -> u64 {
+        self.versions.get(key).copied().unwrap_or(0)
+    }
+
+    /// Take a key out, along with its TTL and version, to move it elsewhere
+    pub fn take_key(&mut self, key: &[u8]) -> KeyState {
+        KeyState {
+            key: key.to_vec(),
+            value: self.remove_entry(key),
+            raw: self.raw.remove(key),
+            version: self.versions.remove(key),
+        }
+    }
+
+    /// Put in a key taken with `take_key`, as it was. Doesn't count as a change
+    pub fn put_key(&mut self, state: KeyState) {
+        let KeyState { key, value, raw, version } = state;
+        self.raw.remove(&key);
+        if raw {
+            self.raw.insert(key.clone());
+        }
+        match version {
+            Some(version) => self.versions.insert(key.clone(), version),
+            None => self.versions.remove(&key),
+        };
+        match value {
+            Some(value) => self.insert_entry(&key, value),
+            None => { self.remove_entry(&key); }
+        }
+    }
+
+    /// Split the keys into `count` stores, to be served by separate tasks.
+    /// Keys go where `shard_of` says. The settings are the same for all of
+    /// them, and the first one gets the count of unsaved changes
+    pub fn split(mut self, count: usize) -> Vec<Store> {
+        let mut shards = (0..count)
+            .map(|_| Store {
+                packed_threshold: self.packed_threshold,
+                last_save: self.last_save,
+                last_save_ok: self.last_save_ok,
+                notify_flags: self.notify_flags,
+                last_version: self.last_version.clone(),
+                ..Store::default()
+            })
+            .collect::<Vec<_>>();
+        shards[0].dirty = self.dirty;
+
+        let keys = self.data.keys().chain(self.versions.keys()).cloned().collect::<HashSet<_>>();
+        for key in keys {
+            let state = self.take_key(&key);
+            shards[shard_of(&key, count)].put_key(state);
+        }
+        shards
+    }
+
+    pub fn set_notify_flags(&mut self, flags: NotifyFlags) {
+        self.notify_flags = flags;
+    }
+
+    /// Record a change to a key: it gets a new version, and a keyspace
+    /// notification is queued if its class is enabled
+    fn notify(&mut self, class: EventClass, event: &str, key: &[u8]) {
+        self.touch(key);
+        if self.notify_flags.enabled(class) {
+            self.notifications.extend(self.notify_flags.channels(event, key));
+        }
+    }
+
+    /// Keyspace notifications queued since the last call, as (channel, message)
+    pub fn take_notifications(&mut self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        std::mem::take(&mut self.notifications)
+    }
+
+    /// Remove a collection that was left without elements
+    fn remove_emptied(&mut self, key: &[u8]) {
+        self.remove_entry(key);
+        self.notify(EventClass::Generic, "del", key);
+    }
+
+    /// Replace the value of a key, keeping its TTL. Missing keys are created
+    fn overwrite(&mut self, key: &[u8], value: RedisType) {
+        match self.get_live(key) {
+            Some(entry) => {
+                *entry.value_mut() = value;
+                self.dirty += 1;
+                self.touch(key);
             }
-        } else {
-            None
+            None => self.write(key, value, None),
         }
     }
-}
 
-async fn replicate(replicas: &[Sender<Vec<u8>>], payload: RedisType) {
-    let as_vec = payload.to_vec();
+    /// Consider the current data saved, eg. right after loading it from disk
+    pub fn mark_saved(&mut self) {
+        self.saved(self.dirty, true);
+    }
 
-    for replica in replicas {
-        replica.send(as_vec.clone()).await.unwrap();
+    /// Record the result of saving a snapshot that included `changes` changes.
+    /// Whatever changed after the snapshot still counts as unsaved
+    fn saved(&mut self, changes: u64, ok: bool) {
+        if ok {
+            self.dirty = self.dirty.saturating_sub(changes);
+            self.last_save = SystemTime::now();
+        }
+        self.last_save_ok = ok;
     }
-}
 
-pub async fn store_loop(mut store: Store, mut rx: Receiver<StoreCommand>) {
-    // Naive implementation. Clients and replicas might
-    // close their connection, which will result on the channel
-    // being dropped. We should use a different structure and
-    // sends should not blindly be accepted as OK
-    let mut clients: Vec<Sender<CommandResponse>> = Vec::new();
-    let mut replicas: Vec<Sender<Vec<u8>>> = Vec::new();
+    pub fn persistence_info(&self) -> PersistenceInfo {
+        PersistenceInfo {
+            changes_since_save: self.dirty,
+            last_save: self.last_save,
+            last_save_ok: self.last_save_ok,
+        }
+    }
 
-    loop {
-        if let Some(cmd) = rx.recv().await {
-            match cmd {
-                StoreCommand::InitClient(tx) => {
-                    let id = clients.len();
-                    clients.push(tx.clone());
-                    tx.send(CommandResponse::ClientId(id)).await.unwrap();
-                }
-                StoreCommand::InitReplica(tx) => replicas.push(tx),
-                StoreCommand::Set { key, value } => {
-                    if !replicas.is_empty() {
-                        match &value {
-                            RedisType::String(string) => {
-                                let val = RedisType::Array(vec![
-                                    RedisType::from("SET"),
-                                    RedisType::from(key.clone()),
-                                    RedisType::from(string.clone()),
-                                ]);
-                                replicate(replicas.as_slice(), val).await;
-                            }
-                            _ => panic!("SET accepted a value that is not a string!")
-                        }
-                    }
-                    store.write(&key, value, None);
-                }
-                StoreCommand::SetEx { key, value, until } => {
-                    if !replicas.is_empty() {
-                        match &value {
-                            RedisType::String(string) => {
-                                let pxat = until.duration_since(UNIX_EPOCH)
-                                                      .unwrap()
-                                                      .as_millis();
-                                let val = RedisType::Array(vec![
-                                    RedisType::from("SET"),
-                                    RedisType::from(key.clone()),
-                                    RedisType::from(string.clone()),
-                                    RedisType::from("PXAT"),
-                                    RedisType::Timestamp(pxat),
-                                ]);
-
-                                replicate(
-                                    replicas.as_slice(),
-                                    val
-                                    ).await;
-                            }
-                            _ => panic!("SET accepted a value that is not a string!")
-                        }
-                    }
-                    store.write(&key, value, Some(until));
-                }
-                StoreCommand::Get { id, key } => {
-                    clients[id].send(CommandResponse::Get(store.read(&key))).await.unwrap()
-                }
-                StoreCommand::AllKeys(id) => {
-                    let keys = store.data
-                        .keys()
-                        .map(|s| RedisType::from(s.as_str()))
-                        .collect::<Vec<_>>();
-                    clients[id].send(CommandResponse::Keys(RedisType::Array(keys))).await.unwrap()
+    /// Every key, along with its value and expiration time, in no particular
+    /// order. Iterating doesn't trigger the lazy expiry: keys that expired but
+    /// haven't been accessed since are still there, so check the expiration
+    /// time if that matters
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &RedisType, Option<SystemTime>)> {
+        self.data.iter().map(|(key, value)| (key.as_slice(), value.value(), value.expires()))
+    }
+
+    /// Copy of all the data that hasn't expired yet
+    pub fn snapshot(&self) -> Vec<RedisFileEntry> {
+        let now = SystemTime::now();
+
+        self.data.iter()
+            .filter_map(|(key, value)| match value {
+                StoreValue::Permanent(value) => Some(RedisFileEntry {
+                    key: key.clone(),
+                    value: value.clone(),
+                    expires: None,
+                }),
+                StoreValue::Expirable { value, until } if *until > now => Some(RedisFileEntry {
+                    key: key.clone(),
+                    value: value.clone(),
+                    expires: Some(*until),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Entry for `key`, unless it's missing or expired. Expired entries are removed
+    fn get_live(&mut self, key: &[u8]) -> Option<&mut StoreValue> {
+        let expired = matches!(
+            self.data.get(key),
+            Some(StoreValue::Expirable { until, .. }) if SystemTime::now() >= *until
+        );
+
+        if expired {
+            self.remove_expired(key);
+            None
+        } else {
+            self.data.get_mut(key)
+        }
+    }
+
+    fn remove_expired(&mut self, key: &[u8]) {
+        self.remove_entry(key);
+        self.raw.remove(key);
+        self.notify(EventClass::Expired, "expired", key);
+    }
+
+    /// One round of the active expiry: look at some of the keys with a TTL,
+    /// picked at random, and remove those that expired. Returns how many were
+    /// looked at, and the ones removed
+    fn expire_round(&mut self) -> (usize, Vec<Vec<u8>>) {
+        let now = SystemTime::now();
+        let total = self.volatile.len();
+        let positions = if total <= ACTIVE_EXPIRE_SAMPLE {
+            (0..total).collect::<Vec<_>>()
+        } else {
+            let random = RandomState::new();
+            let mut positions = Vec::with_capacity(ACTIVE_EXPIRE_SAMPLE);
+            let mut round: u64 = 0;
+            while positions.len() < ACTIVE_EXPIRE_SAMPLE {
+                let position = random.hash_one(round) as usize % total;
+                if !positions.contains(&position) {
+                    positions.push(position);
                 }
-                StoreCommand::ReplicaCount(id) => {
-                    // TODO: The replica count is very naive because at the moment we're not doing
-                    //       anything about disconnected clients.
-                    clients[id].send(CommandResponse::ReplicaCount(replicas.len())).await.unwrap()
+                round += 1;
+            }
+            positions
+        };
+
+        let sampled = positions.len();
+        let expired = positions.into_iter()
+            .map(|position| &self.volatile.keys[position])
+            .filter(|&key| self.data.get(key).and_then(StoreValue::expires).is_some_and(|until| now >= until))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for key in &expired {
+            self.remove_expired(key);
+        }
+        (sampled, expired)
+    }
+
+    /// Remove keys that expired but haven't been accessed since, the way
+    /// Redis does in the background: in rounds of a few keys, while they
+    /// keep finding enough of them expired. Returns the keys removed
+    pub fn active_expire(&mut self) -> Vec<Vec<u8>> {
+        let start = Instant::now();
+        let mut removed = vec![];
+
+        loop {
+            let (sampled, expired) = self.expire_round();
+            let more = expired.len() * 4 > sampled;
+            removed.extend(expired);
+            if !more || start.elapsed() >= ACTIVE_EXPIRE_BUDGET {
+                break
+            }
+        }
+        removed
+    }
+
+    /// Set the expiration time of an existing key, if the condition allows it.
+    /// Returns whether the TTL was changed
+    pub fn expire(&mut self, key: &[u8], until: SystemTime, condition: ExpireCondition) -> bool {
+        let Some(entry) = self.get_live(key) else {
+            return false
+        };
+
+        let allowed = condition.allows(entry.expires(), until);
+        if allowed {
+            entry.set_expiry(Some(until));
+            self.track_expiry(key, Some(until));
+            self.dirty += 1;
+            self.notify(EventClass::Generic, "expire", key);
+        }
+        allowed
+    }
+
+    /// Absolute expiration time of a key, in unix milliseconds. -1 if the key
+    /// has no TTL, -2 if it doesn't exist
+    pub fn expire_time(&mut self, key: &[u8]) -> i64 {
+        match self.get_live(key) {
+            Some(entry) => match entry.expires() {
+                Some(until) => until.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64,
+                None => -1,
+            },
+            None => -2,
+        }
+    }
+
+    /// Make a key permanent. Returns whether there was a TTL to remove
+    pub fn persist(&mut self, key: &[u8]) -> bool {
+        match self.get_live(key) {
+            Some(entry) if entry.expires().is_some() => {
+                entry.set_expiry(None);
+                self.track_expiry(key, None);
+                self.dirty += 1;
+                self.notify(EventClass::Generic, "persist", key);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Read a string value, optionally changing its TTL. The second element is
+    /// the command to propagate to the replicas, if the TTL changed
+    fn get_ex(&mut self, key: &[u8], ttl: Option<TtlChange>) -> (CommandResponse, Option<RedisType>) {
+        let Some(entry) = self.get_live(key) else {
+            return (CommandResponse::Get(None), None)
+        };
+        if !entry.value().is_string() {
+            return (CommandResponse::WrongType, None)
+        }
+
+        let value = entry.value().clone();
+        let propagate = match ttl {
+            Some(TtlChange::ExpireAt(until)) => {
+                entry.set_expiry(Some(until));
+                Some(pexpireat_command(key, until))
+            }
+            Some(TtlChange::Persist) if entry.expires().is_some() => {
+                entry.set_expiry(None);
+                Some(command(&[b"PERSIST", key]))
+            }
+            _ => None,
+        };
+        if propagate.is_some() {
+            if let Some(entry) = self.data.get(key) {
+                self.track_expiry(key, entry.expires());
+            }
+            self.dirty += 1;
+            self.touch(key);
+        }
+
+        (CommandResponse::Get(Some(value)), propagate)
+    }
+
+    /// Overwrite part of a string value, starting at `offset` and padding with
+    /// zeros if needed. Missing keys are treated as empty strings. Answers with
+    /// the resulting length
+    pub fn set_range(&mut self, key: &[u8], offset: usize, value: &[u8]) -> CommandResponse {
+        let mut bytes = match self.get_live(key) {
+            Some(entry) => match entry.value().string_bytes() {
+                Some(bytes) => bytes,
+                None => return CommandResponse::WrongType,
+            },
+            None => vec![],
+        };
+        // An empty value doesn't modify the string, nor creates the key
+        if value.is_empty() {
+            return CommandResponse::Integer(bytes.len() as i64)
+        }
+
+        let end = offset + value.len();
+        if bytes.len() < end {
+            bytes.resize(end, 0);
+        }
+        bytes[offset..end].copy_from_slice(value);
+        let length = bytes.len();
+
+        self.overwrite(key, RedisType::String(bytes));
+        self.raw.insert(key.to_vec());
+        self.notify(EventClass::String, "setrange", key);
+
+        CommandResponse::Integer(length as i64)
+    }
+
+    /// Add `value` at the end of a string, creating it if needed. Answers with
+    /// the new length
+    pub fn append(&mut self, key: &[u8], value: &[u8]) -> CommandResponse {
+        let mut bytes = match self.get_live(key) {
+            Some(entry) => match entry.value().string_bytes() {
+                Some(bytes) => bytes,
+                None => return CommandResponse::WrongType,
+            },
+            None => vec![],
+        };
+        bytes.extend_from_slice(value);
+        let length = bytes.len();
+
+        self.overwrite(key, RedisType::String(bytes));
+        self.raw.insert(key.to_vec());
+        self.notify(EventClass::String, "append", key);
+
+        CommandResponse::Integer(length as i64)
+    }
+
+    /// Add values at the head (`left`) or the tail of a list, creating it if
+    /// needed, unless `must_exist` is set. Values pushed to the head end up in
+    /// reverse order, like in Redis
+    pub fn push(&mut self, key: &[u8], values: Vec<Vec<u8>>, left: bool, must_exist: bool) -> CommandResponse {
+        if self.get_live(key).is_none() {
+            if must_exist {
+                return CommandResponse::Integer(0)
+            }
+            self.insert_entry(key, StoreValue::Permanent(RedisType::List(VecDeque::new())));
+        }
+        let Some(RedisType::List(list)) = self.get_live(key).map(StoreValue::value_mut) else {
+            return CommandResponse::WrongType
+        };
+
+        let pushed = values.len() as u64;
+        for value in values {
+            if left {
+                list.push_front(value);
+            } else {
+                list.push_back(value);
+            }
+        }
+        let length = list.len();
+        self.dirty += pushed;
+        self.notify(EventClass::List, if left { "lpush" } else { "rpush" }, key);
+
+        CommandResponse::Integer(length as i64)
+    }
+
+    /// Take elements from the head (`left`) or the tail of a list. The key is
+    /// removed along with its last element
+    pub fn pop(&mut self, key: &[u8], count: Option<usize>, left: bool) -> (CommandResponse, Option<RedisType>) {
+        let list = match self.get_live(key).map(StoreValue::value_mut) {
+            Some(RedisType::List(list)) => list,
+            Some(_) => return (CommandResponse::WrongType, None),
+            None => return (CommandResponse::Get(None), None),
+        };
+
+        let taken = count.unwrap_or(1).min(list.len());
+        let popped = (0..taken)
+            .filter_map(|_| if left { list.pop_front() } else { list.pop_back() })
+            .collect::<VecDeque<_>>();
+        let empty = list.is_empty();
+        if !popped.is_empty() {
+            self.notify(EventClass::List, if left { "lpop" } else { "rpop" }, key);
+        }
+        if empty {
+            self.remove_emptied(key);
+        }
+        if popped.is_empty() {
+            return (CommandResponse::Get(Some(RedisType::List(popped))), None)
+        }
+        self.dirty += taken as u64;
+
+        let mut cmd = vec![RedisType::from(if left { "LPOP" } else { "RPOP" }), RedisType::from(key)];
+        let response = match count {
+            Some(_) => {
+                cmd.push(RedisType::from(taken.to_string()));
+                RedisType::List(popped)
+            }
+            None => RedisType::String(popped.into_iter().next().unwrap()),
+        };
+
+        (CommandResponse::Get(Some(response)), Some(RedisType::Array(cmd)))
+    }
+
+    /// Pop an element from the first of `keys` that isn't empty. Answers with
+    /// the key and the element
+    pub fn pop_first(&mut self, keys: &[Vec<u8>], left: bool) -> (CommandResponse, Option<RedisType>) {
+        for key in keys {
+            match self.pop(key, None, left) {
+                (CommandResponse::Get(Some(element)), replicated) => {
+                    let pair = RedisType::Array(vec![RedisType::from(key.as_slice()), element]);
+                    return (CommandResponse::Get(Some(pair)), replicated)
                 }
+                (CommandResponse::Get(None), _) => {}
+                other => return other,
+            }
+        }
+
+        (CommandResponse::Get(None), None)
+    }
+
+    /// Move an element from one end of `source` to one end of `destination`,
+    /// which can be the same list
+    pub fn lmove(&mut self, source: &[u8], destination: &[u8], from_left: bool, to_left: bool) -> CommandResponse {
+        if self.get_live(destination).is_some_and(|entry| !matches!(entry.value(), RedisType::List(_))) {
+            return CommandResponse::WrongType
+        }
+
+        match self.pop(source, None, from_left).0 {
+            CommandResponse::Get(Some(RedisType::String(element))) => {
+                self.push(destination, vec![element.clone()], to_left, false);
+                CommandResponse::Get(Some(RedisType::String(element)))
+            }
+            other => other,
+        }
+    }
+
+    /// Elements of a list from `start` to `stop`, both included. Missing keys
+    /// are empty lists
+    pub fn lrange(&mut self, key: &[u8], start: i64, stop: i64) -> CommandResponse {
+        let elements = match self.get_live(key).map(|entry| entry.value()) {
+            Some(RedisType::List(list)) => match list_range(start, stop, list.len()) {
+                Some((from, to)) => list.range(from..=to).cloned().collect(),
+                None => VecDeque::new(),
+            },
+            Some(_) => return CommandResponse::WrongType,
+            None => VecDeque::new(),
+        };
+
+        CommandResponse::Get(Some(RedisType::List(elements)))
+    }
+
+    /// Number of elements of a list. Missing keys are empty lists
+    pub fn llen(&mut self, key: &[u8]) -> CommandResponse {
+        match self.get_live(key).map(|entry| entry.value()) {
+            Some(RedisType::List(list)) => CommandResponse::Integer(list.len() as i64),
+            Some(_) => CommandResponse::WrongType,
+            None => CommandResponse::Integer(0),
+        }
+    }
+
+    /// Element of a list at `index`, if there's one
+    pub fn lindex(&mut self, key: &[u8], index: i64) -> CommandResponse {
+        match self.get_live(key).map(|entry| entry.value()) {
+            Some(RedisType::List(list)) => {
+                let element = list_index(index, list.len()).map(|index| RedisType::String(list[index].clone()));
+                CommandResponse::Get(element)
             }
+            Some(_) => CommandResponse::WrongType,
+            None => CommandResponse::Get(None),
+        }
+    }
+
+    /// Indices of the elements of a list equal to `element`. Answers with the
+    /// first one, or with a list of them if `options.count` is given
+    pub fn lpos(&mut self, key: &[u8], element: &[u8], options: &LPosOptions) -> CommandResponse {
+        let list = match self.get_live(key).map(|entry| entry.value()) {
+            Some(RedisType::List(list)) => list,
+            Some(_) => return CommandResponse::WrongType,
+            None => &VecDeque::new(),
+        };
+
+        let scanned = if options.maxlen == 0 { list.len() } else { options.maxlen.min(list.len()) };
+        let wanted = match options.count {
+            Some(0) => usize::MAX,
+            Some(count) => count,
+            None => 1,
+        };
+        let indices: Box<dyn Iterator<Item = usize>> = if options.rank > 0 {
+            Box::new(0..list.len())
+        } else {
+            Box::new((0..list.len()).rev())
+        };
+        let found = indices
+            .take(scanned)
+            .filter(|&index| list[index] == element)
+            .skip(options.rank.unsigned_abs() as usize - 1)
+            .take(wanted)
+            .map(|index| RedisType::Int(index as i64))
+            .collect::<Vec<_>>();
+
+        match options.count {
+            Some(_) => CommandResponse::Get(Some(RedisType::Array(found))),
+            None => CommandResponse::Get(found.into_iter().next()),
         }
     }
+
+    /// Replace the element of a list at `index`. Answers with 1 when done
+    pub fn lset(&mut self, key: &[u8], index: i64, value: Vec<u8>) -> CommandResponse {
+        let list = match self.get_live(key).map(StoreValue::value_mut) {
+            Some(RedisType::List(list)) => list,
+            Some(_) => return CommandResponse::WrongType,
+            None => return CommandResponse::Error(String::from("no such key")),
+        };
+        let Some(index) = list_index(index, list.len()) else {
+            return CommandResponse::Error(String::from("index out of range"))
+        };
+
+        list[index] = value;
+        self.dirty += 1;
+        self.notify(EventClass::List, "lset", key);
+
+        CommandResponse::Integer(1)
+    }
+
+    /// Insert `value` right before or after the first element equal to `pivot`
+    pub fn linsert(&mut self, key: &[u8], before: bool, pivot: &[u8], value: Vec<u8>) -> CommandResponse {
+        let list = match self.get_live(key).map(StoreValue::value_mut) {
+            Some(RedisType::List(list)) => list,
+            Some(_) => return CommandResponse::WrongType,
+            None => return CommandResponse::Integer(0),
+        };
+        let Some(position) = list.iter().position(|element| element == pivot) else {
+            return CommandResponse::Integer(-1)
+        };
+
+        list.insert(if before { position } else { position + 1 }, value);
+        let length = list.len();
+        self.dirty += 1;
+        self.notify(EventClass::List, "linsert", key);
+
+        CommandResponse::Integer(length as i64)
+    }
+
+    /// Remove the elements of a list equal to `value`: the first `count` ones
+    /// if it's positive, the last ones if it's negative, or all of them if it's
+    /// zero. The key is removed along with its last element
+    pub fn lrem(&mut self, key: &[u8], count: i64, value: &[u8]) -> CommandResponse {
+        let list = match self.get_live(key).map(StoreValue::value_mut) {
+            Some(RedisType::List(list)) => list,
+            Some(_) => return CommandResponse::WrongType,
+            None => return CommandResponse::Integer(0),
+        };
+
+        let limit = if count == 0 { usize::MAX } else { count.unsigned_abs() as usize };
+        let mut removed = 0;
+        if count < 0 {
+            let mut index = list.len();
+            while index > 0 && removed < limit {
+                index -= 1;
+                if list[index] == value {
+                    list.remove(index);
+                    removed += 1;
+                }
+            }
+        } else {
+            let mut index = 0;
+            while index < list.len() && removed < limit {
+                if list[index] == value {
+                    list.remove(index);
+                    removed += 1;
+                } else {
+                    index += 1;
+                }
+            }
+        }
+
+        let empty = list.is_empty();
+        if removed > 0 {
+            self.notify(EventClass::List, "lrem", key);
+        }
+        if empty {
+            self.remove_emptied(key);
+        }
+        self.dirty += removed as u64;
+
+        CommandResponse::Integer(removed as i64)
+    }
+
+    /// Hash at `key`, created if missing, or `None` if the key holds another
+    /// type of value
+    fn hash_to_write(&mut self, key: &[u8]) -> Option<&mut HashMap<Vec<u8>, Vec<u8>>> {
+        if self.get_live(key).is_none() {
+            self.insert_entry(key, StoreValue::Permanent(RedisType::Hash(HashMap::new())));
+        }
+        match self.get_live(key).map(StoreValue::value_mut) {
+            Some(RedisType::Hash(hash)) => Some(hash),
+            _ => None,
+        }
+    }
+
+    /// Set fields of a hash, creating it if needed
+    pub fn hset(&mut self, key: &[u8], pairs: Vec<(Vec<u8>, Vec<u8>)>) -> CommandResponse {
+        let Some(hash) = self.hash_to_write(key) else {
+            return CommandResponse::WrongType
+        };
+
+        let changes = pairs.len() as u64;
+        let added = pairs.into_iter()
+            .filter(|(field, value)| hash.insert(field.clone(), value.clone()).is_none())
+            .count();
+        self.dirty += changes;
+        self.notify(EventClass::Hash, "hset", key);
+
+        CommandResponse::Integer(added as i64)
+    }
+
+    pub fn hget(&mut self, key: &[u8], field: &[u8]) -> CommandResponse {
+        match self.get_live(key).map(|entry| entry.value()) {
+            Some(RedisType::Hash(hash)) => CommandResponse::Get(hash.get(field).map(|value| RedisType::from(value.as_slice()))),
+            Some(_) => CommandResponse::WrongType,
+            None => CommandResponse::Get(None),
+        }
+    }
+
+    /// Values of some fields of a hash, `None` for the ones that don't exist
+    pub fn hmget(&mut self, key: &[u8], fields: &[Vec<u8>]) -> CommandResponse {
+        let empty = HashMap::new();
+        let hash = match self.get_live(key).map(|entry| entry.value()) {
+            Some(RedisType::Hash(hash)) => hash,
+            Some(_) => return CommandResponse::WrongType,
+            None => &empty,
+        };
+
+        let values = fields.iter()
+            .map(|field| hash.get(field).map(|value| RedisType::from(value.as_slice())))
+            .collect();
+        CommandResponse::Values(values)
+    }
+
+    /// Set a field of a hash, only if it doesn't exist yet
+    pub fn hsetnx(&mut self, key: &[u8], field: &[u8], value: &[u8]) -> CommandResponse {
+        let Some(hash) = self.hash_to_write(key) else {
+            return CommandResponse::WrongType
+        };
+        if hash.contains_key(field) {
+            return CommandResponse::Integer(0)
+        }
+
+        hash.insert(field.to_vec(), value.to_vec());
+        self.dirty += 1;
+        self.notify(EventClass::Hash, "hset", key);
+
+        CommandResponse::Integer(1)
+    }
+
+    /// Add `delta` to the integer in a field of a hash. Missing fields count
+    /// as 0. Answers with the result
+    pub fn hincr_by(&mut self, key: &[u8], field: &[u8], delta: i64) -> CommandResponse {
+        let Some(hash) = self.hash_to_write(key) else {
+            return CommandResponse::WrongType
+        };
+        let current = match hash.get(field) {
+            Some(value) => match std::str::from_utf8(value).ok().and_then(|value| value.parse::<i64>().ok()) {
+                Some(number) => number,
+                None => return CommandResponse::Error("hash value is not an integer".into()),
+            },
+            None => 0,
+        };
+
+        let Some(result) = current.checked_add(delta) else {
+            return CommandResponse::Error("increment or decrement would overflow".into())
+        };
+        hash.insert(field.to_vec(), result.to_string().into_bytes());
+        self.dirty += 1;
+        self.notify(EventClass::Hash, "hincrby", key);
+
+        CommandResponse::Integer(result)
+    }
+
+    /// Same as `hincr_by`, for floating point numbers. Answers with the result
+    /// as a string
+    pub fn hincr_by_float(&mut self, key: &[u8], field: &[u8], delta: f64) -> CommandResponse {
+        let Some(hash) = self.hash_to_write(key) else {
+            return CommandResponse::WrongType
+        };
+        let current = match hash.get(field) {
+            Some(value) => match std::str::from_utf8(value).ok().and_then(|value| value.parse::<f64>().ok()) {
+                Some(number) if number.is_finite() => number,
+                _ => return CommandResponse::Error("hash value is not a float".into()),
+            },
+            None => 0.0,
+        };
+
+        let result = current + delta;
+        if !result.is_finite() {
+            return CommandResponse::Error("increment would produce NaN or Infinity".into())
+        }
+        // Shortest form that reads back the same, and never in exponent form
+        let result = result.to_string();
+        hash.insert(field.to_vec(), result.clone().into_bytes());
+        self.dirty += 1;
+        self.notify(EventClass::Hash, "hincrbyfloat", key);
+
+        CommandResponse::Get(Some(RedisType::from(result)))
+    }
+
+    /// Remove fields from a hash. The key is removed along with its last field
+    pub fn hdel(&mut self, key: &[u8], fields: &[Vec<u8>]) -> CommandResponse {
+        let hash = match self.get_live(key).map(StoreValue::value_mut) {
+            Some(RedisType::Hash(hash)) => hash,
+            Some(_) => return CommandResponse::WrongType,
+            None => return CommandResponse::Integer(0),
+        };
+
+        let removed = fields.iter().filter(|&field| hash.remove(field).is_some()).count();
+        let empty = hash.is_empty();
+        if removed > 0 {
+            self.notify(EventClass::Hash, "hdel", key);
+        }
+        if empty {
+            self.remove_emptied(key);
+        }
+        self.dirty += removed as u64;
+
+        CommandResponse::Integer(removed as i64)
+    }
+
+    /// Answers with an integer computed from the hash at `key`, or from an
+    /// empty one if it's missing
+    fn hash_integer<F>(&mut self, key: &[u8], compute: F) -> CommandResponse
+    where
+        F: FnOnce(&HashMap<Vec<u8>, Vec<u8>>) -> usize
+    {
+        match self.get_live(key).map(|entry| entry.value()) {
+            Some(RedisType::Hash(hash)) => CommandResponse::Integer(compute(hash) as i64),
+            Some(_) => CommandResponse::WrongType,
+            None => CommandResponse::Integer(compute(&HashMap::new()) as i64),
+        }
+    }
+
+    pub fn hexists(&mut self, key: &[u8], field: &[u8]) -> CommandResponse {
+        self.hash_integer(key, |hash| hash.contains_key(field) as usize)
+    }
+
+    pub fn hlen(&mut self, key: &[u8]) -> CommandResponse {
+        self.hash_integer(key, HashMap::len)
+    }
+
+    /// Length of the value of a field, 0 if it doesn't exist
+    pub fn hstrlen(&mut self, key: &[u8], field: &[u8]) -> CommandResponse {
+        self.hash_integer(key, |hash| hash.get(field).map_or(0, Vec::len))
+    }
+
+    pub fn hgetall(&mut self, key: &[u8]) -> CommandResponse {
+        match self.get_live(key).map(|entry| entry.value()) {
+            Some(hash @ RedisType::Hash(_)) => CommandResponse::Get(Some(hash.clone())),
+            Some(_) => CommandResponse::WrongType,
+            None => CommandResponse::Get(Some(RedisType::Hash(HashMap::new()))),
+        }
+    }
+
+    /// Set at `key`, created if missing, or `None` if the key holds another
+    /// type of value
+    fn set_to_write(&mut self, key: &[u8]) -> Option<&mut HashSet<Vec<u8>>> {
+        if self.get_live(key).is_none() {
+            self.insert_entry(key, StoreValue::Permanent(RedisType::Set(HashSet::new())));
+        }
+        match self.get_live(key).map(StoreValue::value_mut) {
+            Some(RedisType::Set(set)) => Some(set),
+            _ => None,
+        }
+    }
+
+    /// Add members to a set, creating it if needed
+    pub fn sadd(&mut self, key: &[u8], members: Vec<Vec<u8>>) -> CommandResponse {
+        let Some(set) = self.set_to_write(key) else {
+            return CommandResponse::WrongType
+        };
+
+        let added = members.into_iter().filter(|member| set.insert(member.clone())).count();
+        self.dirty += added as u64;
+        if added > 0 {
+            self.notify(EventClass::Set, "sadd", key);
+        }
+
+        CommandResponse::Integer(added as i64)
+    }
+
+    /// Remove members from a set. The key is removed along with its last member
+    pub fn srem(&mut self, key: &[u8], members: &[Vec<u8>]) -> CommandResponse {
+        let set = match self.get_live(key).map(StoreValue::value_mut) {
+            Some(RedisType::Set(set)) => set,
+            Some(_) => return CommandResponse::WrongType,
+            None => return CommandResponse::Integer(0),
+        };
+
+        let removed = members.iter().filter(|&member| set.remove(member)).count();
+        let empty = set.is_empty();
+        if removed > 0 {
+            self.notify(EventClass::Set, "srem", key);
+        }
+        if empty {
+            self.remove_emptied(key);
+        }
+        self.dirty += removed as u64;
+
+        CommandResponse::Integer(removed as i64)
+    }
+
+    /// Answers with 1 or 0 for each member, depending on whether it's in the set
+    pub fn sismember(&mut self, key: &[u8], members: &[Vec<u8>]) -> CommandResponse {
+        let empty = HashSet::new();
+        let set = match self.get_live(key).map(|entry| entry.value()) {
+            Some(RedisType::Set(set)) => set,
+            Some(_) => return CommandResponse::WrongType,
+            None => &empty,
+        };
+
+        let found = members.iter()
+            .map(|member| Some(RedisType::Int(set.contains(member) as i64)))
+            .collect();
+        CommandResponse::Values(found)
+    }
+
+    pub fn scard(&mut self, key: &[u8]) -> CommandResponse {
+        match self.get_live(key).map(|entry| entry.value()) {
+            Some(RedisType::Set(set)) => CommandResponse::Integer(set.len() as i64),
+            Some(_) => CommandResponse::WrongType,
+            None => CommandResponse::Integer(0),
+        }
+    }
+
+    /// Combine the sets at `keys`, with missing keys counting as empty sets.
+    /// `None` if any of them holds another type of value
+    fn combined_sets(&mut self, keys: &[Vec<u8>], operation: SetOperation) -> Option<HashSet<Vec<u8>>> {
+        let mut sets = vec![];
+        for key in keys {
+            match self.get_live(key).map(|entry| entry.value()) {
+                Some(RedisType::Set(set)) => sets.push(set.clone()),
+                Some(_) => return None,
+                None => sets.push(HashSet::new()),
+            }
+        }
+
+        let mut sets = sets.into_iter();
+        let first = sets.next().unwrap_or_default();
+        Some(sets.fold(first, |result, set| match operation {
+            SetOperation::Intersection => result.intersection(&set).cloned().collect(),
+            SetOperation::Union => result.union(&set).cloned().collect(),
+            SetOperation::Difference => result.difference(&set).cloned().collect(),
+        }))
+    }
+
+    pub fn combine(&mut self, keys: &[Vec<u8>], operation: SetOperation) -> CommandResponse {
+        match self.combined_sets(keys, operation) {
+            Some(set) => CommandResponse::Get(Some(RedisType::Set(set))),
+            None => CommandResponse::WrongType,
+        }
+    }
+
+    /// Store the combination of the sets at `keys` in `destination`, replacing
+    /// whatever was there. Empty results remove `destination`. Along with the
+    /// response, returns the commands that replicate the change
+    pub fn combine_store(&mut self, destination: &[u8], keys: &[Vec<u8>], operation: SetOperation)
+        -> (CommandResponse, Vec<RedisType>)
+    {
+        let Some(set) = self.combined_sets(keys, operation) else {
+            return (CommandResponse::WrongType, vec![])
+        };
+
+        let size = set.len();
+        let mut replicated = vec![command(&[b"DEL", destination])];
+        if set.is_empty() {
+            self.delete(&[destination.to_vec()]);
+        } else {
+            let mut cmd = vec![b"SADD".as_slice(), destination];
+            cmd.extend(set.iter().map(Vec::as_slice));
+            replicated.push(command(&cmd));
+            self.write(destination, RedisType::Set(set), None);
+        }
+
+        (CommandResponse::Integer(size as i64), replicated)
+    }
+
+    /// Move a member from one set to another. Answers with 1 if it was in
+    /// `source`, even if both sets are the same
+    pub fn smove(&mut self, source: &[u8], destination: &[u8], member: &[u8]) -> CommandResponse {
+        if self.get_live(destination).is_some_and(|entry| !matches!(entry.value(), RedisType::Set(_))) {
+            return CommandResponse::WrongType
+        }
+        let found = match self.get_live(source).map(|entry| entry.value()) {
+            Some(RedisType::Set(set)) => set.contains(member),
+            Some(_) => return CommandResponse::WrongType,
+            None => false,
+        };
+
+        if found && source != destination {
+            self.srem(source, &[member.to_vec()]);
+            self.sadd(destination, vec![member.to_vec()]);
+        }
+        CommandResponse::Integer(found as i64)
+    }
+
+    /// Size of the intersection of the sets at `keys`, without building it.
+    /// Stops counting at `limit`, unless it's 0
+    pub fn intercard(&mut self, keys: &[Vec<u8>], limit: usize) -> CommandResponse {
+        for key in keys {
+            match self.get_live(key).map(|entry| entry.value()) {
+                Some(RedisType::Set(_)) => {}
+                Some(_) => return CommandResponse::WrongType,
+                None => return CommandResponse::Integer(0),
+            }
+        }
+        let mut sets = keys.iter()
+            .filter_map(|key| match self.data.get(key).map(StoreValue::value) {
+                Some(RedisType::Set(set)) => Some(set),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // Going through the smallest set means fewer lookups
+        sets.sort_by_key(|set| set.len());
+        let Some((smallest, others)) = sets.split_first() else {
+            return CommandResponse::Integer(0)
+        };
+
+        let limit = if limit == 0 { usize::MAX } else { limit };
+        let count = smallest.iter()
+            .filter(|member| others.iter().all(|set| set.contains(*member)))
+            .take(limit)
+            .count();
+        CommandResponse::Integer(count as i64)
+    }
+
+    pub fn smembers(&mut self, key: &[u8]) -> CommandResponse {
+        match self.get_live(key).map(|entry| entry.value()) {
+            Some(set @ RedisType::Set(_)) => CommandResponse::Get(Some(set.clone())),
+            Some(_) => CommandResponse::WrongType,
+            None => CommandResponse::Get(Some(RedisType::Set(HashSet::new()))),
+        }
+    }
+
+    /// Add members to a sorted set, or update their scores, creating it if
+    /// needed. Along with the response, returns the ZADD that replicates the
+    /// changes, with the resulting scores
+    pub fn zadd(&mut self, key: &[u8], options: &ZAddOptions, pairs: &[(f64, Vec<u8>)]) -> (CommandResponse, Option<RedisType>) {
+        let skipped = || match options.incr {
+            true => CommandResponse::Get(None),
+            false => CommandResponse::Integer(0),
+        };
+        // XX never creates the key
+        if self.get_live(key).is_none() {
+            if options.xx {
+                return (skipped(), None)
+            }
+            self.insert_entry(key, StoreValue::Permanent(RedisType::ZSet(SortedSet::default())));
+        }
+        let Some(RedisType::ZSet(zset)) = self.get_live(key).map(StoreValue::value_mut) else {
+            return (CommandResponse::WrongType, None)
+        };
+
+        let mut counted = 0;
+        let mut last = None;
+        let mut changes = vec![RedisType::from("ZADD"), RedisType::from(key)];
+        for (score, member) in pairs {
+            let added = match zset.add(member, *score, options) {
+                Ok(added) => added,
+                Err(error) => return (CommandResponse::Error(error.to_string()), None),
+            };
+            if let Added::New(score) | Added::Updated(score) = added {
+                changes.push(RedisType::from(format_score(score)));
+                changes.push(RedisType::from(member.as_slice()));
+            }
+            last = match added {
+                Added::New(score) | Added::Updated(score) | Added::Unchanged(score) => Some(score),
+                Added::Skipped => None,
+            };
+            if matches!(added, Added::New(_)) || (options.ch && matches!(added, Added::Updated(_))) {
+                counted += 1;
+            }
+        }
+        let empty = zset.is_empty();
+        if empty {
+            self.remove_entry(key);
+        }
+
+        let changed = (changes.len() - 2) / 2;
+        self.dirty += changed as u64;
+        if changed > 0 {
+            self.notify(EventClass::SortedSet, if options.incr { "zincr" } else { "zadd" }, key);
+        }
+        let replicated = (changed > 0).then_some(RedisType::Array(changes));
+        let response = match options.incr {
+            true => CommandResponse::Get(last.map(RedisType::Double)),
+            false => CommandResponse::Integer(counted),
+        };
+        (response, replicated)
+    }
+
+    pub fn zscore(&mut self, key: &[u8], member: &[u8]) -> CommandResponse {
+        match self.get_live(key).map(|entry| entry.value()) {
+            Some(RedisType::ZSet(zset)) => CommandResponse::Get(zset.score(member).map(RedisType::Double)),
+            Some(_) => CommandResponse::WrongType,
+            None => CommandResponse::Get(None),
+        }
+    }
+
+    pub fn zrank(&mut self, key: &[u8], member: &[u8], rev: bool) -> CommandResponse {
+        match self.get_live(key).map(|entry| entry.value()) {
+            Some(RedisType::ZSet(zset)) => CommandResponse::Get(zset.rank(member, rev).map(|rank| RedisType::Int(rank as i64))),
+            Some(_) => CommandResponse::WrongType,
+            None => CommandResponse::Get(None),
+        }
+    }
+
+    pub fn zcard(&mut self, key: &[u8]) -> CommandResponse {
+        match self.get_live(key).map(|entry| entry.value()) {
+            Some(RedisType::ZSet(zset)) => CommandResponse::Integer(zset.len() as i64),
+            Some(_) => CommandResponse::WrongType,
+            None => CommandResponse::Integer(0),
+        }
+    }
+
+    /// Size of the intersection of some sorted sets, like `intercard` does
+    /// for sets. Scores don't matter
+    pub fn zintercard(&mut self, keys: &[Vec<u8>], limit: usize) -> CommandResponse {
+        for key in keys {
+            match self.get_live(key).map(|entry| entry.value()) {
+                Some(RedisType::ZSet(_)) => {}
+                Some(_) => return CommandResponse::WrongType,
+                None => return CommandResponse::Integer(0),
+            }
+        }
+        let mut zsets = keys.iter()
+            .filter_map(|key| match self.data.get(key).map(StoreValue::value) {
+                Some(RedisType::ZSet(zset)) => Some(zset),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        zsets.sort_by_key(|zset| zset.len());
+        let Some((smallest, others)) = zsets.split_first() else {
+            return CommandResponse::Integer(0)
+        };
+
+        let limit = if limit == 0 { usize::MAX } else { limit };
+        let count = smallest.iter()
+            .filter(|(member, _)| others.iter().all(|zset| zset.score(member).is_some()))
+            .take(limit)
+            .count();
+        CommandResponse::Integer(count as i64)
+    }
+
+    /// Remove the `count` members with the lowest scores, or the highest ones
+    /// with `max`. Along with the response, returns the ZREM that replicates
+    /// the removal
+    pub fn zpop(&mut self, key: &[u8], count: usize, max: bool) -> (CommandResponse, Option<RedisType>) {
+        let zset = match self.get_live(key).map(StoreValue::value_mut) {
+            Some(RedisType::ZSet(zset)) => zset,
+            Some(_) => return (CommandResponse::WrongType, None),
+            None => return (CommandResponse::Get(Some(RedisType::Array(vec![]))), None),
+        };
+
+        let mut reply = vec![];
+        let mut cmd = vec![RedisType::from("ZREM"), RedisType::from(key)];
+        for (member, score) in (0..count).map_while(|_| zset.pop(max)) {
+            cmd.push(RedisType::from(member.as_slice()));
+            reply.push(RedisType::from(member));
+            reply.push(RedisType::from(format_score(score)));
+        }
+        let empty = zset.is_empty();
+        let popped = reply.len() / 2;
+        if popped > 0 {
+            self.notify(EventClass::SortedSet, if max { "zpopmax" } else { "zpopmin" }, key);
+        }
+        if empty {
+            self.remove_emptied(key);
+        }
+        if popped == 0 {
+            return (CommandResponse::Get(Some(RedisType::Array(reply))), None)
+        }
+        self.dirty += popped as u64;
+
+        (CommandResponse::Get(Some(RedisType::Array(reply))), Some(RedisType::Array(cmd)))
+    }
+
+    pub fn zrem(&mut self, key: &[u8], members: &[Vec<u8>]) -> CommandResponse {
+        let zset = match self.get_live(key).map(StoreValue::value_mut) {
+            Some(RedisType::ZSet(zset)) => zset,
+            Some(_) => return CommandResponse::WrongType,
+            None => return CommandResponse::Integer(0),
+        };
+
+        let removed = members.iter().filter(|&member| zset.remove(member)).count();
+        let empty = zset.is_empty();
+        if removed > 0 {
+            self.notify(EventClass::SortedSet, "zrem", key);
+        }
+        if empty {
+            self.remove_emptied(key);
+        }
+        self.dirty += removed as u64;
+
+        CommandResponse::Integer(removed as i64)
+    }
+
+    pub fn zcount(&mut self, key: &[u8], min: &ScoreBound, max: &ScoreBound) -> CommandResponse {
+        match self.get_live(key).map(|entry| entry.value()) {
+            Some(RedisType::ZSet(zset)) => CommandResponse::Integer(zset.count(min, max) as i64),
+            Some(_) => CommandResponse::WrongType,
+            None => CommandResponse::Integer(0),
+        }
+    }
+
+    pub fn zrange(&mut self, key: &[u8], range: &ZRange) -> CommandResponse {
+        let zset = match self.get_live(key).map(|entry| entry.value()) {
+            Some(RedisType::ZSet(zset)) => zset,
+            Some(_) => return CommandResponse::WrongType,
+            None => return CommandResponse::Get(Some(RedisType::Array(vec![]))),
+        };
+
+        let mut reply = vec![];
+        for (member, score) in zset.range(range) {
+            reply.push(RedisType::from(member));
+            if range.withscores {
+                reply.push(RedisType::from(format_score(score)));
+            }
+        }
+        CommandResponse::Get(Some(RedisType::Array(reply)))
+    }
+
+    /// Run the BITFIELD operations on a string. Missing keys are only created
+    /// if there is any write. Answers with the result of each operation
+    pub fn bitfield(&mut self, key: &[u8], ops: &[BitfieldOp]) -> CommandResponse {
+        let mut bytes = match self.get_live(key) {
+            Some(entry) => match entry.value().string_bytes() {
+                Some(bytes) => bytes,
+                None => return CommandResponse::WrongType,
+            },
+            None => vec![],
+        };
+        let results = bitfield::apply(&mut bytes, ops)
+            .into_iter()
+            .map(|result| result.map(RedisType::Int))
+            .collect();
+
+        if ops.iter().any(BitfieldOp::is_write) {
+            self.overwrite(key, RedisType::String(bytes));
+            self.raw.insert(key.to_vec());
+        }
+
+        CommandResponse::Values(results)
+    }
+
+    /// Add `delta` to the integer stored at `key`. Missing keys count as 0. On
+    /// error, the value is left untouched
+    pub fn incr_by(&mut self, key: &[u8], delta: i64) -> CommandResponse {
+        let current = match self.get_live(key) {
+            Some(entry) => match entry.value().string_bytes() {
+                Some(bytes) => match std::str::from_utf8(&bytes).ok().and_then(|s| s.parse::<i64>().ok()) {
+                    Some(number) => number,
+                    None => return CommandResponse::Error("value is not an integer or out of range".into()),
+                },
+                None => return CommandResponse::WrongType,
+            },
+            None => 0,
+        };
+
+        let Some(result) = current.checked_add(delta) else {
+            return CommandResponse::Error("increment or decrement would overflow".into())
+        };
+        let value = RedisType::from(result.to_string());
+        self.overwrite(key, value);
+        self.raw.remove(key);
+        self.notify(EventClass::String, "incrby", key);
+
+        CommandResponse::Integer(result)
+    }
+
+    /// Replace a string value, answering with the previous one. Like SET, this
+    /// discards any TTL the key had
+    pub fn get_set(&mut self, key: &[u8], value: RedisType) -> CommandResponse {
+        let previous = match self.get_live(key) {
+            Some(entry) if !entry.value().is_string() => return CommandResponse::WrongType,
+            Some(entry) => Some(entry.value().clone()),
+            None => None,
+        };
+
+        self.write(key, value, None);
+        self.notify(EventClass::String, "set", key);
+        CommandResponse::Get(previous)
+    }
+
+    /// Implementation of SET. The second element is the command to propagate to
+    /// the replicas, if the value was written
+    fn set_with(&mut self, key: &[u8], value: RedisType, options: &SetOptions) -> (CommandResponse, Option<RedisType>) {
+        let existing = self.get_live(key);
+        if options.get && existing.as_ref().is_some_and(|entry| !entry.value().is_string()) {
+            return (CommandResponse::WrongType, None)
+        }
+
+        let previous = existing.as_ref().map(|entry| entry.value().clone());
+        let kept_ttl = existing.and_then(|entry| entry.expires());
+        let allowed = match options.condition {
+            Some(SetCondition::IfMissing) => previous.is_none(),
+            Some(SetCondition::IfExists) => previous.is_some(),
+            None => true,
+        };
+
+        let propagate = if allowed {
+            let expires = match options.ttl {
+                SetTtl::Discard => None,
+                SetTtl::Keep => kept_ttl,
+                SetTtl::ExpireAt(until) => Some(until),
+            };
+            let mut cmd = vec![RedisType::from("SET"), RedisType::from(key), value.clone()];
+            if let Some(until) = expires {
+                let millis = until.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+                cmd.push(RedisType::from("PXAT"));
+                cmd.push(RedisType::from(millis.to_string()));
+            }
+            self.write(key, value, expires);
+            self.notify(EventClass::String, "set", key);
+            Some(RedisType::Array(cmd))
+        } else {
+            None
+        };
+
+        let response = if options.get {
+            CommandResponse::Get(previous)
+        } else {
+            CommandResponse::Integer(allowed as i64)
+        };
+        (response, propagate)
+    }
+
+    /// Write the value only if the key doesn't exist. Returns whether it did
+    pub fn set_nx(&mut self, key: &[u8], value: RedisType) -> bool {
+        if self.get_live(key).is_some() {
+            return false
+        }
+
+        self.write(key, value, None);
+        self.notify(EventClass::String, "set", key);
+        true
+    }
+
+    /// Move the value at `key`, along with its TTL, to `new_key`. With `nx`,
+    /// nothing happens if `new_key` exists. Answers with whether it was moved
+    pub fn rename(&mut self, key: &[u8], new_key: &[u8], nx: bool) -> CommandResponse {
+        if self.get_live(key).is_none() {
+            return CommandResponse::Error("no such key".into())
+        }
+        if key == new_key {
+            return CommandResponse::Integer(!nx as i64)
+        }
+        if nx && self.get_live(new_key).is_some() {
+            return CommandResponse::Integer(0)
+        }
+
+        let value = self.remove_entry(key).unwrap();
+        let raw = self.raw.remove(key);
+        self.raw.remove(new_key);
+        if raw {
+            self.raw.insert(new_key.to_vec());
+        }
+        self.insert_entry(new_key, value);
+        self.dirty += 1;
+        self.notify(EventClass::Generic, "rename_from", key);
+        self.notify(EventClass::Generic, "rename_to", new_key);
+        CommandResponse::Integer(1)
+    }
+
+    /// Remove every key
+    pub fn flush(&mut self) {
+        self.dirty += self.data.len() as u64;
+        let keys = self.data.keys().cloned().collect::<Vec<_>>();
+        for key in keys {
+            self.touch(&key);
+        }
+        self.data.clear();
+        self.volatile.clear();
+        self.raw.clear();
+    }
+
+    /// Number of keys, including the expired ones that haven't been removed yet
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Keys that haven't expired and match a glob-style pattern, in no
+    /// particular order
+    pub fn keys(&self, pattern: &[u8]) -> Vec<Vec<u8>> {
+        let now = SystemTime::now();
+        self.data.iter()
+            .filter(|(_, value)| value.expires().is_none_or(|until| until > now))
+            .filter(|(key, _)| glob_match(pattern, key))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// One step of SCAN: the keys from position `cursor` on, about `count` of
+    /// them (more if some share the position of the last one), and the
+    /// cursor to go on from, which is 0 once every key has been seen. Keys are
+    /// filtered after being picked, so a step may find fewer than `count`
+    /// keys, or none at all, before the scan is over
+    pub fn scan(&self, cursor: u64, count: usize, pattern: Option<&[u8]>, kind: Option<&str>) -> (u64, Vec<Vec<u8>>) {
+        let mut batch = self.data.keys()
+            .map(|key| (scan_position(key), key))
+            .filter(|&(position, _)| position >= cursor)
+            .collect::<Vec<_>>();
+        batch.sort_unstable();
+
+        let count = count.max(1);
+        let next = match batch.get(count - 1) {
+            Some(&(last, _)) if batch.len() > count => {
+                batch.retain(|&(position, _)| position <= last);
+                // Wrapping around to 0 means there's nothing past `last`
+                last.wrapping_add(1)
+            }
+            _ => 0,
+        };
+
+        let now = SystemTime::now();
+        let keys = batch.into_iter()
+            .filter_map(|(_, key)| Some((key, self.data.get(key)?)))
+            .filter(|(_, value)| value.expires().is_none_or(|until| until > now))
+            .filter(|(_, value)| kind.is_none_or(|kind| type_name(value.value()) == kind))
+            .filter(|(key, _)| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+            .map(|(key, _)| key.clone())
+            .collect();
+        (next, keys)
+    }
+
+    /// Any key that hasn't expired, or `None` if there are none. Expired keys
+    /// found along the way are removed
+    pub fn random_key(&mut self) -> Option<Vec<u8>> {
+        // Each RandomState is seeded differently, which is enough randomness here
+        let random = RandomState::new();
+        let mut round: u64 = 0;
+
+        while !self.data.is_empty() {
+            let index = random.hash_one(round) as usize % self.data.len();
+            let key = self.data.keys().nth(index).cloned()?;
+            if self.get_live(&key).is_some() {
+                return Some(key)
+            }
+            round += 1;
+        }
+        None
+    }
+
+    /// Remove the keys. Returns how many of them existed
+    pub fn delete(&mut self, keys: &[Vec<u8>]) -> usize {
+        let mut removed = 0;
+        for key in keys {
+            if self.get_live(key).is_some() {
+                self.remove_entry(key);
+                self.raw.remove(key);
+                self.dirty += 1;
+                removed += 1;
+                self.notify(EventClass::Generic, "del", key);
+            }
+        }
+        removed
+    }
+
+    /// How many of the keys exist. Keys given several times are counted each time
+    pub fn exists(&mut self, keys: &[Vec<u8>]) -> usize {
+        keys.iter().filter(|key| self.get_live(key).is_some()).count()
+    }
+
+    /// Copy the value at `source`, along with its TTL, to `destination`. Unless
+    /// `replace` is set, an existing destination is left alone. Returns whether
+    /// the value was copied
+    pub fn copy(&mut self, source: &[u8], destination: &[u8], replace: bool) -> bool {
+        let Some(value) = self.get_live(source).map(|entry| entry.clone()) else {
+            return false
+        };
+        if !replace && self.get_live(destination).is_some() {
+            return false
+        }
+
+        if self.raw.contains(source) {
+            self.raw.insert(destination.to_vec());
+        } else {
+            self.raw.remove(destination);
+        }
+        self.insert_entry(destination, value);
+        self.dirty += 1;
+        self.notify(EventClass::Generic, "copy_to", destination);
+        true
+    }
+
+    /// Name of the type of value stored at `key`, as reported by TYPE
+    pub fn type_of(&mut self, key: &[u8]) -> &'static str {
+        self.get_live(key).map_or("none", |entry| type_name(entry.value()))
+    }
+
+    /// Internal encoding of the value stored at `key`, as reported by OBJECT
+    /// ENCODING
+    pub fn encoding_of(&mut self, key: &[u8]) -> Option<&'static str> {
+        let raw = self.raw.contains(key);
+        let packed_threshold = self.packed_threshold;
+        let list_encoding = |sizes: &mut dyn Iterator<Item = usize>| {
+            let mut total = 0;
+            for size in sizes {
+                if size > packed_threshold {
+                    return "quicklist"
+                }
+                total += size;
+            }
+            if total <= LISTPACK_SIZE_LIMIT { "listpack" } else { "quicklist" }
+        };
+        let encoding = match self.get_live(key)?.value() {
+            RedisType::Int(_) => "int",
+            RedisType::String(_) if raw => "raw",
+            RedisType::String(bytes) if is_integer_string(bytes) => "int",
+            RedisType::String(bytes) if bytes.len() <= EMBSTR_SIZE_LIMIT => "embstr",
+            RedisType::String(_) => "raw",
+            RedisType::Array(elements) => list_encoding(&mut elements.iter().map(|element| element.to_vec().len())),
+            RedisType::List(elements) => list_encoding(&mut elements.iter().map(Vec::len)),
+            RedisType::Map(_) | RedisType::Hash(_) => "hashtable",
+            RedisType::Set(_) => "hashtable",
+            RedisType::ZSet(_) => "skiplist",
+            RedisType::Timestamp(_)
+            | RedisType::Push(_)
+            | RedisType::Verbatim { .. }
+            | RedisType::Double(_)
+            | RedisType::Bool(_)
+            | RedisType::Null => "raw",
+        };
+        Some(encoding)
+    }
+
+    /// Values for several keys. Anything that is not a string reads as missing
+    pub fn read_strings(&mut self, keys: &[Vec<u8>]) -> Vec<Option<RedisType>> {
+        keys.iter()
+            .map(|key| self.read(key).filter(|value| value.is_string()))
+            .collect()
+    }
+
+    pub fn read(&mut self, key: &[u8]) -> Option<RedisType> {
+        if let Some(val) = self.data.get(key) {
+            match val {
+                StoreValue::Permanent(value) => Some(value.clone()),
+                StoreValue::Expirable { value, until } => {
+                    if SystemTime::now() < *until {
+                        Some(value.clone())
+                    } else {
+                        self.remove_expired(key);
+                        None
+                    }
+                }
+            }
+        } else {
+            None
+        }
+    }
+}
+
+/// Absolute expiration times are propagated as PEXPIREAT, so that masters and
+/// replicas agree on the deadline regardless of delays
+fn pexpireat_command(key: &[u8], until: SystemTime) -> RedisType {
+    let millis = until.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+
+    command(&[b"PEXPIREAT", key, millis.to_string().as_bytes()])
+}
+
+/// Replicated commands carry keys and values as they are, which aren't
+/// necessarily valid UTF-8
+fn command(args: &[&[u8]]) -> RedisType {
+    RedisType::Array(args.iter().map(|arg| RedisType::from(*arg)).collect())
+}
+
+async fn propagate(replicas: &[Sender<Bytes>], payload: Option<RedisType>) {
+    if let Some(payload) = payload {
+        replicate(replicas, payload).await;
+    }
+}
+
+/// Send a command to every replica. The encoded payload is shared among
+/// all of them, rather than copied for each one
+async fn replicate(replicas: &[Sender<Bytes>], payload: RedisType) {
+    let encoded = Bytes::from(payload.to_vec());
+
+    for replica in replicas {
+        // Disconnected replicas are simply skipped
+        let _ = replica.send(encoded.clone()).await;
+    }
+}
+
+/// A client waiting in BLPOP or BRPOP for any of `keys` to get elements
+struct BlockedPop {
+    keys: Vec<Vec<u8>>,
+    left: bool,
+    waiter: Waiter,
+}
+
+/// Outcome of trying to pop for a blocked client
+enum Claim {
+    /// Popped something, or failed. The answer goes through `tx`
+    Served { tx: oneshot::Sender<CommandResponse>, response: CommandResponse, replicated: Option<RedisType> },
+    /// Nothing to pop yet
+    Empty,
+    /// The client gave up, or someone else answered it already
+    Gone,
+}
+
+/// Try to pop for a blocked client. Unless `wait` is false, lists without
+/// elements leave it waiting. Popping and taking the sender happen at once,
+/// so that two shards can't both pop for the same client
+fn claim(store: &mut Store, keys: &[Vec<u8>], left: bool, waiter: &Waiter, wait: bool) -> Claim {
+    let mut slot = waiter.lock().unwrap();
+    if slot.as_ref().is_none_or(oneshot::Sender::is_closed) {
+        return Claim::Gone
+    }
+    match store.pop_first(keys, left) {
+        (CommandResponse::Get(None), _) if wait => Claim::Empty,
+        (response, replicated) => Claim::Served { tx: slot.take().unwrap(), response, replicated },
+    }
+}
+
+/// Serve the blocked clients that can pop something now, in the order they
+/// blocked. The ones that gave up (timed out or disconnected) are dropped
+async fn serve_blocked(store: &mut Store, blocked: &mut Vec<BlockedPop>, replicas: &[Sender<Bytes>]) {
+    let mut waiting = vec![];
+
+    for client in blocked.drain(..) {
+        let (tx, response, replicated) = match claim(store, &client.keys, client.left, &client.waiter, true) {
+            Claim::Served { tx, response, replicated } => (tx, response, replicated),
+            Claim::Empty => {
+                waiting.push(client);
+                continue
+            }
+            Claim::Gone => continue,
+        };
+
+        match tx.send(response) {
+            Ok(()) => propagate(replicas, replicated).await,
+            // Gave up just now: the element goes back where it was
+            Err(CommandResponse::Get(Some(RedisType::Array(pair)))) => {
+                if let [RedisType::String(key), RedisType::String(element)] = &pair[..] {
+                    store.push(key, vec![element.clone()], client.left, false);
+                }
+            }
+            Err(_) => {}
+        }
+    }
+
+    *blocked = waiting;
+}
+
+/// Answer a client. The client may be gone already (eg. it was interrupted
+/// by a shutdown while waiting for the answer), which is not an error
+async fn respond(clients: &HashMap<usize, Sender<CommandResponse>>, id: usize, response: CommandResponse) {
+    if let Some(client) = clients.get(&id) {
+        let _ = client.send(response).await;
+    }
+}
+
+/// Pop for BLPOP or BRPOP right away if possible. Otherwise, and if `wait`,
+/// the client is left waiting until any of the lists gets elements
+async fn block_pop(
+    store: &mut Store,
+    blocked: &mut Vec<BlockedPop>,
+    replicas: &[Sender<Bytes>],
+    keys: Vec<Vec<u8>>,
+    left: bool,
+    waiter: Waiter,
+    wait: bool,
+) {
+    match claim(store, &keys, left, &waiter, wait) {
+        Claim::Served { tx, response, replicated } => {
+            propagate(replicas, replicated).await;
+            let _ = tx.send(response);
+        }
+        Claim::Empty => blocked.push(BlockedPop { keys, left, waiter }),
+        Claim::Gone => {}
+    }
+}
+
+/// Ask the store to run an active expiry cycle every now and then, until the
+/// server shuts down
+pub async fn expire_loop(store_tx: StoreHandle, mut shutdown: watch::Receiver<bool>) {
+    let mut timer = interval(ACTIVE_EXPIRE_PERIOD);
+    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = timer.tick() => {
+                if store_tx.send(StoreCommand::ExpireCycle).await.is_err() {
+                    break
+                }
+            }
+            _ = shutdown.changed() => break,
+        }
+    }
+}
+
+pub async fn store_loop(store: Store, rx: Receiver<StoreCommand>) {
+    shard_loop(store, rx, None).await
+}
+
+/// Serve the commands for a store, or for a shard of it. Shards other than
+/// the first one send their keyspace notifications to the `hub`, which is
+/// the one keeping track of the subscriptions
+pub(crate) async fn shard_loop(mut store: Store, mut rx: Receiver<StoreCommand>, hub: Option<Sender<StoreCommand>>) {
+    // Naive implementation. Clients and replicas might
+    // close their connection, which will result on the channel
+    // being dropped. We should use a different structure and
+    // sends should not blindly be accepted as OK
+    let mut clients: HashMap<usize, Sender<CommandResponse>> = HashMap::new();
+    let mut replicas: Vec<Sender<Bytes>> = Vec::new();
+    let mut blocked: Vec<BlockedPop> = Vec::new();
+    let mut pubsub = PubSub::default();
+    // Commands of the transaction being executed, if any
+    let mut transaction: Option<Receiver<StoreCommand>> = None;
+
+    loop {
+        let cmd = match &mut transaction {
+            Some(commands) => match commands.recv().await {
+                Some(cmd) => cmd,
+                None => {
+                    transaction = None;
+                    continue
+                }
+            },
+            None => match rx.recv().await {
+                Some(cmd) => cmd,
+                None => break,
+            },
+        };
+
+        match cmd {
+            StoreCommand::InitClient(tx) => {
+                let id = clients.len();
+                clients.insert(id, tx.clone());
+                let _ = tx.send(CommandResponse::ClientId(id)).await;
+            }
+            StoreCommand::AddClient { id, tx } => {
+                clients.insert(id, tx);
+            }
+            StoreCommand::Lend { keys, tx } => {
+                let _ = tx.send(keys.iter().map(|key| store.take_key(key)).collect());
+            }
+            StoreCommand::Adopt(states) => {
+                for state in states {
+                    store.put_key(state);
+                }
+            }
+            StoreCommand::Sync(tx) => {
+                let _ = tx.send(());
+            }
+            StoreCommand::Flush(tx) => {
+                store.flush();
+                let _ = tx.send(());
+            }
+            StoreCommand::ExpireCycle => {
+                // Replicas don't expire keys on their own, they get a DEL for each one
+                for key in store.active_expire() {
+                    replicate(&replicas, command(&[b"DEL", &key])).await;
+                }
+            }
+            StoreCommand::Notify(notifications) => {
+                for (channel, message) in notifications {
+                    pubsub.publish(&channel, Bytes::from(message));
+                }
+            }
+            StoreCommand::SetNotifyFlags(flags) => {
+                store.set_notify_flags(flags);
+            }
+            StoreCommand::GetVersions { id, keys } => {
+                let versions = keys.iter().map(|key| store.version(key)).collect();
+                respond(&clients, id, CommandResponse::Versions(versions)).await
+            }
+            StoreCommand::Exec { id, watched, commands } => {
+                let unchanged = watched.iter().all(|(key, version)| store.version(key) == *version);
+                if unchanged {
+                    transaction = Some(commands);
+                }
+                respond(&clients, id, CommandResponse::Integer(unchanged as i64)).await
+            }
+            StoreCommand::Subscribe { id, names, pattern, tx } => {
+                let replies = pubsub.subscribe(id, tx, &names, pattern);
+                let count = pubsub.subscription_count(id);
+                respond(&clients, id, CommandResponse::Subscriptions { replies, count }).await
+            }
+            StoreCommand::Unsubscribe { id, names, pattern } => {
+                let replies = pubsub.unsubscribe(id, &names, pattern);
+                let count = pubsub.subscription_count(id);
+                respond(&clients, id, CommandResponse::Subscriptions { replies, count }).await
+            }
+            StoreCommand::ActiveChannels { id, pattern } => {
+                let channels = pubsub.active_channels(pattern.as_deref()).into_iter().map(RedisType::from).collect();
+                respond(&clients, id, CommandResponse::Get(Some(RedisType::Array(channels)))).await
+            }
+            StoreCommand::NumSub { id, channels } => {
+                let mut counts = vec![];
+                for channel in channels {
+                    let count = pubsub.subscriber_count(&channel);
+                    counts.extend([RedisType::from(channel), RedisType::Int(count as i64)]);
+                }
+                respond(&clients, id, CommandResponse::Get(Some(RedisType::Array(counts)))).await
+            }
+            StoreCommand::NumPat(id) => {
+                respond(&clients, id, CommandResponse::Integer(pubsub.pattern_count() as i64)).await
+            }
+            StoreCommand::Publish { id, channel, message } => {
+                let received = pubsub.publish(&channel, message.clone());
+                replicate(&replicas, command(&[b"PUBLISH", &channel, &message])).await;
+                respond(&clients, id, CommandResponse::Integer(received as i64)).await
+            }
+            StoreCommand::InitReplica { tx, snapshot } => {
+                replicas.push(tx);
+                let _ = snapshot.send(store.snapshot());
+            }
+            StoreCommand::Snapshot(tx) => {
+                let _ = tx.send((store.snapshot(), store.dirty));
+            }
+            StoreCommand::Saved { changes, ok } => {
+                store.saved(changes, ok);
+            }
+            StoreCommand::PersistenceInfo(tx) => {
+                let _ = tx.send(store.persistence_info());
+            }
+            StoreCommand::Set { key, value } => {
+                if !replicas.is_empty() {
+                    match &value {
+                        RedisType::String(string) => {
+                            let val = RedisType::Array(vec![
+                                RedisType::from("SET"),
+                                RedisType::from(key.clone()),
+                                RedisType::from(string.clone()),
+                            ]);
+                            replicate(replicas.as_slice(), val).await;
+                        }
+                        _ => panic!("SET accepted a value that is not a string!")
+                    }
+                }
+                store.write(&key, value, None);
+                store.notify(EventClass::String, "set", &key);
+            }
+            StoreCommand::SetEx { key, value, until } => {
+                if !replicas.is_empty() {
+                    match &value {
+                        RedisType::String(string) => {
+                            let pxat = until.duration_since(UNIX_EPOCH)
+                                                  .unwrap()
+                                                  .as_millis();
+                            let val = RedisType::Array(vec![
+                                RedisType::from("SET"),
+                                RedisType::from(key.clone()),
+                                RedisType::from(string.clone()),
+                                RedisType::from("PXAT"),
+                                RedisType::Timestamp(pxat),
+                            ]);
+
+                            replicate(
+                                replicas.as_slice(),
+                                val
+                                ).await;
+                        }
+                        _ => panic!("SET accepted a value that is not a string!")
+                    }
+                }
+                store.write(&key, value, Some(until));
+                store.notify(EventClass::String, "set", &key);
+                store.notify(EventClass::Generic, "expire", &key);
+            }
+            StoreCommand::Load { key, value, expires } => {
+                store.write(&key, value, expires);
+            }
+            StoreCommand::MSet { pairs } => {
+                if !replicas.is_empty() {
+                    let mut cmd = vec![RedisType::from("MSET")];
+                    for (key, value) in &pairs {
+                        cmd.push(RedisType::from(key.as_slice()));
+                        cmd.push(value.clone());
+                    }
+                    replicate(&replicas, RedisType::Array(cmd)).await;
+                }
+                for (key, value) in pairs {
+                    store.write(&key, value, None);
+                    store.notify(EventClass::String, "set", &key);
+                }
+            }
+            StoreCommand::MGet { id, keys } => {
+                respond(&clients, id, CommandResponse::Values(store.read_strings(&keys))).await
+            }
+            StoreCommand::MGetPart { keys, tx } => {
+                let _ = tx.send(store.read_strings(&keys));
+            }
+            StoreCommand::SetWith { id, key, value, options } => {
+                let (response, replicated) = store.set_with(&key, value, &options);
+                propagate(&replicas, replicated).await;
+                respond(&clients, id, response).await
+            }
+            StoreCommand::Get { id, key } => {
+                respond(&clients, id, CommandResponse::Get(store.read(&key))).await
+            }
+            StoreCommand::GetEx { id, key, ttl } => {
+                let (response, replicated) = store.get_ex(&key, ttl);
+                propagate(&replicas, replicated).await;
+                respond(&clients, id, response).await
+            }
+            StoreCommand::Expire { id, key, until, condition } => {
+                let updated = store.expire(&key, until, condition);
+                if updated {
+                    replicate(&replicas, pexpireat_command(&key, until)).await;
+                }
+                respond(&clients, id, CommandResponse::Integer(updated as i64)).await
+            }
+            StoreCommand::ExpireTime { id, key } => {
+                respond(&clients, id, CommandResponse::Integer(store.expire_time(&key))).await
+            }
+            StoreCommand::Persist { id, key } => {
+                let updated = store.persist(&key);
+                if updated {
+                    replicate(&replicas, command(&[b"PERSIST", &key])).await;
+                }
+                respond(&clients, id, CommandResponse::Integer(updated as i64)).await
+            }
+            StoreCommand::SetRange { id, key, offset, value } => {
+                let response = store.set_range(&key, offset, &value);
+                if matches!(response, CommandResponse::Integer(_)) && !value.is_empty() {
+                    let cmd = RedisType::Array(vec![
+                        RedisType::from("SETRANGE"),
+                        RedisType::from(key),
+                        RedisType::from(offset.to_string()),
+                        RedisType::from(value),
+                    ]);
+                    replicate(&replicas, cmd).await;
+                }
+                respond(&clients, id, response).await
+            }
+            StoreCommand::Append { id, key, value } => {
+                let response = store.append(&key, &value);
+                if let CommandResponse::Integer(_) = response {
+                    let cmd = RedisType::Array(vec![
+                        RedisType::from("APPEND"),
+                        RedisType::from(key),
+                        RedisType::from(value),
+                    ]);
+                    replicate(&replicas, cmd).await;
+                }
+                respond(&clients, id, response).await
+            }
+            StoreCommand::Push { id, key, values, left, must_exist } => {
+                let mut cmd = vec![RedisType::from(if left { "LPUSH" } else { "RPUSH" }), RedisType::from(key.as_slice())];
+                cmd.extend(values.iter().cloned().map(RedisType::from));
+                let response = store.push(&key, values, left, must_exist);
+                if let CommandResponse::Integer(1..) = response {
+                    replicate(&replicas, RedisType::Array(cmd)).await;
+                }
+                respond(&clients, id, response).await
+            }
+            StoreCommand::Pop { id, key, count, left } => {
+                let (response, replicated) = store.pop(&key, count, left);
+                propagate(&replicas, replicated).await;
+                respond(&clients, id, response).await
+            }
+            StoreCommand::LMove { id, source, destination, from_left, to_left } => {
+                let response = store.lmove(&source, &destination, from_left, to_left);
+                if let CommandResponse::Get(Some(_)) = response {
+                    let side = |left| if left { b"LEFT".as_slice() } else { b"RIGHT" };
+                    let cmd = command(&[b"LMOVE", &source, &destination, side(from_left), side(to_left)]);
+                    replicate(&replicas, cmd).await;
+                }
+                respond(&clients, id, response).await
+            }
+            StoreCommand::BlockPop { keys, left, tx } => {
+                // Nobody else gets to push during a transaction, so there's no point in waiting
+                let waiter = Arc::new(Mutex::new(Some(tx)));
+                block_pop(&mut store, &mut blocked, &replicas, keys, left, waiter, transaction.is_none()).await
+            }
+            StoreCommand::BlockPopShared { keys, left, waiter } => {
+                block_pop(&mut store, &mut blocked, &replicas, keys, left, waiter, true).await
+            }
+            StoreCommand::LRange { id, key, start, stop } => {
+                respond(&clients, id, store.lrange(&key, start, stop)).await
+            }
+            StoreCommand::LLen { id, key } => {
+                respond(&clients, id, store.llen(&key)).await
+            }
+            StoreCommand::LPos { id, key, element, options } => {
+                respond(&clients, id, store.lpos(&key, &element, &options)).await
+            }
+            StoreCommand::LIndex { id, key, index } => {
+                respond(&clients, id, store.lindex(&key, index)).await
+            }
+            StoreCommand::LSet { id, key, index, value } => {
+                let cmd = RedisType::Array(vec![
+                    RedisType::from("LSET"),
+                    RedisType::from(key.as_slice()),
+                    RedisType::from(index.to_string()),
+                    RedisType::from(value.clone()),
+                ]);
+                let response = store.lset(&key, index, value);
+                if let CommandResponse::Integer(_) = response {
+                    replicate(&replicas, cmd).await;
+                }
+                respond(&clients, id, response).await
+            }
+            StoreCommand::LInsert { id, key, before, pivot, value } => {
+                let cmd = RedisType::Array(vec![
+                    RedisType::from("LINSERT"),
+                    RedisType::from(key.as_slice()),
+                    RedisType::from(if before { "BEFORE" } else { "AFTER" }),
+                    RedisType::from(pivot.clone()),
+                    RedisType::from(value.clone()),
+                ]);
+                let response = store.linsert(&key, before, &pivot, value);
+                if let CommandResponse::Integer(1..) = response {
+                    replicate(&replicas, cmd).await;
+                }
+                respond(&clients, id, response).await
+            }
+            StoreCommand::LRem { id, key, count, value } => {
+                let response = store.lrem(&key, count, &value);
+                if let CommandResponse::Integer(1..) = response {
+                    let cmd = RedisType::Array(vec![
+                        RedisType::from("LREM"),
+                        RedisType::from(key),
+                        RedisType::from(count.to_string()),
+                        RedisType::from(value),
+                    ]);
+                    replicate(&replicas, cmd).await;
+                }
+                respond(&clients, id, response).await
+            }
+            StoreCommand::HSet { id, key, pairs } => {
+                let mut cmd = vec![RedisType::from("HSET"), RedisType::from(key.as_slice())];
+                for (field, value) in &pairs {
+                    cmd.push(RedisType::from(field.as_slice()));
+                    cmd.push(RedisType::from(value.as_slice()));
+                }
+                let response = store.hset(&key, pairs);
+                if let CommandResponse::Integer(_) = response {
+                    replicate(&replicas, RedisType::Array(cmd)).await;
+                }
+                respond(&clients, id, response).await
+            }
+            StoreCommand::HGet { id, key, field } => {
+                respond(&clients, id, store.hget(&key, &field)).await
+            }
+            StoreCommand::HMGet { id, key, fields } => {
+                respond(&clients, id, store.hmget(&key, &fields)).await
+            }
+            StoreCommand::HSetNx { id, key, field, value } => {
+                let response = store.hsetnx(&key, &field, &value);
+                if let CommandResponse::Integer(1) = response {
+                    replicate(&replicas, command(&[b"HSETNX", &key, &field, &value])).await;
+                }
+                respond(&clients, id, response).await
+            }
+            StoreCommand::HIncrBy { id, key, field, delta } => {
+                let response = store.hincr_by(&key, &field, delta);
+                if let CommandResponse::Integer(result) = response {
+                    let cmd = command(&[b"HSET", &key, &field, result.to_string().as_bytes()]);
+                    replicate(&replicas, cmd).await;
+                }
+                respond(&clients, id, response).await
+            }
+            StoreCommand::HIncrByFloat { id, key, field, delta } => {
+                let response = store.hincr_by_float(&key, &field, delta);
+                if let CommandResponse::Get(Some(result)) = &response {
+                    let cmd = vec![RedisType::from("HSET"), RedisType::from(key), RedisType::from(field), result.clone()];
+                    replicate(&replicas, RedisType::Array(cmd)).await;
+                }
+                respond(&clients, id, response).await
+            }
+            StoreCommand::HDel { id, key, fields } => {
+                let response = store.hdel(&key, &fields);
+                if let CommandResponse::Integer(1..) = response {
+                    let mut cmd = vec![b"HDEL".as_slice(), &key];
+                    cmd.extend(fields.iter().map(Vec::as_slice));
+                    replicate(&replicas, command(&cmd)).await;
+                }
+                respond(&clients, id, response).await
+            }
+            StoreCommand::HExists { id, key, field } => {
+                respond(&clients, id, store.hexists(&key, &field)).await
+            }
+            StoreCommand::HLen { id, key } => {
+                respond(&clients, id, store.hlen(&key)).await
+            }
+            StoreCommand::HStrLen { id, key, field } => {
+                respond(&clients, id, store.hstrlen(&key, &field)).await
+            }
+            StoreCommand::HGetAll { id, key } => {
+                respond(&clients, id, store.hgetall(&key)).await
+            }
+            StoreCommand::SAdd { id, key, members } => {
+                let mut cmd = vec![b"SADD".as_slice(), &key];
+                cmd.extend(members.iter().map(Vec::as_slice));
+                let cmd = command(&cmd);
+                let response = store.sadd(&key, members);
+                if let CommandResponse::Integer(1..) = response {
+                    replicate(&replicas, cmd).await;
+                }
+                respond(&clients, id, response).await
+            }
+            StoreCommand::SRem { id, key, members } => {
+                let response = store.srem(&key, &members);
+                if let CommandResponse::Integer(1..) = response {
+                    let mut cmd = vec![b"SREM".as_slice(), &key];
+                    cmd.extend(members.iter().map(Vec::as_slice));
+                    replicate(&replicas, command(&cmd)).await;
+                }
+                respond(&clients, id, response).await
+            }
+            StoreCommand::SMembers { id, key } => {
+                respond(&clients, id, store.smembers(&key)).await
+            }
+            StoreCommand::SIsMember { id, key, members } => {
+                respond(&clients, id, store.sismember(&key, &members)).await
+            }
+            StoreCommand::SCard { id, key } => {
+                respond(&clients, id, store.scard(&key)).await
+            }
+            StoreCommand::Combine { id, keys, operation } => {
+                respond(&clients, id, store.combine(&keys, operation)).await
+            }
+            StoreCommand::ZAdd { id, key, options, pairs } => {
+                let (response, replicated) = store.zadd(&key, &options, &pairs);
+                propagate(&replicas, replicated).await;
+                respond(&clients, id, response).await
+            }
+            StoreCommand::ZScore { id, key, member } => {
+                respond(&clients, id, store.zscore(&key, &member)).await
+            }
+            StoreCommand::ZRank { id, key, member, rev } => {
+                respond(&clients, id, store.zrank(&key, &member, rev)).await
+            }
+            StoreCommand::ZCard { id, key } => {
+                respond(&clients, id, store.zcard(&key)).await
+            }
+            StoreCommand::ZPop { id, key, count, max } => {
+                let (response, replicated) = store.zpop(&key, count, max);
+                propagate(&replicas, replicated).await;
+                respond(&clients, id, response).await
+            }
+            StoreCommand::ZRem { id, key, members } => {
+                let response = store.zrem(&key, &members);
+                if let CommandResponse::Integer(1..) = response {
+                    let mut cmd = vec![b"ZREM".as_slice(), &key];
+                    cmd.extend(members.iter().map(Vec::as_slice));
+                    replicate(&replicas, command(&cmd)).await;
+                }
+                respond(&clients, id, response).await
+            }
+            StoreCommand::ZCount { id, key, min, max } => {
+                respond(&clients, id, store.zcount(&key, &min, &max)).await
+            }
+            StoreCommand::ZRange { id, key, range } => {
+                respond(&clients, id, store.zrange(&key, &range)).await
+            }
+            StoreCommand::SMove { id, source, destination, member } => {
+                let response = store.smove(&source, &destination, &member);
+                if matches!(response, CommandResponse::Integer(1)) && source != destination {
+                    replicate(&replicas, command(&[b"SREM", &source, &member])).await;
+                    replicate(&replicas, command(&[b"SADD", &destination, &member])).await;
+                }
+                respond(&clients, id, response).await
+            }
+            StoreCommand::InterCard { id, keys, limit } => {
+                respond(&clients, id, store.intercard(&keys, limit)).await
+            }
+            StoreCommand::ZInterCard { id, keys, limit } => {
+                respond(&clients, id, store.zintercard(&keys, limit)).await
+            }
+            StoreCommand::CombineStore { id, destination, keys, operation } => {
+                let (response, replicated) = store.combine_store(&destination, &keys, operation);
+                for cmd in replicated {
+                    replicate(&replicas, cmd).await;
+                }
+                respond(&clients, id, response).await
+            }
+            StoreCommand::BitField { id, key, ops } => {
+                let response = store.bitfield(&key, &ops);
+                let writes = ops.iter().filter(|op| op.is_write()).collect::<Vec<_>>();
+                if matches!(response, CommandResponse::Values(_)) && !writes.is_empty() {
+                    let mut cmd = vec![RedisType::from("BITFIELD"), RedisType::from(key)];
+                    cmd.extend(writes.into_iter().flat_map(BitfieldOp::to_args).map(RedisType::from));
+                    replicate(&replicas, RedisType::Array(cmd)).await;
+                }
+                respond(&clients, id, response).await
+            }
+            StoreCommand::IncrBy { id, key, delta } => {
+                let response = store.incr_by(&key, delta);
+                if let CommandResponse::Integer(_) = response {
+                    let cmd = command(&[b"INCRBY", &key, delta.to_string().as_bytes()]);
+                    replicate(&replicas, cmd).await;
+                }
+                respond(&clients, id, response).await
+            }
+            StoreCommand::GetSet { id, key, value } => {
+                let response = store.get_set(&key, value.clone());
+                if let CommandResponse::Get(_) = response {
+                    replicate(&replicas, RedisType::Array(vec![
+                        RedisType::from("SET"),
+                        RedisType::from(key),
+                        value,
+                    ])).await;
+                }
+                respond(&clients, id, response).await
+            }
+            StoreCommand::SetNx { id, key, value } => {
+                let written = store.set_nx(&key, value.clone());
+                if written {
+                    replicate(&replicas, RedisType::Array(vec![
+                        RedisType::from("SET"),
+                        RedisType::from(key),
+                        value,
+                    ])).await;
+                }
+                respond(&clients, id, CommandResponse::Integer(written as i64)).await
+            }
+            StoreCommand::SetPackedThreshold(threshold) => {
+                store.packed_threshold = threshold;
+            }
+            StoreCommand::Type { id, key } => {
+                respond(&clients, id, CommandResponse::Type(store.type_of(&key))).await
+            }
+            StoreCommand::Delete { id, keys } => {
+                let removed = store.delete(&keys);
+                if removed > 0 {
+                    let mut cmd = vec![RedisType::from("DEL")];
+                    cmd.extend(keys.into_iter().map(RedisType::from));
+                    replicate(&replicas, RedisType::Array(cmd)).await;
+                }
+                respond(&clients, id, CommandResponse::Integer(removed as i64)).await
+            }
+            StoreCommand::Exists { id, keys } => {
+                respond(&clients, id, CommandResponse::Integer(store.exists(&keys) as i64)).await
+            }
+            StoreCommand::Copy { id, source, destination, replace } => {
+                let copied = store.copy(&source, &destination, replace);
+                if copied {
+                    let mut cmd = vec![b"COPY".as_slice(), &source, &destination];
+                    if replace {
+                        cmd.push(b"REPLACE");
+                    }
+                    replicate(&replicas, command(&cmd)).await;
+                }
+                respond(&clients, id, CommandResponse::Integer(copied as i64)).await
+            }
+            StoreCommand::Rename { id, key, new_key, nx } => {
+                let response = store.rename(&key, &new_key, nx);
+                if let CommandResponse::Integer(1) = response {
+                    let name = if nx { b"RENAMENX".as_slice() } else { b"RENAME" };
+                    replicate(&replicas, command(&[name, &key, &new_key])).await;
+                }
+                respond(&clients, id, response).await
+            }
+            StoreCommand::Replicate(cmd) => {
+                replicate(&replicas, cmd).await;
+            }
+            StoreCommand::ObjectEncoding { id, key } => {
+                respond(&clients, id, CommandResponse::Encoding(store.encoding_of(&key))).await
+            }
+            StoreCommand::DbSize(id) => {
+                respond(&clients, id, CommandResponse::Integer(store.size() as i64)).await
+            }
+            StoreCommand::FlushDb(id) => {
+                store.flush();
+                replicate(&replicas, RedisType::from(vec!["FLUSHDB"])).await;
+                respond(&clients, id, CommandResponse::Integer(0)).await
+            }
+            StoreCommand::RandomKey(id) => {
+                let key = store.random_key().map(RedisType::from);
+                respond(&clients, id, CommandResponse::Get(key)).await
+            }
+            StoreCommand::Keys { id, pattern } => {
+                let keys = store.keys(&pattern)
+                    .into_iter()
+                    .map(RedisType::from)
+                    .collect::<Vec<_>>();
+                respond(&clients, id, CommandResponse::Keys(RedisType::Array(keys))).await
+            }
+            StoreCommand::Scan { id, cursor, count, pattern, kind } => {
+                let (cursor, keys) = store.scan(cursor, count, pattern.as_deref(), kind.as_deref());
+                respond(&clients, id, CommandResponse::Scan { cursor, keys }).await
+            }
+            StoreCommand::ReplicaCount(id) => {
+                // TODO: The replica count is very naive because at the moment we're not doing
+                //       anything about disconnected clients.
+                respond(&clients, id, CommandResponse::ReplicaCount(replicas.len())).await
+            }
+        }
+
+        let notifications = store.take_notifications();
+        match &hub {
+            Some(hub) if !notifications.is_empty() => {
+                let _ = hub.send(StoreCommand::Notify(notifications)).await;
+            }
+            Some(_) => {}
+            None => {
+                for (channel, message) in notifications {
+                    pubsub.publish(&channel, Bytes::from(message));
+                }
+            }
+        }
+
+        // Any command might have given elements to the lists they're waiting for
+        if !blocked.is_empty() {
+            serve_blocked(&mut store, &mut blocked, &replicas).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use bytes::Bytes;
+    use itertools::Itertools;
+    use tokio::sync::{mpsc::{self, Receiver, Sender}, oneshot};
+
+    use crate::{
+        notify::NotifyFlags,
+        store::{
+            store_loop, CommandResponse, ExpireCondition, LPosOptions, SetCondition, SetOperation, SetOptions, SetTtl,
+            Store, StoreCommand, TtlChange, CMD_BUFFER,
+        },
+        types::RedisType,
+    };
+
+    struct Harness {
+        store_tx: Sender<StoreCommand>,
+        client_rx: Receiver<CommandResponse>,
+        replica_rx: Receiver<Bytes>,
+        id: usize,
+    }
+
+    async fn start(store: Store) -> Harness {
+        let (store_tx, store_rx) = mpsc::channel(CMD_BUFFER);
+        tokio::spawn(store_loop(store, store_rx));
+
+        let (client_tx, mut client_rx) = mpsc::channel(CMD_BUFFER);
+        store_tx.send(StoreCommand::InitClient(client_tx)).await.unwrap();
+        let Some(CommandResponse::ClientId(id)) = client_rx.recv().await else { panic!() };
+
+        let (replica_tx, replica_rx) = mpsc::channel(CMD_BUFFER);
+        let (snapshot, _) = oneshot::channel();
+        store_tx.send(StoreCommand::InitReplica { tx: replica_tx, snapshot }).await.unwrap();
+
+        Harness { store_tx, client_rx, replica_rx, id }
+    }
+
+    fn frame(parts: &[&str]) -> Vec<u8> {
+        RedisType::from(parts.to_vec()).to_vec()
+    }
+
+    impl Harness {
+        async fn getex(&mut self, key: &str, ttl: Option<TtlChange>) -> Option<RedisType> {
+            let cmd = StoreCommand::GetEx { id: self.id, key: key.into(), ttl };
+            self.store_tx.send(cmd).await.unwrap();
+            match self.client_rx.recv().await {
+                Some(CommandResponse::Get(value)) => value,
+                _ => panic!("unexpected response to GETEX"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_getex_propagation() {
+        let mut store = Store::default();
+        let later = SystemTime::now() + Duration::from_secs(3600);
+        store.write(b"permanent", RedisType::from("a"), None);
+        store.write(b"volatile", RedisType::from("b"), Some(later));
+        let mut harness = start(store).await;
+
+        // A plain GETEX doesn't change anything
+        assert_eq!(harness.getex("permanent", None).await, Some(RedisType::from("a")));
+
+        let at = UNIX_EPOCH + Duration::from_millis(4102444800000);
+        assert_eq!(harness.getex("permanent", Some(TtlChange::ExpireAt(at))).await, Some(RedisType::from("a")));
+        assert_eq!(harness.replica_rx.recv().await.unwrap(), frame(&["PEXPIREAT", "permanent", "4102444800000"]));
+
+        assert_eq!(harness.getex("volatile", Some(TtlChange::Persist)).await, Some(RedisType::from("b")));
+        assert_eq!(harness.replica_rx.recv().await.unwrap(), frame(&["PERSIST", "volatile"]));
+
+        // Nothing to persist, nothing to propagate. Missing keys aren't propagated either
+        assert_eq!(harness.getex("volatile", Some(TtlChange::Persist)).await, Some(RedisType::from("b")));
+        assert_eq!(harness.getex("missing", Some(TtlChange::ExpireAt(at))).await, None);
+
+        harness.store_tx.send(StoreCommand::Set { key: "next".into(), value: RedisType::from("c") }).await.unwrap();
+        assert_eq!(harness.replica_rx.recv().await.unwrap(), frame(&["SET", "next", "c"]));
+    }
+
+    #[tokio::test]
+    async fn test_load_with_replicas() {
+        let mut harness = start(Store::default()).await;
+        let later = SystemTime::now() + Duration::from_secs(3600);
+
+        // Loaded values can be of any type, and they aren't propagated
+        let list = RedisType::List(["a", "b"].iter().map(|element| element.as_bytes().to_vec()).collect());
+        let hash = RedisType::Hash([(b"field".to_vec(), b"value".to_vec())].into());
+        harness.store_tx.send(StoreCommand::Load { key: "list".into(), value: list, expires: None }).await.unwrap();
+        harness.store_tx.send(StoreCommand::Load { key: "hash".into(), value: hash, expires: Some(later) }).await.unwrap();
+        harness.store_tx.send(StoreCommand::Set { key: "next".into(), value: RedisType::from("c") }).await.unwrap();
+        assert_eq!(harness.replica_rx.recv().await.unwrap(), frame(&["SET", "next", "c"]));
+
+        for (key, kind) in [("list", "list"), ("hash", "hash")] {
+            harness.store_tx.send(StoreCommand::Type { id: harness.id, key: key.into() }).await.unwrap();
+            assert!(matches!(harness.client_rx.recv().await, Some(CommandResponse::Type(found)) if found == kind));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blocked_pop() {
+        let mut harness = start(Store::default()).await;
+        let block = |keys: &[&str]| {
+            let (tx, rx) = oneshot::channel();
+            let keys = keys.iter().map(|key| key.as_bytes().to_vec()).collect();
+            (StoreCommand::BlockPop { keys, left: true, tx }, rx)
+        };
+        let push = |id, key: &str, value: &str| {
+            StoreCommand::Push { id, key: key.into(), values: vec![value.into()], left: false, must_exist: false }
+        };
+
+        // This one gives up before anything arrives
+        let (cmd, gave_up) = block(&["first"]);
+        harness.store_tx.send(cmd).await.unwrap();
+        drop(gave_up);
+        let (cmd, mut waiting) = block(&["second", "first"]);
+        harness.store_tx.send(cmd).await.unwrap();
+        assert!(waiting.try_recv().is_err());
+
+        harness.store_tx.send(push(harness.id, "first", "a")).await.unwrap();
+        assert!(matches!(harness.client_rx.recv().await, Some(CommandResponse::Integer(1))));
+        let Ok(CommandResponse::Get(Some(pair))) = waiting.await else { panic!("expected a key and a value") };
+        assert_eq!(pair, RedisType::from(vec!["first", "a"]));
+        assert_eq!(harness.replica_rx.recv().await.unwrap(), frame(&["RPUSH", "first", "a"]));
+        assert_eq!(harness.replica_rx.recv().await.unwrap(), frame(&["LPOP", "first"]));
+
+        // Lists with elements are served right away
+        harness.store_tx.send(push(harness.id, "second", "b")).await.unwrap();
+        harness.client_rx.recv().await.unwrap();
+        let (cmd, rx) = block(&["first", "second"]);
+        harness.store_tx.send(cmd).await.unwrap();
+        let Ok(CommandResponse::Get(Some(pair))) = rx.await else { panic!("expected a key and a value") };
+        assert_eq!(pair, RedisType::from(vec!["second", "b"]));
+    }
+
+    #[test]
+    fn test_set_range() {
+        let mut store = Store::default();
+        let later = SystemTime::now() + Duration::from_secs(3600);
+        store.write(b"key", RedisType::from("Hello World"), Some(later));
+
+        assert!(matches!(store.set_range(b"key", 6, b"Redis"), CommandResponse::Integer(11)));
+        assert_eq!(store.read(b"key"), Some(RedisType::from("Hello Redis")));
+        // The TTL is kept
+        assert!(store.persist(b"key"));
+
+        // Missing keys are padded with zeros
+        assert!(matches!(store.set_range(b"new", 3, b"abc"), CommandResponse::Integer(6)));
+        assert_eq!(store.read(b"new"), Some(RedisType::from(b"\0\0\0abc".to_vec())));
+
+        assert!(matches!(store.set_range(b"other", 10, b""), CommandResponse::Integer(0)));
+        assert_eq!(store.read(b"other"), None);
+
+        store.write(b"number", RedisType::Int(12), None);
+        assert!(matches!(store.set_range(b"number", 1, b"3"), CommandResponse::Integer(2)));
+        assert_eq!(store.read(b"number"), Some(RedisType::from("13")));
+
+        store.write(b"array", RedisType::Array(vec![]), None);
+        assert!(matches!(store.set_range(b"array", 0, b"x"), CommandResponse::WrongType));
+    }
+
+    #[tokio::test]
+    async fn test_set_expiry_propagation() {
+        let mut harness = start(Store::default()).await;
+
+        let at = UNIX_EPOCH + Duration::from_secs(4102444800);
+        let options = SetOptions { ttl: SetTtl::ExpireAt(at), ..Default::default() };
+        let cmd = StoreCommand::SetWith { id: harness.id, key: "key".into(), value: RedisType::from("v"), options };
+        harness.store_tx.send(cmd).await.unwrap();
+        assert!(matches!(harness.client_rx.recv().await, Some(CommandResponse::Integer(1))));
+
+        // Replicas get the absolute time, whatever the form used by the client
+        assert_eq!(harness.replica_rx.recv().await.unwrap(), frame(&["SET", "key", "v", "PXAT", "4102444800000"]));
+    }
+
+    #[tokio::test]
+    async fn test_expire_propagation() {
+        let mut store = Store::default();
+        store.write(b"key", RedisType::from("value"), None);
+        let mut harness = start(store).await;
+
+        let until = UNIX_EPOCH + Duration::from_millis(4102444800000);
+        for key in ["missing", "key"] {
+            harness.store_tx.send(StoreCommand::Expire { id: harness.id, key: key.into(), until, condition: ExpireCondition::default() }).await.unwrap();
+            harness.client_rx.recv().await.unwrap();
+        }
+
+        // Only the key that exists is propagated, with the absolute time
+        assert_eq!(harness.replica_rx.recv().await.unwrap(), frame(&["PEXPIREAT", "key", "4102444800000"]));
+    }
+
+    #[tokio::test]
+    async fn test_bitfield() {
+        let mut store = Store::default();
+        store.write(b"array", RedisType::Array(vec![]), None);
+        let mut harness = start(store).await;
+        let ops = crate::bitfield::parse_bitfield(&["GET", "u8", "0", "OVERFLOW", "SAT", "INCRBY", "u8", "0", "300"]).unwrap();
+
+        let cmd = StoreCommand::BitField { id: harness.id, key: "array".into(), ops: ops.clone() };
+        harness.store_tx.send(cmd).await.unwrap();
+        assert!(matches!(harness.client_rx.recv().await, Some(CommandResponse::WrongType)));
+
+        // Only the writes are propagated
+        harness.store_tx.send(StoreCommand::BitField { id: harness.id, key: "bits".into(), ops }).await.unwrap();
+        let Some(CommandResponse::Values(values)) = harness.client_rx.recv().await else { panic!() };
+        assert_eq!(values, vec![Some(RedisType::Int(0)), Some(RedisType::Int(255))]);
+        let expected = frame(&["BITFIELD", "bits", "OVERFLOW", "SAT", "INCRBY", "u8", "0", "300"]);
+        assert_eq!(harness.replica_rx.recv().await.unwrap(), expected);
+        assert_eq!(harness.getex("bits", None).await, Some(RedisType::from(vec![255u8])));
+    }
+
+    #[test]
+    fn test_active_expire() {
+        let mut store = Store::default();
+        let later = SystemTime::now() + Duration::from_secs(3600);
+        for n in 0..100 {
+            store.write(format!("expired{n}").as_bytes(), RedisType::from("x"), Some(UNIX_EPOCH));
+            store.write(format!("permanent{n}").as_bytes(), RedisType::from("x"), None);
+        }
+        store.write(b"volatile", RedisType::from("x"), Some(later));
+
+        // Rounds go on while more than a quarter of the sample has expired
+        let removed = store.active_expire();
+        assert_eq!(removed.len(), 100);
+        assert!(removed.iter().all(|key| key.starts_with(b"expired")));
+        assert_eq!(store.size(), 101);
+
+        let mut store = Store::default();
+        store.write(b"permanent", RedisType::from("x"), None);
+        assert!(store.active_expire().is_empty());
+    }
+
+    #[test]
+    fn test_volatile_index() {
+        let mut store = Store::default();
+        let later = SystemTime::now() + Duration::from_secs(3600);
+        let volatile = |store: &Store| store.volatile.keys.iter().map(|key| String::from_utf8_lossy(key).to_string()).sorted().collect::<Vec<_>>();
+        for key in ["a", "b", "c"] {
+            store.write(key.as_bytes(), RedisType::from("x"), Some(later));
+        }
+        store.write(b"permanent", RedisType::from("x"), None);
+        assert_eq!(volatile(&store), ["a", "b", "c"]);
+
+        store.persist(b"a");
+        store.delete(&[b"b".to_vec()]);
+        store.rename(b"c", b"d", false);
+        store.copy(b"d", b"e", false);
+        store.expire(b"permanent", later, ExpireCondition::default());
+        assert_eq!(volatile(&store), ["d", "e", "permanent"]);
+        // Positions are kept right as keys are swapped around
+        assert!(store.volatile.keys.iter().enumerate().all(|(position, key)| store.volatile.positions[key] == position));
+
+        store.write(b"d", RedisType::from("x"), None);
+        assert_eq!(volatile(&store), ["e", "permanent"]);
+        store.flush();
+        assert!(volatile(&store).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_active_expire_propagation() {
+        let mut store = Store::default();
+        store.write(b"expired", RedisType::from("x"), Some(UNIX_EPOCH));
+        let mut harness = start(store).await;
+
+        harness.store_tx.send(StoreCommand::ExpireCycle).await.unwrap();
+        assert_eq!(harness.replica_rx.recv().await.unwrap(), frame(&["DEL", "expired"]));
+        harness.store_tx.send(StoreCommand::DbSize(harness.id)).await.unwrap();
+        assert!(matches!(harness.client_rx.recv().await, Some(CommandResponse::Integer(0))));
+    }
+
+    #[tokio::test]
+    async fn test_delete_propagation() {
+        let mut store = Store::default();
+        store.write(b"a", RedisType::from("1"), None);
+        store.write(b"b", RedisType::from("2"), Some(UNIX_EPOCH));
+        let mut harness = start(store).await;
+
+        // Expired keys don't count
+        let keys = vec![b"a".to_vec(), b"a".to_vec(), b"b".to_vec()];
+        harness.store_tx.send(StoreCommand::Exists { id: harness.id, keys: keys.clone() }).await.unwrap();
+        assert!(matches!(harness.client_rx.recv().await, Some(CommandResponse::Integer(2))));
+
+        harness.store_tx.send(StoreCommand::Delete { id: harness.id, keys: keys.clone() }).await.unwrap();
+        assert!(matches!(harness.client_rx.recv().await, Some(CommandResponse::Integer(1))));
+        assert_eq!(harness.replica_rx.recv().await.unwrap(), frame(&["DEL", "a", "a", "b"]));
+
+        // Nothing left to delete, nothing to propagate
+        harness.store_tx.send(StoreCommand::Delete { id: harness.id, keys }).await.unwrap();
+        assert!(matches!(harness.client_rx.recv().await, Some(CommandResponse::Integer(0))));
+        harness.store_tx.send(StoreCommand::Set { key: "next".into(), value: RedisType::from("c") }).await.unwrap();
+        assert_eq!(harness.replica_rx.recv().await.unwrap(), frame(&["SET", "next", "c"]));
+    }
+
+    #[tokio::test]
+    async fn test_mset_propagation() {
+        let mut harness = start(Store::default()).await;
+
+        let pairs = vec![("a".into(), RedisType::from("1")), ("b".into(), RedisType::from("2"))];
+        harness.store_tx.send(StoreCommand::MSet { pairs }).await.unwrap();
+        assert_eq!(harness.replica_rx.recv().await.unwrap(), frame(&["MSET", "a", "1", "b", "2"]));
+
+        let keys = vec!["a".into(), "missing".into(), "b".into()];
+        harness.store_tx.send(StoreCommand::MGet { id: harness.id, keys }).await.unwrap();
+        match harness.client_rx.recv().await {
+            Some(CommandResponse::Values(values)) => {
+                assert_eq!(values, vec![Some(RedisType::from("1")), None, Some(RedisType::from("2"))])
+            }
+            _ => panic!("unexpected response to MGET"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_setnx_propagation() {
+        let mut harness = start(Store::default()).await;
+
+        for expected in [1, 0] {
+            let cmd = StoreCommand::SetNx { id: harness.id, key: "key".into(), value: RedisType::from("value") };
+            harness.store_tx.send(cmd).await.unwrap();
+            assert!(matches!(harness.client_rx.recv().await, Some(CommandResponse::Integer(n)) if n == expected));
+        }
+        harness.store_tx.send(StoreCommand::Set { key: "next".into(), value: RedisType::from("c") }).await.unwrap();
+
+        // Only the successful SETNX is propagated
+        assert_eq!(harness.replica_rx.recv().await.unwrap(), frame(&["SET", "key", "value"]));
+        assert_eq!(harness.replica_rx.recv().await.unwrap(), frame(&["SET", "next", "c"]));
+    }
+
+    #[test]
+    fn test_set_with() {
+        let mut store = Store::default();
+        let later = SystemTime::now() + Duration::from_secs(3600);
+        let nx = SetOptions { condition: Some(SetCondition::IfMissing), ..Default::default() };
+        let xx = SetOptions { condition: Some(SetCondition::IfExists), ..Default::default() };
+
+        let (response, propagated) = store.set_with(b"key", RedisType::from("a"), &xx);
+        assert!(matches!(response, CommandResponse::Integer(0)));
+        assert!(propagated.is_none());
+        assert_eq!(store.read(b"key"), None);
+
+        let (response, _) = store.set_with(b"key", RedisType::from("a"), &nx);
+        assert!(matches!(response, CommandResponse::Integer(1)));
+        let (response, _) = store.set_with(b"key", RedisType::from("b"), &nx);
+        assert!(matches!(response, CommandResponse::Integer(0)));
+        assert_eq!(store.read(b"key"), Some(RedisType::from("a")));
+
+        // GET returns the previous value, even if nothing is written
+        let get_nx = SetOptions { get: true, ..nx };
+        let (response, _) = store.set_with(b"key", RedisType::from("b"), &get_nx);
+        assert!(matches!(response, CommandResponse::Get(Some(v)) if v == RedisType::from("a")));
+
+        let at = SetOptions { ttl: SetTtl::ExpireAt(later), ..Default::default() };
+        store.set_with(b"key", RedisType::from("c"), &at);
+        let keep = SetOptions { ttl: SetTtl::Keep, ..Default::default() };
+        let (_, propagated) = store.set_with(b"key", RedisType::from("d"), &keep);
+        let millis = later.duration_since(UNIX_EPOCH).unwrap().as_millis().to_string();
+        assert_eq!(propagated, Some(RedisType::from(vec!["SET", "key", "d", "PXAT", millis.as_str()])));
+
+        // Without KEEPTTL, the TTL is discarded
+        store.set_with(b"key", RedisType::from("e"), &SetOptions::default());
+        assert!(!store.persist(b"key"));
+
+        store.write(b"array", RedisType::Array(vec![]), None);
+        let get = SetOptions { get: true, ..Default::default() };
+        assert!(matches!(store.set_with(b"array", RedisType::from("x"), &get).0, CommandResponse::WrongType));
+    }
+
+    #[test]
+    fn test_type_of() {
+        let mut store = Store::default();
+        store.write(b"string", RedisType::from("value"), None);
+        store.write(b"number", RedisType::Int(10), None);
+        store.write(b"expired", RedisType::from("value"), Some(UNIX_EPOCH));
+
+        assert_eq!(store.type_of(b"string"), "string");
+        assert_eq!(store.type_of(b"number"), "string");
+        assert_eq!(store.type_of(b"expired"), "none");
+        assert_eq!(store.type_of(b"missing"), "none");
+    }
+
+    #[test]
+    fn test_string_encoding() {
+        let mut store = Store::default();
+        store.write(b"short", RedisType::from("x".repeat(44)), None);
+        store.write(b"long", RedisType::from("x".repeat(45)), None);
+        store.write(b"number", RedisType::from("-12345"), None);
+        store.write(b"padded", RedisType::from("012345"), None);
+
+        assert_eq!(store.encoding_of(b"short"), Some("embstr"));
+        assert_eq!(store.encoding_of(b"long"), Some("raw"));
+        assert_eq!(store.encoding_of(b"number"), Some("int"));
+        assert_eq!(store.encoding_of(b"padded"), Some("embstr"));
+        assert_eq!(store.encoding_of(b"missing"), None);
+
+        // Modifying strings in place makes them raw, even if they're short
+        store.append(b"number", b"6");
+        assert_eq!(store.encoding_of(b"number"), Some("raw"));
+        store.set_range(b"new", 0, b"abc");
+        assert_eq!(store.encoding_of(b"new"), Some("raw"));
+
+        // ... until they're written again
+        store.incr_by(b"number", 1);
+        assert_eq!(store.encoding_of(b"number"), Some("int"));
+        store.write(b"new", RedisType::from("abc"), None);
+        assert_eq!(store.encoding_of(b"new"), Some("embstr"));
+    }
+
+    #[test]
+    fn test_list_encoding() {
+        let mut store = Store::default();
+        let elements = |size: usize, count: usize| RedisType::List(vec![vec![b'x'; size]; count].into());
+        store.write(b"small", elements(10, 10), None);
+        store.write(b"big", elements(100, 100), None);
+        store.write(b"medium", elements(500, 5), None);
+
+        assert_eq!(store.encoding_of(b"small"), Some("listpack"));
+        assert_eq!(store.encoding_of(b"big"), Some("quicklist"));
+        assert_eq!(store.encoding_of(b"medium"), Some("listpack"));
+
+        // Elements over the threshold don't fit in a listpack
+        store.packed_threshold = 100;
+        assert_eq!(store.encoding_of(b"small"), Some("listpack"));
+        assert_eq!(store.encoding_of(b"medium"), Some("quicklist"));
+    }
+
+    #[test]
+    fn test_rename() {
+        let mut store = Store::default();
+        let later = UNIX_EPOCH + Duration::from_millis(4102444800123);
+        store.write(b"volatile", RedisType::from("a"), Some(later));
+        store.write(b"other", RedisType::from("b"), None);
+
+        // The TTL goes along with the value
+        assert!(matches!(store.rename(b"volatile", b"moved", false), CommandResponse::Integer(1)));
+        assert_eq!(store.read(b"volatile"), None);
+        assert_eq!(store.read(b"moved"), Some(RedisType::from("a")));
+        assert_eq!(store.expire_time(b"moved"), 4102444800123);
+
+        assert!(matches!(store.rename(b"moved", b"other", true), CommandResponse::Integer(0)));
+        assert_eq!(store.read(b"other"), Some(RedisType::from("b")));
+        assert!(matches!(store.rename(b"other", b"moved", false), CommandResponse::Integer(1)));
+        assert_eq!(store.read(b"moved"), Some(RedisType::from("b")));
+        assert_eq!(store.expire_time(b"moved"), -1);
+
+        assert!(matches!(store.rename(b"missing", b"moved", true), CommandResponse::Error(_)));
+        assert!(matches!(store.rename(b"moved", b"moved", false), CommandResponse::Integer(1)));
+        assert!(matches!(store.rename(b"moved", b"moved", true), CommandResponse::Integer(0)));
+    }
+
+    #[test]
+    fn test_random_key() {
+        let mut store = Store::default();
+        assert_eq!(store.random_key(), None);
+
+        // Expired keys are never picked
+        for index in 0..10 {
+            store.write(format!("expired:{index}").as_bytes(), RedisType::from("value"), Some(UNIX_EPOCH));
+        }
+        assert_eq!(store.random_key(), None);
+        assert!(store.data.is_empty());
+
+        store.write(b"a", RedisType::from("value"), None);
+        store.write(b"b", RedisType::from("value"), None);
+        let picked: std::collections::HashSet<_> = (0..100).filter_map(|_| store.random_key()).collect();
+        assert_eq!(picked.len(), 2);
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut store = Store::default();
+        for key in ["user:1", "user:2", "hello", "hallo", "hillo"] {
+            store.write(key.as_bytes(), RedisType::from("value"), None);
+        }
+        store.write(b"user:3", RedisType::from("value"), Some(UNIX_EPOCH));
+
+        let keys = |store: &Store, pattern: &str| store.keys(pattern.as_bytes()).into_iter().sorted().collect::<Vec<_>>();
+        assert_eq!(keys(&store, "user:*"), vec![b"user:1", b"user:2"]);
+        assert_eq!(keys(&store, "h[ae]llo"), vec![b"hallo", b"hello"]);
+        assert_eq!(keys(&store, "*").len(), 5);
+        assert!(keys(&store, "nothing").is_empty());
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut store = Store::default();
+        for index in 0..100 {
+            store.write(format!("key:{index}").as_bytes(), RedisType::from("value"), None);
+        }
+        store.write(b"list", RedisType::List(Default::default()), None);
+        store.write(b"expired", RedisType::from("value"), Some(UNIX_EPOCH));
+
+        // Keys written and removed halfway don't get in the way of the rest
+        let mut seen = std::collections::HashSet::new();
+        let mut cursor = 0;
+        let mut steps = 0;
+        loop {
+            let (next, keys) = store.scan(cursor, 10, None, None);
+            seen.extend(keys);
+            store.write(format!("new:{steps}").as_bytes(), RedisType::from("value"), None);
+            store.delete(&[format!("new:{}", steps / 2).into_bytes()]);
+            steps += 1;
+            cursor = next;
+            if cursor == 0 {
+                break
+            }
+        }
+        assert!((0..100).all(|index| seen.contains(format!("key:{index}").as_bytes())));
+        assert!(seen.contains(b"list".as_slice()));
+        assert!(!seen.contains(b"expired".as_slice()));
+
+        let (cursor, keys) = store.scan(0, 1000, Some(b"key:1?"), None);
+        assert_eq!(cursor, 0);
+        assert_eq!(keys.len(), 10);
+        assert_eq!(store.scan(0, 1000, None, Some("list")).1, vec![b"list"]);
+    }
+
+    #[test]
+    fn test_copy() {
+        let mut store = Store::default();
+        let later = UNIX_EPOCH + Duration::from_millis(4102444800123);
+        store.write(b"volatile", RedisType::from("a"), Some(later));
+        store.write(b"other", RedisType::from("b"), None);
+
+        assert!(store.copy(b"volatile", b"copied", false));
+        assert_eq!(store.read(b"copied"), Some(RedisType::from("a")));
+        assert_eq!(store.expire_time(b"copied"), 4102444800123);
+
+        // Both TTLs are independent
+        assert!(store.persist(b"copied"));
+        assert_eq!(store.expire_time(b"volatile"), 4102444800123);
+
+        assert!(!store.copy(b"volatile", b"other", false));
+        assert_eq!(store.read(b"other"), Some(RedisType::from("b")));
+        assert!(store.copy(b"volatile", b"other", true));
+        assert_eq!(store.read(b"other"), Some(RedisType::from("a")));
+        assert!(!store.copy(b"missing", b"other", true));
+    }
+
+    #[test]
+    fn test_push() {
+        let list = |values: &[&str]| RedisType::List(values.iter().map(|value| value.as_bytes().to_vec()).collect());
+        let values = |values: &[&str]| values.iter().map(|value| value.as_bytes().to_vec()).collect::<Vec<_>>();
+        let mut store = Store::default();
+
+        assert!(matches!(store.push(b"list", values(&["a", "b"]), false, false), CommandResponse::Integer(2)));
+        assert!(matches!(store.push(b"list", values(&["c", "d"]), true, false), CommandResponse::Integer(4)));
+        assert_eq!(store.read(b"list"), Some(list(&["d", "c", "a", "b"])));
+        assert_eq!(store.type_of(b"list"), "list");
+
+        store.write(b"string", RedisType::from("value"), None);
+        assert!(matches!(store.push(b"string", values(&["a"]), true, false), CommandResponse::WrongType));
+        assert_eq!(store.read(b"string"), Some(RedisType::from("value")));
+
+        // LPUSHX and RPUSHX
+        assert!(matches!(store.push(b"list", values(&["e"]), false, true), CommandResponse::Integer(5)));
+        assert!(matches!(store.push(b"missing", values(&["a"]), true, true), CommandResponse::Integer(0)));
+        assert_eq!(store.read(b"missing"), None);
+        assert!(matches!(store.push(b"string", values(&["a"]), true, true), CommandResponse::WrongType));
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut store = Store::default();
+        let later = UNIX_EPOCH + Duration::from_millis(4102444800123);
+        let earlier = SystemTime::now() - Duration::from_secs(10);
+        store.write(b"permanent", RedisType::from("a"), None);
+        store.write(b"volatile", RedisType::from("b"), Some(later));
+        store.write(b"expired", RedisType::from("c"), Some(earlier));
+
+        let mut entries = store.iter()
+            .map(|(key, value, expires)| (String::from_utf8_lossy(key).to_string(), value.clone(), expires))
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(entries, vec![
+            ("expired".to_string(), RedisType::from("c"), Some(earlier)),
+            ("permanent".to_string(), RedisType::from("a"), None),
+            ("volatile".to_string(), RedisType::from("b"), Some(later)),
+        ]);
+
+        // Still there after iterating
+        assert_eq!(store.size(), 3);
+    }
+
+    #[test]
+    fn test_pop() {
+        let values = |values: &[&str]| values.iter().map(|value| value.as_bytes().to_vec()).collect::<Vec<_>>();
+        let list = |values: &[&str]| RedisType::List(values.iter().map(|value| value.as_bytes().to_vec()).collect());
+        let mut store = Store::default();
+        store.push(b"list", values(&["a", "b", "c", "d", "e"]), false, false);
+
+        let (response, replicated) = store.pop(b"list", None, true);
+        assert!(matches!(response, CommandResponse::Get(Some(v)) if v == RedisType::from("a")));
+        assert_eq!(replicated, Some(RedisType::from(vec!["LPOP", "list"])));
+
+        let (response, replicated) = store.pop(b"list", Some(2), false);
+        assert!(matches!(response, CommandResponse::Get(Some(v)) if v == list(&["e", "d"])));
+        assert_eq!(replicated, Some(RedisType::from(vec!["RPOP", "list", "2"])));
+
+        // Only what's left is popped, and the key goes away with it
+        let (response, replicated) = store.pop(b"list", Some(10), true);
+        assert!(matches!(response, CommandResponse::Get(Some(v)) if v == list(&["b", "c"])));
+        assert_eq!(replicated, Some(RedisType::from(vec!["LPOP", "list", "2"])));
+        assert_eq!(store.read(b"list"), None);
+
+        assert!(matches!(store.pop(b"list", None, true), (CommandResponse::Get(None), None)));
+        store.write(b"string", RedisType::from("value"), None);
+        assert!(matches!(store.pop(b"string", Some(1), true), (CommandResponse::WrongType, None)));
+    }
+
+    #[test]
+    fn test_lmove() {
+        let values = |values: &[&str]| values.iter().map(|value| value.as_bytes().to_vec()).collect::<Vec<_>>();
+        let list = |values: &[&str]| RedisType::List(values.iter().map(|value| value.as_bytes().to_vec()).collect());
+        let mut store = Store::default();
+        store.push(b"source", values(&["a", "b", "c"]), false, false);
+
+        assert!(matches!(store.lmove(b"source", b"destination", false, true), CommandResponse::Get(Some(v)) if v == RedisType::from("c")));
+        assert!(matches!(store.lmove(b"source", b"destination", true, false), CommandResponse::Get(Some(v)) if v == RedisType::from("a")));
+        assert_eq!(store.read(b"source"), Some(list(&["b"])));
+        assert_eq!(store.read(b"destination"), Some(list(&["c", "a"])));
+
+        // Rotation
+        assert!(matches!(store.lmove(b"destination", b"destination", true, false), CommandResponse::Get(Some(v)) if v == RedisType::from("c")));
+        assert_eq!(store.read(b"destination"), Some(list(&["a", "c"])));
+        assert!(matches!(store.lmove(b"source", b"source", true, true), CommandResponse::Get(Some(v)) if v == RedisType::from("b")));
+        assert_eq!(store.read(b"source"), Some(list(&["b"])));
+
+        assert!(matches!(store.lmove(b"missing", b"destination", true, true), CommandResponse::Get(None)));
+        store.write(b"string", RedisType::from("value"), None);
+        assert!(matches!(store.lmove(b"source", b"string", true, true), CommandResponse::WrongType));
+        assert!(matches!(store.lmove(b"string", b"source", true, true), CommandResponse::WrongType));
+        assert_eq!(store.read(b"source"), Some(list(&["b"])));
+    }
+
+    #[test]
+    fn test_lrange() {
+        let values = |values: &[&str]| values.iter().map(|value| value.as_bytes().to_vec()).collect::<Vec<_>>();
+        let range = |store: &mut Store, start, stop| match store.lrange(b"list", start, stop) {
+            CommandResponse::Get(Some(RedisType::List(list))) => list.into_iter().collect::<Vec<_>>(),
+            _ => panic!("expected a list"),
+        };
+        let mut store = Store::default();
+        store.push(b"list", values(&["a", "b", "c", "d", "e"]), false, false);
+
+        assert_eq!(range(&mut store, 0, -1), values(&["a", "b", "c", "d", "e"]));
+        assert_eq!(range(&mut store, 1, 2), values(&["b", "c"]));
+        assert_eq!(range(&mut store, -2, -1), values(&["d", "e"]));
+        assert_eq!(range(&mut store, -100, 1), values(&["a", "b"]));
+        assert_eq!(range(&mut store, 3, 100), values(&["d", "e"]));
+        assert!(range(&mut store, 3, 1).is_empty());
+        assert!(range(&mut store, 5, 10).is_empty());
+        assert!(range(&mut store, 0, -100).is_empty());
+
+        assert!(matches!(store.lrange(b"missing", 0, -1), CommandResponse::Get(Some(RedisType::List(list))) if list.is_empty()));
+        store.write(b"string", RedisType::from("value"), None);
+        assert!(matches!(store.lrange(b"string", 0, -1), CommandResponse::WrongType));
+    }
+
+    #[test]
+    fn test_llen_lindex() {
+        let values = |values: &[&str]| values.iter().map(|value| value.as_bytes().to_vec()).collect::<Vec<_>>();
+        let mut store = Store::default();
+        store.push(b"list", values(&["a", "b", "c"]), false, false);
+
+        assert!(matches!(store.llen(b"list"), CommandResponse::Integer(3)));
+        assert!(matches!(store.llen(b"missing"), CommandResponse::Integer(0)));
+
+        for (index, expected) in [(0, "a"), (2, "c"), (-1, "c"), (-3, "a")] {
+            assert!(matches!(store.lindex(b"list", index), CommandResponse::Get(Some(v)) if v == RedisType::from(expected)));
+        }
+        for index in [3, -4] {
+            assert!(matches!(store.lindex(b"list", index), CommandResponse::Get(None)));
+        }
+        assert!(matches!(store.lindex(b"missing", 0), CommandResponse::Get(None)));
+
+        store.write(b"string", RedisType::from("value"), None);
+        assert!(matches!(store.llen(b"string"), CommandResponse::WrongType));
+        assert!(matches!(store.lindex(b"string", 0), CommandResponse::WrongType));
+    }
+
+    #[test]
+    fn test_lpos() {
+        let values = |values: &[&str]| values.iter().map(|value| value.as_bytes().to_vec()).collect::<Vec<_>>();
+        let position = |store: &mut Store, rank, count, maxlen| {
+            match store.lpos(b"list", b"a", &LPosOptions { rank, count, maxlen }) {
+                CommandResponse::Get(Some(RedisType::Int(index))) => vec![index],
+                CommandResponse::Get(None) => vec![],
+                CommandResponse::Get(Some(RedisType::Array(indices))) => indices.into_iter()
+                    .map(|index| match index { RedisType::Int(index) => index, _ => panic!() })
+                    .collect(),
+                _ => panic!("unexpected response"),
+            }
+        };
+        let mut store = Store::default();
+        store.push(b"list", values(&["a", "b", "c", "a", "b", "a"]), false, false);
+
+        assert_eq!(position(&mut store, 1, None, 0), vec![0]);
+        assert_eq!(position(&mut store, 2, None, 0), vec![3]);
+        assert_eq!(position(&mut store, -1, None, 0), vec![5]);
+        assert_eq!(position(&mut store, 4, None, 0), vec![]);
+        assert_eq!(position(&mut store, 1, Some(0), 0), vec![0, 3, 5]);
+        assert_eq!(position(&mut store, 1, Some(2), 0), vec![0, 3]);
+        assert_eq!(position(&mut store, -2, Some(0), 0), vec![3, 0]);
+        assert_eq!(position(&mut store, 1, Some(0), 4), vec![0, 3]);
+        assert_eq!(position(&mut store, -1, Some(0), 2), vec![5]);
+
+        assert!(matches!(store.lpos(b"list", b"x", &LPosOptions::default()), CommandResponse::Get(None)));
+        let options = LPosOptions { count: Some(0), ..Default::default() };
+        assert!(matches!(store.lpos(b"missing", b"a", &options), CommandResponse::Get(Some(RedisType::Array(v))) if v.is_empty()));
+        store.write(b"string", RedisType::from("value"), None);
+        assert!(matches!(store.lpos(b"string", b"a", &options), CommandResponse::WrongType));
+    }
+
+    #[test]
+    fn test_lset() {
+        let values = |values: &[&str]| values.iter().map(|value| value.as_bytes().to_vec()).collect::<Vec<_>>();
+        let list = |values: &[&str]| RedisType::List(values.iter().map(|value| value.as_bytes().to_vec()).collect());
+        let mut store = Store::default();
+        store.push(b"list", values(&["a", "b", "c"]), false, false);
+
+        assert!(matches!(store.lset(b"list", 0, b"x".to_vec()), CommandResponse::Integer(1)));
+        assert!(matches!(store.lset(b"list", -1, b"z".to_vec()), CommandResponse::Integer(1)));
+        assert_eq!(store.read(b"list"), Some(list(&["x", "b", "z"])));
+
+        for index in [3, -4] {
+            assert!(matches!(store.lset(b"list", index, b"y".to_vec()), CommandResponse::Error(e) if e == "index out of range"));
+        }
+        assert!(matches!(store.lset(b"missing", 0, b"y".to_vec()), CommandResponse::Error(e) if e == "no such key"));
+        store.write(b"string", RedisType::from("value"), None);
+        assert!(matches!(store.lset(b"string", 0, b"y".to_vec()), CommandResponse::WrongType));
+    }
+
+    #[test]
+    fn test_linsert() {
+        let values = |values: &[&str]| values.iter().map(|value| value.as_bytes().to_vec()).collect::<Vec<_>>();
+        let list = |values: &[&str]| RedisType::List(values.iter().map(|value| value.as_bytes().to_vec()).collect());
+        let mut store = Store::default();
+        store.push(b"list", values(&["a", "b", "a"]), false, false);
+
+        assert!(matches!(store.linsert(b"list", true, b"a", b"x".to_vec()), CommandResponse::Integer(4)));
+        assert!(matches!(store.linsert(b"list", false, b"a", b"y".to_vec()), CommandResponse::Integer(5)));
+        assert_eq!(store.read(b"list"), Some(list(&["x", "a", "y", "b", "a"])));
+
+        assert!(matches!(store.linsert(b"list", true, b"z", b"x".to_vec()), CommandResponse::Integer(-1)));
+        assert!(matches!(store.linsert(b"missing", true, b"a", b"x".to_vec()), CommandResponse::Integer(0)));
+        assert_eq!(store.read(b"missing"), None);
+        store.write(b"string", RedisType::from("value"), None);
+        assert!(matches!(store.linsert(b"string", true, b"a", b"x".to_vec()), CommandResponse::WrongType));
+    }
+
+    #[test]
+    fn test_lrem() {
+        let values = |values: &[&str]| values.iter().map(|value| value.as_bytes().to_vec()).collect::<Vec<_>>();
+        let list = |values: &[&str]| RedisType::List(values.iter().map(|value| value.as_bytes().to_vec()).collect());
+        let mut store = Store::default();
+        store.push(b"list", values(&["a", "b", "a", "c", "a", "b", "a"]), false, false);
+
+        assert!(matches!(store.lrem(b"list", 2, b"a"), CommandResponse::Integer(2)));
+        assert_eq!(store.read(b"list"), Some(list(&["b", "c", "a", "b", "a"])));
+        assert!(matches!(store.lrem(b"list", -1, b"b"), CommandResponse::Integer(1)));
+        assert_eq!(store.read(b"list"), Some(list(&["b", "c", "a", "a"])));
+        assert!(matches!(store.lrem(b"list", 0, b"a"), CommandResponse::Integer(2)));
+        assert_eq!(store.read(b"list"), Some(list(&["b", "c"])));
+        assert!(matches!(store.lrem(b"list", 0, b"x"), CommandResponse::Integer(0)));
+
+        // Removing everything removes the key
+        assert!(matches!(store.lrem(b"list", -5, b"b"), CommandResponse::Integer(1)));
+        assert!(matches!(store.lrem(b"list", 1, b"c"), CommandResponse::Integer(1)));
+        assert_eq!(store.read(b"list"), None);
+        assert!(matches!(store.lrem(b"list", 1, b"c"), CommandResponse::Integer(0)));
+
+        store.write(b"string", RedisType::from("value"), None);
+        assert!(matches!(store.lrem(b"string", 0, b"value"), CommandResponse::WrongType));
+    }
+
+    #[test]
+    fn test_hset_hget() {
+        let pairs = |pairs: &[(&str, &str)]| pairs.iter().map(|&(f, v)| (f.as_bytes().to_vec(), v.as_bytes().to_vec())).collect::<Vec<_>>();
+        let mut store = Store::default();
+
+        assert!(matches!(store.hset(b"hash", pairs(&[("a", "1"), ("b", "2")])), CommandResponse::Integer(2)));
+        assert!(matches!(store.hset(b"hash", pairs(&[("b", "3"), ("c", "4")])), CommandResponse::Integer(1)));
+        assert_eq!(store.type_of(b"hash"), "hash");
+
+        assert!(matches!(store.hget(b"hash", b"b"), CommandResponse::Get(Some(v)) if v == RedisType::from("3")));
+        assert!(matches!(store.hget(b"hash", b"x"), CommandResponse::Get(None)));
+        assert!(matches!(store.hget(b"missing", b"a"), CommandResponse::Get(None)));
+
+        let expected = RedisType::Hash(pairs(&[("a", "1"), ("b", "3"), ("c", "4")]).into_iter().collect());
+        assert!(matches!(store.hgetall(b"hash"), CommandResponse::Get(Some(v)) if v == expected));
+        assert!(matches!(store.hgetall(b"missing"), CommandResponse::Get(Some(RedisType::Hash(h))) if h.is_empty()));
+
+        store.write(b"string", RedisType::from("value"), None);
+        assert!(matches!(store.hgetall(b"string"), CommandResponse::WrongType));
+        assert!(matches!(store.hset(b"string", pairs(&[("a", "1")])), CommandResponse::WrongType));
+        assert!(matches!(store.hget(b"string", b"a"), CommandResponse::WrongType));
+    }
+
+    #[test]
+    fn test_hmget_hsetnx() {
+        let fields = |fields: &[&str]| fields.iter().map(|field| field.as_bytes().to_vec()).collect::<Vec<_>>();
+        let mut store = Store::default();
+
+        assert!(matches!(store.hsetnx(b"hash", b"a", b"1"), CommandResponse::Integer(1)));
+        assert!(matches!(store.hsetnx(b"hash", b"a", b"2"), CommandResponse::Integer(0)));
+        assert!(matches!(store.hsetnx(b"hash", b"b", b"3"), CommandResponse::Integer(1)));
+
+        let CommandResponse::Values(values) = store.hmget(b"hash", &fields(&["a", "x", "b"])) else { panic!() };
+        assert_eq!(values, vec![Some(RedisType::from("1")), None, Some(RedisType::from("3"))]);
+        let CommandResponse::Values(values) = store.hmget(b"missing", &fields(&["a"])) else { panic!() };
+        assert_eq!(values, vec![None]);
+
+        store.write(b"string", RedisType::from("value"), None);
+        assert!(matches!(store.hmget(b"string", &fields(&["a"])), CommandResponse::WrongType));
+        assert!(matches!(store.hsetnx(b"string", b"a", b"1"), CommandResponse::WrongType));
+    }
+
+    #[test]
+    fn test_hincr_by() {
+        let mut store = Store::default();
+
+        assert!(matches!(store.hincr_by(b"hash", b"n", 5), CommandResponse::Integer(5)));
+        assert!(matches!(store.hincr_by(b"hash", b"n", -7), CommandResponse::Integer(-2)));
+        assert!(matches!(store.hget(b"hash", b"n"), CommandResponse::Get(Some(v)) if v == RedisType::from("-2")));
+
+        store.hset(b"hash", vec![("big".into(), i64::MAX.to_string().into_bytes()), ("text".into(), "abc".into())]);
+        assert!(matches!(store.hincr_by(b"hash", b"big", 1), CommandResponse::Error(e) if e.contains("overflow")));
+        assert!(matches!(store.hincr_by(b"hash", b"text", 1), CommandResponse::Error(e) if e == "hash value is not an integer"));
+
+        let float = |store: &mut Store, field: &str, delta| match store.hincr_by_float(b"hash", field.as_bytes(), delta) {
+            CommandResponse::Get(Some(RedisType::String(bytes))) => String::from_utf8(bytes).unwrap(),
+            _ => panic!("expected a float"),
+        };
+        store.hset(b"hash", vec![("f".into(), "10.50".into())]);
+        assert_eq!(float(&mut store, "f", 0.1), "10.6");
+        assert_eq!(float(&mut store, "f", -5.6), "5");
+        assert_eq!(float(&mut store, "new", 2.5e5), "250000");
+        assert_eq!(float(&mut store, "n", 0.5), "-1.5");
+        assert!(matches!(store.hincr_by_float(b"hash", b"text", 1.0), CommandResponse::Error(e) if e == "hash value is not a float"));
+        assert!(matches!(store.hincr_by_float(b"hash", b"f", f64::MAX), CommandResponse::Get(Some(_))));
+        assert!(matches!(store.hincr_by_float(b"hash", b"f", f64::MAX), CommandResponse::Error(e) if e.contains("Infinity")));
+
+        store.write(b"string", RedisType::from("value"), None);
+        assert!(matches!(store.hincr_by(b"string", b"n", 1), CommandResponse::WrongType));
+        assert!(matches!(store.hincr_by_float(b"string", b"n", 1.0), CommandResponse::WrongType));
+    }
+
+    #[test]
+    fn test_hash_fields() {
+        let fields = |fields: &[&str]| fields.iter().map(|field| field.as_bytes().to_vec()).collect::<Vec<_>>();
+        let mut store = Store::default();
+        store.hset(b"hash", vec![("a".into(), "hello".into()), ("b".into(), "".into()), ("c".into(), "x".into())]);
+
+        assert!(matches!(store.hlen(b"hash"), CommandResponse::Integer(3)));
+        assert!(matches!(store.hexists(b"hash", b"a"), CommandResponse::Integer(1)));
+        assert!(matches!(store.hexists(b"hash", b"x"), CommandResponse::Integer(0)));
+        assert!(matches!(store.hstrlen(b"hash", b"a"), CommandResponse::Integer(5)));
+        assert!(matches!(store.hstrlen(b"hash", b"b"), CommandResponse::Integer(0)));
+        assert!(matches!(store.hstrlen(b"hash", b"x"), CommandResponse::Integer(0)));
+
+        assert!(matches!(store.hdel(b"hash", &fields(&["a", "x", "a"])), CommandResponse::Integer(1)));
+        assert!(matches!(store.hlen(b"hash"), CommandResponse::Integer(2)));
+        // Removing the last field removes the key
+        assert!(matches!(store.hdel(b"hash", &fields(&["b", "c"])), CommandResponse::Integer(2)));
+        assert_eq!(store.read(b"hash"), None);
+        assert!(matches!(store.hlen(b"hash"), CommandResponse::Integer(0)));
+        assert!(matches!(store.hdel(b"hash", &fields(&["a"])), CommandResponse::Integer(0)));
+
+        store.write(b"string", RedisType::from("value"), None);
+        assert!(matches!(store.hdel(b"string", &fields(&["a"])), CommandResponse::WrongType));
+        assert!(matches!(store.hexists(b"string", b"a"), CommandResponse::WrongType));
+        assert!(matches!(store.hlen(b"string"), CommandResponse::WrongType));
+        assert!(matches!(store.hstrlen(b"string", b"a"), CommandResponse::WrongType));
+    }
+
+    #[test]
+    fn test_sets() {
+        let members = |members: &[&str]| members.iter().map(|member| member.as_bytes().to_vec()).collect::<Vec<_>>();
+        let set = |members: &[&str]| RedisType::Set(members.iter().map(|member| member.as_bytes().to_vec()).collect());
+        let mut store = Store::default();
+
+        assert!(matches!(store.sadd(b"set", members(&["a", "b", "a"])), CommandResponse::Integer(2)));
+        assert!(matches!(store.sadd(b"set", members(&["b", "c"])), CommandResponse::Integer(1)));
+        assert_eq!(store.type_of(b"set"), "set");
+        assert!(matches!(store.smembers(b"set"), CommandResponse::Get(Some(v)) if v == set(&["a", "b", "c"])));
+
+        assert!(matches!(store.srem(b"set", &members(&["a", "x"])), CommandResponse::Integer(1)));
+        assert!(matches!(store.smembers(b"set"), CommandResponse::Get(Some(v)) if v == set(&["b", "c"])));
+        // Removing the last member removes the key
+        assert!(matches!(store.srem(b"set", &members(&["b", "c"])), CommandResponse::Integer(2)));
+        assert_eq!(store.read(b"set"), None);
+        assert!(matches!(store.smembers(b"set"), CommandResponse::Get(Some(v)) if v == set(&[])));
+        assert!(matches!(store.srem(b"set", &members(&["a"])), CommandResponse::Integer(0)));
+
+        store.sadd(b"set", members(&["a", "b"]));
+        assert!(matches!(store.scard(b"set"), CommandResponse::Integer(2)));
+        assert!(matches!(store.scard(b"missing"), CommandResponse::Integer(0)));
+        let CommandResponse::Values(found) = store.sismember(b"set", &members(&["b", "x", "a"])) else { panic!() };
+        assert_eq!(found, vec![Some(RedisType::Int(1)), Some(RedisType::Int(0)), Some(RedisType::Int(1))]);
+        let CommandResponse::Values(found) = store.sismember(b"missing", &members(&["a"])) else { panic!() };
+        assert_eq!(found, vec![Some(RedisType::Int(0))]);
+
+        store.write(b"string", RedisType::from("value"), None);
+        assert!(matches!(store.scard(b"string"), CommandResponse::WrongType));
+        assert!(matches!(store.sismember(b"string", &members(&["a"])), CommandResponse::WrongType));
+        assert!(matches!(store.sadd(b"string", members(&["a"])), CommandResponse::WrongType));
+        assert!(matches!(store.srem(b"string", &members(&["a"])), CommandResponse::WrongType));
+        assert!(matches!(store.smembers(b"string"), CommandResponse::WrongType));
+    }
+
+    #[test]
+    fn test_combine() {
+        let members = |members: &[&str]| members.iter().map(|member| member.as_bytes().to_vec()).collect::<Vec<_>>();
+        let set = |members: &[&str]| RedisType::Set(members.iter().map(|member| member.as_bytes().to_vec()).collect());
+        let mut store = Store::default();
+        store.sadd(b"a", members(&["1", "2", "3", "4"]));
+        store.sadd(b"b", members(&["2", "3", "5"]));
+        store.sadd(b"c", members(&["3", "6"]));
+
+        let combine = |store: &mut Store, keys: &[&str], operation| match store.combine(&members(keys), operation) {
+            CommandResponse::Get(Some(result)) => result,
+            _ => panic!("expected a set"),
+        };
+        assert_eq!(combine(&mut store, &["a", "b"], SetOperation::Intersection), set(&["2", "3"]));
+        assert_eq!(combine(&mut store, &["a", "b", "c"], SetOperation::Intersection), set(&["3"]));
+        assert_eq!(combine(&mut store, &["a", "missing"], SetOperation::Intersection), set(&[]));
+        assert_eq!(combine(&mut store, &["b", "c", "missing"], SetOperation::Union), set(&["2", "3", "5", "6"]));
+        assert_eq!(combine(&mut store, &["a", "b", "c"], SetOperation::Difference), set(&["1", "4"]));
+        assert_eq!(combine(&mut store, &["missing", "a"], SetOperation::Difference), set(&[]));
+        assert_eq!(combine(&mut store, &["a"], SetOperation::Difference), set(&["1", "2", "3", "4"]));
+
+        store.write(b"string", RedisType::from("value"), None);
+        assert!(matches!(store.combine(&members(&["a", "string"]), SetOperation::Union), CommandResponse::WrongType));
+    }
+
+    #[test]
+    fn test_smove_intercard() {
+        let members = |members: &[&str]| members.iter().map(|member| member.as_bytes().to_vec()).collect::<Vec<_>>();
+        let set = |members: &[&str]| RedisType::Set(members.iter().map(|member| member.as_bytes().to_vec()).collect());
+        let mut store = Store::default();
+        store.sadd(b"a", members(&["1", "2"]));
+
+        assert!(matches!(store.smove(b"a", b"b", b"1"), CommandResponse::Integer(1)));
+        assert!(matches!(store.smove(b"a", b"b", b"x"), CommandResponse::Integer(0)));
+        assert!(matches!(store.smove(b"a", b"a", b"2"), CommandResponse::Integer(1)));
+        assert_eq!(store.read(b"a"), Some(set(&["2"])));
+        assert_eq!(store.read(b"b"), Some(set(&["1"])));
+        // The source goes away with its last member
+        assert!(matches!(store.smove(b"a", b"b", b"2"), CommandResponse::Integer(1)));
+        assert_eq!(store.read(b"a"), None);
+        assert_eq!(store.read(b"b"), Some(set(&["1", "2"])));
+
+        store.sadd(b"c", members(&["1", "2", "3"]));
+        store.sadd(b"d", members(&["2", "3", "4", "5"]));
+        assert!(matches!(store.intercard(&members(&["c", "d"]), 0), CommandResponse::Integer(2)));
+        assert!(matches!(store.intercard(&members(&["c", "d"]), 1), CommandResponse::Integer(1)));
+        assert!(matches!(store.intercard(&members(&["b", "c", "d"]), 0), CommandResponse::Integer(1)));
+        assert!(matches!(store.intercard(&members(&["c", "missing"]), 0), CommandResponse::Integer(0)));
+
+        store.write(b"string", RedisType::from("value"), None);
+        assert!(matches!(store.smove(b"b", b"string", b"1"), CommandResponse::WrongType));
+        assert!(matches!(store.smove(b"string", b"b", b"1"), CommandResponse::WrongType));
+        assert!(matches!(store.intercard(&members(&["c", "string"]), 0), CommandResponse::WrongType));
+    }
+
+    #[test]
+    fn test_combine_store() {
+        let members = |members: &[&str]| members.iter().map(|member| member.as_bytes().to_vec()).collect::<Vec<_>>();
+        let mut store = Store::default();
+        store.sadd(b"a", members(&["1", "2"]));
+        store.sadd(b"b", members(&["2", "3"]));
+        store.write(b"destination", RedisType::from("value"), Some(SystemTime::now() + Duration::from_secs(100)));
+
+        let (response, replicated) = store.combine_store(b"destination", &members(&["a", "b"]), SetOperation::Intersection);
+        assert!(matches!(response, CommandResponse::Integer(1)));
+        assert_eq!(replicated, vec![RedisType::from(vec!["DEL", "destination"]), RedisType::from(vec!["SADD", "destination", "2"])]);
+        assert_eq!(store.read(b"destination"), Some(RedisType::Set(members(&["2"]).into_iter().collect())));
+        // The TTL is gone along with the old value
+        assert!(!store.persist(b"destination"));
+
+        // The destination can be one of the sources
+        let (response, _) = store.combine_store(b"a", &members(&["a", "b"]), SetOperation::Union);
+        assert!(matches!(response, CommandResponse::Integer(3)));
+
+        let (response, replicated) = store.combine_store(b"destination", &members(&["a", "missing"]), SetOperation::Intersection);
+        assert!(matches!(response, CommandResponse::Integer(0)));
+        assert_eq!(replicated, vec![RedisType::from(vec!["DEL", "destination"])]);
+        assert_eq!(store.read(b"destination"), None);
+
+        store.write(b"string", RedisType::from("value"), None);
+        let (response, replicated) = store.combine_store(b"a", &members(&["a", "string"]), SetOperation::Union);
+        assert!(matches!(response, CommandResponse::WrongType) && replicated.is_empty());
+        assert!(matches!(store.scard(b"a"), CommandResponse::Integer(3)));
+    }
+
+    #[test]
+    fn test_zadd() {
+        let mut store = Store::default();
+        let pairs = |pairs: &[(f64, &str)]| pairs.iter().map(|&(score, member)| (score, member.as_bytes().to_vec())).collect::<Vec<_>>();
+        let options = |flags: &[&str]| crate::zset::parse_zadd(&[flags, &["1", "x"]].concat()).unwrap().0;
+
+        let (response, replicated) = store.zadd(b"zset", &options(&[]), &pairs(&[(1.0, "a"), (2.0, "b")]));
+        assert!(matches!(response, CommandResponse::Integer(2)));
+        assert_eq!(replicated, Some(RedisType::from(vec!["ZADD", "zset", "1", "a", "2", "b"])));
+        assert_eq!(store.type_of(b"zset"), "zset");
+
+        // Only the changes are replicated
+        let (response, replicated) = store.zadd(b"zset", &options(&[]), &pairs(&[(1.0, "a"), (3.0, "b"), (1.5, "c")]));
+        assert!(matches!(response, CommandResponse::Integer(1)));
+        assert_eq!(replicated, Some(RedisType::from(vec!["ZADD", "zset", "3", "b", "1.5", "c"])));
+        let (response, _) = store.zadd(b"zset", &options(&["CH"]), &pairs(&[(1.0, "a"), (4.0, "b"), (1.0, "d")]));
+        assert!(matches!(response, CommandResponse::Integer(2)));
+        let (response, replicated) = store.zadd(b"zset", &options(&["GT"]), &pairs(&[(0.0, "a")]));
+        assert!(matches!(response, CommandResponse::Integer(0)));
+        assert_eq!(replicated, None);
+
+        // INCR replicates the resulting score
+        let (response, replicated) = store.zadd(b"zset", &options(&["INCR"]), &pairs(&[(2.5, "a")]));
+        assert!(matches!(response, CommandResponse::Get(Some(v)) if v == RedisType::Double(3.5)));
+        assert_eq!(replicated, Some(RedisType::from(vec!["ZADD", "zset", "3.5", "a"])));
+        let (response, _) = store.zadd(b"zset", &options(&["INCR", "NX"]), &pairs(&[(2.5, "a")]));
+        assert!(matches!(response, CommandResponse::Get(None)));
+        // Which is what ZINCRBY does, creating missing members
+        let (response, _) = store.zadd(b"zset", &options(&["INCR"]), &pairs(&[(-2.0, "e")]));
+        assert!(matches!(response, CommandResponse::Get(Some(v)) if v == RedisType::Double(-2.0)));
+
+        // XX doesn't create the key
+        let (response, _) = store.zadd(b"missing", &options(&["XX"]), &pairs(&[(1.0, "a")]));
+        assert!(matches!(response, CommandResponse::Integer(0)));
+        assert_eq!(store.read(b"missing"), None);
+
+        store.write(b"string", RedisType::from("value"), None);
+        assert!(matches!(store.zadd(b"string", &options(&[]), &pairs(&[(1.0, "a")])).0, CommandResponse::WrongType));
+    }
+
+    #[test]
+    fn test_zset_lookups() {
+        let mut store = Store::default();
+        let pairs = [(1.0, b"b".to_vec()), (1.0, b"a".to_vec()), (0.5, b"c".to_vec())];
+        store.zadd(b"zset", &Default::default(), &pairs);
+
+        assert!(matches!(store.zscore(b"zset", b"c"), CommandResponse::Get(Some(v)) if v == RedisType::Double(0.5)));
+        assert!(matches!(store.zscore(b"zset", b"d"), CommandResponse::Get(None)));
+        assert!(matches!(store.zrank(b"zset", b"b", false), CommandResponse::Get(Some(RedisType::Int(2)))));
+        assert!(matches!(store.zrank(b"zset", b"a", true), CommandResponse::Get(Some(RedisType::Int(1)))));
+        assert!(matches!(store.zrank(b"missing", b"a", false), CommandResponse::Get(None)));
+        assert!(matches!(store.zcard(b"zset"), CommandResponse::Integer(3)));
+        assert!(matches!(store.zcard(b"missing"), CommandResponse::Integer(0)));
+
+        store.write(b"string", RedisType::from("value"), None);
+        assert!(matches!(store.zrank(b"string", b"a", false), CommandResponse::WrongType));
+    }
+
+    #[test]
+    fn test_zpop() {
+        let mut store = Store::default();
+        let pairs = [(1.0, b"a".to_vec()), (2.0, b"b".to_vec()), (3.0, b"c".to_vec())];
+        store.zadd(b"zset", &Default::default(), &pairs);
+
+        let (response, replicated) = store.zpop(b"zset", 2, true);
+        assert!(matches!(response, CommandResponse::Get(Some(v)) if v == RedisType::from(vec!["c", "3", "b", "2"])));
+        assert_eq!(replicated, Some(RedisType::from(vec!["ZREM", "zset", "c", "b"])));
+
+        // Emptying the sorted set deletes the key
+        let (response, replicated) = store.zpop(b"zset", 5, false);
+        assert!(matches!(response, CommandResponse::Get(Some(v)) if v == RedisType::from(vec!["a", "1"])));
+        assert_eq!(replicated, Some(RedisType::from(vec!["ZREM", "zset", "a"])));
+        assert_eq!(store.read(b"zset"), None);
+
+        let (response, replicated) = store.zpop(b"zset", 1, false);
+        assert!(matches!(response, CommandResponse::Get(Some(v)) if v == RedisType::Array(vec![])));
+        assert_eq!(replicated, None);
+    }
+
+    #[test]
+    fn test_zrem() {
+        let mut store = Store::default();
+        let pairs = [(1.0, b"a".to_vec()), (2.0, b"b".to_vec())];
+        store.zadd(b"zset", &Default::default(), &pairs);
+
+        let members = |members: &[&str]| members.iter().map(|member| member.as_bytes().to_vec()).collect::<Vec<_>>();
+        assert!(matches!(store.zrem(b"zset", &members(&["a", "c", "a"])), CommandResponse::Integer(1)));
+        assert!(matches!(store.zcard(b"zset"), CommandResponse::Integer(1)));
+        assert!(matches!(store.zrem(b"zset", &members(&["b"])), CommandResponse::Integer(1)));
+        assert_eq!(store.read(b"zset"), None);
+        assert!(matches!(store.zrem(b"zset", &members(&["b"])), CommandResponse::Integer(0)));
+    }
+
+    #[test]
+    fn test_zintercard() {
+        let mut store = Store::default();
+        let pairs = |members: &[&str]| members.iter().map(|member| (1.0, member.as_bytes().to_vec())).collect::<Vec<_>>();
+        store.zadd(b"small", &Default::default(), &pairs(&["b", "c", "d"]));
+        store.zadd(b"large", &Default::default(), &pairs(&["a", "b", "c", "d", "e"]));
+        store.zadd(b"other", &Default::default(), &pairs(&["x", "y"]));
+
+        let keys = |keys: &[&str]| keys.iter().map(|key| key.as_bytes().to_vec()).collect::<Vec<_>>();
+        assert!(matches!(store.zintercard(&keys(&["large", "small"]), 0), CommandResponse::Integer(3)));
+        // LIMIT caps the count
+        assert!(matches!(store.zintercard(&keys(&["large", "small"]), 2), CommandResponse::Integer(2)));
+        assert!(matches!(store.zintercard(&keys(&["large", "small"]), 10), CommandResponse::Integer(3)));
+        // Nothing in common
+        assert!(matches!(store.zintercard(&keys(&["large", "other"]), 0), CommandResponse::Integer(0)));
+        assert!(matches!(store.zintercard(&keys(&["large", "missing"]), 0), CommandResponse::Integer(0)));
+
+        store.sadd(b"set", vec![b"b".to_vec()]);
+        assert!(matches!(store.zintercard(&keys(&["large", "set"]), 0), CommandResponse::WrongType));
+    }
+
+    #[test]
+    fn test_versions() {
+        let mut store = Store::default();
+        assert_eq!(store.version(b"key"), 0);
+
+        store.write(b"key", RedisType::from("1"), None);
+        let written = store.version(b"key");
+        store.read(b"key");
+        assert_eq!(store.version(b"key"), written);
+        store.incr_by(b"key", 1);
+        let incremented = store.version(b"key");
+        assert!(incremented > written);
+
+        // Removing a key and writing it again doesn't take it back
+        store.delete(&[b"key".to_vec()]);
+        store.write(b"key", RedisType::from("1"), None);
+        assert!(store.version(b"key") > incremented);
+
+        let before = store.version(b"key");
+        store.flush();
+        assert!(store.version(b"key") > before);
+    }
+
+    #[test]
+    fn test_notifications() {
+        let mut store = Store::default();
+        store.hset(b"hash", vec![(b"field".to_vec(), b"value".to_vec())]);
+        assert!(store.take_notifications().is_empty());
+
+        store.set_notify_flags(NotifyFlags::parse("Kgh").unwrap());
+        store.hset(b"hash", vec![(b"other".to_vec(), b"value".to_vec())]);
+        store.hdel(b"hash", &[b"field".to_vec(), b"other".to_vec()]);
+        let event = |event: &str| (b"__keyspace@0__:hash".to_vec(), event.as_bytes().to_vec());
+        assert_eq!(store.take_notifications(), vec![event("hset"), event("hdel"), event("del")]);
+
+        // Only the classes asked for
+        store.write(b"string", RedisType::from("1"), Some(UNIX_EPOCH));
+        store.incr_by(b"string", 1);
+        assert!(store.take_notifications().is_empty());
+
+        store.set_notify_flags(NotifyFlags::parse("Ex").unwrap());
+        store.write(b"string", RedisType::from("1"), Some(UNIX_EPOCH));
+        assert_eq!(store.read(b"string"), None);
+        assert_eq!(store.take_notifications(), vec![(b"__keyevent@0__:expired".to_vec(), b"string".to_vec())]);
+    }
+
+    #[test]
+    fn test_read_strings() {
+        let mut store = Store::default();
+        store.write(b"string", RedisType::from("value"), None);
+        store.write(b"array", RedisType::Array(vec![]), None);
+
+        let keys = vec!["string".into(), "array".into()];
+        assert_eq!(store.read_strings(&keys), vec![Some(RedisType::from("value")), None]);
+    }
+
+    #[test]
+    fn test_get_set() {
+        let mut store = Store::default();
+        let later = SystemTime::now() + Duration::from_secs(3600);
+        store.write(b"key", RedisType::from("old"), Some(later));
+
+        assert!(matches!(store.get_set(b"key", RedisType::from("new")), CommandResponse::Get(Some(v)) if v == RedisType::from("old")));
+        assert_eq!(store.read(b"key"), Some(RedisType::from("new")));
+        // The TTL is gone
+        assert!(!store.persist(b"key"));
+
+        assert!(matches!(store.get_set(b"missing", RedisType::from("value")), CommandResponse::Get(None)));
+
+        store.write(b"array", RedisType::Array(vec![]), None);
+        assert!(matches!(store.get_set(b"array", RedisType::from("value")), CommandResponse::WrongType));
+        assert_eq!(store.read(b"array"), Some(RedisType::Array(vec![])));
+    }
+
+    #[test]
+    fn test_expire_time() {
+        let mut store = Store::default();
+        store.write(b"permanent", RedisType::from("value"), None);
+        store.write(b"volatile", RedisType::from("value"), Some(UNIX_EPOCH + Duration::from_millis(4102444800123)));
+
+        assert_eq!(store.expire_time(b"volatile"), 4102444800123);
+        assert_eq!(store.expire_time(b"permanent"), -1);
+        assert_eq!(store.expire_time(b"missing"), -2);
+    }
+
+    #[test]
+    fn test_expire_and_persist() {
+        let mut store = Store::default();
+        store.write(b"key", RedisType::from("value"), None);
+
+        assert!(!store.persist(b"key"));
+        assert!(store.expire(b"key", SystemTime::now() + Duration::from_secs(10), ExpireCondition::default()));
+        assert!(store.persist(b"key"));
+        assert!(!store.expire(b"missing", SystemTime::now(), ExpireCondition::default()));
+
+        // Expiring in the past removes the key
+        assert!(store.expire(b"key", UNIX_EPOCH, ExpireCondition::default()));
+        assert_eq!(store.read(b"key"), None);
+    }
+
+    #[tokio::test]
+    async fn test_persist_outlives_deadline() {
+        let mut store = Store::default();
+        store.write(b"key", RedisType::from("value"), Some(SystemTime::now() + Duration::from_millis(50)));
+        let mut harness = start(store).await;
+
+        harness.store_tx.send(StoreCommand::Persist { id: harness.id, key: "key".into() }).await.unwrap();
+        assert!(matches!(harness.client_rx.recv().await, Some(CommandResponse::Integer(1))));
+        assert_eq!(harness.replica_rx.recv().await.unwrap(), frame(&["PERSIST", "key"]));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(harness.getex("key", None).await, Some(RedisType::from("value")));
+        harness.store_tx.send(StoreCommand::ExpireTime { id: harness.id, key: "key".into() }).await.unwrap();
+        assert!(matches!(harness.client_rx.recv().await, Some(CommandResponse::Integer(-1))));
+    }
+
+    #[test]
+    fn test_expire_conditions() {
+        let nx = ExpireCondition { nx: true, ..Default::default() };
+        let xx = ExpireCondition { xx: true, ..Default::default() };
+        let gt = ExpireCondition { gt: true, ..Default::default() };
+        let lt = ExpireCondition { lt: true, ..Default::default() };
+        let xx_lt = ExpireCondition { xx: true, lt: true, ..Default::default() };
+
+        let mut store = Store::default();
+        let soon = SystemTime::now() + Duration::from_secs(100);
+        let later = soon + Duration::from_secs(100);
+        store.write(b"key", RedisType::from("value"), None);
+
+        // No TTL counts as infinite
+        assert!(!store.expire(b"key", soon, xx));
+        assert!(!store.expire(b"key", soon, gt));
+        assert!(!store.expire(b"key", soon, xx_lt));
+        assert!(store.expire(b"key", later, lt));
+        assert!(!store.expire(b"key", soon, nx));
+
+        assert!(!store.expire(b"key", later, gt));
+        assert!(store.expire(b"key", soon, xx_lt));
+        assert!(!store.expire(b"key", later, lt));
+        assert!(store.expire(b"key", later, gt));
+        assert!(store.expire(b"key", soon, xx));
+        assert_eq!(store.expire_time(b"key"), soon.duration_since(UNIX_EPOCH).unwrap().as_millis() as i64);
+    }
 }
@@ -0,0 +1,58 @@
+use anyhow::{bail, Result};
+
+use tokio::{
+    io::BufReader,
+    net::{TcpStream, ToSocketAddrs},
+};
+
+use crate::{
+    io::*,
+    types::RedisType,
+};
+
+/// Minimal client to talk to a running server. Meant for tests and small
+/// scripts, not as a general purpose Redis client.
+pub struct TestClient {
+    stream: TcpReader,
+}
+
+impl TestClient {
+    pub async fn connect<A: ToSocketAddrs>(address: A) -> Result<Self> {
+        let stream = TcpStream::connect(address).await?;
+
+        Ok(TestClient { stream: BufReader::new(stream) })
+    }
+
+    /// Send a command without waiting for the reply
+    pub async fn send(&mut self, cmd: &[&str]) -> Result<()> {
+        RedisType::from(cmd.to_vec()).write(&mut self.stream).await
+    }
+
+    /// Read the next reply. Error replies are returned as `Err`, with the
+    /// message sent by the server.
+    pub async fn read(&mut self) -> Result<RedisType> {
+        read_reply(&mut self.stream).await
+    }
+
+    /// Send an arbitrary command and return the reply. Error replies are
+    /// returned as `Err`, with the message sent by the server.
+    pub async fn raw(&mut self, cmd: &[&str]) -> Result<RedisType> {
+        self.send(cmd).await?;
+        self.read().await
+    }
+
+    pub async fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match self.raw(&["SET", key, value]).await? {
+            RedisType::String(string) if string == b"OK" => Ok(()),
+            other => bail!("unexpected reply to SET: {other:?}"),
+        }
+    }
+
+    pub async fn get(&mut self, key: &str) -> Result<Option<String>> {
+        match self.raw(&["GET", key]).await? {
+            RedisType::String(bytes) => Ok(Some(String::from_utf8(bytes)?)),
+            RedisType::Null => Ok(None),
+            other => bail!("unexpected reply to GET: {other:?}"),
+        }
+    }
+}
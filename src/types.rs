@@ -1,91 +1,215 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
 use anyhow::Result;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::zset::{format_score, SortedSet};
 
-use crate::io::*;
+/// Protocol spoken by a connection
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum RedisType {
-    String(String),
+    // Bulk strings are binary safe. They may not be valid UTF-8
+    String(Vec<u8>),
     Int(i64),
     Timestamp(u128),
     Array(Vec<RedisType>),
+    // Out-of-band data, like pub/sub messages. Sent as arrays to RESP2 clients
+    Push(Vec<RedisType>),
+    // Stored lists. They're sent as arrays of bulk strings
+    List(VecDeque<Vec<u8>>),
+    Map(Vec<(RedisType, RedisType)>),
+    // Stored hashes. They're sent as maps, in no particular order
+    Hash(HashMap<Vec<u8>, Vec<u8>>),
+    // Stored sets. They're sent as sets, in no particular order
+    Set(HashSet<Vec<u8>>),
+    // Stored sorted sets. They're sent as arrays of members and scores, from
+    // the lowest score to the highest
+    ZSet(SortedSet),
+    // Text meant for humans, like the output of INFO, with its format (eg.
+    // "txt"). Sent as a bulk string with just the text to RESP2 clients
+    Verbatim { format: [u8; 3], text: String },
+    // Sent as bulk strings to RESP2 clients
+    Double(f64),
+    // Sent as integers (1 or 0) to RESP2 clients
+    Bool(bool),
+    Null,
 }
 
 impl RedisType {
-    pub async fn write(&self, stream: &mut TcpReader) -> Result<()> {
+    /// Whether this is a value that string commands can operate on
+    pub fn is_string(&self) -> bool {
+        matches!(self, RedisType::String(_) | RedisType::Int(_))
+    }
+
+    /// Contents of a string value, as stored. Integers are turned into their
+    /// decimal representation
+    pub fn string_bytes(&self) -> Option<Vec<u8>> {
         match self {
-            RedisType::String(string) => {
-                write_string(stream, string).await?
-            }
-            RedisType::Int(number) => {
-                write_integer(stream, *number).await?
-            }
-            RedisType::Array(array) => {
-                write_array_size(stream, array.len()).await?;
-                let mut stack = vec![array.iter()];
-                while let Some(last) = stack.last_mut() {
-                    if let Some(element) = last.next() {
-                        match element {
-                            RedisType::Array(array) => {
-                                write_array_size(stream, array.len()).await?;
-                                stack.push(array.iter())
-                            },
-                            // Duplicated code because async functions can't be recursive
-                            // as-is. There ways to circumvent this, but they are a pain
-                            // in the ass or require the use of crates not provided by the
-                            // project (and CodeCrafters don't support modifying Cargo.toml
-                            RedisType::String(string) => {
-                                write_string(stream, string).await?
-                            },
-                            RedisType::Int(number) => {
-                                write_integer(stream, *number).await?
-                            },
-                            RedisType::Timestamp(_) => todo!(),
-                        }
-                    } else {
-                        stack.pop();
-                    }
-                }
-            }
-            RedisType::Timestamp(_) => todo!(),
+            RedisType::String(bytes) => Some(bytes.clone()),
+            RedisType::Int(number) => Some(number.to_string().into_bytes()),
+            _ => None,
         }
-        Ok(())
     }
 
-    pub fn to_vec(&self) -> Vec<u8> {
+    /// Serialize for a connection using the given protocol. Types that only
+    /// exist in RESP3 are downgraded to their RESP2 form: maps become flat
+    /// arrays of keys and values, sets and pushes become arrays, doubles
+    /// become bulk strings and booleans become integers.
+    pub fn encode(&self, proto: Protocol) -> Vec<u8> {
+        let mut output = vec![];
+        self.encode_into(&mut output, proto);
+        output
+    }
+
+    fn encode_into(&self, output: &mut Vec<u8>, proto: Protocol) {
         match self {
-            RedisType::String(string) => {
-                format!("${}\r\n{}\r\n", string.len(), string)
-                    .as_bytes()
-                    .to_vec()
+            RedisType::String(bytes) => {
+                output.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                output.extend_from_slice(bytes);
+                output.extend_from_slice(b"\r\n");
             }
             RedisType::Int(number) => {
-                format!(":{number}\r\n").as_bytes().to_vec()
+                output.extend_from_slice(format!(":{number}\r\n").as_bytes());
             }
             RedisType::Timestamp(millis) => {
-                format!(":{millis}\r\n").as_bytes().to_vec()
+                output.extend_from_slice(format!(":{millis}\r\n").as_bytes());
             }
             RedisType::Array(array) => {
-                let mut size = format!("*{}\r\n", array.len()).as_bytes().to_vec();
-
-                size.extend( 
-                    array.iter() .map(|comp| comp.to_vec())
-                    .collect::<Vec<_>>()
-                    .concat());
-
-                size
+                output.extend_from_slice(format!("*{}\r\n", array.len()).as_bytes());
+                for element in array {
+                    element.encode_into(output, proto);
+                }
             }
+            RedisType::Push(elements) => {
+                let kind = match proto {
+                    Protocol::Resp2 => '*',
+                    Protocol::Resp3 => '>',
+                };
+                output.extend_from_slice(format!("{kind}{}\r\n", elements.len()).as_bytes());
+                for element in elements {
+                    element.encode_into(output, proto);
+                }
+            }
+            RedisType::List(elements) => {
+                output.extend_from_slice(format!("*{}\r\n", elements.len()).as_bytes());
+                for element in elements {
+                    output.extend_from_slice(format!("${}\r\n", element.len()).as_bytes());
+                    output.extend_from_slice(element);
+                    output.extend_from_slice(b"\r\n");
+                }
+            }
+            RedisType::Map(pairs) => {
+                let header = match proto {
+                    Protocol::Resp2 => format!("*{}\r\n", pairs.len() * 2),
+                    Protocol::Resp3 => format!("%{}\r\n", pairs.len()),
+                };
+                output.extend_from_slice(header.as_bytes());
+                for (key, value) in pairs {
+                    key.encode_into(output, proto);
+                    value.encode_into(output, proto);
+                }
+            }
+            RedisType::Hash(fields) => {
+                let header = match proto {
+                    Protocol::Resp2 => format!("*{}\r\n", fields.len() * 2),
+                    Protocol::Resp3 => format!("%{}\r\n", fields.len()),
+                };
+                output.extend_from_slice(header.as_bytes());
+                for (field, value) in fields {
+                    RedisType::from(field.as_slice()).encode_into(output, proto);
+                    RedisType::from(value.as_slice()).encode_into(output, proto);
+                }
+            }
+            RedisType::Set(elements) => {
+                let kind = match proto {
+                    Protocol::Resp2 => '*',
+                    Protocol::Resp3 => '~',
+                };
+                output.extend_from_slice(format!("{kind}{}\r\n", elements.len()).as_bytes());
+                for element in elements {
+                    RedisType::from(element.as_slice()).encode_into(output, proto);
+                }
+            }
+            RedisType::ZSet(zset) => {
+                output.extend_from_slice(format!("*{}\r\n", zset.len() * 2).as_bytes());
+                for (member, score) in zset.iter() {
+                    RedisType::from(member).encode_into(output, proto);
+                    RedisType::from(format_score(score)).encode_into(output, proto);
+                }
+            }
+            RedisType::Verbatim { format, text } => match proto {
+                Protocol::Resp2 => RedisType::from(text.as_str()).encode_into(output, proto),
+                Protocol::Resp3 => {
+                    output.extend_from_slice(format!("={}\r\n", text.len() + 4).as_bytes());
+                    output.extend_from_slice(format);
+                    output.push(b':');
+                    output.extend_from_slice(text.as_bytes());
+                    output.extend_from_slice(b"\r\n");
+                }
+            },
+            RedisType::Double(number) => match proto {
+                Protocol::Resp2 => RedisType::from(format_score(*number)).encode_into(output, proto),
+                Protocol::Resp3 => output.extend_from_slice(format!(",{}\r\n", format_score(*number)).as_bytes()),
+            },
+            RedisType::Bool(value) => {
+                let encoded: &[u8] = match (proto, value) {
+                    (Protocol::Resp2, true) => b":1\r\n",
+                    (Protocol::Resp2, false) => b":0\r\n",
+                    (Protocol::Resp3, true) => b"#t\r\n",
+                    (Protocol::Resp3, false) => b"#f\r\n",
+                };
+                output.extend_from_slice(encoded);
+            }
+            RedisType::Null => match proto {
+                Protocol::Resp2 => output.extend_from_slice(b"$-1\r\n"),
+                Protocol::Resp3 => output.extend_from_slice(b"_\r\n"),
+            },
         }
     }
+
+    /// Write using the given protocol
+    pub async fn write_as<W: AsyncWrite + Unpin>(&self, stream: &mut W, proto: Protocol) -> Result<()> {
+        stream.write_all(&self.encode(proto)).await?;
+        Ok(())
+    }
+
+    /// Write as RESP2, which every client and replica understands
+    pub async fn write<W: AsyncWrite + Unpin>(&self, stream: &mut W) -> Result<()> {
+        self.write_as(stream, Protocol::Resp2).await
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.encode(Protocol::Resp2)
+    }
 }
 
 impl From<&str> for RedisType {
     fn from(value: &str) -> Self {
-        RedisType::String(String::from(value))
+        RedisType::String(value.as_bytes().to_vec())
     }
 }
 
 impl From<String> for RedisType {
     fn from(value: String) -> Self {
+        RedisType::String(value.into_bytes())
+    }
+}
+
+impl From<&[u8]> for RedisType {
+    fn from(value: &[u8]) -> Self {
+        RedisType::String(value.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for RedisType {
+    fn from(value: Vec<u8>) -> Self {
         RedisType::String(value)
     }
 }
@@ -95,3 +219,79 @@ impl From<Vec<&str>> for RedisType {
         RedisType::Array(value.into_iter().map(RedisType::from).collect())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::types::{Protocol, RedisType};
+
+    #[test]
+    fn test_encode_map() {
+        let map = RedisType::Map(vec![
+            (RedisType::from("dir"), RedisType::from("/tmp")),
+            (RedisType::from("port"), RedisType::Int(6379)),
+        ]);
+
+        assert_eq!(map.encode(Protocol::Resp2), b"*4\r\n$3\r\ndir\r\n$4\r\n/tmp\r\n$4\r\nport\r\n:6379\r\n");
+        assert_eq!(map.encode(Protocol::Resp3), b"%2\r\n$3\r\ndir\r\n$4\r\n/tmp\r\n$4\r\nport\r\n:6379\r\n");
+    }
+
+    #[test]
+    fn test_encode_scalars() {
+        let scalars = RedisType::Array(vec![
+            RedisType::Double(2.5),
+            RedisType::Double(f64::NEG_INFINITY),
+            RedisType::Bool(true),
+            RedisType::Bool(false),
+            RedisType::Null,
+        ]);
+
+        assert_eq!(scalars.encode(Protocol::Resp2), b"*5\r\n$3\r\n2.5\r\n$4\r\n-inf\r\n:1\r\n:0\r\n$-1\r\n");
+        assert_eq!(scalars.encode(Protocol::Resp3), b"*5\r\n,2.5\r\n,-inf\r\n#t\r\n#f\r\n_\r\n");
+        // Replicas always get RESP2
+        assert_eq!(RedisType::Double(1.5).to_vec(), b"$3\r\n1.5\r\n");
+    }
+
+    #[test]
+    fn test_encode_verbatim() {
+        let verbatim = RedisType::Verbatim { format: *b"txt", text: String::from("# Server\r\n") };
+
+        assert_eq!(verbatim.encode(Protocol::Resp2), b"$10\r\n# Server\r\n\r\n");
+        assert_eq!(verbatim.encode(Protocol::Resp3), b"=14\r\ntxt:# Server\r\n\r\n");
+    }
+
+    #[test]
+    fn test_encode_push() {
+        let push = RedisType::Push(vec![RedisType::from("message"), RedisType::from("news"), RedisType::from("hi")]);
+
+        assert_eq!(push.encode(Protocol::Resp2), b"*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n");
+        assert_eq!(push.encode(Protocol::Resp3), b">3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n");
+    }
+
+    #[test]
+    fn test_encode_nested() {
+        let nested = RedisType::Array(vec![
+            RedisType::Set([b"a".to_vec()].into()),
+            RedisType::Map(vec![(RedisType::from("k"), RedisType::Map(vec![]))]),
+            RedisType::Null,
+        ]);
+
+        assert_eq!(nested.encode(Protocol::Resp2), b"*3\r\n*1\r\n$1\r\na\r\n*2\r\n$1\r\nk\r\n*0\r\n$-1\r\n");
+        assert_eq!(nested.encode(Protocol::Resp3), b"*3\r\n~1\r\n$1\r\na\r\n%1\r\n$1\r\nk\r\n%0\r\n_\r\n");
+    }
+
+    #[test]
+    fn test_encode_list() {
+        let list = RedisType::List(vec![b"a".to_vec(), b"".to_vec(), b"\r\n".to_vec()].into());
+
+        assert_eq!(list.to_vec(), b"*3\r\n$1\r\na\r\n$0\r\n\r\n$2\r\n\r\n\r\n");
+        assert_eq!(RedisType::List(Default::default()).to_vec(), b"*0\r\n");
+    }
+
+    #[test]
+    fn test_encode_hash() {
+        let hash = RedisType::Hash([(b"field".to_vec(), b"value".to_vec())].into());
+
+        assert_eq!(hash.encode(Protocol::Resp2), b"*2\r\n$5\r\nfield\r\n$5\r\nvalue\r\n");
+        assert_eq!(hash.encode(Protocol::Resp3), b"%1\r\n$5\r\nfield\r\n$5\r\nvalue\r\n");
+    }
+}
@@ -0,0 +1,551 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::{bail, Error, Result};
+
+use crate::common_cli_rep::parse_integer;
+use crate::store::list_range;
+
+/// Score of a member. Scores are never NaN, so they can be totally ordered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Score(f64);
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// A member along with its score, the way ZADD takes them
+pub type ScoredMember = (f64, Vec<u8>);
+
+/// Members with a score, ordered by score and then lexicographically, like
+/// Redis does
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SortedSet {
+    scores: HashMap<Vec<u8>, f64>,
+    order: BTreeSet<(Score, Vec<u8>)>,
+}
+
+/// What ZADD did with a member, along with its resulting score
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Added {
+    New(f64),
+    Updated(f64),
+    Unchanged(f64),
+    /// Left alone because of NX, XX, GT or LT
+    Skipped,
+}
+
+/// Flags of ZADD
+#[derive(Debug, Default, PartialEq)]
+pub struct ZAddOptions {
+    pub nx: bool,
+    pub xx: bool,
+    pub gt: bool,
+    pub lt: bool,
+    pub ch: bool,
+    pub incr: bool,
+}
+
+/// End of a score range, like `1.5`, `(1.5` (exclusive) or `-inf`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreBound {
+    pub score: f64,
+    pub exclusive: bool,
+}
+
+/// End of a lexicographical range: `-`, `+`, `[member` or `(member`
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexBound {
+    Min,
+    Max,
+    Inclusive(Vec<u8>),
+    Exclusive(Vec<u8>),
+}
+
+/// How ZRANGE selects members. Score and lexicographical ranges are kept as
+/// (min, max), no matter the order they were given in
+#[derive(Debug, Clone, PartialEq)]
+pub enum RangeBy {
+    Rank(i64, i64),
+    Score(ScoreBound, ScoreBound),
+    Lex(LexBound, LexBound),
+}
+
+/// Arguments of ZRANGE, after the key
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZRange {
+    pub by: RangeBy,
+    pub rev: bool,
+    /// Offset and count. A negative count means every member after the offset
+    pub limit: Option<(i64, i64)>,
+    pub withscores: bool,
+}
+
+impl ScoreBound {
+    fn above(&self, score: f64) -> bool {
+        if self.exclusive { score > self.score } else { score >= self.score }
+    }
+
+    fn below(&self, score: f64) -> bool {
+        if self.exclusive { score < self.score } else { score <= self.score }
+    }
+}
+
+impl LexBound {
+    fn above(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Min => true,
+            LexBound::Max => false,
+            LexBound::Inclusive(bound) => member >= bound.as_slice(),
+            LexBound::Exclusive(bound) => member > bound.as_slice(),
+        }
+    }
+
+    fn below(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(bound) => member <= bound.as_slice(),
+            LexBound::Exclusive(bound) => member < bound.as_slice(),
+        }
+    }
+}
+
+/// Parse the end of a score range
+pub fn parse_score_bound(arg: &str) -> Result<ScoreBound> {
+    let (score, exclusive) = match arg.strip_prefix('(') {
+        Some(score) => (score, true),
+        None => (arg, false),
+    };
+    let score = parse_score(score).map_err(|_| Error::msg("min or max is not a float"))?;
+    Ok(ScoreBound { score, exclusive })
+}
+
+/// Parse the end of a lexicographical range
+pub fn parse_lex_bound(arg: &[u8]) -> Result<LexBound> {
+    match arg {
+        b"-" => Ok(LexBound::Min),
+        b"+" => Ok(LexBound::Max),
+        [b'[', member @ ..] => Ok(LexBound::Inclusive(member.to_vec())),
+        [b'(', member @ ..] => Ok(LexBound::Exclusive(member.to_vec())),
+        _ => bail!("min or max not valid string range item"),
+    }
+}
+
+/// Parse the arguments of ZRANGE after the key: the start and stop of the
+/// range, followed by the options. Lexicographical bounds are taken as they
+/// are, like the members they're compared to
+pub fn parse_zrange<A: AsRef<[u8]>>(args: &[A]) -> Result<ZRange> {
+    let [start, stop, rest @ ..] = args else {
+        bail!("syntax error")
+    };
+    let text = |arg: &A| String::from_utf8_lossy(arg.as_ref()).to_string();
+    let (mut by_score, mut by_lex, mut rev, mut withscores) = (false, false, false, false);
+    let mut limit = None;
+
+    let mut rest = rest.iter();
+    while let Some(option) = rest.next() {
+        match text(option).to_ascii_lowercase().as_str() {
+            "byscore" => by_score = true,
+            "bylex" => by_lex = true,
+            "rev" => rev = true,
+            "withscores" => withscores = true,
+            "limit" => {
+                let (Some(offset), Some(count)) = (rest.next(), rest.next()) else {
+                    bail!("syntax error")
+                };
+                limit = Some((parse_integer(&text(offset))?, parse_integer(&text(count))?));
+            }
+            _ => bail!("syntax error"),
+        }
+    }
+
+    if by_score && by_lex {
+        bail!("syntax error")
+    }
+    if limit.is_some() && !by_score && !by_lex {
+        bail!("syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX")
+    }
+    if withscores && by_lex {
+        bail!("syntax error, WITHSCORES not supported in combination with BYLEX")
+    }
+
+    // With REV, score and lexicographical ranges go from max to min
+    let (min, max) = if rev && (by_score || by_lex) { (stop, start) } else { (start, stop) };
+    let by = if by_score {
+        RangeBy::Score(parse_score_bound(&text(min))?, parse_score_bound(&text(max))?)
+    } else if by_lex {
+        RangeBy::Lex(parse_lex_bound(min.as_ref())?, parse_lex_bound(max.as_ref())?)
+    } else {
+        RangeBy::Rank(parse_integer(&text(start))?, parse_integer(&text(stop))?)
+    };
+    Ok(ZRange { by, rev, limit, withscores })
+}
+
+/// Parse the arguments of ZRANGEBYSCORE after the key, as the equivalent
+/// ZRANGE ... BYSCORE
+pub fn parse_zrangebyscore(args: &[&str]) -> Result<ZRange> {
+    let [min, max, rest @ ..] = args else {
+        bail!("syntax error")
+    };
+    let mut range = ZRange {
+        by: RangeBy::Score(parse_score_bound(min)?, parse_score_bound(max)?),
+        rev: false,
+        limit: None,
+        withscores: false,
+    };
+
+    let mut rest = rest.iter();
+    while let Some(option) = rest.next() {
+        match option.to_ascii_lowercase().as_str() {
+            "withscores" => range.withscores = true,
+            "limit" => {
+                let (Some(offset), Some(count)) = (rest.next(), rest.next()) else {
+                    bail!("syntax error")
+                };
+                range.limit = Some((parse_integer(offset)?, parse_integer(count)?));
+            }
+            _ => bail!("syntax error"),
+        }
+    }
+    Ok(range)
+}
+
+/// Parse a score, which may be `inf`, `+inf` or `-inf`, but never NaN
+pub fn parse_score(arg: &str) -> Result<f64> {
+    arg.parse::<f64>().ok()
+        .filter(|score| !score.is_nan())
+        .ok_or_else(|| Error::msg("value is not a valid float"))
+}
+
+/// Format a score the way Redis replies with it
+pub fn format_score(score: f64) -> String {
+    score.to_string()
+}
+
+/// Parse the arguments of ZADD after the key: the flags, followed by the
+/// score and member pairs. Members are taken as they are, they don't need to
+/// be text
+pub fn parse_zadd<A: AsRef<[u8]>>(args: &[A]) -> Result<(ZAddOptions, Vec<ScoredMember>)> {
+    let mut options = ZAddOptions::default();
+    let mut rest = args;
+    let text = |arg: &A| String::from_utf8_lossy(arg.as_ref()).to_string();
+
+    while let Some((flag, tail)) = rest.split_first() {
+        match text(flag).to_ascii_lowercase().as_str() {
+            "nx" => options.nx = true,
+            "xx" => options.xx = true,
+            "gt" => options.gt = true,
+            "lt" => options.lt = true,
+            "ch" => options.ch = true,
+            "incr" => options.incr = true,
+            _ => break,
+        }
+        rest = tail;
+    }
+
+    if rest.is_empty() || !rest.len().is_multiple_of(2) {
+        bail!("syntax error")
+    }
+    if options.nx && options.xx {
+        bail!("XX and NX options at the same time are not compatible")
+    }
+    if (options.gt && (options.lt || options.nx)) || (options.lt && options.nx) {
+        bail!("GT, LT, and/or NX options at the same time are not compatible")
+    }
+    if options.incr && rest.len() > 2 {
+        bail!("INCR option supports a single increment-element pair")
+    }
+
+    let pairs = rest.chunks(2)
+        .map(|pair| Ok((parse_score(&text(&pair[0]))?, pair[1].as_ref().to_vec())))
+        .collect::<Result<Vec<_>>>()?;
+    Ok((options, pairs))
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Position of a member, counting from the lowest score, or from the
+    /// highest one with `rev`
+    pub fn rank(&self, member: &[u8], rev: bool) -> Option<usize> {
+        let score = self.score(member)?;
+        let below = self.order.range(..(Score(score), member.to_vec())).count();
+        Some(if rev { self.len() - below - 1 } else { below })
+    }
+
+    /// Members and their scores, from the lowest score to the highest
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&[u8], f64)> {
+        self.order.iter().map(|(score, member)| (member.as_slice(), score.0))
+    }
+
+    /// Members selected by a ZRANGE, with their scores, in the order they're
+    /// replied with
+    pub fn range(&self, range: &ZRange) -> Vec<(&[u8], f64)> {
+        let ordered: Box<dyn Iterator<Item = (&[u8], f64)>> = match range.rev {
+            true => Box::new(self.iter().rev()),
+            false => Box::new(self.iter()),
+        };
+        let selected: Box<dyn Iterator<Item = (&[u8], f64)>> = match &range.by {
+            RangeBy::Rank(start, stop) => match list_range(*start, *stop, self.len()) {
+                Some((start, stop)) => Box::new(ordered.skip(start).take(stop - start + 1)),
+                None => return vec![],
+            },
+            RangeBy::Score(min, max) => Box::new(ordered.filter(|&(_, score)| min.above(score) && max.below(score))),
+            RangeBy::Lex(min, max) => Box::new(ordered.filter(|&(member, _)| min.above(member) && max.below(member))),
+        };
+
+        match range.limit {
+            Some((offset, _)) if offset < 0 => vec![],
+            Some((offset, count)) if count >= 0 => selected.skip(offset as usize).take(count as usize).collect(),
+            Some((offset, _)) => selected.skip(offset as usize).collect(),
+            None => selected.collect(),
+        }
+    }
+
+    /// Number of members with a score between `min` and `max`
+    pub fn count(&self, min: &ScoreBound, max: &ScoreBound) -> usize {
+        self.iter().filter(|&(_, score)| min.above(score) && max.below(score)).count()
+    }
+
+    /// Set the score of a member, adding it if needed. Returns whether it's new
+    pub fn insert(&mut self, member: &[u8], score: f64) -> bool {
+        // -0 and 0 are the same score
+        let score = score + 0.0;
+        let previous = self.scores.insert(member.to_vec(), score);
+        if let Some(previous) = previous {
+            self.order.remove(&(Score(previous), member.to_vec()));
+        }
+        self.order.insert((Score(score), member.to_vec()));
+        previous.is_none()
+    }
+
+    /// Remove the member with the lowest score, or the highest with `max`
+    pub fn pop(&mut self, max: bool) -> Option<(Vec<u8>, f64)> {
+        let (score, member) = if max { self.order.pop_last()? } else { self.order.pop_first()? };
+        self.scores.remove(&member);
+        Some((member, score.0))
+    }
+
+    /// Remove a member. Returns whether it was there
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => self.order.remove(&(Score(score), member.to_vec())),
+            None => false,
+        }
+    }
+
+    /// Add a member, or update its score, following the ZADD flags
+    pub fn add(&mut self, member: &[u8], score: f64, options: &ZAddOptions) -> Result<Added> {
+        let Some(current) = self.score(member) else {
+            if options.xx {
+                return Ok(Added::Skipped)
+            }
+            self.insert(member, score);
+            return Ok(Added::New(score))
+        };
+        if options.nx {
+            return Ok(Added::Skipped)
+        }
+
+        let score = if options.incr { current + score } else { score };
+        if score.is_nan() {
+            bail!("resulting score is not a number (NaN)")
+        }
+        if (options.gt && score <= current) || (options.lt && score >= current) {
+            return Ok(Added::Skipped)
+        }
+
+        if score == current {
+            Ok(Added::Unchanged(score))
+        } else {
+            self.insert(member, score);
+            Ok(Added::Updated(score))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::zset::{
+        parse_score_bound, parse_zadd, parse_zrange, parse_zrangebyscore, Added, LexBound, RangeBy, ScoreBound, SortedSet, ZAddOptions,
+    };
+
+    fn members(zset: &SortedSet) -> Vec<(&str, f64)> {
+        zset.iter().map(|(member, score)| (std::str::from_utf8(member).unwrap(), score)).collect()
+    }
+
+    #[test]
+    fn test_order() {
+        let mut zset = SortedSet::default();
+        assert!(zset.insert(b"b", 1.0));
+        assert!(zset.insert(b"a", 1.0));
+        assert!(zset.insert(b"c", -0.0));
+        assert!(zset.insert(b"d", f64::INFINITY));
+        assert!(!zset.insert(b"d", 0.0));
+
+        // Ties are broken by member, and -0 is the same as 0
+        assert_eq!(members(&zset), vec![("c", 0.0), ("d", 0.0), ("a", 1.0), ("b", 1.0)]);
+        assert!(zset.remove(b"d"));
+        assert!(!zset.remove(b"d"));
+        assert_eq!(zset.len(), 3);
+
+        assert_eq!(zset.pop(true), Some((b"b".to_vec(), 1.0)));
+        assert_eq!(zset.pop(false), Some((b"c".to_vec(), 0.0)));
+        assert_eq!(zset.score(b"c"), None);
+        assert_eq!(zset.len(), 1);
+    }
+
+    #[test]
+    fn test_rank() {
+        let mut zset = SortedSet::default();
+        for (member, score) in [("c", 2.0), ("b", 1.0), ("a", 1.0), ("d", f64::NEG_INFINITY)] {
+            zset.insert(member.as_bytes(), score);
+        }
+
+        assert_eq!(zset.rank(b"d", false), Some(0));
+        assert_eq!(zset.rank(b"a", false), Some(1));
+        assert_eq!(zset.rank(b"b", false), Some(2));
+        assert_eq!(zset.rank(b"c", true), Some(0));
+        assert_eq!(zset.rank(b"d", true), Some(3));
+        assert_eq!(zset.rank(b"e", false), None);
+    }
+
+    #[test]
+    fn test_add() {
+        let mut zset = SortedSet::default();
+        let options = |flags: &[&str]| parse_zadd(&[flags, &["1", "x"]].concat()).unwrap().0;
+
+        assert_eq!(zset.add(b"a", 5.0, &ZAddOptions::default()).unwrap(), Added::New(5.0));
+        assert_eq!(zset.add(b"a", 5.0, &ZAddOptions::default()).unwrap(), Added::Unchanged(5.0));
+        assert_eq!(zset.add(b"a", 3.0, &options(&["nx"])).unwrap(), Added::Skipped);
+        assert_eq!(zset.add(b"b", 3.0, &options(&["xx"])).unwrap(), Added::Skipped);
+        assert_eq!(zset.add(b"a", 3.0, &options(&["gt"])).unwrap(), Added::Skipped);
+        assert_eq!(zset.add(b"a", 7.0, &options(&["gt"])).unwrap(), Added::Updated(7.0));
+        assert_eq!(zset.add(b"a", 9.0, &options(&["lt"])).unwrap(), Added::Skipped);
+        assert_eq!(zset.add(b"a", 2.0, &options(&["incr"])).unwrap(), Added::Updated(9.0));
+        // GT and LT don't stop new members
+        assert_eq!(zset.add(b"b", 1.0, &options(&["lt"])).unwrap(), Added::New(1.0));
+
+        zset.insert(b"inf", f64::INFINITY);
+        assert!(zset.add(b"inf", f64::NEG_INFINITY, &options(&["incr"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_zadd() {
+        let (options, pairs) = parse_zadd(&["XX", "ch", "1.5", "a", "-inf", "b"]).unwrap();
+        assert_eq!(options, ZAddOptions { xx: true, ch: true, ..Default::default() });
+        assert_eq!(pairs, vec![(1.5, b"a".to_vec()), (f64::NEG_INFINITY, b"b".to_vec())]);
+
+        for wrong in [
+            &["1"][..],
+            &["NX"],
+            &["1", "a", "2"],
+            &["nan", "a"],
+            &["one", "a"],
+            &["NX", "XX", "1", "a"],
+            &["GT", "NX", "1", "a"],
+            &["GT", "LT", "1", "a"],
+            &["INCR", "1", "a", "2", "b"],
+        ] {
+            assert!(parse_zadd(wrong).is_err(), "{wrong:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn test_parse_zrange() {
+        let range = parse_zrange(&["0", "-1"]).unwrap();
+        assert_eq!(range.by, RangeBy::Rank(0, -1));
+        assert!(!range.rev && !range.withscores && range.limit.is_none());
+
+        // REV swaps the ends of score ranges
+        let range = parse_zrange(&["(5", "-inf", "BYSCORE", "REV", "LIMIT", "1", "-1", "withscores"]).unwrap();
+        let (min, max) = (ScoreBound { score: f64::NEG_INFINITY, exclusive: false }, ScoreBound { score: 5.0, exclusive: true });
+        assert_eq!(range.by, RangeBy::Score(min, max));
+        assert!(range.rev && range.withscores);
+        assert_eq!(range.limit, Some((1, -1)));
+
+        let range = parse_zrange(&["[a", "+", "bylex"]).unwrap();
+        assert_eq!(range.by, RangeBy::Lex(LexBound::Inclusive(b"a".to_vec()), LexBound::Max));
+
+        for wrong in [
+            &["0"][..],
+            &["a", "1"],
+            &["0", "1", "LIMIT", "0", "1"],
+            &["0", "1", "BYSCORE", "LIMIT", "0"],
+            &["0", "1", "BYSCORE", "BYLEX"],
+            &["[a", "[b", "BYLEX", "WITHSCORES"],
+            &["a", "b", "BYLEX"],
+            &["(", "1", "BYSCORE"],
+            &["nan", "1", "BYSCORE"],
+            &["0", "1", "REVERSE"],
+        ] {
+            assert!(parse_zrange(wrong).is_err(), "{wrong:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn test_parse_zrangebyscore() {
+        let range = parse_zrangebyscore(&["(1", "+inf", "LIMIT", "0", "2", "WITHSCORES"]).unwrap();
+        assert_eq!(range, parse_zrange(&["(1", "+inf", "BYSCORE", "LIMIT", "0", "2", "WITHSCORES"]).unwrap());
+
+        for wrong in [&["1"][..], &["1", "2", "REV"], &["1", "2", "LIMIT", "0"], &["a", "2"]] {
+            assert!(parse_zrangebyscore(wrong).is_err(), "{wrong:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn test_range() {
+        let mut zset = SortedSet::default();
+        for (member, score) in [("a", 1.0), ("b", 2.0), ("c", 3.0), ("d", 3.0), ("e", 5.0)] {
+            zset.insert(member.as_bytes(), score);
+        }
+        let range = |args: &[&str]| -> Vec<&str> {
+            zset.range(&parse_zrange(args).unwrap()).into_iter().map(|(member, _)| std::str::from_utf8(member).unwrap()).collect()
+        };
+
+        assert_eq!(range(&["0", "-1"]), vec!["a", "b", "c", "d", "e"]);
+        assert_eq!(range(&["1", "2"]), vec!["b", "c"]);
+        assert_eq!(range(&["-2", "100"]), vec!["d", "e"]);
+        assert_eq!(range(&["0", "1", "REV"]), vec!["e", "d"]);
+        assert!(range(&["3", "1"]).is_empty());
+
+        assert_eq!(range(&["(1", "3", "BYSCORE"]), vec!["b", "c", "d"]);
+        assert_eq!(range(&["+inf", "(3", "BYSCORE", "REV"]), vec!["e"]);
+        assert_eq!(range(&["-inf", "+inf", "BYSCORE", "LIMIT", "1", "2"]), vec!["b", "c"]);
+        assert_eq!(range(&["+inf", "-inf", "BYSCORE", "REV", "LIMIT", "3", "-1"]), vec!["b", "a"]);
+        assert!(range(&["-inf", "+inf", "BYSCORE", "LIMIT", "-1", "2"]).is_empty());
+
+        assert_eq!(range(&["[b", "(d", "BYLEX"]), vec!["b", "c"]);
+        assert_eq!(range(&["+", "(c", "BYLEX", "REV"]), vec!["e", "d"]);
+        assert_eq!(range(&["-", "+", "BYLEX", "LIMIT", "0", "1"]), vec!["a"]);
+
+        let range = parse_zrange(&["2", "3", "BYSCORE", "WITHSCORES"]).unwrap();
+        assert_eq!(zset.range(&range), vec![(&b"b"[..], 2.0), (b"c", 3.0), (b"d", 3.0)]);
+
+        let bound = |arg| parse_score_bound(arg).unwrap();
+        assert_eq!(zset.count(&bound("(1"), &bound("3")), 3);
+        assert_eq!(zset.count(&bound("-inf"), &bound("+inf")), 5);
+        assert_eq!(zset.count(&bound("4"), &bound("3")), 0);
+    }
+}
@@ -0,0 +1,770 @@
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use redis_starter_rust::{
+    config::Configuration, server::Server, store::Store, testclient::TestClient, types::RedisType,
+};
+
+async fn read_reply(stream: &mut TcpStream, expected: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0; expected.len()];
+    stream.read_exact(&mut buf).await.unwrap();
+    buf
+}
+
+#[tokio::test]
+async fn test_embedded_set_get() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0")
+        .store(Store::default())
+        .run()
+        .await
+        .unwrap();
+
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+
+    stream.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n").await.unwrap();
+    assert_eq!(read_reply(&mut stream, b"+OK\r\n").await, b"+OK\r\n");
+
+    stream.write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n").await.unwrap();
+    assert_eq!(read_reply(&mut stream, b"$3\r\nbar\r\n").await, b"$3\r\nbar\r\n");
+
+    server.shutdown().await.unwrap();
+
+    // The server closes open connections when shutting down
+    let mut buf = [0; 1];
+    assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_embedded_with_test_client() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0")
+        .store(Store::default())
+        .run()
+        .await
+        .unwrap();
+
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+
+    client.set("foo", "bar").await.unwrap();
+    assert_eq!(client.get("foo").await.unwrap(), Some(String::from("bar")));
+    assert_eq!(client.get("missing").await.unwrap(), None);
+    assert!(client.raw(&["NOSUCHCOMMAND"]).await.is_err());
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_incr_overflow_keeps_value() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0")
+        .store(Store::default())
+        .run()
+        .await
+        .unwrap();
+
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+    let max = i64::MAX.to_string();
+
+    client.set("counter", &max).await.unwrap();
+    let error = client.raw(&["INCR", "counter"]).await.unwrap_err();
+    assert_eq!(error.to_string(), "ERR increment or decrement would overflow");
+    assert_eq!(client.get("counter").await.unwrap(), Some(max));
+
+    client.set("counter", "10").await.unwrap();
+    assert_eq!(client.raw(&["INCR", "counter"]).await.unwrap(), RedisType::Int(11));
+    assert_eq!(client.raw(&["DECRBY", "counter", "20"]).await.unwrap(), RedisType::Int(-9));
+    assert_eq!(client.get("counter").await.unwrap(), Some(String::from("-9")));
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_client_reply_modes() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0")
+        .store(Store::default())
+        .run()
+        .await
+        .unwrap();
+
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+
+    // Nothing is answered while replies are off, not even errors
+    client.send(&["CLIENT", "REPLY", "OFF"]).await.unwrap();
+    client.send(&["SET", "key", "1"]).await.unwrap();
+    client.send(&["NOSUCHCOMMAND"]).await.unwrap();
+    client.send(&["INCR", "key"]).await.unwrap();
+    assert_eq!(client.raw(&["CLIENT", "REPLY", "ON"]).await.unwrap(), RedisType::from("OK"));
+    assert_eq!(client.get("key").await.unwrap(), Some(String::from("2")));
+
+    // SKIP only affects the next command
+    client.send(&["CLIENT", "REPLY", "SKIP"]).await.unwrap();
+    client.send(&["INCR", "key"]).await.unwrap();
+    assert_eq!(client.raw(&["INCR", "key"]).await.unwrap(), RedisType::Int(4));
+    assert_eq!(client.raw(&["INCR", "key"]).await.unwrap(), RedisType::Int(5));
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_setex_and_psetex() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0")
+        .store(Store::default())
+        .run()
+        .await
+        .unwrap();
+
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+
+    assert_eq!(client.raw(&["SETEX", "key", "100", "value"]).await.unwrap(), RedisType::from("OK"));
+    assert_eq!(client.get("key").await.unwrap(), Some(String::from("value")));
+    assert!(matches!(client.raw(&["EXPIRETIME", "key"]).await.unwrap(), RedisType::Int(t) if t > 0));
+
+    client.raw(&["PSETEX", "short", "1", "value"]).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    assert_eq!(client.get("short").await.unwrap(), None);
+
+    for cmd in [["SETEX", "key", "0", "value"], ["PSETEX", "key", "-5", "value"]] {
+        let error = client.raw(&cmd).await.unwrap_err();
+        assert!(error.to_string().starts_with("ERR invalid expire time"), "{error}");
+    }
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_expire_and_pexpire() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0")
+        .store(Store::default())
+        .run()
+        .await
+        .unwrap();
+
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+
+    client.set("key", "value").await.unwrap();
+    assert_eq!(client.raw(&["EXPIRE", "missing", "10"]).await.unwrap(), RedisType::Int(0));
+    assert_eq!(client.raw(&["EXPIRE", "key", "100"]).await.unwrap(), RedisType::Int(1));
+    let RedisType::Int(expires) = client.raw(&["PEXPIRETIME", "key"]).await.unwrap() else { panic!() };
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as i64;
+    assert!((now + 90_000..=now + 100_000).contains(&expires));
+
+    assert_eq!(client.raw(&["PEXPIRE", "key", "1"]).await.unwrap(), RedisType::Int(1));
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    assert_eq!(client.get("key").await.unwrap(), None);
+
+    // Expiring in the past removes the key
+    client.set("key", "value").await.unwrap();
+    assert_eq!(client.raw(&["EXPIRE", "key", "-1"]).await.unwrap(), RedisType::Int(1));
+    assert_eq!(client.get("key").await.unwrap(), None);
+
+    // Expired keys go away even if nobody asks for them
+    client.raw(&["SET", "key", "value", "PX", "10"]).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    assert_eq!(client.raw(&["DBSIZE"]).await.unwrap(), RedisType::Int(0));
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_shutdown_command() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0")
+        .store(Store::default())
+        .run()
+        .await
+        .unwrap();
+
+    let mut idle = TcpStream::connect(server.local_addr()).await.unwrap();
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+    client.set("key", "value").await.unwrap();
+
+    client.send(&["SHUTDOWN", "NOSAVE"]).await.unwrap();
+    assert!(client.read().await.is_err());
+
+    // Every connection is closed, and the server stops on its own
+    let mut buf = [0; 1];
+    assert_eq!(idle.read(&mut buf).await.unwrap(), 0);
+    tokio::time::timeout(std::time::Duration::from_secs(5), server.wait()).await.unwrap().unwrap();
+}
+
+async fn info_field(client: &mut TestClient, field: &str) -> String {
+    let RedisType::String(info) = client.raw(&["INFO", "persistence"]).await.unwrap() else {
+        panic!("INFO should return a bulk string")
+    };
+    String::from_utf8(info).unwrap()
+        .lines()
+        .find_map(|line| line.strip_prefix(field).and_then(|rest| rest.strip_prefix(':')).map(String::from))
+        .unwrap_or_else(|| panic!("{field} is missing from INFO"))
+}
+
+#[tokio::test]
+async fn test_save_keeps_millisecond_ttl() {
+    let dir = std::env::temp_dir().join(format!("redis-save-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut config = Configuration::default();
+    config.update(String::from("dir"), dir.to_string_lossy().into_owned()).unwrap();
+
+    let server = Server::builder().bind("127.0.0.1:0").config(config.clone()).run().await.unwrap();
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+    client.raw(&["SET", "foo", "bar", "PX", "1500"]).await.unwrap();
+    let RedisType::Int(expires) = client.raw(&["PEXPIRETIME", "foo"]).await.unwrap() else {
+        panic!("PEXPIRETIME should return an integer")
+    };
+    assert_eq!(info_field(&mut client, "rdb_changes_since_last_save").await, "1");
+    assert_eq!(client.raw(&["SAVE"]).await.unwrap(), RedisType::from("OK"));
+    assert_eq!(info_field(&mut client, "rdb_changes_since_last_save").await, "0");
+    assert_eq!(info_field(&mut client, "rdb_last_bgsave_status").await, "ok");
+    server.shutdown().await.unwrap();
+
+    // A new server loads the file, with the same expiration time
+    let server = Server::builder().bind("127.0.0.1:0").config(config).run().await.unwrap();
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+    assert_eq!(client.get("foo").await.unwrap(), Some(String::from("bar")));
+    assert_eq!(client.raw(&["PEXPIRETIME", "foo"]).await.unwrap(), RedisType::Int(expires));
+    server.shutdown().await.unwrap();
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_save_aggregates() {
+    let dir = std::env::temp_dir().join(format!("redis-save-aggregates-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut config = Configuration::default();
+    config.update(String::from("dir"), dir.to_string_lossy().into_owned()).unwrap();
+
+    let server = Server::builder().bind("127.0.0.1:0").config(config.clone()).run().await.unwrap();
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+    client.raw(&["RPUSH", "list", "a", "b", "a"]).await.unwrap();
+    client.raw(&["SADD", "set", "member"]).await.unwrap();
+    client.raw(&["HSET", "hash", "field", "value"]).await.unwrap();
+    client.raw(&["ZADD", "zset", "1.5", "a", "-inf", "b"]).await.unwrap();
+    client.raw(&["PEXPIRE", "hash", "100000"]).await.unwrap();
+    assert_eq!(client.raw(&["SAVE"]).await.unwrap(), RedisType::from("OK"));
+    server.shutdown().await.unwrap();
+
+    let server = Server::builder().bind("127.0.0.1:0").config(config).run().await.unwrap();
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+    assert_eq!(client.raw(&["LRANGE", "list", "0", "-1"]).await.unwrap(), RedisType::from(vec!["a", "b", "a"]));
+    assert_eq!(client.raw(&["SMEMBERS", "set"]).await.unwrap(), RedisType::from(vec!["member"]));
+    assert_eq!(client.raw(&["HGET", "hash", "field"]).await.unwrap(), RedisType::from("value"));
+    assert!(matches!(client.raw(&["PEXPIRETIME", "hash"]).await.unwrap(), RedisType::Int(at) if at > 0));
+    let zrange = client.raw(&["ZRANGE", "zset", "0", "-1", "WITHSCORES"]).await.unwrap();
+    assert_eq!(zrange, RedisType::from(vec!["b", "-inf", "a", "1.5"]));
+    server.shutdown().await.unwrap();
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_expiretime_units() {
+    let server = Server::builder().bind("127.0.0.1:0").store(Store::default()).run().await.unwrap();
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+
+    client.set("permanent", "value").await.unwrap();
+    client.raw(&["SET", "volatile", "value", "PXAT", "4102444800123"]).await.unwrap();
+
+    assert_eq!(client.raw(&["EXPIRETIME", "volatile"]).await.unwrap(), RedisType::Int(4102444800));
+    assert_eq!(client.raw(&["PEXPIRETIME", "volatile"]).await.unwrap(), RedisType::Int(4102444800123));
+    for command in ["EXPIRETIME", "PEXPIRETIME"] {
+        assert_eq!(client.raw(&[command, "permanent"]).await.unwrap(), RedisType::Int(-1));
+        assert_eq!(client.raw(&[command, "missing"]).await.unwrap(), RedisType::Int(-2));
+    }
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_flushdb() {
+    let server = Server::builder().bind("127.0.0.1:0").store(Store::default()).run().await.unwrap();
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+
+    client.set("a", "1").await.unwrap();
+    client.raw(&["SET", "b", "2", "EX", "100"]).await.unwrap();
+    assert_eq!(client.raw(&["DBSIZE"]).await.unwrap(), RedisType::Int(2));
+
+    assert_eq!(client.raw(&["FLUSHDB"]).await.unwrap(), RedisType::from("OK"));
+    assert_eq!(client.raw(&["DBSIZE"]).await.unwrap(), RedisType::Int(0));
+    assert_eq!(client.get("a").await.unwrap(), None);
+
+    client.set("a", "1").await.unwrap();
+    assert_eq!(client.raw(&["FLUSHDB", "async"]).await.unwrap(), RedisType::from("OK"));
+    assert_eq!(client.raw(&["DBSIZE"]).await.unwrap(), RedisType::Int(0));
+    assert!(client.raw(&["FLUSHDB", "later"]).await.is_err());
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_object_encoding() {
+    let server = Server::builder().bind("127.0.0.1:0").store(Store::default()).run().await.unwrap();
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+
+    client.set("number", "12345").await.unwrap();
+    client.set("short", &"x".repeat(44)).await.unwrap();
+    client.set("long", &"x".repeat(45)).await.unwrap();
+    for (key, encoding) in [("number", "int"), ("short", "embstr"), ("long", "raw")] {
+        assert_eq!(client.raw(&["OBJECT", "ENCODING", key]).await.unwrap(), RedisType::from(encoding));
+    }
+
+    client.raw(&["APPEND", "number", "6"]).await.unwrap();
+    assert_eq!(client.raw(&["OBJECT", "ENCODING", "number"]).await.unwrap(), RedisType::from("raw"));
+
+    let error = client.raw(&["OBJECT", "ENCODING", "missing"]).await.unwrap_err();
+    assert_eq!(error.to_string(), "ERR no such key");
+
+    // Lists of medium elements leave the listpack once the threshold is lowered
+    let medium = "x".repeat(500);
+    client.raw(&["RPUSH", "list", &medium, &medium, &medium]).await.unwrap();
+    assert_eq!(client.raw(&["OBJECT", "ENCODING", "list"]).await.unwrap(), RedisType::from("listpack"));
+    assert_eq!(client.raw(&["DEBUG", "QUICKLIST-PACKED-THRESHOLD", "100"]).await.unwrap(), RedisType::from("OK"));
+    assert_eq!(client.raw(&["OBJECT", "ENCODING", "list"]).await.unwrap(), RedisType::from("quicklist"));
+    assert_eq!(client.raw(&["DEBUG", "QUICKLIST-PACKED-THRESHOLD", "1K"]).await.unwrap(), RedisType::from("OK"));
+    assert_eq!(client.raw(&["OBJECT", "ENCODING", "list"]).await.unwrap(), RedisType::from("listpack"));
+    assert!(client.raw(&["DEBUG", "QUICKLIST-PACKED-THRESHOLD", "lots"]).await.is_err());
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_blocking_pop() {
+    let server = Server::builder().bind("127.0.0.1:0").store(Store::default()).run().await.unwrap();
+    let mut blocked = TestClient::connect(server.local_addr()).await.unwrap();
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+
+    assert_eq!(blocked.raw(&["BLPOP", "list", "0.05"]).await.unwrap(), RedisType::Null);
+
+    blocked.send(&["BRPOP", "list", "other", "0"]).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    client.raw(&["RPUSH", "other", "a", "b"]).await.unwrap();
+    assert_eq!(blocked.read().await.unwrap(), RedisType::from(vec!["other", "b"]));
+    assert_eq!(client.raw(&["LLEN", "other"]).await.unwrap(), RedisType::Int(1));
+
+    assert!(blocked.raw(&["BLPOP", "list", "-1"]).await.is_err());
+    client.set("string", "value").await.unwrap();
+    assert!(blocked.raw(&["BLPOP", "string", "0"]).await.is_err());
+
+    // Shutting down doesn't wait for blocked clients
+    blocked.send(&["BLPOP", "list", "0"]).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    tokio::time::timeout(std::time::Duration::from_secs(5), server.shutdown()).await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_pushx() {
+    let server = Server::builder().bind("127.0.0.1:0").store(Store::default()).run().await.unwrap();
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+
+    // Nothing is created for missing keys
+    assert_eq!(client.raw(&["LPUSHX", "list", "a"]).await.unwrap(), RedisType::Int(0));
+    assert_eq!(client.raw(&["RPUSHX", "list", "a"]).await.unwrap(), RedisType::Int(0));
+    assert_eq!(client.raw(&["EXISTS", "list"]).await.unwrap(), RedisType::Int(0));
+
+    client.raw(&["RPUSH", "list", "a"]).await.unwrap();
+    assert_eq!(client.raw(&["LPUSHX", "list", "b", "c"]).await.unwrap(), RedisType::Int(3));
+    assert_eq!(client.raw(&["RPUSHX", "list", "d"]).await.unwrap(), RedisType::Int(4));
+    assert_eq!(client.raw(&["LRANGE", "list", "0", "-1"]).await.unwrap(), RedisType::from(vec!["c", "b", "a", "d"]));
+
+    client.set("string", "value").await.unwrap();
+    for command in ["LPUSHX", "RPUSHX"] {
+        let error = client.raw(&[command, "string", "a"]).await.unwrap_err();
+        assert!(error.to_string().starts_with("WRONGTYPE"));
+    }
+    assert_eq!(client.get("string").await.unwrap(), Some(String::from("value")));
+    assert!(client.raw(&["LPUSHX", "list"]).await.is_err());
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_set_membership() {
+    let server = Server::builder().bind("127.0.0.1:0").store(Store::default()).run().await.unwrap();
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+    let flags = |flags: &[i64]| RedisType::Array(flags.iter().map(|&flag| RedisType::Int(flag)).collect());
+
+    client.raw(&["SADD", "set", "a", "b", "c"]).await.unwrap();
+    assert_eq!(client.raw(&["SISMEMBER", "set", "b"]).await.unwrap(), RedisType::Int(1));
+    assert_eq!(client.raw(&["SISMEMBER", "set", "x"]).await.unwrap(), RedisType::Int(0));
+    // In the order the members are given
+    assert_eq!(client.raw(&["SMISMEMBER", "set", "c", "x", "a", "c"]).await.unwrap(), flags(&[1, 0, 1, 1]));
+    assert_eq!(client.raw(&["SCARD", "set"]).await.unwrap(), RedisType::Int(3));
+
+    assert_eq!(client.raw(&["SISMEMBER", "missing", "a"]).await.unwrap(), RedisType::Int(0));
+    assert_eq!(client.raw(&["SMISMEMBER", "missing", "a", "b"]).await.unwrap(), flags(&[0, 0]));
+    assert_eq!(client.raw(&["SCARD", "missing"]).await.unwrap(), RedisType::Int(0));
+
+    client.set("string", "value").await.unwrap();
+    for command in [&["SISMEMBER", "string", "a"][..], &["SMISMEMBER", "string", "a"], &["SCARD", "string"]] {
+        let error = client.raw(command).await.unwrap_err();
+        assert!(error.to_string().starts_with("WRONGTYPE"));
+    }
+    assert!(client.raw(&["SISMEMBER", "set", "a", "b"]).await.is_err());
+    assert!(client.raw(&["SMISMEMBER", "set"]).await.is_err());
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_zincrby_and_zcount() {
+    let server = Server::builder().bind("127.0.0.1:0").store(Store::default()).run().await.unwrap();
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+
+    // Missing members, and keys, are created with the increment as score
+    assert_eq!(client.raw(&["ZINCRBY", "zset", "1.5", "a"]).await.unwrap(), RedisType::from("1.5"));
+    assert_eq!(client.raw(&["ZINCRBY", "zset", "2", "a"]).await.unwrap(), RedisType::from("3.5"));
+    assert_eq!(client.raw(&["ZINCRBY", "zset", "-1", "b"]).await.unwrap(), RedisType::from("-1"));
+    client.raw(&["ZADD", "zset", "1", "c", "2", "d"]).await.unwrap();
+    assert_eq!(client.raw(&["ZSCORE", "zset", "a"]).await.unwrap(), RedisType::from("3.5"));
+
+    // Scores are -1, 1, 2 and 3.5
+    assert_eq!(client.raw(&["ZCOUNT", "zset", "1", "2"]).await.unwrap(), RedisType::Int(2));
+    assert_eq!(client.raw(&["ZCOUNT", "zset", "(1", "2"]).await.unwrap(), RedisType::Int(1));
+    assert_eq!(client.raw(&["ZCOUNT", "zset", "(1", "(2"]).await.unwrap(), RedisType::Int(0));
+    assert_eq!(client.raw(&["ZCOUNT", "zset", "-inf", "+inf"]).await.unwrap(), RedisType::Int(4));
+    assert_eq!(client.raw(&["ZCOUNT", "zset", "(-1", "+inf"]).await.unwrap(), RedisType::Int(3));
+    assert_eq!(client.raw(&["ZCOUNT", "zset", "-inf", "(3.5"]).await.unwrap(), RedisType::Int(3));
+    assert_eq!(client.raw(&["ZCOUNT", "zset", "3", "1"]).await.unwrap(), RedisType::Int(0));
+    assert_eq!(client.raw(&["ZCOUNT", "missing", "-inf", "+inf"]).await.unwrap(), RedisType::Int(0));
+
+    let error = client.raw(&["ZINCRBY", "zset", "lots", "a"]).await.unwrap_err();
+    assert_eq!(error.to_string(), "ERR value is not a valid float");
+    let error = client.raw(&["ZCOUNT", "zset", "(x", "2"]).await.unwrap_err();
+    assert_eq!(error.to_string(), "ERR min or max is not a float");
+    assert_eq!(client.raw(&["ZSCORE", "zset", "a"]).await.unwrap(), RedisType::from("3.5"));
+
+    client.set("string", "value").await.unwrap();
+    assert!(client.raw(&["ZINCRBY", "string", "1", "a"]).await.unwrap_err().to_string().starts_with("WRONGTYPE"));
+    assert!(client.raw(&["ZCOUNT", "string", "0", "1"]).await.unwrap_err().to_string().starts_with("WRONGTYPE"));
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_hash_contents() {
+    let server = Server::builder().bind("127.0.0.1:0").store(Store::default()).run().await.unwrap();
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+
+    let fields = (0..20).map(|n| format!("field{n}")).collect::<Vec<_>>();
+    let mut cmd = vec!["HSET", "hash"];
+    for field in &fields {
+        cmd.extend([field.as_str(), field.as_str()]);
+    }
+    assert_eq!(client.raw(&cmd).await.unwrap(), RedisType::Int(20));
+
+    // Fields and values are in the same order, whatever it is
+    let keys = client.raw(&["HKEYS", "hash"]).await.unwrap();
+    assert_eq!(client.raw(&["HVALS", "hash"]).await.unwrap(), keys);
+    let RedisType::Array(all) = client.raw(&["HGETALL", "hash"]).await.unwrap() else { panic!() };
+    assert_eq!(RedisType::Array(all.into_iter().step_by(2).collect()), keys);
+
+    assert_eq!(client.raw(&["HKEYS", "missing"]).await.unwrap(), RedisType::Array(vec![]));
+    client.set("string", "value").await.unwrap();
+    assert!(client.raw(&["HGETALL", "string"]).await.is_err());
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_publish_subscribe() {
+    let server = Server::builder().bind("127.0.0.1:0").store(Store::default()).run().await.unwrap();
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+    let mut subscriber = TestClient::connect(server.local_addr()).await.unwrap();
+
+    let confirmation = |channel, count| RedisType::Array(vec![RedisType::from("subscribe"), RedisType::from(channel), RedisType::Int(count)]);
+    subscriber.send(&["SUBSCRIBE", "news", "sports"]).await.unwrap();
+    assert_eq!(subscriber.read().await.unwrap(), confirmation("news", 1));
+    assert_eq!(subscriber.read().await.unwrap(), confirmation("sports", 2));
+
+    assert_eq!(client.raw(&["PUBLISH", "news", "hello"]).await.unwrap(), RedisType::Int(1));
+    assert_eq!(client.raw(&["PUBLISH", "weather", "sunny"]).await.unwrap(), RedisType::Int(0));
+    assert_eq!(subscriber.read().await.unwrap(), RedisType::from(vec!["message", "news", "hello"]));
+
+    // Only a few commands are allowed while subscribed
+    assert_eq!(subscriber.raw(&["PING"]).await.unwrap(), RedisType::from(vec!["pong", ""]));
+    assert!(subscriber.raw(&["GET", "key"]).await.is_err());
+    assert_eq!(subscriber.raw(&["SUBSCRIBE", "news"]).await.unwrap(), confirmation("news", 2));
+    assert_eq!(client.raw(&["PUBLISH", "sports", "goal"]).await.unwrap(), RedisType::Int(1));
+    assert_eq!(subscriber.read().await.unwrap(), RedisType::from(vec!["message", "sports", "goal"]));
+
+    // Back to normal once there are no subscriptions left
+    let unsubscribed = |channel, count| RedisType::Array(vec![RedisType::from("unsubscribe"), RedisType::from(channel), RedisType::Int(count)]);
+    assert_eq!(subscriber.raw(&["UNSUBSCRIBE", "news"]).await.unwrap(), unsubscribed("news", 1));
+    assert_eq!(client.raw(&["PUBLISH", "news", "hello"]).await.unwrap(), RedisType::Int(0));
+    assert_eq!(subscriber.raw(&["UNSUBSCRIBE"]).await.unwrap(), unsubscribed("sports", 0));
+    assert_eq!(subscriber.raw(&["PING"]).await.unwrap(), RedisType::from("PONG"));
+    assert_eq!(subscriber.get("key").await.unwrap(), None);
+
+    let psubscribed = RedisType::Array(vec![RedisType::from("psubscribe"), RedisType::from("news.*"), RedisType::Int(1)]);
+    assert_eq!(subscriber.raw(&["PSUBSCRIBE", "news.*"]).await.unwrap(), psubscribed);
+    assert_eq!(client.raw(&["PUBLISH", "news.art", "painting"]).await.unwrap(), RedisType::Int(1));
+    assert_eq!(subscriber.read().await.unwrap(), RedisType::from(vec!["pmessage", "news.*", "news.art", "painting"]));
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_keyspace_notifications() {
+    let server = Server::builder().bind("127.0.0.1:0").store(Store::default()).run().await.unwrap();
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+    let mut subscriber = TestClient::connect(server.local_addr()).await.unwrap();
+
+    assert!(client.raw(&["CONFIG", "SET", "notify-keyspace-events", "KEQ"]).await.is_err());
+    assert_eq!(client.raw(&["CONFIG", "SET", "notify-keyspace-events", "K$l"]).await.unwrap(), RedisType::from("OK"));
+    subscriber.raw(&["PSUBSCRIBE", "__key*__:*"]).await.unwrap();
+
+    client.set("key", "value").await.unwrap();
+    client.raw(&["DEL", "key"]).await.unwrap();
+    client.raw(&["RPUSH", "list", "a"]).await.unwrap();
+    let message = |channel: &str, event| RedisType::from(vec!["pmessage", "__key*__:*", channel, event]);
+    assert_eq!(subscriber.read().await.unwrap(), message("__keyspace@0__:key", "set"));
+    // Generic events like DEL weren't enabled
+    assert_eq!(subscriber.read().await.unwrap(), message("__keyspace@0__:list", "rpush"));
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_transactions() {
+    let server = Server::builder().bind("127.0.0.1:0").store(Store::default()).run().await.unwrap();
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+    let mut other = TestClient::connect(server.local_addr()).await.unwrap();
+
+    assert_eq!(client.raw(&["EXEC"]).await.unwrap_err().to_string(), "ERR EXEC without MULTI");
+    assert_eq!(client.raw(&["MULTI"]).await.unwrap(), RedisType::from("OK"));
+    assert!(client.raw(&["MULTI"]).await.is_err());
+    assert_eq!(client.raw(&["SET", "key", "1"]).await.unwrap(), RedisType::from("QUEUED"));
+    assert_eq!(client.raw(&["INCR", "key"]).await.unwrap(), RedisType::from("QUEUED"));
+    // Nothing runs until EXEC
+    assert_eq!(other.get("key").await.unwrap(), None);
+    assert_eq!(client.raw(&["EXEC"]).await.unwrap(), RedisType::Array(vec![RedisType::from("OK"), RedisType::Int(2)]));
+
+    client.raw(&["MULTI"]).await.unwrap();
+    client.raw(&["INCR", "key"]).await.unwrap();
+    assert_eq!(client.raw(&["DISCARD"]).await.unwrap(), RedisType::from("OK"));
+    assert_eq!(client.get("key").await.unwrap(), Some(String::from("2")));
+
+    // Changes to a watched key make EXEC fail
+    assert_eq!(client.raw(&["WATCH", "key", "missing"]).await.unwrap(), RedisType::from("OK"));
+    other.set("key", "10").await.unwrap();
+    client.raw(&["MULTI"]).await.unwrap();
+    assert!(client.raw(&["WATCH", "key"]).await.is_err());
+    client.raw(&["INCR", "key"]).await.unwrap();
+    assert_eq!(client.raw(&["EXEC"]).await.unwrap(), RedisType::Null);
+    assert_eq!(client.get("key").await.unwrap(), Some(String::from("10")));
+
+    // Watches are gone after EXEC, and with UNWATCH
+    client.raw(&["WATCH", "missing"]).await.unwrap();
+    other.set("missing", "here").await.unwrap();
+    client.raw(&["UNWATCH"]).await.unwrap();
+    client.raw(&["MULTI"]).await.unwrap();
+    client.raw(&["INCR", "key"]).await.unwrap();
+    assert_eq!(client.raw(&["EXEC"]).await.unwrap(), RedisType::Array(vec![RedisType::Int(11)]));
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_binary_values() {
+    let server = Server::builder().bind("127.0.0.1:0").store(Store::default()).run().await.unwrap();
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+
+    // Invalid UTF-8 and null bytes come back as they were sent
+    stream.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nbin\r\n$4\r\n\xff\x00\r\n\r\n").await.unwrap();
+    assert_eq!(read_reply(&mut stream, b"+OK\r\n").await, b"+OK\r\n");
+    stream.write_all(b"*3\r\n$6\r\nAPPEND\r\n$3\r\nbin\r\n$1\r\n\xfe\r\n").await.unwrap();
+    assert_eq!(read_reply(&mut stream, b":5\r\n").await, b":5\r\n");
+    stream.write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nbin\r\n").await.unwrap();
+    assert_eq!(read_reply(&mut stream, b"$5\r\n\xff\x00\r\n\xfe\r\n").await, b"$5\r\n\xff\x00\r\n\xfe\r\n");
+
+    stream.write_all(b"*3\r\n$5\r\nRPUSH\r\n$4\r\nlist\r\n$2\r\n\x00\x80\r\n").await.unwrap();
+    assert_eq!(read_reply(&mut stream, b":1\r\n").await, b":1\r\n");
+    stream.write_all(b"*2\r\n$4\r\nLPOP\r\n$4\r\nlist\r\n").await.unwrap();
+    assert_eq!(read_reply(&mut stream, b"$2\r\n\x00\x80\r\n").await, b"$2\r\n\x00\x80\r\n");
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_binary_keys() {
+    let server = Server::builder().bind("127.0.0.1:0").store(Store::default()).run().await.unwrap();
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+
+    // Both keys would be "k\u{FFFD}" if they went through a lossy conversion
+    stream.write_all(b"*3\r\n$3\r\nSET\r\n$2\r\nk\xff\r\n$2\r\nv1\r\n").await.unwrap();
+    assert_eq!(read_reply(&mut stream, b"+OK\r\n").await, b"+OK\r\n");
+    stream.write_all(b"*3\r\n$3\r\nSET\r\n$2\r\nk\xfe\r\n$2\r\nv2\r\n").await.unwrap();
+    assert_eq!(read_reply(&mut stream, b"+OK\r\n").await, b"+OK\r\n");
+    stream.write_all(b"*2\r\n$3\r\nGET\r\n$2\r\nk\xff\r\n").await.unwrap();
+    assert_eq!(read_reply(&mut stream, b"$2\r\nv1\r\n").await, b"$2\r\nv1\r\n");
+
+    // Same for fields and members
+    stream.write_all(b"*6\r\n$4\r\nHSET\r\n$4\r\nhash\r\n$1\r\n\xff\r\n$1\r\na\r\n$1\r\n\xfe\r\n$1\r\nb\r\n").await.unwrap();
+    assert_eq!(read_reply(&mut stream, b":2\r\n").await, b":2\r\n");
+    stream.write_all(b"*3\r\n$4\r\nHGET\r\n$4\r\nhash\r\n$1\r\n\xff\r\n").await.unwrap();
+    assert_eq!(read_reply(&mut stream, b"$1\r\na\r\n").await, b"$1\r\na\r\n");
+    stream.write_all(b"*4\r\n$4\r\nSADD\r\n$3\r\nset\r\n$1\r\n\xff\r\n$1\r\n\xfe\r\n").await.unwrap();
+    assert_eq!(read_reply(&mut stream, b":2\r\n").await, b":2\r\n");
+    stream.write_all(b"*2\r\n$4\r\nKEYS\r\n$2\r\nk\xff\r\n").await.unwrap();
+    assert_eq!(read_reply(&mut stream, b"*1\r\n$2\r\nk\xff\r\n").await, b"*1\r\n$2\r\nk\xff\r\n");
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_binary_channels() {
+    let server = Server::builder().bind("127.0.0.1:0").store(Store::default()).run().await.unwrap();
+    let mut subscriber = TcpStream::connect(server.local_addr()).await.unwrap();
+    let mut publisher = TcpStream::connect(server.local_addr()).await.unwrap();
+
+    subscriber.write_all(b"*2\r\n$9\r\nSUBSCRIBE\r\n$2\r\nn\xff\r\n").await.unwrap();
+    let confirmation = b"*3\r\n$9\r\nsubscribe\r\n$2\r\nn\xff\r\n:1\r\n";
+    assert_eq!(read_reply(&mut subscriber, confirmation).await, confirmation);
+
+    // Both channels would be "n\u{FFFD}" if they went through a lossy conversion
+    publisher.write_all(b"*3\r\n$7\r\nPUBLISH\r\n$2\r\nn\xfe\r\n$5\r\nhello\r\n").await.unwrap();
+    assert_eq!(read_reply(&mut publisher, b":0\r\n").await, b":0\r\n");
+    publisher.write_all(b"*3\r\n$7\r\nPUBLISH\r\n$2\r\nn\xff\r\n$5\r\nhello\r\n").await.unwrap();
+    assert_eq!(read_reply(&mut publisher, b":1\r\n").await, b":1\r\n");
+    let message = b"*3\r\n$7\r\nmessage\r\n$2\r\nn\xff\r\n$5\r\nhello\r\n";
+    assert_eq!(read_reply(&mut subscriber, message).await, message);
+
+    publisher.write_all(b"*3\r\n$6\r\nPUBSUB\r\n$8\r\nCHANNELS\r\n$2\r\nn?\r\n").await.unwrap();
+    assert_eq!(read_reply(&mut publisher, b"*1\r\n$2\r\nn\xff\r\n").await, b"*1\r\n$2\r\nn\xff\r\n");
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_scan() {
+    let mut config = Configuration::default();
+    config.update("store-shards".into(), "3".into()).unwrap();
+    let server = Server::builder().config(config).bind("127.0.0.1:0").run().await.unwrap();
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+
+    for index in 0..50 {
+        client.set(&format!("key:{index}"), "value").await.unwrap();
+    }
+    client.raw(&["SADD", "set", "member"]).await.unwrap();
+
+    // Every key there from start to end is found, no matter the shard
+    let mut found = vec![];
+    let mut cursor = String::from("0");
+    loop {
+        let RedisType::Array(reply) = client.raw(&["SCAN", &cursor, "COUNT", "7"]).await.unwrap() else { panic!() };
+        let [RedisType::String(next), RedisType::Array(keys)] = &reply[..] else { panic!() };
+        found.extend(keys.clone());
+        client.set(&format!("new:{cursor}"), "value").await.unwrap();
+        cursor = String::from_utf8(next.to_vec()).unwrap();
+        if cursor == "0" {
+            break
+        }
+    }
+    assert!((0..50).all(|index| found.contains(&RedisType::from(format!("key:{index}")))));
+    assert!(found.contains(&RedisType::from("set")));
+
+    let everything = client.raw(&["SCAN", "0", "COUNT", "1000", "MATCH", "key:1*", "TYPE", "string"]).await.unwrap();
+    let RedisType::Array(reply) = everything else { panic!() };
+    assert_eq!(reply[0], RedisType::from("0"));
+    assert!(matches!(&reply[1], RedisType::Array(keys) if keys.len() == 11));
+    let sets = client.raw(&["SCAN", "0", "COUNT", "1000", "TYPE", "SET"]).await.unwrap();
+    assert_eq!(sets, RedisType::Array(vec![RedisType::from("0"), RedisType::from(vec!["set"])]));
+
+    assert!(client.raw(&["SCAN", "x"]).await.is_err());
+    assert!(client.raw(&["SCAN", "0", "COUNT", "0"]).await.is_err());
+    assert!(client.raw(&["SCAN", "0", "TYPE", "blob"]).await.is_err());
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_sharded_store() {
+    let mut config = Configuration::default();
+    config.update("store-shards".into(), "4".into()).unwrap();
+    config.update("notify-keyspace-events".into(), "KEA".into()).unwrap();
+    let mut store = Store::default();
+    store.write(b"loaded", RedisType::from("yes"), None);
+    let server = Server::builder().config(config).bind("127.0.0.1:0").store(store).run().await.unwrap();
+    let mut client = TestClient::connect(server.local_addr()).await.unwrap();
+    let mut other = TestClient::connect(server.local_addr()).await.unwrap();
+
+    let keys = (0..10).map(|n| format!("key{n}")).collect::<Vec<_>>();
+    let keys = keys.iter().map(String::as_str).collect::<Vec<_>>();
+    let mut mset = vec!["MSET"];
+    for key in &keys {
+        mset.extend([*key, *key]);
+    }
+    client.raw(&mset).await.unwrap();
+    assert_eq!(client.get("loaded").await.unwrap(), Some(String::from("yes")));
+    assert_eq!(client.raw(&["DBSIZE"]).await.unwrap(), RedisType::Int(11));
+    let RedisType::Array(found) = client.raw(&["KEYS", "*"]).await.unwrap() else { panic!() };
+    assert_eq!(found.len(), 11);
+    let RedisType::Array(found) = client.raw(&["KEYS", "key[1-3]"]).await.unwrap() else { panic!() };
+    assert_eq!(found.len(), 3);
+    assert_eq!(client.raw(&[&["MGET"], &keys[..]].concat()).await.unwrap(), RedisType::from(keys.clone()));
+
+    // Commands on keys from different shards
+    assert_eq!(client.raw(&["RENAME", "key0", "renamed"]).await.unwrap(), RedisType::from("OK"));
+    assert_eq!(client.get("renamed").await.unwrap(), Some(String::from("key0")));
+    assert_eq!(client.raw(&["EXISTS", "key0", "key1", "renamed"]).await.unwrap(), RedisType::Int(2));
+    assert_eq!(client.raw(&["DEL", "key1", "key2", "key3", "key4"]).await.unwrap(), RedisType::Int(4));
+    let mget = client.raw(&["MGET", "key5", "missing", "renamed", "key5"]).await.unwrap();
+    assert_eq!(mget, RedisType::Array(vec![RedisType::from("key5"), RedisType::Null, RedisType::from("key0"), RedisType::from("key5")]));
+    for n in 0..8 {
+        let copy = format!("copy{n}");
+        assert_eq!(client.raw(&["COPY", "renamed", &copy]).await.unwrap(), RedisType::Int(1));
+        assert_eq!(client.get(&copy).await.unwrap(), Some(String::from("key0")));
+        assert_eq!(client.raw(&["COPY", "key5", &copy]).await.unwrap(), RedisType::Int(0));
+        assert_eq!(client.raw(&["COPY", "key5", &copy, "REPLACE"]).await.unwrap(), RedisType::Int(1));
+        assert_eq!(client.get(&copy).await.unwrap(), Some(String::from("key5")));
+    }
+    assert_eq!(client.raw(&["DEL", "copy0", "copy1", "copy2", "copy3", "copy4", "copy5", "copy6", "copy7"]).await.unwrap(), RedisType::Int(8));
+    for (n, key) in keys[5..].iter().enumerate() {
+        client.raw(&["SADD", &format!("set{n}"), key, "common"]).await.unwrap();
+    }
+    assert_eq!(client.raw(&["SUNIONSTORE", "union", "set0", "set1", "set2", "set3"]).await.unwrap(), RedisType::Int(5));
+    assert_eq!(client.raw(&["SCARD", "union"]).await.unwrap(), RedisType::Int(5));
+
+    // Blocked clients are woken up by whichever shard gets the elements
+    other.send(&["BLPOP", "list0", "list1", "list2", "list3", "0"]).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    client.raw(&["RPUSH", "list3", "a"]).await.unwrap();
+    assert_eq!(other.read().await.unwrap(), RedisType::from(vec!["list3", "a"]));
+    client.raw(&["RPUSH", "list2", "b"]).await.unwrap();
+    assert_eq!(client.raw(&["LMOVE", "list2", "list9", "LEFT", "LEFT"]).await.unwrap(), RedisType::from("b"));
+    assert_eq!(client.raw(&["LRANGE", "list9", "0", "-1"]).await.unwrap(), RedisType::from(vec!["b"]));
+
+    // Transactions see all the shards, and WATCH as well
+    client.raw(&["WATCH", "key5", "key6", "key7"]).await.unwrap();
+    other.set("key6", "changed").await.unwrap();
+    client.raw(&["MULTI"]).await.unwrap();
+    client.raw(&["DEL", "key5", "key7"]).await.unwrap();
+    assert_eq!(client.raw(&["EXEC"]).await.unwrap(), RedisType::Null);
+    client.raw(&["MULTI"]).await.unwrap();
+    client.raw(&["DEL", "key5", "key7"]).await.unwrap();
+    client.raw(&["SET", "key8", "last"]).await.unwrap();
+    assert_eq!(client.raw(&["EXEC"]).await.unwrap(), RedisType::Array(vec![RedisType::Int(2), RedisType::from("OK")]));
+
+    // Notifications come from every shard
+    other.raw(&["SUBSCRIBE", "__keyevent@0__:del"]).await.unwrap();
+    client.raw(&["DEL", "key6", "key8", "key9"]).await.unwrap();
+    let mut deleted = vec![];
+    for _ in 0..3 {
+        let RedisType::Array(message) = other.read().await.unwrap() else { panic!() };
+        deleted.push(message[2].clone());
+    }
+    deleted.sort_by_key(|key| format!("{key:?}"));
+    assert_eq!(RedisType::Array(deleted), RedisType::from(vec!["key6", "key8", "key9"]));
+
+    assert_ne!(client.raw(&["RANDOMKEY"]).await.unwrap(), RedisType::Null);
+    assert_eq!(client.raw(&["FLUSHDB"]).await.unwrap(), RedisType::from("OK"));
+    assert_eq!(client.raw(&["DBSIZE"]).await.unwrap(), RedisType::Int(0));
+    assert_eq!(client.raw(&["RANDOMKEY"]).await.unwrap(), RedisType::Null);
+
+    server.shutdown().await.unwrap();
+}
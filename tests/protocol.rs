@@ -0,0 +1,281 @@
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use redis_starter_rust::{config::Configuration, server::Server, store::Store};
+
+async fn read_until_closed(stream: &mut TcpStream) -> Vec<u8> {
+    let mut buf = vec![];
+    stream.read_to_end(&mut buf).await.unwrap();
+    buf
+}
+
+#[tokio::test]
+async fn test_too_many_arguments() {
+    let mut config = Configuration::default();
+    config.update("max-command-args".into(), "3".into()).unwrap();
+    let server = Server::builder()
+        .config(config)
+        .bind("127.0.0.1:0")
+        .store(Store::default())
+        .run()
+        .await
+        .unwrap();
+
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+
+    // Commands within the limit are fine
+    stream.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n").await.unwrap();
+    let mut buf = [0; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"+OK\r\n");
+
+    // The count alone is enough to reject the command and close the connection
+    stream.write_all(b"*1000000\r\n").await.unwrap();
+    assert_eq!(read_until_closed(&mut stream).await, b"-ERR Protocol error: too many arguments\r\n");
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_bulk_too_long() {
+    let mut config = Configuration::default();
+    config.update("proto-max-bulk-len".into(), "1kb".into()).unwrap();
+    let server = Server::builder()
+        .config(config)
+        .bind("127.0.0.1:0")
+        .store(Store::default())
+        .run()
+        .await
+        .unwrap();
+
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+
+    stream.write_all(b"*2\r\n$4\r\nECHO\r\n$1024\r\n").await.unwrap();
+    stream.write_all(&[b'a'; 1024]).await.unwrap();
+    stream.write_all(b"\r\n").await.unwrap();
+    let mut buf = vec![0; 1024 + 9];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert!(buf.starts_with(b"$1024\r\na"));
+
+    // The length alone is enough to reject the command and close the connection
+    stream.write_all(b"*2\r\n$4\r\nECHO\r\n$1000000000000\r\n").await.unwrap();
+    assert_eq!(read_until_closed(&mut stream).await, b"-ERR Protocol error: invalid bulk length\r\n");
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_inline_request_too_big() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0")
+        .store(Store::default())
+        .run()
+        .await
+        .unwrap();
+
+    let stream = TcpStream::connect(server.local_addr()).await.unwrap();
+    let (mut reader, mut writer) = stream.into_split();
+
+    // The server gives up before reading everything, so the writes may fail
+    let writer = tokio::spawn(async move {
+        let _ = writer.write_all(&vec![b'a'; 128 * 1024]).await;
+        writer
+    });
+
+    let mut reply = vec![];
+    let _ = reader.read_to_end(&mut reply).await;
+    assert_eq!(reply, b"-ERR Protocol error: too big inline request\r\n");
+
+    drop(writer.await.unwrap());
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_empty_commands() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0")
+        .store(Store::default())
+        .run()
+        .await
+        .unwrap();
+
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+
+    // None of these get a reply, and the connection stays open
+    stream.write_all(b"*0\r\n\r\n*-1\r\n   \r\n").await.unwrap();
+    stream.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+    let mut buf = [0; 7];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"+PONG\r\n");
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_pipelining() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0")
+        .store(Store::default())
+        .run()
+        .await
+        .unwrap();
+
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+
+    // All at once, with errors and skipped replies along the way
+    stream.write_all(concat!(
+        "SET counter 1\r\n",
+        "INCR counter\r\n",
+        "LPUSH counter a\r\n",
+        "GET\r\n",
+        "CLIENT REPLY SKIP\r\n",
+        "INCR counter\r\n",
+        "GET counter\r\n",
+    ).as_bytes()).await.unwrap();
+    let expected = concat!(
+        "+OK\r\n",
+        ":2\r\n",
+        "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
+        "-ERR wrong number of arguments for 'get' command\r\n",
+        "$1\r\n3\r\n",
+    );
+    let mut buf = vec![0; expected.len()];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(String::from_utf8_lossy(&buf), expected);
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_inline_quoting() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0")
+        .store(Store::default())
+        .run()
+        .await
+        .unwrap();
+
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+
+    stream.write_all(b"ECHO \"hello world\"\r\nECHO \"\"\r\n").await.unwrap();
+    let expected = b"$11\r\nhello world\r\n$0\r\n\r\n";
+    let mut buf = vec![0; expected.len()];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, expected);
+
+    stream.write_all(b"ECHO \"hello\r\n").await.unwrap();
+    assert_eq!(read_until_closed(&mut stream).await, b"-ERR Protocol error: unbalanced quotes in request\r\n");
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_hello() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0")
+        .store(Store::default())
+        .run()
+        .await
+        .unwrap();
+
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+
+    stream.write_all(b"HELLO 4\r\n").await.unwrap();
+    let expected = b"-NOPROTO unsupported protocol version\r\n";
+    let mut buf = vec![0; expected.len()];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, expected);
+
+    // The reply is a map once RESP3 is on
+    stream.write_all(b"HELLO 3 AUTH default secret SETNAME conn\r\nCLIENT GETNAME\r\n").await.unwrap();
+    let expected: &[u8] = b"%7\r\n$6\r\nserver\r\n$18\r\ncodecrafters-redis\r\n$7\r\nversion\r\n$3\r\n0.2\r\n\
+        $5\r\nproto\r\n:3\r\n$2\r\nid\r\n:0\r\n$4\r\nmode\r\n$10\r\nstandalone\r\n$4\r\nrole\r\n$6\r\nmaster\r\n\
+        $7\r\nmodules\r\n*0\r\n$4\r\nconn\r\n";
+    let mut buf = vec![0; expected.len()];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, expected);
+
+    stream.write_all(b"HELLO 2\r\n").await.unwrap();
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"*14\r");
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_resp3_replies() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0")
+        .store(Store::default())
+        .run()
+        .await
+        .unwrap();
+
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+
+    stream.write_all(b"HSET hash field value\r\nHGETALL hash\r\nCONFIG GET dbfilename\r\n").await.unwrap();
+    let expected = b":1\r\n*2\r\n$5\r\nfield\r\n$5\r\nvalue\r\n*2\r\n$10\r\ndbfilename\r\n$8\r\ndump.rdb\r\n";
+    let mut buf = vec![0; expected.len()];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, expected);
+
+    // Same replies, as maps
+    stream.write_all(b"HELLO 3 SETNAME conn\r\nCLIENT GETNAME\r\n").await.unwrap();
+    let mut buf = vec![];
+    while !buf.ends_with(b"$4\r\nconn\r\n") {
+        assert!(stream.read_buf(&mut buf).await.unwrap() > 0);
+    }
+    stream.write_all(b"HGETALL hash\r\nCONFIG GET dbfilename\r\n").await.unwrap();
+    let expected = b"%1\r\n$5\r\nfield\r\n$5\r\nvalue\r\n%1\r\n$10\r\ndbfilename\r\n$8\r\ndump.rdb\r\n";
+    let mut buf = vec![0; expected.len()];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, expected);
+
+    stream.write_all(b"ZADD zset 1.5 a\r\nZSCORE zset a\r\nGET missing\r\n").await.unwrap();
+    let expected = b":1\r\n,1.5\r\n_\r\n";
+    let mut buf = vec![0; expected.len()];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, expected);
+
+    stream.write_all(b"MGET nokey\r\nKEYS nokey*\r\nSCAN 0 MATCH nokey*\r\n").await.unwrap();
+    let expected = b"*1\r\n_\r\n*0\r\n*2\r\n$1\r\n0\r\n*0\r\n";
+    let mut buf = vec![0; expected.len()];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, expected);
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_pop_count_on_missing_key() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0")
+        .store(Store::default())
+        .run()
+        .await
+        .unwrap();
+
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+
+    stream.write_all(b"LPOP missing\r\nLPOP missing 2\r\nRPOP missing 2\r\n").await.unwrap();
+    let expected = b"$-1\r\n*-1\r\n*-1\r\n";
+    let mut buf = vec![0; expected.len()];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, expected);
+
+    stream.write_all(b"HELLO 3\r\n").await.unwrap();
+    let mut buf = vec![];
+    while !buf.ends_with(b"*0\r\n") {
+        assert!(stream.read_buf(&mut buf).await.unwrap() > 0);
+    }
+    stream.write_all(b"LPOP missing 2\r\nRPUSH list a\r\nLPOP list 2\r\n").await.unwrap();
+    let expected = b"_\r\n:1\r\n*1\r\n$1\r\na\r\n";
+    let mut buf = vec![0; expected.len()];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, expected);
+
+    server.shutdown().await.unwrap();
+}
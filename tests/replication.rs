@@ -0,0 +1,201 @@
+use std::time::Duration;
+
+use tokio::{io::{AsyncReadExt, BufReader}, net::TcpStream};
+
+use redis_starter_rust::{
+    config::Configuration,
+    io::get_string,
+    rdb::Rdb,
+    server::{Server, ServerHandle},
+    store::Store,
+    testclient::TestClient,
+    types::RedisType,
+};
+
+const KEYS: usize = 2000;
+const VALUE_SIZE: usize = 2048;
+
+fn value_for(index: usize) -> String {
+    format!("{index:08}").repeat(VALUE_SIZE / 8)
+}
+
+async fn start_master(diskless: bool) -> ServerHandle {
+    let mut config = Configuration::default();
+    config.update("repl-diskless-sync".into(), (if diskless { "yes" } else { "no" }).into()).unwrap();
+
+    // ~4MB of data
+    let mut store = Store::default();
+    for index in 0..KEYS {
+        store.write(format!("key:{index}").as_bytes(), RedisType::from(value_for(index)), None);
+    }
+
+    Server::builder().config(config).store(store).bind("127.0.0.1:0").run().await.unwrap()
+}
+
+async fn start_replica(master: &ServerHandle) -> ServerHandle {
+    let mut config = Configuration::default();
+    config.update("replicaof".into(), master.local_addr().to_string()).unwrap();
+
+    Server::builder().config(config).bind("127.0.0.1:0").run().await.unwrap()
+}
+
+async fn wait_for_keys(client: &mut TestClient, count: usize) {
+    for _ in 0..100 {
+        if let RedisType::Array(keys) = client.raw(&["KEYS", "*"]).await.unwrap() {
+            if keys.len() == count {
+                return
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("The replica didn't receive all the keys in time");
+}
+
+async fn check_full_sync(diskless: bool) {
+    let master = start_master(diskless).await;
+    let replica = start_replica(&master).await;
+    let mut client = TestClient::connect(replica.local_addr()).await.unwrap();
+
+    wait_for_keys(&mut client, KEYS).await;
+    for index in [0, KEYS / 2, KEYS - 1] {
+        let value = client.get(&format!("key:{index}")).await.unwrap();
+        assert_eq!(value, Some(value_for(index)));
+    }
+
+    // Commands are replicated after the initial sync
+    let mut master_client = TestClient::connect(master.local_addr()).await.unwrap();
+    master_client.set("after-sync", "yes").await.unwrap();
+    wait_for_keys(&mut client, KEYS + 1).await;
+
+    replica.shutdown().await.unwrap();
+    master.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_full_sync_from_memory() {
+    check_full_sync(false).await;
+}
+
+#[tokio::test]
+async fn test_full_sync_diskless() {
+    check_full_sync(true).await;
+}
+
+#[tokio::test]
+async fn test_full_sync_aggregates() {
+    for diskless in [false, true] {
+        let mut config = Configuration::default();
+        config.update("repl-diskless-sync".into(), (if diskless { "yes" } else { "no" }).into()).unwrap();
+        let master = Server::builder().config(config).store(Store::default()).bind("127.0.0.1:0").run().await.unwrap();
+        let mut master_client = TestClient::connect(master.local_addr()).await.unwrap();
+        master_client.raw(&["RPUSH", "list", "a", "b"]).await.unwrap();
+        master_client.raw(&["SADD", "set", "member"]).await.unwrap();
+        master_client.raw(&["HSET", "hash", "field", "value"]).await.unwrap();
+        master_client.raw(&["ZADD", "zset", "2.5", "member"]).await.unwrap();
+
+        let replica = start_replica(&master).await;
+        let mut client = TestClient::connect(replica.local_addr()).await.unwrap();
+        wait_for_keys(&mut client, 4).await;
+        assert_eq!(client.raw(&["LRANGE", "list", "0", "-1"]).await.unwrap(), RedisType::from(vec!["a", "b"]));
+        assert_eq!(client.raw(&["SMEMBERS", "set"]).await.unwrap(), RedisType::from(vec!["member"]));
+        assert_eq!(client.raw(&["HGET", "hash", "field"]).await.unwrap(), RedisType::from("value"));
+        assert_eq!(client.raw(&["ZSCORE", "zset", "member"]).await.unwrap(), RedisType::from("2.5"));
+
+        replica.shutdown().await.unwrap();
+        master.shutdown().await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_debug_replicate() {
+    let mut config = Configuration::default();
+    config.update("enable-debug-replicate".into(), "yes".into()).unwrap();
+    let master = Server::builder().config(config).store(Store::default()).bind("127.0.0.1:0").run().await.unwrap();
+    let replica = start_replica(&master).await;
+    let mut client = TestClient::connect(replica.local_addr()).await.unwrap();
+    let mut master_client = TestClient::connect(master.local_addr()).await.unwrap();
+
+    // Make sure the replica is connected before injecting anything
+    master_client.set("synced", "yes").await.unwrap();
+    wait_for_keys(&mut client, 1).await;
+
+    master_client.raw(&["DEBUG", "REPLICATE", "SET", "injected", "value"]).await.unwrap();
+    wait_for_keys(&mut client, 2).await;
+    assert_eq!(client.get("injected").await.unwrap(), Some(String::from("value")));
+    // The master doesn't run the command
+    assert_eq!(master_client.get("injected").await.unwrap(), None);
+
+    replica.shutdown().await.unwrap();
+    master.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_debug_replicate_disabled() {
+    let master = Server::builder().store(Store::default()).bind("127.0.0.1:0").run().await.unwrap();
+    let mut client = TestClient::connect(master.local_addr()).await.unwrap();
+
+    assert!(client.raw(&["DEBUG", "REPLICATE", "SET", "injected", "value"]).await.is_err());
+
+    master.shutdown().await.unwrap();
+}
+
+fn replica_offset(info: &str) -> Option<usize> {
+    info.lines()
+        .find_map(|line| line.strip_prefix("slave0:"))?
+        .split(',')
+        .find_map(|field| field.strip_prefix("offset="))?
+        .parse()
+        .ok()
+}
+
+async fn wait_for_offset(client: &mut TestClient, expected: usize) {
+    for _ in 0..50 {
+        let RedisType::String(info) = client.raw(&["INFO", "replication"]).await.unwrap() else {
+            panic!("INFO should return a bulk string")
+        };
+        if replica_offset(&String::from_utf8_lossy(&info)) == Some(expected) {
+            return
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("The master didn't get the replica offset {expected} in time");
+}
+
+#[tokio::test]
+async fn test_periodic_ack() {
+    let master = Server::builder().store(Store::default()).bind("127.0.0.1:0").run().await.unwrap();
+    let replica = start_replica(&master).await;
+    let mut master_client = TestClient::connect(master.local_addr()).await.unwrap();
+
+    // The replica reports its offset on its own, without a GETACK
+    wait_for_offset(&mut master_client, 0).await;
+    master_client.set("foo", "bar").await.unwrap();
+    wait_for_offset(&mut master_client, RedisType::from(vec!["SET", "foo", "bar"]).to_vec().len()).await;
+
+    replica.shutdown().await.unwrap();
+    master.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_rdb_repl_id_matches_fullresync() {
+    let mut store = Store::default();
+    store.write(b"foo", RedisType::from("bar"), None);
+    let master = Server::builder().store(store).bind("127.0.0.1:0").run().await.unwrap();
+
+    let mut stream = BufReader::new(TcpStream::connect(master.local_addr()).await.unwrap());
+    RedisType::from(vec!["PSYNC", "?", "-1"]).write(&mut stream).await.unwrap();
+    let fullresync = get_string(&mut stream).await.unwrap().unwrap().string;
+    let id = fullresync.split(' ').nth(1).unwrap().to_string();
+
+    let length = get_string(&mut stream).await.unwrap().unwrap().string;
+    let length = length.strip_prefix('$').unwrap().parse::<usize>().unwrap();
+    let mut rdb = vec![0; length];
+    stream.read_exact(&mut rdb).await.unwrap();
+
+    let rdb = Rdb::from_reader(rdb.as_slice()).await.unwrap();
+    assert_eq!(rdb.metadata().get("repl-id"), Some(&id));
+    assert_eq!(rdb.metadata().get("repl-offset"), Some(&String::from("0")));
+
+    drop(stream);
+    master.shutdown().await.unwrap();
+}